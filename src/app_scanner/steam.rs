@@ -53,6 +53,8 @@ pub fn scan_steam_applications(config: &SteamApplicationScannerConfig) -> Result
 			continue;
 		}
 
+		application.category = Some("Steam".to_string());
+
 		if let Some(run_before) = &config.run_before {
 			application.run_before = Some(
 				run_before
@@ -0,0 +1,266 @@
+use std::{fs::{OpenOptions, Permissions}, io::Write, os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt}, path::Path, time::{Duration, SystemTime}};
+
+use openssl::{asn1::Asn1Time, pkey::{PKey, Private}, x509::X509};
+use tokio::sync::watch;
+
+use crate::{config::WebserverConfig, crypto::{create_certificate, create_private_key}, publisher};
+
+/// Number of days a newly created certificate is valid.
+const VALIDITY_DAYS: u32 = 3650;
+
+/// Certificates are renewed when they expire within this number of days.
+const RENEW_BEFORE_DAYS: u32 = 30;
+
+/// Interval at which the certificate is checked for changes on disk and for expiry.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Permissions of the private key, which only its owner can read.
+const PRIVATE_KEY_MODE: u32 = 0o600;
+
+/// Permissions of the certificate, which is public.
+const CERTIFICATE_MODE: u32 = 0o644;
+
+/// Certificate and private key used by the server.
+#[derive(Clone)]
+pub struct ServerIdentity {
+	pub certificate: X509,
+	pub private_key: PKey<Private>,
+}
+
+/// Load the server certificate and private key, creating or renewing them when necessary.
+pub fn load_or_create(config: &WebserverConfig) -> Result<ServerIdentity, ()> {
+	if !config.certificate.exists() && !config.private_key.exists() {
+		tracing::info!("No certificate found, creating a new one.");
+		return regenerate(config);
+	}
+
+	if config.private_key.exists() {
+		secure_private_key(config)?;
+	}
+
+	let identity = load(config)?;
+	if expires_soon(&identity.certificate)? {
+		tracing::info!("Certificate expires within {RENEW_BEFORE_DAYS} days, renewing it.");
+		return regenerate(config);
+	}
+
+	if identity.certificate.subject_alt_names().is_none() {
+		tracing::info!(
+			"Certificate doesn't contain the hostname or addresses of this host, \
+			use the `regen-cert` command to create a new certificate."
+		);
+	}
+
+	Ok(identity)
+}
+
+/// Create a new certificate and save it, reusing the existing private key if there is one.
+///
+/// Clients store the server certificate when pairing, so they have to pair again after the certificate changed.
+/// Certificates of paired clients are stored separately and remain trusted.
+pub fn regenerate(config: &WebserverConfig) -> Result<ServerIdentity, ()> {
+	let private_key = if config.private_key.exists() {
+		load_private_key(&config.private_key)?
+	} else {
+		create_private_key()
+			.map_err(|e| tracing::error!("Failed to create private key: {e}"))?
+	};
+
+	let host_name = publisher::host_name().unwrap_or_else(|| "moonshine".to_string());
+	let addresses = publisher::network_addresses();
+	let certificate = create_certificate(&private_key, &host_name, &addresses, VALIDITY_DAYS)
+		.map_err(|e| tracing::error!("Failed to create certificate: {e}"))?;
+
+	let identity = ServerIdentity { certificate, private_key };
+	save(config, &identity)?;
+	tracing::info!("Created a new certificate for '{host_name}' with addresses {addresses:?}.");
+
+	Ok(identity)
+}
+
+/// Periodically check the certificate, reloading it when it changed on disk and renewing it before it expires.
+///
+/// Changes are published through `identity_tx`, so that listeners can use the new certificate without restarting.
+pub fn spawn_watcher(config: WebserverConfig, identity_tx: watch::Sender<ServerIdentity>) {
+	tokio::spawn(async move {
+		let mut last_modified = modified(&config);
+
+		while !identity_tx.is_closed() {
+			tokio::time::sleep(CHECK_INTERVAL).await;
+
+			let identity = if modified(&config) != last_modified {
+				tracing::info!("Certificate changed on disk, reloading it.");
+				secure_private_key(&config).and_then(|()| load(&config))
+			} else if expires_soon(&identity_tx.borrow().certificate).unwrap_or(false) {
+				tracing::info!("Certificate expires within {RENEW_BEFORE_DAYS} days, renewing it.");
+				regenerate(&config)
+			} else {
+				continue;
+			};
+
+			last_modified = modified(&config);
+			if let Ok(identity) = identity {
+				identity_tx.send_replace(identity);
+			}
+		}
+
+		tracing::debug!("Stopped watching certificate.");
+	});
+}
+
+/// Check that the certificate and private key can be used, explaining how to fix them when they can't.
+pub fn check(config: &WebserverConfig) -> Result<(), ()> {
+	if !config.certificate.exists() && !config.private_key.exists() {
+		tracing::info!("No certificate found, a new one is created when the server starts.");
+		return Ok(());
+	}
+
+	let identity = load(config)
+		.map_err(|()| tracing::error!("Make sure the certificate and private key are readable, or remove both to create new ones."))?;
+
+	let matches = identity.certificate.public_key()
+		.is_ok_and(|public_key| public_key.public_eq(&identity.private_key));
+	if !matches {
+		tracing::error!("The private key doesn't belong to the certificate, create a new certificate with `moonshine regen-cert`.");
+		return Err(());
+	}
+
+	let now = Asn1Time::days_from_now(0)
+		.map_err(|e| tracing::error!("Failed to get the current time: {e}"))?;
+	if identity.certificate.not_after() < now {
+		tracing::error!("The certificate expired on {}, create a new certificate with `moonshine regen-cert`.", identity.certificate.not_after());
+		return Err(());
+	}
+	if expires_soon(&identity.certificate)? {
+		tracing::warn!("The certificate expires on {}, it is renewed when the server starts.", identity.certificate.not_after());
+	} else {
+		tracing::info!("The certificate is valid until {}.", identity.certificate.not_after());
+	}
+
+	Ok(())
+}
+
+fn load(config: &WebserverConfig) -> Result<ServerIdentity, ()> {
+	let certificate = std::fs::read(&config.certificate)
+		.map_err(|e| tracing::error!("Failed to read server certificate: {e}"))?;
+	let certificate = X509::from_pem(&certificate)
+		.map_err(|e| tracing::error!("Failed to parse server certificate: {e}"))?;
+
+	let private_key = load_private_key(&config.private_key)?;
+
+	Ok(ServerIdentity { certificate, private_key })
+}
+
+fn load_private_key(path: &Path) -> Result<PKey<Private>, ()> {
+	let private_key = std::fs::read(path)
+		.map_err(|e| tracing::error!("Failed to read private key: {e}"))?;
+	PKey::private_key_from_pem(&private_key)
+		.map_err(|e| tracing::error!("Failed to parse private key: {e}"))
+}
+
+fn save(config: &WebserverConfig, identity: &ServerIdentity) -> Result<(), ()> {
+	let private_key = identity.private_key.private_key_to_pem_pkcs8()
+		.map_err(|e| tracing::error!("Failed to serialize private key: {e}"))?;
+	write_file(&config.private_key, &private_key, PRIVATE_KEY_MODE)?;
+	tracing::debug!("Saved private key to {}", config.private_key.display());
+
+	let certificate = identity.certificate.to_pem()
+		.map_err(|e| tracing::error!("Failed to serialize PEM: {e}"))?;
+	write_file(&config.certificate, &certificate, CERTIFICATE_MODE)?;
+	tracing::debug!("Saved certificate to {}", config.certificate.display());
+
+	Ok(())
+}
+
+/// Write a file with permissions `mode` by writing to a temporary file first, so that readers never see a partially written file.
+fn write_file(path: &Path, contents: &[u8], mode: u32) -> Result<(), ()> {
+	let directory = path.parent()
+		.ok_or_else(|| tracing::error!("Failed to find parent directory for {}.", path.display()))?;
+	std::fs::create_dir_all(directory)
+		.map_err(|e| tracing::error!("Failed to create directory {}: {e}", directory.display()))?;
+
+	let temporary_path = path.with_extension("tmp");
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.mode(mode)
+		.open(&temporary_path)
+		.map_err(|e| tracing::error!("Failed to create {}: {e}", temporary_path.display()))?;
+
+	// The mode only applies to new files, a temporary file that was left behind keeps its permissions otherwise.
+	file.set_permissions(Permissions::from_mode(mode))
+		.map_err(|e| tracing::error!("Failed to set the permissions of {}: {e}", temporary_path.display()))?;
+	file.write_all(contents)
+		.map_err(|e| tracing::error!("Failed to write {}: {e}", temporary_path.display()))?;
+	std::fs::rename(&temporary_path, path)
+		.map_err(|e| tracing::error!("Failed to move {} to {}: {e}", temporary_path.display(), path.display()))
+}
+
+/// Make sure that other users can't read the private key, and warn when it belongs to another user.
+///
+/// Older versions wrote the private key with the default permissions, which usually let everyone read it.
+/// Those permissions are restricted when the key belongs to us, otherwise the key is only used if `allow_insecure_private_key` is set.
+fn secure_private_key(config: &WebserverConfig) -> Result<(), ()> {
+	let path = &config.private_key;
+	let metadata = std::fs::metadata(path)
+		.map_err(|e| tracing::error!("Failed to read the permissions of {}: {e}", path.display()))?;
+	let mode = metadata.mode() & 0o777;
+	let owner = metadata.uid();
+	let user = unsafe { libc::geteuid() };
+	// Another user that owns the key can read it or change it, regardless of its permissions.
+	if owner != user {
+		if config.allow_insecure_private_key {
+			tracing::warn!(
+				"Private key {} belongs to user {owner}, while moonshine runs as user {user}. Using it because `allow_insecure_private_key` is set.",
+				path.display(),
+			);
+		} else {
+			tracing::error!(
+				"Private key {} belongs to user {owner}, while moonshine runs as user {user}. Run `chown {user} {}`, or set `allow_insecure_private_key` in the webserver configuration to use it anyway.",
+				path.display(), path.display(),
+			);
+			return Err(());
+		}
+	}
+
+	if mode & 0o007 == 0 {
+		return Ok(());
+	}
+
+	if owner == user {
+		match std::fs::set_permissions(path, Permissions::from_mode(PRIVATE_KEY_MODE)) {
+			Ok(()) => {
+				tracing::info!("Other users could read private key {}, changed its permissions from {mode:o} to {PRIVATE_KEY_MODE:o}.", path.display());
+				return Ok(());
+			},
+			Err(e) => tracing::warn!("Failed to restrict the permissions of private key {}: {e}", path.display()),
+		}
+	}
+
+	if config.allow_insecure_private_key {
+		tracing::warn!("Other users can read private key {} (permissions {mode:o}), using it because `allow_insecure_private_key` is set.", path.display());
+		return Ok(());
+	}
+
+	tracing::error!(
+		"Other users can read private key {} (permissions {mode:o}). Run `chmod {PRIVATE_KEY_MODE:o} {}`, or set `allow_insecure_private_key` in the webserver configuration to use it anyway.",
+		path.display(), path.display(),
+	);
+	Err(())
+}
+
+fn expires_soon(certificate: &X509) -> Result<bool, ()> {
+	let threshold = Asn1Time::days_from_now(RENEW_BEFORE_DAYS)
+		.map_err(|e| tracing::error!("Failed to compute certificate renewal time: {e}"))?;
+
+	Ok(certificate.not_after() < threshold)
+}
+
+/// Time at which the certificate or private key was last modified.
+fn modified(config: &WebserverConfig) -> Option<SystemTime> {
+	let certificate = std::fs::metadata(&config.certificate).and_then(|metadata| metadata.modified()).ok();
+	let private_key = std::fs::metadata(&config.private_key).and_then(|metadata| metadata.modified()).ok();
+
+	certificate.max(private_key)
+}
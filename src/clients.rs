@@ -1,19 +1,49 @@
-use std::{sync::Arc, collections::BTreeMap};
+use std::{sync::Arc, collections::{BTreeMap, HashMap}, net::{IpAddr, Ipv6Addr, SocketAddr}, time::{Duration, Instant}};
 
 use async_shutdown::TriggerShutdownToken;
-use openssl::{hash::MessageDigest, pkey::{PKey, PKeyRef, Private}, md::Md, md_ctx::MdCtx, x509::X509, cipher::Cipher};
-use tokio::sync::{oneshot, mpsc, Notify};
+use openssl::{hash::MessageDigest, pkey::PKeyRef, md::Md, md_ctx::MdCtx, x509::{X509, X509Ref}, cipher::Cipher};
+use tokio::sync::{oneshot, mpsc, watch, Notify};
 
-use crate::{crypto::{encrypt, decrypt}, state::State};
+use crate::{certificate::ServerIdentity, crypto::{create_pairing_key, encrypt, decrypt, secrets_equal}, state::{unix_time, ClientDevice, State}};
+
+/// Time a client has to complete pairing after it started pairing.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Number of failed pairing attempts a client can make before it has to wait between attempts.
+const FREE_PAIRING_ATTEMPTS: u32 = 3;
+
+/// Time a client has to wait after its first attempt beyond the free attempts, doubled for every next failure.
+const PAIRING_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Maximum time a client has to wait between pairing attempts.
+const MAX_PAIRING_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Time after the last failed attempt from an address, after which its failures are forgotten.
+const PAIRING_ATTEMPTS_EXPIRY: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Time in which a client has to start pairing with the PIN of a pairing QR code.
+pub const PAIRING_TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
 
 /// A client that is not yet paired, but in the pairing process.
 pub struct PendingClient {
 	/// Unique id of the client.
 	pub id: String,
 
+	/// Name of the device, as reported by the client.
+	pub name: String,
+
+	/// Address the client started pairing from, failed attempts are counted per address.
+	pub address: IpAddr,
+
+	/// Time at which the client started pairing, pairing has to complete within `PAIRING_TIMEOUT`.
+	pub started: Instant,
+
 	/// Client certificate used for secure communication.
 	pub pem: X509,
 
+	/// Token of a pairing QR code that the client presented, to pair with the PIN of that QR code.
+	pub pairing_token: Option<String>,
+
 	/// Salt provided by the client to use for encryption.
 	pub salt: [u8; 16],
 
@@ -37,6 +67,28 @@ pub struct PendingClient {
 	pub client_hash: Option<Vec<u8>>,
 }
 
+/// A paired device that connected to the HTTPS server.
+#[derive(Clone, Debug)]
+pub struct ConnectedDevice {
+	/// SHA-256 fingerprint of the certificate of the device.
+	pub fingerprint: String,
+
+	/// Name of the device, if it is known.
+	pub name: Option<String>,
+}
+
+impl ConnectedDevice {
+	/// Check if the device is in a list of fingerprints or names.
+	///
+	/// Fingerprints are compared case insensitively, with or without colons between the bytes.
+	pub fn is_listed(&self, list: &[String]) -> bool {
+		list.iter().any(|entry| {
+			entry.replace(':', "").eq_ignore_ascii_case(&self.fingerprint)
+				|| self.name.as_ref().is_some_and(|name| name == entry)
+		})
+	}
+}
+
 pub enum ClientManagerCommand {
 	/// Check if a client is already paired.
 	IsPaired(IsPairedCommand),
@@ -59,6 +111,18 @@ pub enum ClientManagerCommand {
 	/// Add a client to the list of paired clients.
 	AddClient(AddClientCommand),
 
+	/// Check if a certificate belongs to a paired client.
+	IsCertificatePaired(IsCertificatePairedCommand),
+
+	/// Record that a paired device connected.
+	DeviceSeen(DeviceSeenCommand),
+
+	/// Get the devices that paired with this host.
+	GetDevices(GetDevicesCommand),
+
+	/// Create a PIN that a client can pair with by presenting a token, without entering the PIN on the host.
+	CreatePairingToken(CreatePairingTokenCommand),
+
 	// /// Remove client from the list of paired clients.
 	// RemoveClient(RemoveClientCommand),
 }
@@ -135,6 +199,49 @@ pub struct AddClientCommand {
 	pub response: oneshot::Sender<Result<(), String>>,
 }
 
+/// Query the manager to check if a certificate belongs to a paired client.
+pub struct IsCertificatePairedCommand {
+	/// Certificate presented by the client.
+	pub certificate: X509,
+
+	/// Channel used to provide a response.
+	pub response: oneshot::Sender<Result<bool, String>>,
+}
+
+/// Record that a paired device connected.
+pub struct DeviceSeenCommand {
+	/// Certificate presented by the device.
+	pub certificate: X509,
+
+	/// Address the device connected from.
+	pub address: SocketAddr,
+
+	/// Channel used to provide the device.
+	pub response: oneshot::Sender<Result<ConnectedDevice, String>>,
+}
+
+/// Get the devices that paired with this host.
+pub struct GetDevicesCommand {
+	/// Channel used to provide the devices.
+	pub response: oneshot::Sender<Result<Vec<ClientDevice>, String>>,
+}
+
+/// Create a PIN that a client can pair with by presenting a token.
+pub struct CreatePairingTokenCommand {
+	/// Channel used to provide the token and the PIN.
+	pub response: oneshot::Sender<Result<PairingToken, String>>,
+}
+
+/// PIN of a pairing QR code, with the token that a client presents to pair with it.
+#[derive(Clone)]
+pub struct PairingToken {
+	/// Random token that the client passes as `pairingtoken` when it starts pairing.
+	pub token: String,
+
+	/// PIN that the client that presents the token pairs with, as if it was entered on the host.
+	pub pin: String,
+}
+
 // /// Remove client from the list of paired clients.
 // pub struct RemoveClientCommand {
 // 	/// Id of the client.
@@ -152,12 +259,11 @@ pub struct ClientManager {
 impl ClientManager {
 	pub fn new(
 		state: State,
-		server_certs: X509,
-		server_pkey: PKey<Private>,
+		identity: watch::Receiver<ServerIdentity>,
 		shutdown_token: TriggerShutdownToken<i32>,
 	) -> Self {
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = ClientManagerInner { server_certs, server_pkey };
+		let inner = ClientManagerInner { identity };
 		tokio::spawn(async move { inner.run(command_rx, state).await; drop(shutdown_token); });
 
 		Self { command_tx }
@@ -259,6 +365,69 @@ impl ClientManager {
 			.map_err(|e| tracing::warn!("{e}"))
 	}
 
+	pub async fn is_certificate_paired(&self, certificate: X509) -> Result<bool, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ClientManagerCommand::IsCertificatePaired(IsCertificatePairedCommand {
+			certificate,
+			response: response_tx,
+		}))
+			.await
+			.map_err(|e| tracing::error!("Failed to send IsCertificatePaired command to client manager: {e}"))?;
+
+		response_rx
+			.await
+			.map_err(|e| tracing::error!("Failed to wait for response to IsCertificatePaired command from client manager: {e}"))?
+			.map_err(|e| tracing::warn!("{e}"))
+	}
+
+	/// Record that a paired device connected.
+	///
+	/// The name of devices that paired before names were stored is not known.
+	pub async fn device_seen(&self, certificate: X509, address: SocketAddr) -> Result<ConnectedDevice, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ClientManagerCommand::DeviceSeen(DeviceSeenCommand {
+			certificate,
+			address,
+			response: response_tx,
+		}))
+			.await
+			.map_err(|e| tracing::error!("Failed to send DeviceSeen command to client manager: {e}"))?;
+
+		response_rx
+			.await
+			.map_err(|e| tracing::error!("Failed to wait for response to DeviceSeen command from client manager: {e}"))?
+			.map_err(|e| tracing::warn!("{e}"))
+	}
+
+	/// Get the devices that paired with this host, with the settings of their last stream.
+	pub async fn devices(&self) -> Result<Vec<ClientDevice>, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ClientManagerCommand::GetDevices(GetDevicesCommand { response: response_tx }))
+			.await
+			.map_err(|e| tracing::error!("Failed to send GetDevices command to client manager: {e}"))?;
+
+		response_rx
+			.await
+			.map_err(|e| tracing::error!("Failed to wait for response to GetDevices command from client manager: {e}"))?
+			.map_err(|e| tracing::warn!("{e}"))
+	}
+
+	/// Create a one-time PIN for a pairing QR code, which replaces a previous one.
+	///
+	/// A client that presents the token when it starts pairing within `PAIRING_TOKEN_LIFETIME` pairs with this PIN,
+	/// so it doesn't have to be entered on the host. The PIN can't be used again after a client paired with it.
+	pub async fn create_pairing_token(&self) -> Result<PairingToken, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ClientManagerCommand::CreatePairingToken(CreatePairingTokenCommand { response: response_tx }))
+			.await
+			.map_err(|e| tracing::error!("Failed to send CreatePairingToken command to client manager: {e}"))?;
+
+		response_rx
+			.await
+			.map_err(|e| tracing::error!("Failed to wait for response to CreatePairingToken command from client manager: {e}"))?
+			.map_err(|e| tracing::warn!("{e}"))
+	}
+
 	// pub async fn remove_client(&self, id: &str) -> Result<(), ()> {
 	// 	let (response_tx, response_rx) = oneshot::channel();
 	// 	self.command_tx.send(ClientManagerCommand::RemoveClient(RemoveClientCommand {
@@ -276,16 +445,80 @@ impl ClientManager {
 }
 
 struct ClientManagerInner {
-	server_certs: X509,
-	server_pkey: PKey<Private>,
+	identity: watch::Receiver<ServerIdentity>,
+}
+
+/// Pairing token that was handed out, which can be used until a client paired with it.
+struct IssuedPairingToken {
+	token: PairingToken,
+	created: Instant,
+}
+
+impl IssuedPairingToken {
+	fn matches(&self, presented: &str) -> bool {
+		secrets_equal(self.token.token.as_bytes(), presented.as_bytes())
+	}
+}
+
+/// Failed pairing attempts from an address, used to slow down guessing of the PIN.
+///
+/// These are counted per address instead of per client id, because a client can pick a new id for every attempt.
+#[derive(Default)]
+struct PairingAttempts {
+	/// Number of failed attempts since the last successful pairing.
+	failures: u32,
+
+	/// Time of the last failed attempt.
+	last_failure: Option<Instant>,
+
+	/// Time until which the client is not allowed to make another attempt.
+	blocked_until: Option<Instant>,
+}
+
+impl PairingAttempts {
+	fn expired(&self) -> bool {
+		!self.last_failure.is_some_and(|last_failure| last_failure.elapsed() < PAIRING_ATTEMPTS_EXPIRY)
+	}
+
+	/// Remaining time until the client is allowed to make another attempt, if it is blocked.
+	fn blocked_for(&self) -> Option<Duration> {
+		self.blocked_until.and_then(|blocked_until| blocked_until.checked_duration_since(Instant::now()))
+	}
+
+	fn record_failure(&mut self) {
+		self.failures = self.failures.saturating_add(1);
+		self.last_failure = Some(Instant::now());
+		if self.failures > FREE_PAIRING_ATTEMPTS {
+			let exponent = (self.failures - FREE_PAIRING_ATTEMPTS - 1).min(16);
+			let backoff = PAIRING_BACKOFF.saturating_mul(1 << exponent).min(MAX_PAIRING_BACKOFF);
+			self.blocked_until = Some(Instant::now() + backoff);
+		}
+	}
 }
 
 impl ClientManagerInner {
 	async fn run(self, mut command_rx: mpsc::Receiver<ClientManagerCommand>, state: State) {
 		tracing::debug!("Waiting for commands.");
 
-		let mut pending_clients = BTreeMap::new();
+		let mut pending_clients: BTreeMap<String, PendingClient> = BTreeMap::new();
+		let mut attempts: HashMap<IpAddr, PairingAttempts> = HashMap::new();
+		let mut pairing_token: Option<IssuedPairingToken> = None;
 		while let Some(command) = command_rx.recv().await {
+			if pairing_token.as_ref().is_some_and(|token| token.created.elapsed() >= PAIRING_TOKEN_LIFETIME) {
+				tracing::info!("PIN of the pairing QR code expired.");
+				pairing_token = None;
+			}
+
+			pending_clients.retain(|id, client| {
+				let expired = client.started.elapsed() >= PAIRING_TIMEOUT;
+				if expired {
+					tracing::info!("Pairing session of client {id} expired, it has to start pairing again.");
+				}
+
+				!expired
+			});
+			attempts.retain(|_, attempts| !attempts.expired());
+
 			match command {
 				ClientManagerCommand::IsPaired(command) => {
 					match state.has_client(command.id).await {
@@ -300,14 +533,45 @@ impl ClientManagerInner {
 					}
 				},
 
-				ClientManagerCommand::StartPairing(command) => {
+				ClientManagerCommand::StartPairing(mut command) => {
+					// A client that presents the token of the pairing QR code pairs with its PIN, as if it was entered on the host.
+					let client = &mut command.pending_client;
+					if let Some(presented) = &client.pairing_token {
+						match pairing_token.as_ref().filter(|issued| issued.matches(presented)) {
+							Some(issued) => match create_pairing_key(&client.salt, &issued.token.pin) {
+								Ok(key) => {
+									tracing::info!("Pairing '{}' with the PIN of the pairing QR code.", client.name);
+									client.key = Some(key);
+
+									// Nobody waits for the PIN yet, so the notification is stored for the pairing request.
+									client.pin_notify.notify_one();
+								},
+								Err(e) => tracing::error!("Failed to create client key from the PIN of the pairing QR code: {e}"),
+							},
+							None => tracing::warn!("Client {} presented an unknown or expired pairing token, the PIN has to be entered on the host.", client.id),
+						}
+					}
+
 					pending_clients.insert(command.pending_client.id.clone(), command.pending_client);
 				},
 
 				ClientManagerCommand::RegisterPin(command) => {
+					let blocked_for = pending_clients.get(&command.id)
+						.and_then(|client| attempts.get(&attempts_key(client.address)))
+						.and_then(PairingAttempts::blocked_for);
+					if let Some(blocked_for) = blocked_for {
+						command.response.send(Err(format!(
+							"Too many failed pairing attempts from the address of client {}, try again in {} seconds.",
+							command.id,
+							blocked_for.as_secs() + 1,
+						)))
+							.map_err(|_| tracing::error!("Failed to send RegisterPin error.")).ok();
+						continue;
+					}
+
 					match pending_clients.get_mut(&command.id) {
 						Some(client) => {
-							let key = match create_key(&client.salt, &command.pin) {
+							let key = match create_pairing_key(&client.salt, &command.pin) {
 								Ok(key) => key,
 								Err(e) => {
 									tracing::error!("Failed to create client key: {e}");
@@ -338,6 +602,7 @@ impl ClientManagerInner {
 								},
 								Err(e) => {
 									tracing::error!("Failed to respond to client challenge: {e}");
+									fail_pairing(&mut pending_clients, &mut attempts, &command.id);
 									command.response.send(Err(e))
 										.map_err(|_| tracing::error!("Failed to send ClientChallenge error.")).ok();
 									continue;
@@ -377,13 +642,32 @@ impl ClientManagerInner {
 				ClientManagerCommand::CheckClientPairingSecret(command) => {
 					match pending_clients.get_mut(&command.id) {
 						Some(client) => {
-							match check_client_pairing_secret(client, command.client_secret).await {
+							let result = match check_client_pairing_secret(client, command.client_secret).await {
+								// From now on, this client is allowed to connect over HTTPS using its certificate.
+								Ok(()) => add_client_certificate(&state, client).await,
+								Err(e) => Err(e),
+							};
+
+							match result {
 								Ok(()) => {
+									tracing::info!("Paired with device '{}'.", client.name);
+									attempts.remove(&attempts_key(client.address));
+
+									// The PIN of a pairing QR code can only be used once.
+									if client.pairing_token.as_ref().is_some_and(|presented| pairing_token.as_ref().is_some_and(|issued| issued.matches(presented))) {
+										tracing::info!("Used the PIN of the pairing QR code, it can't be used again.");
+										pairing_token = None;
+									}
+
+									// Failing to remember the name of the device shouldn't fail pairing.
+									let _ = add_device(&state, client).await
+										.map_err(|e| tracing::warn!("{e}"));
 									command.response.send(Ok(()))
 										.map_err(|_| tracing::error!("Failed to send CheckClientPairingSecret response.")).ok();
 								},
 								Err(e) => {
 									tracing::error!("Failed to check client pairing secret: {e}");
+									fail_pairing(&mut pending_clients, &mut attempts, &command.id);
 									command.response.send(Err(e))
 										.map_err(|_| tracing::error!("Failed to send CheckClientPairingSecret error.")).ok();
 									continue;
@@ -419,6 +703,63 @@ impl ClientManagerInner {
 					}
 				},
 
+				ClientManagerCommand::IsCertificatePaired(command) => {
+					let result = match command.certificate.to_pem() {
+						Ok(certificate) => {
+							match std::str::from_utf8(&certificate) {
+								Ok(certificate) => {
+									state.has_client_certificate(certificate.to_string()).await
+										.map_err(|()| "Failed to check client certificate.".to_string())
+								},
+								Err(e) => Err(format!("Failed to interpret client certificate: {e}")),
+							}
+						},
+						Err(e) => Err(format!("Failed to serialize client certificate: {e}")),
+					};
+
+					command.response.send(result)
+						.map_err(|_| tracing::error!("Failed to send IsCertificatePaired response.")).ok();
+				},
+
+				ClientManagerCommand::DeviceSeen(command) => {
+					let result = match fingerprint(&command.certificate) {
+						Ok(fingerprint) => {
+							state.device_seen(fingerprint.clone(), command.address.ip().to_string()).await
+								.map(|device| ConnectedDevice { fingerprint, name: device.map(|device| device.name) })
+								.map_err(|()| "Failed to record connection of device.".to_string())
+						},
+						Err(e) => Err(e),
+					};
+
+					command.response.send(result)
+						.map_err(|_| tracing::error!("Failed to send DeviceSeen response.")).ok();
+				},
+
+				ClientManagerCommand::GetDevices(command) => {
+					let result = state.get_devices().await
+						.map_err(|()| "Failed to get paired devices.".to_string());
+					command.response.send(result)
+						.map_err(|_| tracing::error!("Failed to send GetDevices response.")).ok();
+				},
+
+				ClientManagerCommand::CreatePairingToken(command) => {
+					let mut token = [0u8; 16];
+					let mut pin = [0u8; 4];
+					let result = openssl::rand::rand_bytes(&mut token)
+						.and_then(|()| openssl::rand::rand_bytes(&mut pin))
+						.map(|()| PairingToken {
+							token: hex::encode(token),
+							pin: format!("{:04}", u32::from_le_bytes(pin) % 10_000),
+						})
+						.map_err(|e| format!("Failed to create token for pairing QR code: {e}"));
+					if let Ok(token) = &result {
+						pairing_token = Some(IssuedPairingToken { token: token.clone(), created: Instant::now() });
+					}
+
+					command.response.send(result)
+						.map_err(|_| tracing::error!("Failed to send CreatePairingToken response.")).ok();
+				},
+
 				// ClientManagerCommand::RemoveClient(command) => {
 				// 	pending_clients.remove(&command.id);
 				// 	let Ok(result) = state.remove_client(command.id).await else {
@@ -458,7 +799,7 @@ impl ClientManagerInner {
 
 		let mut decrypted = decrypt(Cipher::aes_128_ecb(), &challenge, key)
 			.map_err(|e| format!("Failed to decrypt client challenge: {e}"))?;
-		decrypted.extend_from_slice(self.server_certs.signature().as_slice());
+		decrypted.extend_from_slice(self.identity.borrow().certificate.signature().as_slice());
 		decrypted.extend_from_slice(&server_secret);
 
 		let mut server_challenge = [0u8; 16];
@@ -498,7 +839,7 @@ impl ClientManagerInner {
 			.ok_or("Client does not have a server secret.".to_string())?;
 
 		let mut pairing_secret = server_secret.to_vec();
-		let signed = sign(&server_secret, &self.server_pkey)
+		let signed = sign(&server_secret, &self.identity.borrow().private_key)
 			.map_err(|e| format!("Failed to sign server secret: {e}"))?;
 		pairing_secret.extend(signed);
 
@@ -506,15 +847,41 @@ impl ClientManagerInner {
 	}
 }
 
-fn create_key(salt: &[u8; 16], pin: &str) -> Result<[u8; 16], String> {
-	let mut key = Vec::with_capacity(salt.len() + pin.len());
-	key.extend(salt);
-	key.extend(pin.as_bytes());
-	openssl::hash::hash(MessageDigest::sha256(), &key)
-		.map_err(|e| format!("Failed to hash key for client: {e}"))?
-		.to_vec()[..16]
-		.try_into()
-		.map_err(|e| format!("Received unexpected key result: {e}"))
+/// Abort the pairing session of a client after a failed attempt, most likely because of an incorrect PIN.
+///
+/// The client has to start pairing again, and its address is slowed down after several failures.
+fn fail_pairing(
+	pending_clients: &mut BTreeMap<String, PendingClient>,
+	attempts: &mut HashMap<IpAddr, PairingAttempts>,
+	id: &str,
+) {
+	let Some(client) = pending_clients.remove(id) else {
+		return;
+	};
+
+	let key = attempts_key(client.address);
+	let address_attempts = attempts.entry(key).or_default();
+	address_attempts.record_failure();
+	if let Some(blocked_for) = address_attempts.blocked_for() {
+		tracing::warn!(
+			"Clients from {key} failed to pair {} times, blocking new attempts for {} seconds.",
+			address_attempts.failures,
+			blocked_for.as_secs() + 1,
+		);
+	}
+}
+
+/// Address under which failed pairing attempts from `address` are counted.
+///
+/// A host usually gets a whole /64 IPv6 network, so that is counted as a single address.
+fn attempts_key(address: IpAddr) -> IpAddr {
+	match address.to_canonical() {
+		IpAddr::V6(address) => {
+			let segments = address.segments();
+			IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], 0, 0, 0, 0))
+		},
+		address => address,
+	}
 }
 
 fn sign<T>(data: &[u8], key: &PKeyRef<T>) -> Result<Vec<u8>, openssl::error::ErrorStack>
@@ -548,8 +915,8 @@ async fn check_client_pairing_secret(client: &mut PendingClient, client_secret:
 		None => return Err("Client does not have a server challenge, possibly incorrect pairing procedure?".to_string()),
 	};
 
+	let signed_client_secret = &client_secret[16..];
 	let client_secret = &client_secret[..16];
-	// let signed_client_secret = &client_pairing_secret[16..];
 
 	let mut data = server_challenge.to_vec();
 	data.extend(client.pem.signature().as_slice());
@@ -562,9 +929,51 @@ async fn check_client_pairing_secret(client: &mut PendingClient, client_secret:
 		}
 	};
 
-	if !data.to_vec().eq(client_hash) {
+	if !secrets_equal(&data, client_hash) {
 		return Err("Client hash is not as expected, MITM?".to_string());
 	}
 
+	// Make sure the client owns the certificate it presented.
+	let public_key = client.pem.public_key()
+		.map_err(|e| format!("Failed to get public key from client certificate: {e}"))?;
+	let mut verifier = openssl::sign::Verifier::new(MessageDigest::sha256(), &public_key)
+		.map_err(|e| format!("Failed to create signature verifier: {e}"))?;
+	let valid = verifier.verify_oneshot(signed_client_secret, client_secret)
+		.map_err(|e| format!("Failed to verify client secret signature: {e}"))?;
+	if !valid {
+		return Err("Client secret is not signed by the client certificate, MITM?".to_string());
+	}
+
 	Ok(())
 }
+
+async fn add_client_certificate(state: &State, client: &PendingClient) -> Result<(), String> {
+	let certificate = client.pem.to_pem()
+		.map_err(|e| format!("Failed to serialize client certificate: {e}"))?;
+	let certificate = String::from_utf8(certificate)
+		.map_err(|e| format!("Failed to interpret client certificate: {e}"))?;
+
+	state.add_client_certificate(certificate).await
+		.map_err(|()| "Failed to store client certificate.".to_string())
+}
+
+async fn add_device(state: &State, client: &PendingClient) -> Result<(), String> {
+	let device = ClientDevice {
+		fingerprint: fingerprint(&client.pem)?,
+		name: client.name.clone(),
+		paired_at: unix_time(),
+		last_seen: None,
+		last_address: None,
+		last_stream: None,
+	};
+
+	state.add_device(device).await
+		.map_err(|()| "Failed to store paired device.".to_string())
+}
+
+/// SHA-256 fingerprint of a certificate, which identifies a device since all clients use the same unique id.
+fn fingerprint(certificate: &X509Ref) -> Result<String, String> {
+	certificate.digest(MessageDigest::sha256())
+		.map(hex::encode)
+		.map_err(|e| format!("Failed to compute certificate fingerprint: {e}"))
+}
@@ -1,6 +1,8 @@
-use std::{path::{PathBuf, Path}, collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
+use std::{path::{PathBuf, Path}, collections::{hash_map::DefaultHasher, BTreeMap}, hash::{Hash, Hasher}};
 use serde::{Deserialize, Serialize};
 
+use crate::ffmpeg::encoder::{EncoderProfile, NvencPreset, NvencTune};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
 	/// Name of the Moonshine host.
@@ -12,6 +14,14 @@ pub struct Config {
 	/// Configuration for the webserver.
 	pub webserver: WebserverConfig,
 
+	/// Configuration for publishing the service over mDNS.
+	#[serde(default)]
+	pub mdns: MdnsConfig,
+
+	/// Configuration for the D-Bus service for desktop integration.
+	#[serde(default)]
+	pub dbus: DbusConfig,
+
 	/// Configuration for the streams.
 	pub stream: StreamConfig,
 
@@ -19,6 +29,11 @@ pub struct Config {
 	#[serde(rename = "application")]
 	pub applications: Vec<ApplicationConfig>,
 
+	/// Groups of applications, which decide which devices can see and launch the applications in them.
+	#[serde(rename = "application_group")]
+	#[serde(skip_serializing_if = "Vec::is_empty", default)]
+	pub application_groups: Vec<ApplicationGroupConfig>,
+
 	/// List of scanners that dynamically adds applications when started.
 	#[serde(rename = "application_scanner")]
 	#[serde(skip_serializing_if = "Vec::is_empty", default)]
@@ -26,6 +41,26 @@ pub struct Config {
 
 	/// Time in seconds since last ping after which the stream closes.
 	pub stream_timeout: u64,
+
+	/// Configuration for ending sessions without input from the client.
+	#[serde(default)]
+	pub idle: IdleConfig,
+
+	/// Configuration for running without a desktop session (ie. in a container or VM).
+	#[serde(default)]
+	pub headless: HeadlessConfig,
+
+	/// Configuration for the physical display of the host.
+	#[serde(default)]
+	pub host_display: HostDisplayConfig,
+
+	/// Configuration for where logs are written to.
+	#[serde(default)]
+	pub logging: LoggingConfig,
+
+	/// Configuration for recording sessions to disk.
+	#[serde(default)]
+	pub recording: RecordingConfig,
 }
 
 impl Config {
@@ -33,11 +68,67 @@ impl Config {
 	pub fn read_from_file<P: AsRef<Path>>(file: P) -> Result<Config, ()> {
 		let config = std::fs::read_to_string(file)
 			.map_err(|e| tracing::error!("Failed to open configuration file: {e}"))?;
-		let config: Config = toml::from_str(&config)
+		let mut config: Config = toml::from_str(&config)
 			.map_err(|e| tracing::error!("Failed to parse configuration file: {e}"))?;
+		config.stream.apply_port_base()?;
+		config.check_application_groups()?;
+		config.check_launch_arguments()?;
+		config.check_sandboxes()?;
 
 		Ok(config)
 	}
+
+	/// Lists of devices that are allowed to use `application`, from the application itself and from its group.
+	///
+	/// A device has to be in every list, all paired devices can use the application if there are no lists.
+	pub fn allowed_clients<'a>(&'a self, application: &'a ApplicationConfig) -> Vec<&'a [String]> {
+		let group = application.group.as_ref()
+			.and_then(|group| self.application_groups.iter().find(|g| &g.name == group));
+
+		application.allowed_clients.iter()
+			.chain(group.and_then(|group| group.allowed_clients.as_ref()))
+			.map(Vec::as_slice)
+			.collect()
+	}
+
+	/// Make sure every application is in a group that exists, so that a typo doesn't show it to every device.
+	fn check_application_groups(&self) -> Result<(), ()> {
+		for application in &self.applications {
+			if let Some(group) = &application.group {
+				if !self.application_groups.iter().any(|g| &g.name == group) {
+					tracing::error!("Application '{}' is in group '{group}', but there is no such group.", application.title);
+					return Err(());
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Make sure applications that accept launch arguments have a `run_before` command to pass them to.
+	fn check_launch_arguments(&self) -> Result<(), ()> {
+		for application in &self.applications {
+			let has_command = application.run_before.as_ref().is_some_and(|run_before| !run_before.is_empty());
+			if application.launch_arguments.is_some() && !has_command {
+				tracing::error!("Application '{}' accepts launch arguments, but has no `run_before` command to pass them to.", application.title);
+				return Err(());
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Make sure sandboxes only use restrictions that their tool supports, so that commands don't silently run with fewer restrictions.
+	fn check_sandboxes(&self) -> Result<(), ()> {
+		for application in &self.applications {
+			if application.sandbox.as_ref().is_some_and(|sandbox| sandbox.tool == SandboxTool::Bubblewrap && sandbox.seccomp) {
+				tracing::error!("Application '{}' has a sandbox with `seccomp = true`, which is only supported by firejail.", application.title);
+				return Err(());
+			}
+		}
+
+		Ok(())
+	}
 }
 
 impl Default for Config {
@@ -46,6 +137,8 @@ impl Default for Config {
 			name: "Moonshine".to_string(),
 			address: "0.0.0.0".to_string(),
 			webserver: Default::default(),
+			mdns: Default::default(),
+			dbus: Default::default(),
 			stream: Default::default(),
 			applications: vec![
 				ApplicationConfig {
@@ -61,6 +154,18 @@ impl Default for Config {
 						vec!["$HOME/.local/bin/resolution".to_string()],
 					]),
 					boxart: None,
+					terminate_on_quit: false,
+					allowed_clients: None,
+					ready_check: None,
+					hdr: None,
+					category: None,
+					group: None,
+					install_path: None,
+					hidden: false,
+					encoder_profile: Some(EncoderProfile::Desktop),
+					limit_framerate: false,
+					launch_arguments: None,
+					sandbox: None,
 				},
 
 				ApplicationConfig {
@@ -80,8 +185,21 @@ impl Default for Config {
 						vec!["$HOME/.local/bin/resolution".to_string()],
 					]),
 					boxart: None,
+					terminate_on_quit: false,
+					allowed_clients: None,
+					ready_check: None,
+					hdr: None,
+					category: None,
+					group: None,
+					install_path: None,
+					hidden: false,
+					encoder_profile: None,
+					limit_framerate: false,
+					launch_arguments: None,
+					sandbox: None,
 				},
 			],
+			application_groups: Vec::new(),
 			application_scanners: vec![
 				ApplicationScannerConfig::Steam(SteamApplicationScannerConfig {
 					library: "$HOME/.local/share/Steam".into(),
@@ -106,6 +224,11 @@ impl Default for Config {
 				}),
 			],
 			stream_timeout: 60,
+			idle: Default::default(),
+			headless: Default::default(),
+			host_display: Default::default(),
+			logging: Default::default(),
+			recording: Default::default(),
 		}
 	}
 }
@@ -123,6 +246,10 @@ pub struct WebserverConfig {
 
 	/// Path to the private key for SSL encryption.
 	pub private_key: PathBuf,
+
+	/// Use the private key even if other users can read it, instead of refusing to start.
+	#[serde(default)]
+	pub allow_insecure_private_key: bool,
 }
 
 impl Default for WebserverConfig {
@@ -132,10 +259,65 @@ impl Default for WebserverConfig {
 			port_https: 47984,
 			certificate: "$HOME/.config/moonshine/cert.pem".into(),
 			private_key: "$HOME/.config/moonshine/key.pem".into(),
+			allow_insecure_private_key: false,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MdnsConfig {
+	/// Backend used to publish the service.
+	pub backend: MdnsBackend,
+
+	/// Name to publish the service with, defaults to the name of the Moonshine host.
+	pub name: Option<String>,
+
+	/// Additional TXT records to publish with the service.
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	pub txt_records: BTreeMap<String, String>,
+
+	/// Interval in seconds at which network interfaces are checked for changes.
+	///
+	/// When a change is detected, the service is registered again.
+	pub network_check_interval: u64,
+}
+
+impl Default for MdnsConfig {
+	fn default() -> Self {
+		Self {
+			backend: MdnsBackend::default(),
+			name: None,
+			txt_records: BTreeMap::new(),
+			network_check_interval: 5,
 		}
 	}
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DbusConfig {
+	/// Expose the `org.moonshine.Server` interface on the session bus.
+	pub enabled: bool,
+}
+
+impl Default for DbusConfig {
+	fn default() -> Self {
+		Self { enabled: true }
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MdnsBackend {
+	/// Publish the service through the avahi daemon.
+	#[default]
+	Avahi,
+
+	/// Publish the service using a built-in mDNS responder, this doesn't require avahi to be running.
+	Builtin,
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ApplicationConfig {
 	/// Title of the application.
@@ -153,6 +335,170 @@ pub struct ApplicationConfig {
 	///
 	/// Note that multiple entries can be provided, in which case they will be executed in that same order.
 	pub run_after: Option<Vec<Vec<String>>>,
+
+	/// Terminate the processes started by `run_before` (and the processes they started) when the client quits the application.
+	#[serde(default)]
+	pub terminate_on_quit: bool,
+
+	/// If provided, only devices in this list can launch or resume this application.
+	///
+	/// Entries are either the SHA-256 fingerprint of the certificate of a device, or the name of a device.
+	/// All paired devices can launch the application if this is not provided.
+	#[serde(default)]
+	pub allowed_clients: Option<Vec<String>>,
+
+	/// If provided, the client is only told the application launched once these checks succeed.
+	#[serde(default)]
+	pub ready_check: Option<ReadyCheckConfig>,
+
+	/// Whether the application can output HDR, HDR is only offered to clients if the host can stream it too.
+	///
+	/// Applications are assumed to support HDR if this is not provided.
+	#[serde(default)]
+	pub hdr: Option<bool>,
+
+	/// Category to group the application in, for clients that organize applications.
+	///
+	/// If not provided, the name of the group of the application is used.
+	#[serde(default)]
+	pub category: Option<String>,
+
+	/// Name of the group of the application, which decides which devices can see and launch it.
+	#[serde(default)]
+	pub group: Option<String>,
+
+	/// Path to where the application is installed, reported to clients as metadata.
+	#[serde(default)]
+	pub install_path: Option<PathBuf>,
+
+	/// Leave the application out of the application list, it can still be launched by its ID.
+	#[serde(default)]
+	pub hidden: bool,
+
+	/// What kind of content the application shows, which decides how it is encoded.
+	///
+	/// If not provided, `stream.video.profile` is used.
+	#[serde(default)]
+	pub encoder_profile: Option<EncoderProfile>,
+
+	/// Cap the framerate of the application to the framerate of the stream, so it doesn't render frames that are never streamed.
+	///
+	/// The cap is passed to the commands through the environment variables of MangoHud, libstrangle and DXVK.
+	#[serde(default)]
+	pub limit_framerate: bool,
+
+	/// If provided, clients can pass arguments when launching the application, which are appended to the last `run_before` command.
+	///
+	/// Clients can't pass arguments to applications without this.
+	#[serde(default)]
+	pub launch_arguments: Option<LaunchArgumentsConfig>,
+
+	/// If provided, run the `run_before` commands in a sandbox.
+	#[serde(default)]
+	pub sandbox: Option<SandboxConfig>,
+}
+
+/// A group of applications, for example "Emulators" or the titles that the tablet of a child can see.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApplicationGroupConfig {
+	/// Name of the group, which applications refer to with `group`.
+	pub name: String,
+
+	/// If provided, only devices in this list can see, launch or resume the applications in this group.
+	///
+	/// Entries are either the SHA-256 fingerprint of the certificate of a device, or the name of a device.
+	#[serde(default)]
+	pub allowed_clients: Option<Vec<String>>,
+}
+
+/// Arguments that clients can pass when launching an application, for example to start a specific game through a launcher.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LaunchArgumentsConfig {
+	/// If provided, clients can only pass arguments from this list.
+	///
+	/// Arguments in this list are accepted as they are, without checking the characters in them.
+	pub allowed: Option<Vec<String>>,
+
+	/// Accept arguments that start with `-`, which the command could interpret as options.
+	pub allow_options: bool,
+
+	/// Maximum number of arguments that clients can pass.
+	pub max_count: usize,
+}
+
+impl Default for LaunchArgumentsConfig {
+	fn default() -> Self {
+		Self { allowed: None, allow_options: false, max_count: 8 }
+	}
+}
+
+/// Restrictions for the commands that start an application, so that a client can't do everything the user of the host can.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+	/// Program that runs the commands in the sandbox.
+	pub tool: SandboxTool,
+
+	/// Allow the application to use the network.
+	pub network: bool,
+
+	/// Block system calls that applications don't need, such as loading kernel modules (only supported by firejail).
+	pub seccomp: bool,
+
+	/// Paths that the application can write to, the rest of the filesystem is read-only.
+	///
+	/// Environment variables such as `$HOME` are expanded.
+	pub writable: Vec<String>,
+
+	/// Extra arguments for the sandbox tool, passed before the command.
+	pub extra_arguments: Vec<String>,
+}
+
+impl Default for SandboxConfig {
+	fn default() -> Self {
+		Self {
+			tool: SandboxTool::default(),
+			network: true,
+			seccomp: false,
+			writable: Vec::new(),
+			extra_arguments: Vec::new(),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxTool {
+	/// Run the commands with `bwrap`.
+	#[default]
+	Bubblewrap,
+
+	/// Run the commands with `firejail`, using its default profile.
+	Firejail,
+}
+
+/// Checks that tell when an application is ready to be streamed, all configured checks have to succeed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadyCheckConfig {
+	/// Wait for a visible window with a name that matches this regular expression (using `xdotool`).
+	pub window: Option<String>,
+
+	/// Wait for a process with a command line that matches this regular expression (using `pgrep`).
+	pub process: Option<String>,
+
+	/// Wait for this command to exit successfully.
+	pub command: Option<Vec<String>>,
+
+	/// Maximum time in seconds to wait for the checks to succeed, the client connects after this time regardless.
+	pub timeout: u64,
+}
+
+impl Default for ReadyCheckConfig {
+	fn default() -> Self {
+		Self { window: None, process: None, command: None, timeout: 30 }
+	}
 }
 
 impl ApplicationConfig {
@@ -188,11 +534,179 @@ pub struct SteamApplicationScannerConfig {
 
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeadlessConfig {
+	/// Run in headless mode.
+	///
+	/// In this mode the environment is checked on startup for everything that is required to stream,
+	/// and no desktop notifications are shown.
+	pub enabled: bool,
+
+	/// Stream silence if no audio server is available, instead of failing to start the audio stream.
+	pub null_audio: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdleConfig {
+	/// Time in minutes without input from the client after which the session ends.
+	///
+	/// Sessions don't end because of inactivity if this is not set.
+	pub timeout: Option<u64>,
+
+	/// Run the `run_after` commands of the application when the session ends because of inactivity.
+	pub run_after: bool,
+
+	/// Time in seconds after launching an application within which the client has to start streaming,
+	/// otherwise the client is assumed to be gone and the session ends.
+	pub launch_timeout: u64,
+}
+
+impl Default for IdleConfig {
+	fn default() -> Self {
+		Self { timeout: None, run_after: true, launch_timeout: 120 }
+	}
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HostDisplayConfig {
+	/// Blank the physical display of the host while a client is streaming,
+	/// so that people near the host can't watch along.
+	pub blank_while_streaming: bool,
+
+	/// Method used to blank the display.
+	pub blank_method: BlankMethod,
+
+	/// Show pairing requests that arrive during a session as a notification on the host display,
+	/// which is part of the stream, so the device that is streaming sees where to enter the PIN.
+	pub pairing_overlay: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlankMethod {
+	/// Set the brightness of all outputs to zero (using `xrandr`).
+	///
+	/// The captured image is not affected, since brightness is applied when the image is sent to the display.
+	#[default]
+	Brightness,
+
+	/// Turn off all displays using DPMS (using `xset`).
+	///
+	/// Since input from the client wakes up the displays, they are turned off again periodically.
+	Dpms,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+	/// Directory to write log files to, in addition to writing logs to stdout.
+	///
+	/// Log files are not written if this is not set.
+	pub directory: Option<PathBuf>,
+
+	/// How often to start a new log file.
+	pub rotation: LogRotation,
+
+	/// Maximum number of log files to keep, older log files are removed.
+	pub max_files: usize,
+
+	/// Send logs to the systemd journal.
+	pub journald: bool,
+}
+
+impl Default for LoggingConfig {
+	fn default() -> Self {
+		Self {
+			directory: None,
+			rotation: Default::default(),
+			max_files: 7,
+			journald: false,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+	/// Record the video and audio of each session to a file.
+	pub enabled: bool,
+
+	/// Path of the recordings.
+	///
+	/// `{application}` is replaced with the title of the application and `{timestamp}` with the time the stream started.
+	/// The extension determines the container format (ie. `.mkv` or `.mp4`).
+	pub path: String,
+
+	/// Maximum size of a recording in megabytes, the recording stops when it reaches this size.
+	pub max_size: Option<u64>,
+
+	/// Path to record the input events of each session to, for debugging input handling.
+	///
+	/// Uses the same replacements as `path`, the recording can be replayed with `moonshine replay-input <file>`.
+	/// Input is recorded regardless of `enabled`.
+	pub input_path: Option<String>,
+}
+
+impl Default for RecordingConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			path: "$HOME/Videos/Moonshine/{application}-{timestamp}.mkv".to_string(),
+			max_size: None,
+			input_path: None,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+	Hourly,
+	#[default]
+	Daily,
+	Never,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StreamConfig {
 	/// Port to bind the RTSP server to.
 	pub port: u16,
 
+	/// First port of a range of four consecutive ports, which replaces the ports of the RTSP server and the streams when set.
+	///
+	/// The RTSP server uses this port, the video stream the next port, followed by the control stream and the audio stream.
+	#[serde(default)]
+	pub port_base: Option<u16>,
+
+	/// Send video and audio over the port of the video stream, so that one port less needs to be forwarded.
+	///
+	/// This requires a version of Moonlight that repeats the ping payload of the host in its pings.
+	#[serde(default)]
+	pub single_port: bool,
+
+	/// Configuration for the sockets of the video and audio streams.
+	#[serde(default)]
+	pub socket: StreamSocketConfig,
+
+	/// Configuration for clients that run on the host itself.
+	#[serde(default)]
+	pub loopback: LoopbackConfig,
+
+	/// Cores and scheduling policy of the threads that capture, encode and control the stream.
+	#[serde(default)]
+	pub scheduling: SchedulingConfig,
+
+	/// Encryption of the video and audio streams.
+	#[serde(default)]
+	pub encryption: EncryptionConfig,
+
+	/// Limits on what specific devices are streamed, applied to what the client asks for.
+	#[serde(default)]
+	pub client_limits: Vec<ClientLimitsConfig>,
+
 	/// Configuration for the video stream.
 	pub video: VideoStreamConfig,
 
@@ -203,10 +717,46 @@ pub struct StreamConfig {
 	pub control: ControlStreamConfig,
 }
 
+impl StreamConfig {
+	/// Port that the audio stream is sent from, which is the port of the video stream in single port mode.
+	pub fn audio_port(&self) -> u16 {
+		if self.single_port {
+			self.video.port
+		} else {
+			self.audio.port
+		}
+	}
+
+	/// Replace the ports of the RTSP server and the streams with the range starting at `port_base`, if it is set.
+	fn apply_port_base(&mut self) -> Result<(), ()> {
+		let Some(port_base) = self.port_base else {
+			return Ok(());
+		};
+		if port_base > u16::MAX - 3 {
+			tracing::error!("Port base {port_base} is too large, the range of four ports has to fit below {}.", u16::MAX);
+			return Err(());
+		}
+
+		self.port = port_base;
+		self.video.port = port_base + 1;
+		self.control.port = port_base + 2;
+		self.audio.port = port_base + 3;
+
+		Ok(())
+	}
+}
+
 impl Default for StreamConfig {
 	fn default() -> Self {
 		Self {
 			port: 48010,
+			port_base: None,
+			single_port: false,
+			socket: Default::default(),
+			loopback: Default::default(),
+			scheduling: Default::default(),
+			encryption: Default::default(),
+			client_limits: Vec::new(),
 			video: Default::default(),
 			audio: Default::default(),
 			control: Default::default(),
@@ -214,11 +764,185 @@ impl Default for StreamConfig {
 	}
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamSocketConfig {
+	/// DSCP class of video packets when the client asks for QoS (40 is CS5).
+	pub video_dscp: u8,
+
+	/// DSCP class of audio packets when the client asks for QoS (56 is CS7).
+	pub audio_dscp: u8,
+
+	/// Size of the send buffer of the sockets in bytes, the default of the system is used if not set.
+	pub send_buffer_size: Option<usize>,
+
+	/// Size of the receive buffer of the sockets in bytes, the default of the system is used if not set.
+	pub receive_buffer_size: Option<usize>,
+
+	/// Spread video packets over time at this rate in megabits per second, instead of sending each frame in a burst.
+	///
+	/// This uses SO_TXTIME, which requires the `fq` or `etf` queueing discipline on the network interface.
+	pub pacing_rate: Option<u32>,
+}
+
+impl Default for StreamSocketConfig {
+	fn default() -> Self {
+		Self {
+			video_dscp: 40,
+			audio_dscp: 56,
+			send_buffer_size: None,
+			receive_buffer_size: None,
+			pacing_rate: None,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoopbackConfig {
+	/// Send video and audio without parity packets and video without pacing to clients on the host itself, since packets over loopback aren't lost.
+	///
+	/// The client can still ask for a minimum number of video parity packets, which are always sent.
+	pub enabled: bool,
+}
+
+impl Default for LoopbackConfig {
+	fn default() -> Self {
+		Self { enabled: true }
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchedulingConfig {
+	/// Cores that the thread capturing the video runs on, any core if empty.
+	pub capture_cores: Vec<usize>,
+
+	/// Cores that the thread encoding the video runs on, any core if empty.
+	pub encode_cores: Vec<usize>,
+
+	/// Cores that the thread of the control stream runs on, any core if empty.
+	pub control_cores: Vec<usize>,
+
+	/// Run these threads with the `SCHED_FIFO` realtime policy, so that other processes can't delay them.
+	///
+	/// This needs a high enough `RLIMIT_RTPRIO` (ie. `LimitRTPRIO=` in systemd) or the `CAP_SYS_NICE` capability.
+	pub realtime: bool,
+
+	/// Priority of the threads under the realtime policy, between 1 and 99.
+	pub realtime_priority: u8,
+}
+
+impl Default for SchedulingConfig {
+	fn default() -> Self {
+		Self {
+			capture_cores: Vec::new(),
+			encode_cores: Vec::new(),
+			control_cores: Vec::new(),
+			realtime: false,
+			realtime_priority: 10,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientLimitsConfig {
+	/// Devices these limits apply to.
+	///
+	/// Entries are either the SHA-256 fingerprint of the certificate of a device, or the name of a device.
+	pub clients: Vec<String>,
+
+	/// Highest bitrate to stream to these devices, in kbps.
+	#[serde(default)]
+	pub max_bitrate: Option<usize>,
+
+	/// Highest framerate to stream to these devices.
+	#[serde(default)]
+	pub max_fps: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+	/// Whether the packets of the video stream are encrypted.
+	///
+	/// The control stream is always encrypted, audio is encrypted unless the client asks for it not to be.
+	pub video: EncryptionMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionMode {
+	/// Never encrypt, even if the client asks for it.
+	Disabled,
+
+	/// Encrypt if the client asks for it.
+	#[default]
+	Optional,
+
+	/// Ask clients to encrypt and refuse to stream to clients that don't support it.
+	Required,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdaptiveFecConfig {
+	/// Raise the percentage of parity packets when the client loses frames, and lower it again while it doesn't.
+	///
+	/// The percentage starts at `fec_percentage`.
+	pub enabled: bool,
+
+	/// Lowest percentage of parity packets, used on a network without loss.
+	pub minimum_percentage: u8,
+
+	/// Highest percentage of parity packets, used on a network with a lot of loss.
+	pub maximum_percentage: u8,
+}
+
+impl Default for AdaptiveFecConfig {
+	fn default() -> Self {
+		Self { enabled: false, minimum_percentage: 5, maximum_percentage: 50 }
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FrameQueueConfig {
+	/// Maximum number of captured frames that wait to be encoded.
+	///
+	/// When the encoder falls behind and the queue is full, the oldest frame is dropped for the newest one.
+	/// A deeper queue drops fewer frames, at the cost of latency.
+	pub depth: usize,
+}
+
+impl Default for FrameQueueConfig {
+	fn default() -> Self {
+		Self { depth: 1 }
+	}
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VideoStreamConfig {
 	/// Port to use for streaming video data.
 	pub port: u16,
 
+	/// GPU used for capturing, scaling and encoding.
+	///
+	/// Either the index of the GPU (ie. "1"), its UUID (ie. "GPU-5e4b7a8c-..."), its PCI bus ID (ie. "0000:01:00.0") or part of its name.
+	/// If not set, the first GPU is used.
+	#[serde(default)]
+	pub gpu: Option<String>,
+
+	/// Where the frames of the stream come from.
+	#[serde(default)]
+	pub capture: CaptureBackend,
+
+	/// Name of the Wayland output to capture with `wlr_screencopy` (ie. "DP-1").
+	///
+	/// If not set, the first output is captured.
+	#[serde(default)]
+	pub output: Option<String>,
+
 	/// Type of codec to use for h264.
 	pub codec_h264: String,
 
@@ -227,28 +951,152 @@ pub struct VideoStreamConfig {
 
 	/// What percentage of data packets should be parity packets.
 	pub fec_percentage: u8,
+
+	/// Adjust the percentage of parity packets to the loss that the client reports.
+	#[serde(default)]
+	pub adaptive_fec: AdaptiveFecConfig,
+
+	/// Preset of the encoder, trading encoding speed for quality.
+	#[serde(default)]
+	pub preset: NvencPreset,
+
+	/// What the encoder is tuned for.
+	#[serde(default)]
+	pub tune: NvencTune,
+
+	/// What kind of content is streamed, for applications that don't set their own `encoder_profile`.
+	#[serde(default)]
+	pub profile: EncoderProfile,
+
+	/// Filter used to scale the screen when its resolution differs from the resolution the client asked for.
+	#[serde(default)]
+	pub scaling_filter: ScalingFilter,
+
+	/// Encode frames as soon as they are captured, instead of at the framerate the client asked for.
+	///
+	/// Frames are still limited to the framerate the client asked for, but if fewer frames are produced
+	/// (for example by a game running at a lower framerate), the client receives them as they are produced.
+	#[serde(default)]
+	pub variable_refresh_rate: bool,
+
+	/// Which headers are added to the encoded frames, independent of what the encoder adds by itself.
+	#[serde(default)]
+	pub bitstream: BitstreamConfig,
+
+	/// How many captured frames can wait for the encoder.
+	#[serde(default)]
+	pub frame_queue: FrameQueueConfig,
 }
 
 impl Default for VideoStreamConfig {
 	fn default() -> Self {
 		Self {
 			port: 47998,
+			gpu: None,
+			capture: Default::default(),
+			output: None,
 			codec_h264: "h264_nvenc".to_string(),
 			codec_hevc: "hevc_nvenc".to_string(),
 			fec_percentage: 20,
+			adaptive_fec: Default::default(),
+			preset: Default::default(),
+			tune: Default::default(),
+			profile: Default::default(),
+			scaling_filter: Default::default(),
+			variable_refresh_rate: false,
+			bitstream: Default::default(),
+			frame_queue: Default::default(),
 		}
 	}
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BitstreamConfig {
+	/// Send the latest parameter sets (VPS, SPS and PPS) with every IDR frame that doesn't contain them already.
+	///
+	/// Clients that join or recover from loss can only decode an IDR frame with the parameter sets in front of it.
+	pub repeat_parameter_sets: bool,
+
+	/// Start every frame with an access unit delimiter, which some hardware decoders need to find the start of a frame.
+	pub access_unit_delimiters: bool,
+}
+
+impl Default for BitstreamConfig {
+	fn default() -> Self {
+		Self { repeat_parameter_sets: true, access_unit_delimiters: false }
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureBackend {
+	/// Capture the X screen with NvFBC.
+	#[default]
+	Nvfbc,
+
+	/// Generate moving color bars with a timestamp at the resolution and framerate the client asks for,
+	/// which doesn't need an X server. Useful to develop and demo on machines that can't capture.
+	TestPattern,
+
+	/// Capture an output of a wlroots based Wayland compositor (ie. Sway or Hyprland) with wlr-screencopy.
+	WlrScreencopy,
+
+	/// Capture the X screen through shared memory, which works without NvFBC but is slower.
+	X11Shm,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScalingFilter {
+	/// Use the nearest pixel, which is sharp but causes aliasing.
+	Nearest,
+
+	/// Interpolate between the four nearest pixels.
+	#[default]
+	Bilinear,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AudioStreamConfig {
 	/// Port to use for streaming audio data.
 	pub port: u16,
+
+	/// Name of the PulseAudio source to capture, for example the monitor of a specific sink.
+	///
+	/// If not set, the monitor of the default sink is captured, following the default sink when it changes.
+	#[serde(default)]
+	pub device: Option<String>,
+
+	/// Create a null sink and make it the default sink while streaming, so that audio only plays on the client.
+	///
+	/// Not used when the client asks to play audio on the host as well.
+	#[serde(default)]
+	pub virtual_sink: bool,
+
+	/// Configuration for the parity packets of the audio stream.
+	#[serde(default)]
+	pub fec: AudioFecConfig,
 }
 
 impl Default for AudioStreamConfig {
 	fn default() -> Self {
-		Self { port: 48000 }
+		Self { port: 48000, device: None, virtual_sink: false, fec: Default::default() }
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioFecConfig {
+	/// Send two parity packets for every four audio packets, so that the client can recover up to two lost packets of each block.
+	///
+	/// The number of packets in a block is fixed by Moonlight, so only whether parity packets are sent can be configured.
+	pub enabled: bool,
+}
+
+impl Default for AudioFecConfig {
+	fn default() -> Self {
+		Self { enabled: true }
 	}
 }
 
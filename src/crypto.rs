@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use openssl::{
 	asn1::Asn1Time,
 	bn::{BigNum, MsbOption},
@@ -5,20 +7,32 @@ use openssl::{
 	cipher_ctx::CipherCtx,
 	error::ErrorStack,
 	hash::MessageDigest,
+	nid::Nid,
 	pkey::{PKey, Private},
 	rsa::Rsa,
 	x509::{
 		extension::{
-			BasicConstraints, KeyUsage, SubjectKeyIdentifier
+			BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName, SubjectKeyIdentifier
 		},
-	 	X509
+	 	X509,
+		X509NameBuilder,
 	}
 };
 
-pub fn create_certificate() -> Result<(X509, PKey<Private>), ErrorStack> {
+pub fn create_private_key() -> Result<PKey<Private>, ErrorStack> {
 	let rsa = Rsa::generate(2048)?;
-	let key_pair = PKey::from_rsa(rsa)?;
+	PKey::from_rsa(rsa)
+}
 
+/// Create a self-signed server certificate that is valid for `validity_days` days.
+///
+/// The host name and addresses are added as subject alternative names, so that clients can verify them.
+pub fn create_certificate(
+	key_pair: &PKey<Private>,
+	host_name: &str,
+	addresses: &[IpAddr],
+	validity_days: u32,
+) -> Result<X509, ErrorStack> {
 	let mut cert_builder = X509::builder()?;
 	cert_builder.set_version(2)?;
 	let serial_number = {
@@ -27,13 +41,19 @@ pub fn create_certificate() -> Result<(X509, PKey<Private>), ErrorStack> {
 		serial.to_asn1_integer()?
 	};
 	cert_builder.set_serial_number(&serial_number)?;
-	cert_builder.set_pubkey(&key_pair)?;
+	cert_builder.set_pubkey(key_pair)?;
 	let not_before = Asn1Time::days_from_now(0)?;
 	cert_builder.set_not_before(&not_before)?;
-	let not_after = Asn1Time::days_from_now(3650)?;
+	let not_after = Asn1Time::days_from_now(validity_days)?;
 	cert_builder.set_not_after(&not_after)?;
 
-	cert_builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+	let mut name = X509NameBuilder::new()?;
+	name.append_entry_by_nid(Nid::COMMONNAME, host_name)?;
+	let name = name.build();
+	cert_builder.set_subject_name(&name)?;
+	cert_builder.set_issuer_name(&name)?;
+
+	cert_builder.append_extension(BasicConstraints::new().critical().build()?)?;
 	cert_builder.append_extension(
 		KeyUsage::new()
 			.digital_signature()
@@ -41,15 +61,27 @@ pub fn create_certificate() -> Result<(X509, PKey<Private>), ErrorStack> {
 			.key_agreement()
 			.build()?,
 	)?;
+	cert_builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+
+	let mut subject_alternative_name = SubjectAlternativeName::new();
+	subject_alternative_name
+		.dns(host_name)
+		.dns(&format!("{host_name}.local"))
+		.dns("localhost");
+	for address in addresses {
+		subject_alternative_name.ip(&address.to_string());
+	}
+	let subject_alternative_name = subject_alternative_name.build(&cert_builder.x509v3_context(None, None))?;
+	cert_builder.append_extension(subject_alternative_name)?;
 
 	let subject_key_identifier =
 		SubjectKeyIdentifier::new().build(&cert_builder.x509v3_context(None, None))?;
 	cert_builder.append_extension(subject_key_identifier)?;
 
-	cert_builder.sign(&key_pair, MessageDigest::sha256())?;
+	cert_builder.sign(key_pair, MessageDigest::sha256())?;
 	let cert = cert_builder.build();
 
-	Ok((cert, key_pair))
+	Ok(cert)
 }
 
 pub fn encrypt(cipher: &CipherRef, plaintext: &[u8], key: Option<&[u8]>, iv: Option<&[u8]>, padding: bool) -> Result<Vec<u8>, openssl::error::ErrorStack> {
@@ -79,3 +111,33 @@ pub fn decrypt(cipher: &CipherRef, ciphertext: &[u8], key: &[u8]) -> Result<Vec<
 
 	Ok(plaintext)
 }
+
+/// Derive the key used during pairing from the salt provided by the client and the PIN entered by the user.
+///
+/// The derivation itself is dictated by the protocol, so we can only refuse input that makes the key easier to guess.
+pub fn create_pairing_key(salt: &[u8; 16], pin: &str) -> Result<[u8; 16], String> {
+	if pin.len() != 4 || !pin.bytes().all(|c| c.is_ascii_digit()) {
+		return Err("Expected PIN to consist of 4 digits.".to_string());
+	}
+
+	// A salt without any variation is either a broken or a malicious client, and allows for precomputed keys.
+	if salt.iter().all(|&b| b == salt[0]) {
+		return Err("Client provided a salt without any randomness.".to_string());
+	}
+
+	let mut key = Vec::with_capacity(salt.len() + pin.len());
+	key.extend(salt);
+	key.extend(pin.as_bytes());
+	let hash = openssl::hash::hash(MessageDigest::sha256(), &key);
+	key.fill(0);
+
+	hash
+		.map_err(|e| format!("Failed to hash key for client: {e}"))?[..16]
+		.try_into()
+		.map_err(|e| format!("Received unexpected key result: {e}"))
+}
+
+/// Compare two secrets in constant time, so that timing doesn't reveal how much of a guess was correct.
+pub fn secrets_equal(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
@@ -0,0 +1,113 @@
+use tokio::sync::broadcast::{self, error::RecvError};
+use zbus::{fdo, interface, SignalContext};
+
+use crate::{
+	clients::ClientManager,
+	config::DbusConfig,
+	events::{Event, Events},
+	session::{SessionManager, SessionShutdownReason},
+};
+
+/// Name that the service owns on the session bus.
+const SERVICE_NAME: &str = "org.moonshine.Server";
+
+/// Path of the object that implements the `org.moonshine.Server` interface.
+const OBJECT_PATH: &str = "/org/moonshine/Server";
+
+/// Interface for desktop applets and scripts to follow and control the host.
+struct Server {
+	session_manager: SessionManager,
+	client_manager: ClientManager,
+}
+
+#[interface(name = "org.moonshine.Server")]
+impl Server {
+	/// Whether a session is active, with the title of its application and the name of the device that started it.
+	async fn status(&self) -> fdo::Result<(bool, String, String)> {
+		let context = self.session_manager.get_session_context().await
+			.map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+		Ok(match context {
+			Some(context) => (true, context.application.title, context.device_name.unwrap_or_default()),
+			None => (false, String::new(), String::new()),
+		})
+	}
+
+	/// Stop the active session.
+	async fn stop_session(&self) -> fdo::Result<()> {
+		self.session_manager.stop_session(SessionShutdownReason::HostStopped).await
+			.map_err(|e| fdo::Error::Failed(e.to_string()))
+	}
+
+	/// Submit the PIN that a client shows, to finish a pairing request.
+	async fn submit_pin(&self, client_id: String, pin: String) -> fdo::Result<()> {
+		self.client_manager.register_pin(&client_id, &pin).await
+			.map_err(|()| fdo::Error::Failed(format!("Failed to register PIN for client '{client_id}'.")))
+	}
+
+	/// A client asked to pair, the PIN that the client shows has to be submitted with `SubmitPin`.
+	#[zbus(signal)]
+	async fn pairing_requested(context: &SignalContext<'_>, client_id: &str, device_name: &str) -> zbus::Result<()>;
+
+	/// A client launched an application.
+	#[zbus(signal)]
+	async fn session_started(context: &SignalContext<'_>, application: &str, device_name: &str) -> zbus::Result<()>;
+
+	/// The active session stopped.
+	#[zbus(signal)]
+	async fn session_ended(context: &SignalContext<'_>, application: &str, reason: &str) -> zbus::Result<()>;
+}
+
+/// Expose the `org.moonshine.Server` interface on the session bus, if enabled.
+pub fn spawn(config: DbusConfig, session_manager: SessionManager, client_manager: ClientManager, events: &Events) {
+	if !config.enabled {
+		return;
+	}
+
+	let subscription = events.subscribe();
+	tokio::spawn(async move {
+		let _ = run(Server { session_manager, client_manager }, subscription).await;
+	});
+}
+
+async fn run(server: Server, mut subscription: broadcast::Receiver<Event>) -> Result<(), ()> {
+	// Without a session bus (for example when running headless) there is simply nothing to integrate with.
+	let connection = zbus::connection::Builder::session()
+		.and_then(|builder| builder.name(SERVICE_NAME))
+		.and_then(|builder| builder.serve_at(OBJECT_PATH, server))
+		.map_err(|e| tracing::warn!("Failed to prepare D-Bus service, desktop integration is unavailable: {e}"))?
+		.build()
+		.await
+		.map_err(|e| tracing::warn!("Failed to register '{SERVICE_NAME}' on the D-Bus session bus, desktop integration is unavailable: {e}"))?;
+
+	let interface = connection.object_server().interface::<_, Server>(OBJECT_PATH).await
+		.map_err(|e| tracing::error!("Failed to find D-Bus interface: {e}"))?;
+	let context = interface.signal_context();
+	tracing::info!("Registered '{SERVICE_NAME}' on the D-Bus session bus.");
+
+	loop {
+		let result = match subscription.recv().await {
+			Ok(Event::PairingRequested { client_id, device_name }) => {
+				Server::pairing_requested(context, &client_id, &device_name).await
+			},
+			Ok(Event::SessionStarted { device_name, application, .. }) => {
+				Server::session_started(context, &application, device_name.as_deref().unwrap_or_default()).await
+			},
+			Ok(Event::SessionEnded { application, reason }) => {
+				Server::session_ended(context, &application, &reason.to_string()).await
+			},
+			Ok(_) => continue,
+			Err(RecvError::Lagged(skipped)) => {
+				tracing::warn!("D-Bus service fell behind, skipped {skipped} event(s).");
+				continue;
+			},
+			Err(RecvError::Closed) => break,
+		};
+
+		if let Err(e) = result {
+			tracing::warn!("Failed to emit D-Bus signal: {e}");
+		}
+	}
+
+	Ok(())
+}
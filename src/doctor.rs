@@ -0,0 +1,240 @@
+use std::{io::ErrorKind, net::{TcpListener, ToSocketAddrs, UdpSocket}, path::Path};
+
+use crate::{certificate, config::{CaptureBackend, Config, MdnsBackend}, ffmpeg::capabilities, headless, session::stream::{open_gpu, probe_capture, EncoderCapabilities}};
+
+/// Socket through which avahi is reached.
+const AVAHI_SOCKET: &str = "/run/avahi-daemon/socket";
+
+/// Fix for missing permissions on the NVIDIA device nodes.
+const NVIDIA_DEVICE_FIX: &str = "Make sure the NVIDIA driver is loaded and that your user may use the GPU (ie. `sudo usermod -aG video $USER`, then log in again).";
+
+/// Fix for missing permissions on `/dev/uinput`.
+const UINPUT_FIX: &str = "Run `moonshine <config> setup-permissions` to install a udev rule for it, \
+	or load the uinput module (`sudo modprobe uinput`) and install the udev rule \
+	`KERNEL==\"uinput\", SUBSYSTEM==\"misc\", TAG+=\"uaccess\", OPTIONS+=\"static_node=uinput\"` yourself.";
+
+/// Check which parts of streaming work on this system, explaining what is missing for the parts that don't.
+pub fn run(config: &Config) -> Result<(), ()> {
+	let mut result = Ok(());
+
+	tracing::info!("Checking devices and audio server.");
+	if headless::check_environment(config).is_err() {
+		result = Err(());
+	}
+
+	// Missing devices were reported above, check that the others can be opened.
+	for device in headless::NVIDIA_DEVICES.iter().map(Path::new).filter(|device| device.exists()) {
+		if check_device(device, NVIDIA_DEVICE_FIX).is_err() {
+			result = Err(());
+		}
+	}
+	if check_device(Path::new("/dev/uinput"), UINPUT_FIX).is_err() {
+		result = Err(());
+	}
+	check_render_nodes();
+
+	match open_gpu(config.stream.video.gpu.as_deref()) {
+		Ok(device) => {
+			let name = device.name().unwrap_or_else(|_| "unknown".to_string());
+			tracing::info!("CUDA is usable on GPU '{name}'.");
+		},
+		Err(()) => result = Err(()),
+	}
+
+	tracing::info!("Using FFmpeg {}.", capabilities::version());
+	if capabilities::check_version().is_err() {
+		result = Err(());
+	}
+	if !capabilities::hwdevice_types().iter().any(|device_type| device_type == "cuda") {
+		tracing::error!("FFmpeg was built without CUDA support, which is required for encoding.");
+		result = Err(());
+	}
+	for encoder in [&config.stream.video.codec_h264, &config.stream.video.codec_hevc] {
+		if capabilities::has_encoder(encoder) {
+			tracing::info!("Encoder '{encoder}' is available.");
+		} else {
+			tracing::error!("Encoder '{encoder}' is not available in FFmpeg, make sure FFmpeg was built with NVENC support.");
+			result = Err(());
+		}
+	}
+	EncoderCapabilities::probe(&config.stream.video);
+
+	if config.stream.video.capture == CaptureBackend::TestPattern {
+		tracing::info!("Streaming a test pattern (`stream.video.capture`), the screen is not captured.");
+	} else if config.stream.video.capture == CaptureBackend::WlrScreencopy {
+		if std::env::var_os("WAYLAND_DISPLAY").is_none() {
+			tracing::error!("No Wayland compositor found (`WAYLAND_DISPLAY` is not set), wlr-screencopy can only capture a Wayland output.");
+			result = Err(());
+		} else {
+			match probe_capture(&config.stream.video) {
+				Ok((width, height)) => tracing::info!("wlr-screencopy capture is usable, the output has a resolution of {width}x{height}."),
+				Err(()) => {
+					tracing::error!(
+						"wlr-screencopy capture is not usable. It requires a wlroots based compositor (ie. Sway or Hyprland), \
+						check that `stream.video.output` names one of its outputs."
+					);
+					result = Err(());
+				},
+			}
+		}
+	} else if std::env::var_os("DISPLAY").is_none() {
+		tracing::error!("No X server found (`DISPLAY` is not set), NvFBC can only capture an X server.");
+		result = Err(());
+	} else if config.stream.video.capture == CaptureBackend::X11Shm {
+		match probe_capture(&config.stream.video) {
+			Ok((width, height)) => tracing::info!("X11 shared memory capture is usable, the screen has a resolution of {width}x{height}."),
+			Err(()) => {
+				tracing::error!("X11 shared memory capture is not usable, it requires the MIT-SHM (version 1.2) and DAMAGE extensions.");
+				result = Err(());
+			},
+		}
+	} else {
+		match probe_capture(&config.stream.video) {
+			Ok((width, height)) => tracing::info!("NvFBC capture is usable, the screen has a resolution of {width}x{height}."),
+			Err(()) => {
+				tracing::error!(
+					"NvFBC capture is not usable. NvFBC requires an X server running on the NVIDIA driver, \
+					on consumer GPUs it might have to be enabled with a driver patch (ie. https://github.com/keylase/nvidia-patch)."
+				);
+				result = Err(());
+			},
+		}
+	}
+
+	tracing::info!(
+		"Moonshine captures through NvFBC, X11 shared memory or wlr-screencopy, it doesn't use xdg-desktop-portal, PipeWire or KMS capture \
+		and never asks for permission to capture the screen."
+	);
+
+	tracing::info!("Checking the certificate.");
+	if certificate::check(&config.webserver).is_err() {
+		result = Err(());
+	}
+
+	tracing::info!("Checking ports.");
+	if check_ports(config).is_err() {
+		result = Err(());
+	}
+
+	if config.mdns.backend == MdnsBackend::Avahi {
+		if Path::new(AVAHI_SOCKET).exists() {
+			tracing::info!("Avahi is running, clients on the local network can find this host.");
+		} else {
+			tracing::warn!(
+				"Avahi is not running, so clients won't find this host automatically. \
+				Start it (`sudo systemctl enable --now avahi-daemon`) or use the built-in mDNS responder (`mdns.backend = \"builtin\"`)."
+			);
+		}
+	}
+
+	match result {
+		Ok(()) => tracing::info!("Everything needed for streaming is available."),
+		Err(()) => tracing::error!("Streaming is not possible on this system, see the errors above."),
+	}
+
+	result
+}
+
+/// Check that a device node can be opened for reading and writing, explaining how to fix it when it can't.
+fn check_device(path: &Path, fix: &str) -> Result<(), ()> {
+	match std::fs::OpenOptions::new().read(true).write(true).open(path) {
+		Ok(_) => {
+			tracing::info!("'{}' is accessible.", path.display());
+			Ok(())
+		},
+		Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+			tracing::error!("No permission to open '{}' ({e}). {fix}", path.display());
+			Err(())
+		},
+		Err(e) => {
+			tracing::error!("Failed to open '{}' ({e}). {fix}", path.display());
+			Err(())
+		},
+	}
+}
+
+/// Check the render nodes in `/dev/dri`.
+///
+/// Moonshine itself doesn't use them, but applications started by moonshine often do,
+/// so missing permissions are reported as a warning.
+fn check_render_nodes() {
+	let Ok(entries) = std::fs::read_dir("/dev/dri") else {
+		tracing::debug!("No '/dev/dri' found, skipping render nodes.");
+		return;
+	};
+
+	for entry in entries.flatten() {
+		if !entry.file_name().to_string_lossy().starts_with("renderD") {
+			continue;
+		}
+
+		let path = entry.path();
+		match std::fs::OpenOptions::new().read(true).write(true).open(&path) {
+			Ok(_) => tracing::info!("'{}' is accessible.", path.display()),
+			Err(e) => tracing::warn!(
+				"No access to '{}' ({e}), applications that render on the GPU might fail. \
+				Add your user to the render group (`sudo usermod -aG render $USER`) and log in again.",
+				path.display(),
+			),
+		}
+	}
+}
+
+/// Check that the ports moonshine listens on are free.
+fn check_ports(config: &Config) -> Result<(), ()> {
+	let mut result = Ok(());
+
+	let tcp_ports = [
+		("HTTP", config.webserver.port),
+		("HTTPS", config.webserver.port_https),
+		("RTSP", config.stream.port),
+	];
+	for (name, port) in tcp_ports {
+		let bound = resolve(&config.address, port)
+			.and_then(|address| TcpListener::bind(address).map_err(|e| report_port(name, "TCP", port, e)));
+		if bound.is_err() {
+			result = Err(());
+		}
+	}
+
+	let mut udp_ports = vec![
+		("video", config.stream.video.port),
+		("control", config.stream.control.port),
+	];
+	if !config.stream.single_port {
+		udp_ports.push(("audio", config.stream.audio.port));
+	}
+	for (name, port) in udp_ports {
+		let bound = resolve(&config.address, port)
+			.and_then(|address| UdpSocket::bind(address).map_err(|e| report_port(name, "UDP", port, e)));
+		if bound.is_err() {
+			result = Err(());
+		}
+	}
+
+	if result.is_ok() {
+		tracing::info!("All ports are available.");
+	}
+
+	result
+}
+
+fn resolve(address: &str, port: u16) -> Result<std::net::SocketAddr, ()> {
+	(address, port).to_socket_addrs()
+		.map_err(|e| tracing::error!("Failed to resolve address {address}:{port}: {e}"))?
+		.next()
+		.ok_or_else(|| tracing::error!("Failed to resolve address {address}:{port}."))
+}
+
+fn report_port(name: &str, protocol: &str, port: u16, error: std::io::Error) {
+	match error.kind() {
+		ErrorKind::AddrInUse => tracing::error!(
+			"The {name} port ({protocol} {port}) is already in use, by a running moonshine or another streaming host (ie. Sunshine). \
+			Stop it, or move moonshine to other ports (`stream.port_base`)."
+		),
+		ErrorKind::PermissionDenied => tracing::error!(
+			"No permission to use the {name} port ({protocol} {port}), ports below 1024 require root. Configure a higher port."
+		),
+		_ => tracing::error!("Can't use the {name} port ({protocol} {port}): {error}"),
+	}
+}
@@ -0,0 +1,57 @@
+use crate::session::SessionShutdownReason;
+
+/// Errors that callers can act on, for example to pick a response for the client or an exit code.
+///
+/// Most of moonshine still logs errors where they happen and returns `Result<_, ()>`.
+/// Those errors convert to `Logged`, so they can be passed on with `?` until they get a cause of their own.
+#[derive(Debug, thiserror::Error)]
+pub enum MoonshineError {
+	/// The session manager stopped, which only happens when moonshine is shutting down.
+	#[error("the session manager stopped")]
+	SessionManagerStopped,
+
+	/// There is no active session to act on.
+	#[error("there is no active session")]
+	NoSession,
+
+	/// A session is active already, only one session can be active at a time.
+	#[error("another session is already active")]
+	SessionActive,
+
+	/// The active session isn't streaming to a client.
+	#[error("the session is not streaming")]
+	NotStreaming,
+
+	/// The client didn't set up the streams (through RTSP) before starting them.
+	#[error("the streams were not set up")]
+	StreamNotSetUp,
+
+	/// The session stopped.
+	#[error("the session stopped because {0}")]
+	SessionStopped(SessionShutdownReason),
+
+	/// The network could not be initialized.
+	#[error("failed to initialize the network: {0}")]
+	Network(String),
+
+	/// An error that was logged where it happened.
+	#[error("see the log for details")]
+	Logged,
+}
+
+impl MoonshineError {
+	/// Exit code of moonshine when it stops because of this error.
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			Self::SessionStopped(reason) => reason.exit_code(),
+			Self::Network(_) => 2,
+			_ => 1,
+		}
+	}
+}
+
+impl From<()> for MoonshineError {
+	fn from(_: ()) -> Self {
+		Self::Logged
+	}
+}
@@ -0,0 +1,73 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::session::SessionShutdownReason;
+
+/// Number of events that are kept for subscribers that fall behind, older events are skipped for them.
+const EVENT_CAPACITY: usize = 64;
+
+/// Something that happened on the host, which is pushed to everyone that subscribed to events.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+	/// A client asked to pair, the user has to enter the PIN that the client shows.
+	PairingRequested {
+		client_id: String,
+		device_name: String,
+	},
+
+	/// A client launched an application.
+	SessionStarted {
+		client_id: String,
+		device_name: Option<String>,
+		application_id: i32,
+		application: String,
+	},
+
+	/// The active session stopped.
+	SessionEnded {
+		application: String,
+		reason: SessionShutdownReason,
+	},
+
+	/// A client started streaming the active session.
+	ClientConnected {
+		device_name: Option<String>,
+		address: String,
+	},
+
+	/// Something went wrong that the user should know about.
+	Error {
+		message: String,
+	},
+}
+
+/// Sends events to subscribers, such as the `/api/v1/events` endpoint of the webserver.
+#[derive(Clone)]
+pub struct Events {
+	sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+	pub fn new() -> Self {
+		let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+		Self { sender }
+	}
+
+	/// Send an event to all current subscribers, it is dropped if nobody is subscribed.
+	pub fn send(&self, event: Event) {
+		tracing::trace!("Sending event: {event:?}");
+		let _ = self.sender.send(event);
+	}
+
+	/// Receive all events that are sent from now on.
+	pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+		self.sender.subscribe()
+	}
+}
+
+impl Default for Events {
+	fn default() -> Self {
+		Self::new()
+	}
+}
@@ -0,0 +1,56 @@
+use std::ffi::CStr;
+
+/// Version of FFmpeg that is loaded at runtime (ie. "7.1"), which can differ from the version Moonshine was built against.
+pub fn version() -> String {
+	unsafe { CStr::from_ptr(ffmpeg::sys::av_version_info()) }
+		.to_string_lossy()
+		.into_owned()
+}
+
+/// Major version of libavcodec that is loaded at runtime.
+pub fn avcodec_major_version() -> u32 {
+	unsafe { ffmpeg::sys::avcodec_version() >> 16 }
+}
+
+/// Check that the loaded libavcodec has the same major version as the one Moonshine was built against.
+///
+/// Structures change between major versions, so a mismatch leads to crashes or garbage instead of a clear error.
+pub fn check_version() -> Result<(), ()> {
+	let built = ffmpeg::sys::LIBAVCODEC_VERSION_MAJOR as u32;
+	let loaded = avcodec_major_version();
+	if built != loaded {
+		tracing::error!(
+			"Moonshine was built against libavcodec {built}, but libavcodec {loaded} is installed (FFmpeg {}). \
+			Rebuild Moonshine against the installed version of FFmpeg.",
+			version(),
+		);
+		return Err(());
+	}
+
+	tracing::debug!("Using FFmpeg {} (libavcodec {loaded}).", version());
+	Ok(())
+}
+
+/// Whether an encoder with this name (ie. "hevc_nvenc") is available.
+pub fn has_encoder(name: &str) -> bool {
+	ffmpeg::encoder::find_by_name(name).is_some()
+}
+
+/// Names of the hardware device types that FFmpeg was built with (ie. "cuda").
+pub fn hwdevice_types() -> Vec<String> {
+	let mut types = Vec::new();
+	let mut device_type = ffmpeg::sys::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE;
+	loop {
+		device_type = unsafe { ffmpeg::sys::av_hwdevice_iterate_types(device_type) };
+		if device_type == ffmpeg::sys::AVHWDeviceType::AV_HWDEVICE_TYPE_NONE {
+			break;
+		}
+
+		let name = unsafe { ffmpeg::sys::av_hwdevice_get_type_name(device_type) };
+		if !name.is_null() {
+			types.push(unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned());
+		}
+	}
+
+	types
+}
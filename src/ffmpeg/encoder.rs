@@ -0,0 +1,386 @@
+use std::ffi::CString;
+
+use ffmpeg::format::Pixel;
+use serde::{Deserialize, Serialize};
+
+use super::{check_ret, hwframe::HwFrameContext};
+
+/// Value of an encoder option.
+#[derive(Clone, Copy, Debug)]
+pub enum OptionValue<'a> {
+	String(&'a str),
+	Bool(bool),
+}
+
+impl<'a> From<&'a str> for OptionValue<'a> {
+	fn from(value: &'a str) -> Self {
+		Self::String(value)
+	}
+}
+
+impl From<bool> for OptionValue<'_> {
+	fn from(value: bool) -> Self {
+		Self::Bool(value)
+	}
+}
+
+/// Preset of the NVENC encoders, trading encoding speed for quality.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NvencPreset {
+	#[default]
+	Fast,
+	Medium,
+	Slow,
+
+	/// Fastest preset with the lowest quality.
+	P1,
+	P2,
+	P3,
+	P4,
+	P5,
+	P6,
+
+	/// Slowest preset with the highest quality.
+	P7,
+}
+
+impl NvencPreset {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Self::Fast => "fast",
+			Self::Medium => "medium",
+			Self::Slow => "slow",
+			Self::P1 => "p1",
+			Self::P2 => "p2",
+			Self::P3 => "p3",
+			Self::P4 => "p4",
+			Self::P5 => "p5",
+			Self::P6 => "p6",
+			Self::P7 => "p7",
+		}
+	}
+}
+
+/// What the NVENC encoders are tuned for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NvencTune {
+	HighQuality,
+	LowLatency,
+	#[default]
+	UltraLowLatency,
+	Lossless,
+}
+
+impl NvencTune {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Self::HighQuality => "hq",
+			Self::LowLatency => "ll",
+			Self::UltraLowLatency => "ull",
+			Self::Lossless => "lossless",
+		}
+	}
+}
+
+/// What kind of content is streamed, which decides the encoder options that suit it best.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderProfile {
+	/// Fast moving content, encoded without extra options.
+	#[default]
+	Game,
+
+	/// Mostly static content with text, where adaptive quantization spends bits on flat areas and content that doesn't change.
+	Desktop,
+
+	/// Film and other recorded video, where weighted prediction helps with fades.
+	Video,
+}
+
+impl EncoderProfile {
+	/// Options of the NVENC encoders for this profile.
+	fn options(&self) -> &'static [(&'static str, bool)] {
+		match self {
+			Self::Game => &[],
+			Self::Desktop => &[("spatial-aq", true), ("temporal-aq", true)],
+			Self::Video => &[("spatial-aq", true), ("weighted_pred", true)],
+		}
+	}
+}
+
+/// Codec of a video stream, which decides the profile and level that the encoder signals in the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoCodec {
+	H264,
+	Hevc,
+}
+
+impl VideoCodec {
+	/// Profile of the NVENC encoder, with or without chroma subsampling.
+	fn profile(&self, yuv444: bool) -> &'static str {
+		match (self, yuv444) {
+			(Self::H264, false) => "high",
+			(Self::H264, true) => "high444p",
+			(Self::Hevc, false) => "main",
+			(Self::Hevc, true) => "rext",
+		}
+	}
+
+	/// Lowest level that allows a stream with these properties, or `None` if it exceeds every level.
+	pub fn level(&self, width: u32, height: u32, framerate: u32, bitrate: usize, yuv444: bool) -> Option<&'static str> {
+		// H264 limits frames in macroblocks of 16x16 pixels, HEVC in luma samples.
+		// The bitrate limits are multiplied by a factor that depends on the profile.
+		let (levels, unit, bitrate_factor) = match (self, yuv444) {
+			(Self::H264, false) => (H264_LEVELS, 16, 1.25),
+			(Self::H264, true) => (H264_LEVELS, 16, 4.0),
+			(Self::Hevc, false) => (HEVC_LEVELS, 1, 1.0),
+			(Self::Hevc, true) => (HEVC_LEVELS, 1, 2.0),
+		};
+
+		let (width, height) = (width.div_ceil(unit) as u64, height.div_ceil(unit) as u64);
+		let frame_size = width * height;
+		levels.iter()
+			.find(|level| {
+				// Neither side of a frame may exceed the square root of eight times the largest frame.
+				let max_side = ((level.max_frame_size * 8) as f64).sqrt() as u64;
+				frame_size <= level.max_frame_size
+					&& width <= max_side
+					&& height <= max_side
+					&& frame_size * framerate as u64 <= level.max_rate
+					&& bitrate as f64 <= level.max_bitrate as f64 * 1000.0 * bitrate_factor
+			})
+			.map(|level| level.name)
+	}
+}
+
+/// Limits of a level of a codec.
+struct Level {
+	/// Name of the level, as the NVENC encoders accept it.
+	name: &'static str,
+
+	/// Largest frame, in macroblocks for H264 and in luma samples for HEVC.
+	max_frame_size: u64,
+
+	/// Largest number of macroblocks (H264) or luma samples (HEVC) per second.
+	max_rate: u64,
+
+	/// Largest bitrate in kbps, before the factor of the profile is applied.
+	max_bitrate: u64,
+}
+
+const fn level(name: &'static str, max_frame_size: u64, max_rate: u64, max_bitrate: u64) -> Level {
+	Level { name, max_frame_size, max_rate, max_bitrate }
+}
+
+/// Levels of H264, from table A-1 of the specification.
+const H264_LEVELS: &[Level] = &[
+	level("1", 99, 1_485, 64),
+	level("1.1", 396, 3_000, 192),
+	level("1.2", 396, 6_000, 384),
+	level("1.3", 396, 11_880, 768),
+	level("2", 396, 11_880, 2_000),
+	level("2.1", 792, 19_800, 4_000),
+	level("2.2", 1_620, 20_250, 4_000),
+	level("3", 1_620, 40_500, 10_000),
+	level("3.1", 3_600, 108_000, 14_000),
+	level("3.2", 5_120, 216_000, 20_000),
+	level("4", 8_192, 245_760, 20_000),
+	level("4.1", 8_192, 245_760, 50_000),
+	level("4.2", 8_704, 522_240, 50_000),
+	level("5", 22_080, 589_824, 135_000),
+	level("5.1", 36_864, 983_040, 240_000),
+	level("5.2", 36_864, 2_073_600, 240_000),
+	level("6", 139_264, 4_177_920, 240_000),
+	level("6.1", 139_264, 8_355_840, 480_000),
+	level("6.2", 139_264, 16_711_680, 800_000),
+];
+
+/// Levels of HEVC in the Main tier, from table A.8 of the specification.
+const HEVC_LEVELS: &[Level] = &[
+	level("1", 36_864, 552_960, 128),
+	level("2", 122_880, 3_686_400, 1_500),
+	level("2.1", 245_760, 7_372_800, 3_000),
+	level("3", 552_960, 16_588_800, 6_000),
+	level("3.1", 983_040, 33_177_600, 10_000),
+	level("4", 2_228_224, 66_846_720, 12_000),
+	level("4.1", 2_228_224, 133_693_440, 20_000),
+	level("5", 8_912_896, 267_386_880, 25_000),
+	level("5.1", 8_912_896, 534_773_760, 40_000),
+	level("5.2", 8_912_896, 1_069_547_520, 60_000),
+	level("6", 35_651_584, 1_069_547_520, 60_000),
+	level("6.1", 35_651_584, 2_139_095_040, 120_000),
+	level("6.2", 35_651_584, 4_278_190_080, 240_000),
+];
+
+/// Builds a video encoder without the caller touching the underlying codec context.
+pub struct EncoderBuilder {
+	encoder: ffmpeg::encoder::video::Video,
+}
+
+impl EncoderBuilder {
+	pub fn new(codec_name: &str) -> Result<Self, String> {
+		let codec = ffmpeg::encoder::find_by_name(codec_name)
+			.ok_or_else(|| format!("could not find an encoder with name '{codec_name}'"))?;
+
+		let encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+			.encoder()
+			.video()
+			.map_err(|e| format!("could not create a video encoder: {e}"))?;
+
+		Ok(Self { encoder })
+	}
+
+	pub fn build(self) -> Result<ffmpeg::encoder::Video, ffmpeg::Error> {
+		self.encoder.open()
+	}
+
+	pub fn set_width(mut self, width: u32) -> Self {
+		self.encoder.set_width(width);
+		self
+	}
+
+	pub fn set_height(mut self, height: u32) -> Self {
+		self.encoder.set_height(height);
+		self
+	}
+
+	pub fn set_framerate(mut self, framerate: u32) -> Self {
+		self.encoder.set_frame_rate(Some((framerate as i32, 1)));
+		self.encoder.set_time_base((1, framerate as i32));
+		self
+	}
+
+	/// Set the unit of presentation timestamps to `1 / rate` seconds.
+	///
+	/// Setting the framerate resets the time base to the duration of a frame, so this has to be set after the framerate.
+	pub fn set_time_base(mut self, rate: u32) -> Self {
+		self.encoder.set_time_base((1, rate as i32));
+		self
+	}
+
+	/// Set the average bitrate in bits per second.
+	pub fn set_bitrate(mut self, bitrate: usize) -> Self {
+		self.encoder.set_bit_rate(bitrate);
+		self
+	}
+
+	/// Configure the encoder for streaming: no B-frames and no periodic key frames,
+	/// key frames are only encoded when they are requested.
+	pub fn set_low_latency(mut self) -> Self {
+		self.encoder.set_max_b_frames(0);
+		self.encoder.set_gop(i32::MAX as u32);
+		unsafe { (*self.encoder.as_mut_ptr()).refs = 0 };
+		self
+	}
+
+	/// Encode frames in system memory with this pixel format.
+	pub fn set_pixel_format(mut self, pixel_format: Pixel) -> Self {
+		self.encoder.set_format(pixel_format);
+		self
+	}
+
+	/// Encode frames from the GPU memory of a frame context.
+	pub fn set_hw_frame_context(mut self, context: &HwFrameContext) -> Result<Self, String> {
+		// The encoder takes ownership of this reference and releases it when it is dropped.
+		let frame_context = context.new_ref()?;
+		self.encoder.set_format(Pixel::CUDA);
+		unsafe { (*self.encoder.as_mut_ptr()).hw_frames_ctx = frame_context };
+		Ok(self)
+	}
+
+	pub fn set_preset(self, preset: NvencPreset) -> Result<Self, ffmpeg::Error> {
+		self.set_option("preset", preset.as_str())
+	}
+
+	pub fn set_tune(self, tune: NvencTune) -> Result<Self, ffmpeg::Error> {
+		self.set_option("tune", tune.as_str())
+	}
+
+	/// Set the options that suit the content described by `profile`.
+	pub fn set_profile(mut self, profile: EncoderProfile) -> Result<Self, ffmpeg::Error> {
+		for &(name, value) in profile.options() {
+			self = self.set_option(name, value)?;
+		}
+		Ok(self)
+	}
+
+	/// Signal the profile of `codec` in the stream, encoding without chroma subsampling (4:4:4) if `yuv444` is set.
+	///
+	/// NVENC converts packed RGB frames to 4:2:0 unless it is told to keep the full chroma resolution.
+	pub fn set_codec_profile(self, codec: VideoCodec, yuv444: bool) -> Result<Self, ffmpeg::Error> {
+		let builder = self.set_option("profile", codec.profile(yuv444))?;
+		if yuv444 {
+			builder.set_option("rgb_mode", "yuv444")
+		} else {
+			Ok(builder)
+		}
+	}
+
+	/// Signal a level in the stream, so that decoders know up front whether they can decode it.
+	///
+	/// This is the lowest level of `codec` that allows the stream, the encoder picks one if the stream exceeds every level.
+	pub fn set_level(self, codec: VideoCodec, width: u32, height: u32, framerate: u32, bitrate: usize, yuv444: bool) -> Result<Self, ffmpeg::Error> {
+		match codec.level(width, height, framerate, bitrate, yuv444) {
+			Some(level) => self.set_option("level", level),
+			None => Ok(self),
+		}
+	}
+
+	/// Encode requested key frames as IDR frames, so that the client can start decoding from them.
+	pub fn set_forced_idr(self, forced_idr: bool) -> Result<Self, ffmpeg::Error> {
+		self.set_option("forced-idr", forced_idr)
+	}
+
+	/// Set an option of the encoder that has no dedicated setter.
+	pub fn set_option<'a>(mut self, name: &str, value: impl Into<OptionValue<'a>>) -> Result<Self, ffmpeg::Error> {
+		set_option(&mut self.encoder, name, value.into())?;
+		Ok(self)
+	}
+}
+
+fn set_option(encoder: &mut ffmpeg::encoder::video::Video, name: &str, value: OptionValue) -> Result<(), ffmpeg::Error> {
+	let name = CString::new(name).map_err(|_| ffmpeg::Error::InvalidData)?;
+
+	// Options of the codec itself (ie. "preset") are stored in its private data, which is searched as a child object.
+	let object = unsafe { encoder.as_mut_ptr() } as *mut std::ffi::c_void;
+	let flags = ffmpeg::sys::AV_OPT_SEARCH_CHILDREN;
+	match value {
+		OptionValue::String(value) => {
+			let value = CString::new(value).map_err(|_| ffmpeg::Error::InvalidData)?;
+			check_ret(unsafe { ffmpeg::sys::av_opt_set(object, name.as_ptr(), value.as_ptr(), flags) })
+		},
+		OptionValue::Bool(value) => {
+			check_ret(unsafe { ffmpeg::sys::av_opt_set_int(object, name.as_ptr(), value as i64, flags) })
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lowest_level_is_chosen() {
+		assert_eq!(VideoCodec::H264.level(1280, 720, 60, 10_000_000, false), Some("3.2"));
+		assert_eq!(VideoCodec::H264.level(1920, 1080, 60, 20_000_000, false), Some("4.2"));
+		assert_eq!(VideoCodec::H264.level(3840, 2160, 60, 80_000_000, false), Some("5.2"));
+		assert_eq!(VideoCodec::Hevc.level(1920, 1080, 60, 20_000_000, false), Some("4.1"));
+		assert_eq!(VideoCodec::Hevc.level(3840, 2160, 60, 40_000_000, false), Some("5.1"));
+	}
+
+	#[test]
+	fn bitrate_raises_level() {
+		// 4:4:4 allows a higher bitrate at the same level.
+		assert_eq!(VideoCodec::Hevc.level(3840, 2160, 60, 80_000_000, false), Some("6.1"));
+		assert_eq!(VideoCodec::Hevc.level(3840, 2160, 60, 80_000_000, true), Some("5.1"));
+	}
+
+	#[test]
+	fn stream_exceeding_every_level_has_no_level() {
+		assert_eq!(VideoCodec::H264.level(8192, 8192, 60, 50_000_000, false), None);
+		// A narrow frame that fits in the largest frame of a level can still be too wide for it.
+		assert_eq!(VideoCodec::H264.level(8192, 16, 30, 1_000_000, false), Some("5.1"));
+	}
+}
@@ -28,6 +28,12 @@ impl CudaDeviceContext {
 	// }
 }
 
+impl Drop for CudaDeviceContext {
+	fn drop(&mut self) {
+		unsafe { ffmpeg::sys::av_buffer_unref(&mut self.buffer) };
+	}
+}
+
 pub struct CudaDeviceContextBuilder {
 	buffer: *mut ffmpeg::sys::AVBufferRef,
 }
@@ -43,7 +49,10 @@ impl CudaDeviceContextBuilder {
 	}
 
 	pub fn build(mut self) -> Result<CudaDeviceContext, ffmpeg::Error> {
-		check_ret(unsafe { ffmpeg::sys::av_hwdevice_ctx_init(self.buffer) })?;
+		if let Err(e) = check_ret(unsafe { ffmpeg::sys::av_hwdevice_ctx_init(self.buffer) }) {
+			unsafe { ffmpeg::sys::av_buffer_unref(&mut self.buffer) };
+			return Err(e);
+		}
 		let result = Ok(CudaDeviceContext::new(self.buffer));
 		self.buffer = null_mut();
 
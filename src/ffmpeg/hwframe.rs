@@ -1,6 +1,7 @@
 use std::ptr::null_mut;
 
-use ffmpeg::format::Pixel;
+use cudarc::driver::{result::DriverError, sys::{CUdeviceptr, CUmemorytype, CUDA_MEMCPY2D}};
+use ffmpeg::{format::Pixel, Frame};
 
 use super::{check_ret, hwdevice::CudaDeviceContext};
 
@@ -29,10 +30,87 @@ impl HwFrameContext {
 	// pub fn as_raw(&self) -> &ffmpeg::sys::AVBufferRef {
 	// 	unsafe { &*self.buffer }
 	// }
+
+	/// Create a new reference to this frame context, the caller takes ownership of the reference.
+	pub fn new_ref(&self) -> Result<*mut ffmpeg::sys::AVBufferRef, String> {
+		let buffer = unsafe { ffmpeg::sys::av_buffer_ref(self.buffer) };
+		if buffer.is_null() {
+			return Err("could not create a reference to the hwframe".to_string());
+		}
+
+		Ok(buffer)
+	}
+}
+
+impl Drop for HwFrameContext {
+	fn drop(&mut self) {
+		unsafe { ffmpeg::sys::av_buffer_unref(&mut self.buffer) };
+	}
 }
 
 unsafe impl Send for HwFrameContext { }
 
+/// Hands out frames in the GPU memory of a frame context, reusing the memory of frames that were dropped.
+///
+/// Frames are reference counted and each frame holds a reference to the frame context,
+/// so the memory of the pool is released once the pool and all of its frames are dropped.
+pub struct HwFramePool {
+	context: HwFrameContext,
+}
+
+impl HwFramePool {
+	pub fn new(context: HwFrameContext) -> Self {
+		Self { context }
+	}
+
+	/// Get a frame with the size and format of the frame context.
+	pub fn get(&mut self) -> Result<Frame, ffmpeg::Error> {
+		let mut frame = Frame::empty();
+		check_ret(unsafe { ffmpeg::sys::av_hwframe_get_buffer(self.context.as_raw_mut(), frame.as_mut_ptr(), 0) })?;
+
+		Ok(frame)
+	}
+}
+
+/// Copy an image in CUDA memory to the first plane of a frame in CUDA memory.
+///
+/// Lines of the image are `pitch` bytes apart, while lines of the frame are padded to the line size of the frame.
+pub fn copy_device_to_frame(source: CUdeviceptr, pitch: usize, height: usize, frame: &mut Frame) -> Result<(), DriverError> {
+	unsafe {
+		// Zero is a valid value for all fields, the fields that aren't set are not used for copies between device memory.
+		let mut copy: CUDA_MEMCPY2D = std::mem::zeroed();
+		copy.srcMemoryType = CUmemorytype::CU_MEMORYTYPE_DEVICE;
+		copy.srcDevice = source;
+		copy.srcPitch = pitch;
+		copy.dstMemoryType = CUmemorytype::CU_MEMORYTYPE_DEVICE;
+		copy.dstDevice = (*frame.as_ptr()).data[0] as CUdeviceptr;
+		copy.dstPitch = (*frame.as_ptr()).linesize[0] as usize;
+		copy.WidthInBytes = pitch.min(copy.dstPitch);
+		copy.Height = height;
+
+		cudarc::driver::sys::lib().cuMemcpy2D_v2(&copy).result()
+	}
+}
+
+/// Copy an image in host memory to the first plane of a frame in CUDA memory.
+///
+/// Lines of the image are `pitch` bytes apart, only the lines that are in `source` are copied.
+pub fn copy_host_to_frame(source: &[u8], pitch: usize, height: usize, frame: &mut Frame) -> Result<(), DriverError> {
+	unsafe {
+		let mut copy: CUDA_MEMCPY2D = std::mem::zeroed();
+		copy.srcMemoryType = CUmemorytype::CU_MEMORYTYPE_HOST;
+		copy.srcHost = source.as_ptr() as *const std::ffi::c_void;
+		copy.srcPitch = pitch;
+		copy.dstMemoryType = CUmemorytype::CU_MEMORYTYPE_DEVICE;
+		copy.dstDevice = (*frame.as_ptr()).data[0] as CUdeviceptr;
+		copy.dstPitch = (*frame.as_ptr()).linesize[0] as usize;
+		copy.WidthInBytes = pitch.min(copy.dstPitch);
+		copy.Height = height.min(source.len() / pitch.max(1));
+
+		cudarc::driver::sys::lib().cuMemcpy2D_v2(&copy).result()
+	}
+}
+
 pub struct HwFrameContextBuilder {
 	cuda_device_context: CudaDeviceContext,
 	buffer: *mut ffmpeg::sys::AVBufferRef,
@@ -49,7 +127,10 @@ impl HwFrameContextBuilder {
 	}
 
 	pub fn build(mut self) -> Result<HwFrameContext, ffmpeg::Error> {
-		check_ret(unsafe { ffmpeg::sys::av_hwframe_ctx_init(self.buffer) })?;
+		if let Err(e) = check_ret(unsafe { ffmpeg::sys::av_hwframe_ctx_init(self.buffer) }) {
+			unsafe { ffmpeg::sys::av_buffer_unref(&mut self.buffer) };
+			return Err(e);
+		}
 		let result = Ok(HwFrameContext::new(self.cuda_device_context, self.buffer));
 		self.buffer = null_mut();
 
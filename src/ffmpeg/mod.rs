@@ -1,3 +1,5 @@
+pub mod capabilities;
+pub mod encoder;
 pub mod hwdevice;
 pub mod hwframe;
 
@@ -0,0 +1,6 @@
+//! Entry points for the fuzz targets in `fuzz/`, which parse data the way Moonshine parses the data it receives from clients.
+
+pub use crate::{
+	rtsp::fuzz_message as rtsp_message,
+	session::stream::{fuzz_control_message as control_message, fuzz_input_event as input_event},
+};
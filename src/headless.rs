@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use crate::{config::Config, session::stream::probe_input};
+
+/// Device nodes that NvFBC and NVENC need access to.
+pub const NVIDIA_DEVICES: &[&str] = &["/dev/nvidiactl", "/dev/nvidia0", "/dev/nvidia-modeset"];
+
+/// Check whether we are running inside a container (Docker, Podman, ...).
+pub fn is_container() -> bool {
+	Path::new("/.dockerenv").exists()
+		|| Path::new("/run/.containerenv").exists()
+		|| std::env::var_os("container").is_some()
+}
+
+/// Check whether a PulseAudio (or PipeWire-pulse) server is likely reachable.
+///
+/// This does not connect to the server, it only checks if the socket exists.
+pub fn has_audio_server() -> bool {
+	if let Some(server) = std::env::var_os("PULSE_SERVER") {
+		let server = server.to_string_lossy();
+		return match server.strip_prefix("unix:") {
+			Some(path) => Path::new(path).exists(),
+			// Assume remote servers are reachable, they will fail with a clear error otherwise.
+			None => true,
+		};
+	}
+
+	match std::env::var_os("XDG_RUNTIME_DIR") {
+		Some(runtime_dir) => Path::new(&runtime_dir).join("pulse").join("native").exists(),
+		None => false,
+	}
+}
+
+/// Check that the environment provides everything we need to stream.
+///
+/// Every missing requirement is logged with a hint on how to resolve it,
+/// an error is returned if streaming is impossible in this environment.
+pub fn check_environment(config: &Config) -> Result<(), ()> {
+	let mut result = Ok(());
+
+	for device in NVIDIA_DEVICES {
+		if !Path::new(device).exists() {
+			tracing::error!(
+				"Missing device '{device}', video capture and encoding are not possible. \
+				When running in a container, make sure the NVIDIA container toolkit is used \
+				(ie. `--gpus all` or `--device {device}`)."
+			);
+			result = Err(());
+		}
+	}
+
+	if let Err(e) = probe_input() {
+		tracing::error!("Input from clients can't be handled. {e}");
+		result = Err(());
+	}
+
+	if !has_audio_server() {
+		if config.headless.null_audio {
+			tracing::warn!("No audio server found, streaming silence instead.");
+		} else {
+			tracing::error!(
+				"No audio server found, audio can't be captured. \
+				Either start a PulseAudio / PipeWire server (and set `PULSE_SERVER` if it is not at the default location), \
+				or set `headless.null_audio = true` to stream silence."
+			);
+			result = Err(());
+		}
+	}
+
+	result
+}
@@ -0,0 +1,345 @@
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, path::PathBuf, time::Duration};
+
+use async_shutdown::ShutdownManager;
+use clap::{Parser, Subcommand};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use crate::clients::ClientManager;
+use crate::config::Config;
+use crate::error::MoonshineError;
+use crate::events::Events;
+use crate::rtsp::RtspServer;
+use crate::session::{stream::{replay_input, EncoderCapabilities}, SessionManager};
+use crate::state::State;
+use crate::systemd::{ActivatedSockets, Notifier};
+use crate::webserver::Webserver;
+
+mod app_scanner;
+mod certificate;
+mod clients;
+mod config;
+mod crypto;
+mod dbus;
+mod doctor;
+mod error;
+mod events;
+mod ffmpeg;
+pub mod fuzz;
+mod headless;
+mod logging;
+mod metrics;
+mod permissions;
+mod preview;
+mod rtsp;
+mod session;
+mod state;
+mod publisher;
+mod systemd;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "gui")]
+mod tray;
+mod webserver;
+
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+	/// Path to configuration file.
+	config: PathBuf,
+
+	#[clap(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Create a new server certificate, a running server picks up the new certificate automatically.
+	RegenCert,
+
+	/// Check whether everything needed for streaming is available on this system.
+	Doctor,
+
+	/// Install the udev rule for `/dev/uinput` and add the user to the render group, using sudo where needed.
+	SetupPermissions {
+		/// Make every change without asking for confirmation.
+		#[clap(long)]
+		yes: bool,
+	},
+
+	/// Serve the captured and encoded screen as MPEG-TS over HTTP, to check capture and encoding with any player.
+	Preview {
+		/// Address to serve the preview on, anyone that can reach it can watch the screen.
+		#[clap(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+		address: IpAddr,
+
+		/// Port to serve the preview on.
+		#[clap(long, default_value_t = 8080)]
+		port: u16,
+
+		/// Bitrate of the preview in kbps.
+		#[clap(long, default_value_t = 20000)]
+		bitrate: usize,
+	},
+
+	/// Replay the input events of a recording (see `recording.input_path`), to reproduce problems with input handling.
+	ReplayInput {
+		/// Input recording to replay.
+		file: PathBuf,
+
+		/// Only parse and print the events, without creating virtual input devices.
+		#[clap(long)]
+		parse_only: bool,
+	},
+}
+
+/// Run Moonshine with the arguments it was started with.
+#[allow(clippy::result_unit_err)]
+pub fn main() -> Result<(), ()> {
+	let args = Args::parse();
+
+	// Take what systemd passed before the runtime starts its threads, since these are removed from the environment.
+	let mut systemd_warnings = Vec::new();
+	let notifier = Notifier::from_env(&mut systemd_warnings);
+	let sockets = ActivatedSockets::from_env(&mut systemd_warnings);
+
+	tokio::runtime::Builder::new_multi_thread()
+		.enable_all()
+		.build()
+		.map_err(|e| eprintln!("Failed to create the async runtime: {e}"))?
+		.block_on(run(args, notifier, sockets, systemd_warnings))
+}
+
+async fn run(args: Args, notifier: Option<Notifier>, sockets: ActivatedSockets, systemd_warnings: Vec<String>) -> Result<(), ()> {
+	// Log to stdout until the configuration is loaded, which decides where else to log to.
+	let bootstrap_logging = tracing_subscriber::registry()
+		.with(tracing_subscriber::fmt::layer())
+		.with(EnvFilter::from_default_env())
+		.set_default();
+
+	for warning in systemd_warnings {
+		tracing::warn!("{warning}");
+	}
+
+	let mut config;
+	if args.config.exists() {
+		config = Config::read_from_file(args.config).map_err(|_| std::process::exit(1))?;
+	} else {
+		tracing::info!("No config file found at {}, creating a default config file.", args.config.display());
+		config = Config::default();
+
+		let serialized_config = toml::to_string_pretty(&config)
+			.map_err(|e| tracing::error!("Failed to serialize config: {e}"))?;
+
+		let config_dir = args.config.parent()
+			.ok_or_else(|| tracing::error!("Failed to get parent directory of config file."))?;
+		std::fs::create_dir_all(config_dir)
+			.map_err(|e| tracing::error!("Failed to create config directory: {e}"))?;
+		std::fs::write(args.config, serialized_config)
+			.map_err(|e| tracing::error!("Failed to save config file: {e}"))?;
+	}
+
+	// Resolve these paths so that the rest of the code doesn't need to.
+	let cert_path = config.webserver.certificate.to_string_lossy().to_string();
+	let cert_path = shellexpand::full(&cert_path)
+		.map_err(|e| tracing::error!("Failed to expand certificate path: {e}"))?;
+	config.webserver.certificate = cert_path.to_string().into();
+
+	let private_key_path = config.webserver.private_key.to_string_lossy().to_string();
+	let private_key_path = shellexpand::full(&private_key_path)
+		.map_err(|e| tracing::error!("Failed to expand private key path: {e}"))?;
+	config.webserver.private_key = private_key_path.to_string().into();
+
+	let log_guard = logging::init(&config.logging)?;
+	drop(bootstrap_logging);
+
+	tracing::debug!("Using configuration:\n{:#?}", config);
+
+	crate::ffmpeg::capabilities::check_version().map_err(|()| std::process::exit(1))?;
+
+	match args.command {
+		Some(Command::RegenCert) => {
+			certificate::regenerate(&config.webserver)?;
+			tracing::info!("Clients have to pair again to accept the new certificate.");
+			return Ok(());
+		},
+		Some(Command::Doctor) => {
+			return doctor::run(&config).map_err(|()| std::process::exit(1));
+		},
+		Some(Command::SetupPermissions { yes }) => {
+			return permissions::setup(yes).map_err(|()| std::process::exit(1));
+		},
+		Some(Command::Preview { address, port, bitrate }) => {
+			if let Err(e) = preview::run(config, SocketAddr::new(address, port), bitrate * 1000).await {
+				tracing::error!("Preview failed: {e}.");
+				std::process::exit(e.exit_code());
+			}
+			return Ok(());
+		},
+		Some(Command::ReplayInput { file, parse_only }) => {
+			return replay_input(&file, parse_only).await.map_err(|()| std::process::exit(1));
+		},
+		None => {},
+	}
+
+	if config.headless.enabled {
+		headless::check_environment(&config)?;
+	} else if headless::is_container() {
+		tracing::info!("Running inside a container, consider enabling headless mode (`headless.enabled = true`).");
+	}
+
+	let scanned_applications = app_scanner::scan_applications(&config.application_scanners);
+	tracing::debug!("Adding scanned applications:\n{:#?}", scanned_applications);
+	config.applications.extend(scanned_applications);
+
+	// Spawn a task to wait for CTRL+C, or for SIGTERM with which systemd stops the service, and trigger a shutdown.
+	let shutdown = ShutdownManager::new();
+	let mut terminate = signal(SignalKind::terminate())
+		.map_err(|e| tracing::error!("Failed to listen for the terminate signal: {e}"))?;
+	tokio::spawn({
+		let shutdown = shutdown.clone();
+		async move {
+			tokio::select! {
+				result = tokio::signal::ctrl_c() => {
+					if let Err(e) = result {
+						tracing::error!("Failed to wait for CTRL+C: {e}");
+						std::process::exit(1);
+					}
+
+					tracing::info!("Received interrupt signal. Shutting down server...");
+					shutdown.trigger_shutdown(1).ok();
+				},
+				_ = terminate.recv() => {
+					// Stopping the service is not a failure, which systemd would report for a non-zero exit code.
+					tracing::info!("Received terminate signal. Shutting down server...");
+					shutdown.trigger_shutdown(0).ok();
+				},
+			}
+		}
+	});
+
+	// Create the main application.
+	let moonshine = match Moonshine::new(config, sockets, shutdown.clone()).await {
+		Ok(moonshine) => moonshine,
+		Err(e) => {
+			tracing::error!("Failed to start moonshine: {e}.");
+			drop(log_guard);
+			std::process::exit(e.exit_code());
+		},
+	};
+
+	// Everything is listening now, let systemd know (for units with `Type=notify`).
+	if let Some(notifier) = &notifier {
+		notifier.ready();
+	}
+
+	// Wait until something causes a shutdown trigger, meanwhile pinging the systemd watchdog if it is enabled.
+	// The interval is only used if the watchdog is enabled, its default period is just a placeholder.
+	let watchdog_interval = notifier.as_ref().and_then(Notifier::watchdog_interval);
+	let mut watchdog = tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(1)));
+	loop {
+		tokio::select! {
+			_ = shutdown.wait_shutdown_triggered() => break,
+			_ = watchdog.tick(), if watchdog_interval.is_some() => {
+				// Only tell systemd that moonshine is alive while the managers still respond, so that it restarts moonshine when they hang.
+				let timeout = watchdog_interval.unwrap_or_default();
+				match tokio::time::timeout(timeout, moonshine.responds()).await {
+					Ok(true) => {
+						if let Some(notifier) = &notifier {
+							notifier.watchdog();
+						}
+					},
+					Ok(false) => tracing::warn!("Moonshine failed to respond, not pinging the systemd watchdog."),
+					Err(_) => tracing::warn!("Moonshine didn't respond within {timeout:?}, not pinging the systemd watchdog."),
+				}
+			},
+		}
+	}
+	if let Some(notifier) = &notifier {
+		notifier.stopping();
+	}
+
+	// Drop the main moonshine object, triggering other systems to shutdown too.
+	drop(moonshine);
+
+	// Wait until everything was shutdown.
+	let exit_code = shutdown.wait_shutdown_complete().await;
+	tracing::trace!("Successfully waited for shutdown to complete.");
+
+	// Flush logs that are still being written to file, since exiting skips destructors.
+	drop(log_guard);
+	std::process::exit(exit_code);
+}
+
+pub struct Moonshine {
+	_rtsp_server: RtspServer,
+	session_manager: SessionManager,
+	client_manager: ClientManager,
+	_webserver: Webserver,
+}
+
+impl Moonshine {
+	pub async fn new(
+		config: Config,
+		mut sockets: ActivatedSockets,
+		shutdown: ShutdownManager<i32>,
+	) -> Result<Self, MoonshineError> {
+		let state = State::new().await?;
+
+		let identity = certificate::load_or_create(&config.webserver)?;
+		let (identity_tx, identity_rx) = tokio::sync::watch::channel(identity);
+		certificate::spawn_watcher(config.webserver.clone(), identity_tx);
+
+		// Events that are pushed to the host, for example to update a dashboard.
+		let events = Events::new();
+
+		// Create a manager for interacting with sessions.
+		let session_manager = SessionManager::new(config.clone(), state.clone(), events.clone(), shutdown.trigger_shutdown_token(2))?;
+
+		// Create a manager for saving and loading client state.
+		let client_manager = ClientManager::new(state.clone(), identity_rx.clone(), shutdown.trigger_shutdown_token(3));
+
+		// Probe what the encoders can do, which decides which codecs are offered to clients.
+		let encoder_capabilities = EncoderCapabilities::probe(&config.stream.video);
+
+		// Run the RTSP server.
+		let rtsp_server = RtspServer::new(config.clone(), session_manager.clone(), encoder_capabilities, &mut sockets, shutdown.clone())?;
+
+		// Publish the Moonshine service using zeroconf.
+		publisher::spawn(config.webserver.port, config.name.clone(), config.mdns.clone());
+
+		// Expose the host on the session bus for desktop applets and scripts.
+		dbus::spawn(config.dbus.clone(), session_manager.clone(), client_manager.clone(), &events);
+
+		// Show the state of the session in the tray of the desktop.
+		#[cfg(feature = "gui")]
+		tray::spawn(session_manager.clone(), &events);
+
+		// Create a handler for the webserver.
+		let webserver = Webserver::new(
+			config,
+			state.get_uuid().await?,
+			identity_rx,
+			encoder_capabilities,
+			client_manager.clone(),
+			session_manager.clone(),
+			events,
+			&mut sockets,
+			shutdown,
+		)?;
+		sockets.warn_unused();
+
+		Ok(Self {
+			_rtsp_server: rtsp_server,
+			session_manager,
+			client_manager,
+			_webserver: webserver,
+		})
+	}
+
+	/// Check that the session manager and the client manager still handle commands.
+	pub async fn responds(&self) -> bool {
+		self.session_manager.get_session_context().await.is_ok() && self.client_manager.devices().await.is_ok()
+	}
+}
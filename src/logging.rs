@@ -0,0 +1,55 @@
+use tracing_appender::{non_blocking::WorkerGuard, rolling::{RollingFileAppender, Rotation}};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::config::{LoggingConfig, LogRotation};
+
+/// Log to stdout, and to log files and the systemd journal if configured.
+///
+/// Log files are written from a background thread, the returned guard flushes remaining logs when dropped.
+pub fn init(config: &LoggingConfig) -> Result<Option<WorkerGuard>, ()> {
+	let (file_layer, guard) = match &config.directory {
+		Some(directory) => {
+			let rotation = match config.rotation {
+				LogRotation::Hourly => Rotation::HOURLY,
+				LogRotation::Daily => Rotation::DAILY,
+				LogRotation::Never => Rotation::NEVER,
+			};
+
+			let appender = RollingFileAppender::builder()
+				.rotation(rotation)
+				.filename_prefix("moonshine")
+				.filename_suffix("log")
+				.max_log_files(config.max_files.max(1))
+				.build(directory)
+				.map_err(|e| tracing::error!("Failed to create log file in {}: {e}", directory.display()))?;
+			let (writer, guard) = tracing_appender::non_blocking(appender);
+
+			let layer = tracing_subscriber::fmt::layer()
+				.with_ansi(false)
+				.with_writer(writer);
+			(Some(layer), Some(guard))
+		},
+		None => (None, None),
+	};
+
+	let journald_layer = if config.journald {
+		match tracing_journald::layer() {
+			Ok(layer) => Some(layer.with_syslog_identifier("moonshine".to_string())),
+			Err(e) => {
+				tracing::warn!("Failed to connect to the systemd journal, not logging to the journal: {e}");
+				None
+			},
+		}
+	} else {
+		None
+	};
+
+	tracing_subscriber::registry()
+		.with(tracing_subscriber::fmt::layer())
+		.with(file_layer)
+		.with(journald_layer)
+		.with(EnvFilter::from_default_env())
+		.init();
+
+	Ok(guard)
+}
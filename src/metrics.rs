@@ -0,0 +1,152 @@
+use std::{fmt::Write, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}};
+
+/// Upper bounds of the buckets of the latency histograms, in microseconds.
+const BUCKETS: [u64; 11] = [100, 250, 500, 1_000, 2_000, 4_000, 8_000, 16_000, 32_000, 64_000, 128_000];
+
+/// Latency of every stage of the video stream, since moonshine started.
+///
+/// This is shared by the capture and encoding threads of all sessions, and exported on `/api/v1/metrics`.
+static STAGE_LATENCIES: [Histogram; Stage::ALL.len()] = [const { Histogram::new() }; Stage::ALL.len()];
+
+/// Stage that a frame of the video stream passes through on its way to the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+	/// Getting a frame from NvFBC, the compositor or the X server.
+	Capture = 0,
+
+	/// Copying and scaling the captured frame into the buffer of the encoder.
+	Convert = 1,
+
+	/// Encoding the frame, from sending it to the encoder until receiving its packets.
+	Encode = 2,
+
+	/// Splitting the encoded frame in packets with parity packets.
+	Packetize = 3,
+
+	/// Sending a packet over the socket.
+	Send = 4,
+}
+
+impl Stage {
+	const ALL: [Self; 5] = [Self::Capture, Self::Convert, Self::Encode, Self::Packetize, Self::Send];
+
+	fn name(self) -> &'static str {
+		match self {
+			Self::Capture => "capture",
+			Self::Convert => "convert",
+			Self::Encode => "encode",
+			Self::Packetize => "packetize",
+			Self::Send => "send",
+		}
+	}
+}
+
+/// Measures a stage on the current thread until it is dropped, within a `stage` span at trace level.
+///
+/// The span must not be held across an `.await`, so asynchronous stages use [`record`] instead.
+pub struct StageTimer {
+	stage: Stage,
+	started: Instant,
+	_span: tracing::span::EnteredSpan,
+}
+
+impl StageTimer {
+	pub fn start(stage: Stage) -> Self {
+		Self {
+			stage,
+			started: Instant::now(),
+			_span: tracing::trace_span!("stage", stage = stage.name()).entered(),
+		}
+	}
+}
+
+impl Drop for StageTimer {
+	fn drop(&mut self) {
+		record(self.stage, self.started.elapsed());
+	}
+}
+
+/// Record that `stage` took `duration`.
+///
+/// With the `usdt` feature, this also fires the `moonshine:stage` probe with the stage and the duration in microseconds,
+/// so that tools like `perf` and `bpftrace` can attribute latency spikes.
+pub fn record(stage: Stage, duration: Duration) {
+	let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+	#[cfg(feature = "usdt")]
+	probe::probe!(moonshine, stage, stage as usize, micros);
+
+	tracing::trace!(stage = stage.name(), micros, "Finished stage.");
+	STAGE_LATENCIES[stage as usize].observe(micros);
+}
+
+/// Describe the latency of the stages in the Prometheus text format.
+pub fn render() -> String {
+	let mut output = String::new();
+	output += "# HELP moonshine_stage_latency_seconds Time that frames of the video stream spend in every stage.\n";
+	output += "# TYPE moonshine_stage_latency_seconds histogram\n";
+	for stage in Stage::ALL {
+		STAGE_LATENCIES[stage as usize].render("moonshine_stage_latency_seconds", stage.name(), &mut output);
+	}
+
+	output
+}
+
+/// Histogram of durations, with the buckets in [`BUCKETS`] and one for everything longer.
+struct Histogram {
+	buckets: [AtomicU64; BUCKETS.len() + 1],
+	sum_micros: AtomicU64,
+	count: AtomicU64,
+}
+
+impl Histogram {
+	const fn new() -> Self {
+		Self {
+			buckets: [const { AtomicU64::new(0) }; BUCKETS.len() + 1],
+			sum_micros: AtomicU64::new(0),
+			count: AtomicU64::new(0),
+		}
+	}
+
+	fn observe(&self, micros: u64) {
+		let bucket = BUCKETS.iter().position(|&bound| micros <= bound).unwrap_or(BUCKETS.len());
+		self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+		self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+		self.count.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Write the histogram with cumulative buckets, as Prometheus expects them.
+	fn render(&self, name: &str, stage: &str, output: &mut String) {
+		let mut cumulative = 0;
+		for (index, bucket) in self.buckets.iter().enumerate() {
+			cumulative += bucket.load(Ordering::Relaxed);
+			let bound = BUCKETS.get(index).map(|&bound| (bound as f64 / 1_000_000.0).to_string()).unwrap_or_else(|| "+Inf".to_string());
+			let _ = writeln!(output, "{name}_bucket{{stage=\"{stage}\",le=\"{bound}\"}} {cumulative}");
+		}
+
+		let _ = writeln!(output, "{name}_sum{{stage=\"{stage}\"}} {}", self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+		let _ = writeln!(output, "{name}_count{{stage=\"{stage}\"}} {}", self.count.load(Ordering::Relaxed));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn histogram_buckets_are_cumulative() {
+		let histogram = Histogram::new();
+		histogram.observe(80);
+		histogram.observe(100);
+		histogram.observe(3_000);
+		histogram.observe(200_000);
+
+		let mut output = String::new();
+		histogram.render("latency", "encode", &mut output);
+		let lines: Vec<&str> = output.lines().collect();
+		assert_eq!(lines[0], "latency_bucket{stage=\"encode\",le=\"0.0001\"} 2");
+		assert_eq!(lines[5], "latency_bucket{stage=\"encode\",le=\"0.004\"} 3");
+		assert_eq!(lines[BUCKETS.len()], "latency_bucket{stage=\"encode\",le=\"+Inf\"} 4");
+		assert_eq!(lines[BUCKETS.len() + 1], "latency_sum{stage=\"encode\"} 0.20318");
+		assert_eq!(lines[BUCKETS.len() + 2], "latency_count{stage=\"encode\"} 4");
+	}
+}
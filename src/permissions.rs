@@ -0,0 +1,150 @@
+use std::{io::{ErrorKind, Write}, process::{Command, Stdio}};
+
+/// Udev rule that gives the user that is logged in on the seat access to `/dev/uinput`.
+///
+/// This doesn't use the input group, because its members can also read every input device, including the keyboard.
+const UDEV_RULE: &str = "KERNEL==\"uinput\", SUBSYSTEM==\"misc\", TAG+=\"uaccess\", OPTIONS+=\"static_node=uinput\"\n";
+
+const UDEV_RULE_PATH: &str = "/etc/udev/rules.d/60-moonshine.rules";
+
+/// Loads the uinput module on boot, so that the device exists before moonshine starts.
+const MODULES_LOAD_PATH: &str = "/etc/modules-load.d/moonshine.conf";
+
+/// Groups that give access to the devices used by applications that render on the GPU.
+const GROUPS: &[&str] = &["render"];
+
+const UINPUT_DEVICE: &str = "/dev/uinput";
+
+/// Install the udev rule for `/dev/uinput` and add the user to the groups that streaming needs, then check the result.
+///
+/// Every change is confirmed first, unless `assume_yes` is set. Changes are made with sudo when not running as root.
+pub fn setup(assume_yes: bool) -> Result<(), ()> {
+	let user = target_user()?;
+	tracing::info!("Setting up permissions for user '{user}'.");
+
+	let mut rule_installed = false;
+	if std::fs::read_to_string(UDEV_RULE_PATH).is_ok_and(|rule| rule == UDEV_RULE) {
+		tracing::info!("The udev rule for '{UINPUT_DEVICE}' is already installed in '{UDEV_RULE_PATH}'.");
+	} else if confirm(&format!("Install a udev rule for '{UINPUT_DEVICE}' in '{UDEV_RULE_PATH}' and load the uinput module on boot?"), assume_yes) {
+		run_privileged("tee", &[UDEV_RULE_PATH], Some(UDEV_RULE))?;
+		run_privileged("tee", &[MODULES_LOAD_PATH], Some("uinput\n"))?;
+		run_privileged("modprobe", &["uinput"], None)?;
+		run_privileged("udevadm", &["control", "--reload-rules"], None)?;
+		run_privileged("udevadm", &["trigger", "--subsystem-match=misc", "--sysname-match=uinput"], None)?;
+		rule_installed = true;
+	}
+
+	let mut added_groups = Vec::new();
+	let current_groups = user_groups(&user)?;
+	for group in GROUPS {
+		if current_groups.iter().any(|current| current == group) {
+			tracing::info!("User '{user}' is already in the {group} group.");
+		} else if !group_exists(group) {
+			tracing::warn!("There is no {group} group on this system, skipping it.");
+		} else if confirm(&format!("Add user '{user}' to the {group} group?"), assume_yes) {
+			run_privileged("usermod", &["-aG", group, &user], None)?;
+			added_groups.push(*group);
+		}
+	}
+
+	tracing::info!("Checking the result.");
+	match std::fs::OpenOptions::new().read(true).write(true).open(UINPUT_DEVICE) {
+		Ok(_) => {
+			tracing::info!("'{UINPUT_DEVICE}' is accessible, virtual input devices can be created.");
+			Ok(())
+		},
+		// Group membership only applies to new logins, and the uaccess tag only to new sessions.
+		Err(e) if e.kind() == ErrorKind::PermissionDenied && (rule_installed || !added_groups.is_empty()) => {
+			tracing::warn!("'{UINPUT_DEVICE}' is not accessible yet ({e}), log out and in again (or reboot) for the changes to take effect.");
+			Ok(())
+		},
+		Err(e) => {
+			tracing::error!("'{UINPUT_DEVICE}' is still not accessible ({e}), the `doctor` command explains what is missing.");
+			Err(())
+		},
+	}
+}
+
+/// User whose permissions are set up, which is the user that invoked sudo when running through sudo.
+fn target_user() -> Result<String, ()> {
+	std::env::var("SUDO_USER")
+		.or_else(|_| std::env::var("USER"))
+		.map_err(|_| tracing::error!("Failed to determine the current user, `USER` is not set."))
+}
+
+fn is_root() -> bool {
+	unsafe { libc::geteuid() == 0 }
+}
+
+/// Ask the user to confirm a change on the terminal.
+fn confirm(question: &str, assume_yes: bool) -> bool {
+	if assume_yes {
+		tracing::info!("{question} Yes.");
+		return true;
+	}
+
+	print!("{question} [y/N] ");
+	let _ = std::io::stdout().flush();
+
+	let mut answer = String::new();
+	if std::io::stdin().read_line(&mut answer).is_err() {
+		return false;
+	}
+
+	matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Run a program as root, through sudo when not running as root already.
+fn run_privileged(program: &str, args: &[&str], input: Option<&str>) -> Result<(), ()> {
+	let mut command = if is_root() {
+		Command::new(program)
+	} else {
+		let mut command = Command::new("sudo");
+		command.arg(program);
+		command
+	};
+	command.args(args)
+		.stdin(if input.is_some() { Stdio::piped() } else { Stdio::inherit() })
+		.stdout(Stdio::null());
+
+	tracing::debug!("Running '{program} {}'.", args.join(" "));
+	let mut child = command.spawn()
+		.map_err(|e| tracing::error!("Failed to run '{program}': {e}"))?;
+	if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+		stdin.write_all(input.as_bytes())
+			.map_err(|e| tracing::error!("Failed to write to '{program}': {e}"))?;
+	}
+
+	let status = child.wait()
+		.map_err(|e| tracing::error!("Failed to wait for '{program}': {e}"))?;
+	if !status.success() {
+		tracing::error!("Command '{program} {}' failed with {status}.", args.join(" "));
+		return Err(());
+	}
+
+	Ok(())
+}
+
+/// Names of the groups that `user` is a member of, according to the user database (not the groups of this process).
+fn user_groups(user: &str) -> Result<Vec<String>, ()> {
+	let output = Command::new("id")
+		.args(["-nG", user])
+		.stdin(Stdio::null())
+		.output()
+		.map_err(|e| tracing::error!("Failed to run 'id': {e}"))?;
+	if !output.status.success() {
+		tracing::error!("Failed to get the groups of user '{user}': {}", String::from_utf8_lossy(&output.stderr).trim());
+		return Err(());
+	}
+
+	Ok(String::from_utf8_lossy(&output.stdout).split_whitespace().map(str::to_string).collect())
+}
+
+fn group_exists(group: &str) -> bool {
+	Command::new("getent")
+		.args(["group", group])
+		.stdin(Stdio::null())
+		.stdout(Stdio::null())
+		.status()
+		.is_ok_and(|status| status.success())
+}
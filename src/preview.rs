@@ -0,0 +1,51 @@
+use std::net::SocketAddr;
+
+use async_shutdown::ShutdownManager;
+
+use crate::{config::Config, error::MoonshineError, session::{stream::{probe_capture, StreamSocket, VideoStream, VideoStreamContext}, Recorder, SessionShutdownReason, SessionTimings}};
+
+/// Serve the captured and encoded screen as MPEG-TS over HTTP, so that capture and encoding can be checked with any player.
+///
+/// This runs the same capture and encoding pipeline as a stream to a Moonlight client.
+/// Returns an error when the preview stopped because something failed.
+pub async fn run(config: Config, address: SocketAddr, bitrate: usize) -> Result<(), MoonshineError> {
+	let (width, height) = probe_capture(&config.stream.video)?;
+
+	tracing::info!("Waiting for a player to connect, for example: `mpv http://{address}` or `ffplay http://{address}`.");
+	let recorder = tokio::task::spawn_blocking(move || Recorder::serve(address))
+		.await
+		.map_err(|e| tracing::error!("Failed to wait for a player: {e}"))??;
+
+	let context = VideoStreamContext {
+		width,
+		height,
+		fps: 60,
+		packet_size: 1024,
+		bitrate,
+		minimum_fec_packets: 2,
+		qos: false,
+		video_format: 0,
+		yuv444: false,
+		profile: config.stream.video.profile,
+		loopback: false,
+		encrypted: false,
+	};
+	// No client pings the video stream, let the OS pick a port so that this doesn't conflict with a running server.
+	let socket = StreamSocket::bind(&config.address, 0, None, &config.stream.socket, None).await?;
+	let stop_signal = ShutdownManager::new();
+	let video_stream = VideoStream::new(config, context, socket, Some(recorder), SessionTimings::new(), stop_signal.clone());
+	video_stream.start(None).await?;
+
+	tracing::info!("Serving a preview of {width}x{height}, press CTRL+C to stop.");
+	let reason = tokio::select! {
+		_ = tokio::signal::ctrl_c() => SessionShutdownReason::HostStopped,
+		reason = stop_signal.wait_shutdown_triggered() => reason,
+	};
+
+	let _ = stop_signal.trigger_shutdown(reason);
+	if reason.is_failure() {
+		return Err(MoonshineError::SessionStopped(reason));
+	}
+
+	Ok(())
+}
@@ -0,0 +1,90 @@
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+use zeroconf::prelude::*;
+
+use crate::config::MdnsConfig;
+use super::network_addresses;
+
+/// Time to wait before trying to register the service again after a failure.
+const REGISTER_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Publish the service through avahi, registering it again whenever it gets lost.
+pub fn run(port: u16, name: String, config: MdnsConfig) {
+	let network_check_interval = Duration::from_secs(config.network_check_interval);
+
+	// Keep registering the service, every time the registration is lost (ie. avahi restarted)
+	// or the network interfaces change, the loop below breaks and we register again.
+	loop {
+		let addresses = network_addresses();
+
+		let mut service = match create_service(port, &name, &config.txt_records) {
+			Ok(service) => service,
+			Err(()) => {
+				std::thread::sleep(REGISTER_RETRY_INTERVAL);
+				continue;
+			},
+		};
+
+		let event_loop = match service.register() {
+			Ok(event_loop) => event_loop,
+			Err(e) => {
+				tracing::error!("Failed to register service: {e}");
+				std::thread::sleep(REGISTER_RETRY_INTERVAL);
+				continue;
+			},
+		};
+
+		let mut last_network_check = Instant::now();
+		loop {
+			// Calling `poll()` will keep this service alive.
+			if let Err(e) = event_loop.poll(Duration::from_secs(0)) {
+				tracing::warn!("Failed to publish service, registering it again: {e}");
+				break;
+			}
+
+			if last_network_check.elapsed() >= network_check_interval {
+				last_network_check = Instant::now();
+				if network_addresses() != addresses {
+					tracing::info!("Network interfaces changed, registering service again.");
+					break;
+				}
+			}
+
+			std::thread::sleep(Duration::from_secs(1));
+		}
+	}
+}
+
+fn create_service(port: u16, name: &str, txt_records: &BTreeMap<String, String>) -> Result<zeroconf::MdnsService, ()> {
+	let mut service = zeroconf::MdnsService::new(
+		zeroconf::ServiceType::new("nvstream", "tcp")
+			.map_err(|e| tracing::error!("Failed to publish: {e}"))?,
+		port
+	);
+
+	service.set_registered_callback(Box::new(on_service_registered));
+	service.set_name(name);
+	service.set_network_interface(zeroconf::NetworkInterface::Unspec);
+
+	if !txt_records.is_empty() {
+		let mut txt_record = zeroconf::TxtRecord::new();
+		for (key, value) in txt_records {
+			txt_record.insert(key, value)
+				.map_err(|e| tracing::error!("Failed to add TXT record '{key}={value}': {e}"))?;
+		}
+		service.set_txt_record(txt_record);
+	}
+
+	Ok(service)
+}
+
+fn on_service_registered(
+	result: zeroconf::Result<zeroconf::ServiceRegistration>,
+	_context: Option<std::sync::Arc<dyn std::any::Any>>,
+) {
+	if let Err(e) = result {
+		tracing::error!("Failed to register service: {e}");
+	} else {
+		tracing::info!("Service successfully registered.");
+	}
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use mdns_sd::{DaemonEvent, ServiceDaemon, ServiceInfo};
+
+use crate::config::MdnsConfig;
+use super::host_name;
+
+const SERVICE_TYPE: &str = "_nvstream._tcp.local.";
+
+/// Publish the service using the built-in mDNS responder, without depending on avahi.
+///
+/// Addresses are updated automatically by the responder when network interfaces change.
+pub fn run(port: u16, name: String, config: MdnsConfig) {
+	let _ = publish(port, name, config);
+}
+
+fn publish(port: u16, name: String, config: MdnsConfig) -> Result<(), ()> {
+	let daemon = ServiceDaemon::new()
+		.map_err(|e| tracing::error!("Failed to start mDNS responder: {e}"))?;
+
+	let host_name = format!("{}.local.", host_name().unwrap_or_else(|| name.replace(' ', "-")));
+	let txt_records: HashMap<String, String> = config.txt_records.into_iter().collect();
+	let service = ServiceInfo::new(SERVICE_TYPE, &name, &host_name, (), port, txt_records)
+		.map_err(|e| tracing::error!("Failed to create mDNS service: {e}"))?
+		.enable_addr_auto();
+
+	let events = daemon.monitor()
+		.map_err(|e| tracing::error!("Failed to monitor mDNS responder: {e}"))?;
+	daemon.register(service)
+		.map_err(|e| tracing::error!("Failed to register service: {e}"))?;
+	tracing::info!("Service successfully registered with the built-in mDNS responder.");
+
+	// Waiting for events also keeps the responder alive.
+	while let Ok(event) = events.recv() {
+		match event {
+			DaemonEvent::Error(e) => tracing::warn!("Error in mDNS responder: {e}"),
+			DaemonEvent::NameChange(change) => {
+				tracing::info!("Name conflict on the network, service is now published as '{}'.", change.new_name);
+			},
+			event => tracing::trace!("mDNS responder event: {event:?}"),
+		}
+	}
+
+	tracing::debug!("mDNS responder stopped.");
+	Ok(())
+}
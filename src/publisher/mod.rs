@@ -0,0 +1,117 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use network_interface::NetworkInterfaceConfig;
+
+use crate::config::{MdnsBackend, MdnsConfig};
+
+mod avahi;
+mod builtin;
+
+pub fn spawn(port: u16, name: String, config: MdnsConfig) {
+	let name = config.name.clone().unwrap_or(name);
+
+	tokio::task::spawn_blocking(move || {
+		match config.backend {
+			MdnsBackend::Avahi => avahi::run(port, name, config),
+			MdnsBackend::Builtin => builtin::run(port, name, config),
+		}
+	});
+}
+
+/// Get the hostname of this machine.
+pub fn host_name() -> Option<String> {
+	std::fs::read_to_string("/proc/sys/kernel/hostname")
+		.map(|host_name| host_name.trim().to_string())
+		.ok()
+		.filter(|host_name| !host_name.is_empty())
+}
+
+/// Get a sorted list of all addresses of all network interfaces.
+pub fn network_addresses() -> Vec<IpAddr> {
+	let interfaces = match network_interface::NetworkInterface::show() {
+		Ok(interfaces) => interfaces,
+		Err(e) => {
+			tracing::warn!("Failed to retrieve network interfaces: {e}");
+			return Vec::new();
+		},
+	};
+
+	let mut addresses: Vec<IpAddr> = interfaces
+		.into_iter()
+		.flat_map(|interface| interface.addr.into_iter().map(|address| address.ip()))
+		.collect();
+	addresses.sort();
+
+	addresses
+}
+
+/// Get the IPv4 address of the interface with the default route.
+///
+/// Other devices on the network can usually reach the host on this address,
+/// unlike the addresses of interfaces for containers, virtual machines or VPNs.
+pub fn default_route_address() -> Option<Ipv4Addr> {
+	let routes = std::fs::read_to_string("/proc/net/route")
+		.map_err(|e| tracing::warn!("Failed to read the routing table: {e}"))
+		.ok()?;
+	let interface = default_route_interface(&routes)?;
+
+	let interfaces = network_interface::NetworkInterface::show()
+		.map_err(|e| tracing::warn!("Failed to retrieve network interfaces: {e}"))
+		.ok()?;
+	interfaces.into_iter()
+		.filter(|candidate| candidate.name == interface)
+		.flat_map(|candidate| candidate.addr)
+		.find_map(|address| match address.ip() {
+			IpAddr::V4(address) if !address.is_loopback() && !address.is_link_local() => Some(address),
+			_ => None,
+		})
+}
+
+/// Find the interface of the default route in the IPv4 routing table of the kernel (`/proc/net/route`).
+///
+/// When there are multiple default routes, the one with the lowest metric is used, like the kernel does.
+fn default_route_interface(routes: &str) -> Option<&str> {
+	/// Flag of routes that are in use.
+	const RTF_UP: u32 = 0x1;
+
+	// The columns are: Iface, Destination, Gateway, Flags, RefCnt, Use, Metric, Mask, ...
+	routes.lines()
+		.skip(1)
+		.filter_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			let (interface, destination, flags, metric, mask) = (fields.first()?, fields.get(1)?, fields.get(3)?, fields.get(6)?, fields.get(7)?);
+			let flags = u32::from_str_radix(flags, 16).ok()?;
+			let metric: u32 = metric.parse().ok()?;
+			let default_route = *destination == "00000000" && *mask == "00000000" && flags & RTF_UP != 0;
+			default_route.then_some((metric, *interface))
+		})
+		.min_by_key(|(metric, _)| *metric)
+		.map(|(_, interface)| interface)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ROUTES: &str = "\
+Iface	Destination	Gateway 	Flags	RefCnt	Use	Metric	Mask		MTU	Window	IRTT
+docker0	000011AC	00000000	0001	0	0	0	0000FFFF	0	0	0
+wlan0	00000000	0100A8C0	0003	0	0	600	00000000	0	0	0
+enp5s0	00000000	0100A8C0	0003	0	0	100	00000000	0	0	0
+enp5s0	0000A8C0	00000000	0001	0	0	100	00FFFFFF	0	0	0
+";
+
+	#[test]
+	fn default_route_with_lowest_metric() {
+		assert_eq!(default_route_interface(ROUTES), Some("enp5s0"));
+	}
+
+	#[test]
+	fn no_default_route() {
+		let routes: String = ROUTES.lines()
+			.filter(|line| !line.contains("\t00000000\t0100A8C0"))
+			.map(|line| format!("{line}\n"))
+			.collect();
+		assert_eq!(default_route_interface(&routes), None);
+	}
+}
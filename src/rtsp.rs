@@ -1,23 +1,47 @@
-use std::{net::{ToSocketAddrs, SocketAddr}, str::FromStr};
+use std::{net::{IpAddr, ToSocketAddrs, SocketAddr}, str::FromStr};
 use async_shutdown::ShutdownManager;
 use rtsp_types::{headers::{self, Transport}, Method};
-use tokio::{net::{TcpListener, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}};
+use tokio::{net::TcpStream, io::{AsyncReadExt, AsyncWriteExt}};
 
-use crate::{config::Config, session::{stream::{AudioStreamContext, VideoStreamContext}, manager::SessionManager}};
+use crate::{clients::ConnectedDevice, config::{ClientLimitsConfig, Config, EncryptionMode}, error::MoonshineError, systemd::ActivatedSockets, session::{stream::{ping_payload, AudioStreamContext, EncoderCapabilities, OpusStreamConfig, VideoStreamContext, AUDIO_STREAM, VIDEO_STREAM}, manager::SessionManager}};
+
+/// Maximum size of an RTSP request, clients that send more are disconnected.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Smallest video packet size we accept, the packets also contain a 16 byte header.
+const MINIMUM_PACKET_SIZE: usize = 256;
+
+/// Flag of the `x-ss-general.encryption*` attributes for encrypted video packets.
+const ENCRYPT_VIDEO: u32 = 0x02;
+
+/// Flag of the `x-ss-general.encryption*` attributes for encrypted audio packets.
+const ENCRYPT_AUDIO: u32 = 0x04;
 
 #[derive(Clone)]
 pub struct RtspServer {
 	config: Config,
 	session_manager: SessionManager,
+
+	/// What the encoders can do, to check the codec options that clients ask for.
+	encoder_capabilities: EncoderCapabilities,
 }
 
 impl RtspServer {
 	pub fn new(
 		config: Config,
 		session_manager: SessionManager,
+		encoder_capabilities: EncoderCapabilities,
+		sockets: &mut ActivatedSockets,
 		shutdown: ShutdownManager<i32>,
-	) -> Self {
-		let server = Self { config: config.clone(), session_manager };
+	) -> Result<Self, ()> {
+		let server = Self { config: config.clone(), session_manager, encoder_capabilities };
+
+		// Listen before returning, so that the server is reachable once it is created.
+		let address = (config.address.as_str(), config.stream.port).to_socket_addrs()
+			.map_err(|e| tracing::error!("Failed to resolve address {}:{}: {}", config.address, config.stream.port, e))?
+			.next()
+			.ok_or_else(|| tracing::error!("Failed to resolve address {}:{}", config.address, config.stream.port))?;
+		let listener = sockets.listen(address)?;
 
 		tokio::spawn({
 			let server = server.clone();
@@ -25,14 +49,6 @@ impl RtspServer {
 				let _ = shutdown.wrap_cancel(shutdown.wrap_trigger_shutdown(3, {
 					let server = server.clone();
 					async move {
-						let address = (config.address.as_str(), config.stream.port).to_socket_addrs()
-							.map_err(|e| tracing::error!("Failed to resolve address {}:{}: {}", config.address, config.stream.port, e))?
-							.next()
-							.ok_or_else(|| tracing::error!("Failed to resolve address {}:{}", config.address, config.stream.port))?;
-						let listener = TcpListener::bind(address)
-							.await
-							.map_err(|e| tracing::error!("Failed to bind to address {}: {}", address, e))?;
-
 						tracing::info!("RTSP server listening on {}", address);
 
 						loop {
@@ -59,20 +75,12 @@ impl RtspServer {
 			}
 		});
 
-		server
+		Ok(server)
 	}
 
-	#[allow(clippy::result_unit_err)]
+	/// Describe what the host can stream, for the DESCRIBE response.
 	pub fn description(&self) -> String {
-		// This is a very simple SDP description, the minimal that Moonlight requires.
-		// TODO: Fill this based on server settings.
-		// TODO: Use:
-		//       "a=x-ss-general.featureFlags: <FEATURE FLAGS>"
-		//       "x-nv-video[0].refPicInvalidation=1"
-		//       "a=rtpmap:98 AV1/90000" (For AV1 support)
-		//       "a=fmtp:97 surround-params=<SURROUND PARAMS>"
-		//       "<AUDIO STREAM MAPPING>"
-		"sprop-parameter-sets=AAAAAU\na=fmtp:96 packetization-mode=1".to_string()
+		session_description(&self.config, &self.encoder_capabilities)
 	}
 
 	fn handle_options_request(&self, request: &rtsp_types::Request<Vec<u8>>, cseq: i32) -> rtsp_types::Response<Vec<u8>> {
@@ -82,11 +90,24 @@ impl RtspServer {
 			.build(Vec::new())
 	}
 
-	fn handle_setup_request(
+	async fn handle_setup_request(
 		&self,
 		request: &rtsp_types::Request<Vec<u8>>,
 		cseq: i32,
 	) -> rtsp_types::Response<Vec<u8>> {
+		// The ping payloads are derived from the keys of the session, so there has to be one.
+		let keys = match self.session_manager.get_session_context().await {
+			Ok(Some(context)) => context.keys,
+			Ok(None) => {
+				tracing::warn!("Received SETUP request without an active session.");
+				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+			},
+			Err(e) => {
+				tracing::error!("Failed to get the session for a SETUP request: {e}.");
+				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::InternalServerError);
+			},
+		};
+
 		let transports = match request.typed_header::<rtsp_types::headers::Transports>() {
 			Ok(transports) => transports,
 			Err(e) => {
@@ -125,10 +146,11 @@ impl RtspServer {
 					}
 
 					// Example query: streamid=control/13/0
-					let (stream_id, port) = match query.1.split('/').next() {
-						Some("video") => ("video", self.config.stream.video.port),
-						Some("audio") => ("audio", self.config.stream.audio.port),
-						Some("control") => ("control", self.config.stream.control.port),
+					// The client repeats the ping payload in its pings, which tells the streams apart when they share a port.
+					let (stream_id, port, payload) = match query.1.split('/').next() {
+						Some(VIDEO_STREAM) => (VIDEO_STREAM, self.config.stream.video.port, Some(ping_payload(&keys, VIDEO_STREAM))),
+						Some(AUDIO_STREAM) => (AUDIO_STREAM, self.config.stream.audio_port(), Some(ping_payload(&keys, AUDIO_STREAM))),
+						Some("control") => ("control", self.config.stream.control.port, None),
 						Some(stream) => {
 							tracing::warn!("Unknown stream '{stream}'");
 							return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
@@ -141,12 +163,18 @@ impl RtspServer {
 
 					tracing::info!("Responding with server_port={port} for stream '{stream_id}'.");
 
-					return rtsp_types::Response::builder(request.version(), rtsp_types::StatusCode::Ok)
+					let mut response = rtsp_types::Response::builder(request.version(), rtsp_types::StatusCode::Ok)
 						.header(headers::CSEQ, cseq.to_string())
 						.header(headers::SESSION, "MoonshineSession;timeout = 90".to_string())
-						.header(headers::TRANSPORT, format!("server_port={port}"))
-						.build(Vec::new())
-					;
+						.header(headers::TRANSPORT, format!("server_port={port}"));
+					if let Some(payload) = payload {
+						match headers::HeaderName::from_static_str("X-SS-Ping-Payload") {
+							Ok(header) => response = response.header(header, payload),
+							Err(e) => tracing::warn!("Failed to create ping payload header: {e:?}"),
+						}
+					}
+
+					return response.build(Vec::new());
 				}
 				t => {
 					tracing::warn!("Received request for unsupported transport: {:?}", t);
@@ -175,6 +203,7 @@ impl RtspServer {
 		&self,
 		request: &rtsp_types::Request<Vec<u8>>,
 		cseq: i32,
+		client_address: IpAddr,
 	) -> rtsp_types::Response<Vec<u8>> {
 		let sdp_session = match sdp_types::Session::parse(request.body()) {
 			Ok(sdp_session) => sdp_session,
@@ -200,28 +229,28 @@ impl RtspServer {
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
-		let fps = match get_sdp_attribute(&sdp_session, "x-nv-video[0].maxFPS") {
-			Ok(fps) => fps,
-			Err(()) => {
-				tracing::warn!("Failed to parse xx-nv-video[0].maxFPS in SDP session.");
+		let mut fps: u32 = match get_sdp_attribute(&sdp_session, "x-nv-video[0].maxFPS") {
+			Ok(fps) if fps > 0 => fps,
+			Ok(_) | Err(()) => {
+				tracing::warn!("Failed to parse x-nv-video[0].maxFPS in SDP session.");
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
 		let packet_size = match get_sdp_attribute(&sdp_session, "x-nv-video[0].packetSize") {
-			Ok(packet_size) => packet_size,
-			Err(()) => {
+			Ok(packet_size) if packet_size >= MINIMUM_PACKET_SIZE => packet_size,
+			Ok(_) | Err(()) => {
 				tracing::warn!("Failed to parse x-nv-video[0].packetSize in SDP session.");
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
-		let mut bitrate = match get_sdp_attribute(&sdp_session, "x-ml-video.configuredBitrateKbps") {
-			Ok(bitrate) => bitrate,
-			Err(()) => {
+		// Convert from kbps to bps.
+		let mut bitrate = match get_sdp_attribute::<usize>(&sdp_session, "x-ml-video.configuredBitrateKbps").map(|bitrate| bitrate.checked_mul(1000)) {
+			Ok(Some(bitrate)) => bitrate,
+			Ok(None) | Err(()) => {
 				tracing::warn!("Failed to parse x-ml-video.configuredBitrateKbps in SDP session.");
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
-		bitrate *= 1000; // Convert from kbps to bps.
 		let minimum_fec_packets = match get_sdp_attribute(&sdp_session, "x-nv-vqos[0].fec.minRequiredFecPackets") {
 			Ok(minimum_fec_packets) => minimum_fec_packets,
 			Err(()) => {
@@ -244,7 +273,21 @@ impl RtspServer {
 			},
 		};
 
-		let video_stream_context = VideoStreamContext {
+		// Some devices shouldn't get everything they ask for, like a phone on a metered connection.
+		let device = self.session_manager.get_session_context().await.ok().flatten().and_then(|context| context.device());
+		if let Some(device) = device {
+			apply_client_limits(&self.config.stream.client_limits, &device, &mut fps, &mut bitrate);
+		}
+
+		// Clients that don't negotiate encryption don't send this attribute, they expect encrypted audio and unencrypted video.
+		let encryption_enabled = sdp_session.get_first_attribute_value("x-ss-general.encryptionEnabled").ok().flatten()
+			.and_then(|flags| flags.trim().parse().ok());
+		let Ok((video_encrypted, audio_encrypted)) = negotiate_encryption(self.config.stream.encryption.video, encryption_enabled) else {
+			tracing::warn!("Video encryption is required, but the client didn't enable it.");
+			return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+		};
+
+		let mut video_stream_context = VideoStreamContext {
 			width,
 			height,
 			fps,
@@ -253,8 +296,23 @@ impl RtspServer {
 			minimum_fec_packets,
 			qos: video_qos_type != "0",
 			video_format,
+			yuv444: false,
+			profile: self.config.stream.video.profile,
+			loopback: false,
+			encrypted: video_encrypted,
 		};
 
+		// Only clients that were offered 4:4:4 ask for it, older clients don't send this attribute at all.
+		let chroma_sampling_type = sdp_session.get_first_attribute_value("x-ss-video[0].chromaSamplingType").ok().flatten();
+		if chroma_sampling_type.map(str::trim) == Some("1") {
+			let codec = video_stream_context.codec();
+			if self.encoder_capabilities.yuv444_supported(codec) {
+				video_stream_context.yuv444 = true;
+			} else {
+				tracing::warn!("Client asked for {codec:?} in 4:4:4, but the encoder doesn't support it. Streaming 4:2:0 instead.");
+			}
+		}
+
 		let packet_duration = match get_sdp_attribute(&sdp_session, "x-nv-aqos.packetDuration") {
 			Ok(packet_duration) => packet_duration,
 			Err(()) => {
@@ -270,12 +328,27 @@ impl RtspServer {
 			},
 		};
 
+		// Clients that don't support surround audio don't send these attributes.
+		let channels = sdp_session.get_first_attribute_value("x-nv-audio.surround.numChannels").ok().flatten()
+			.and_then(|channels| channels.trim().parse().ok())
+			.unwrap_or(2);
+		let high_quality = sdp_session.get_first_attribute_value("x-nv-audio.surround.AudioQuality").ok().flatten()
+			.map(str::trim) == Some("1");
+		let opus_config = OpusStreamConfig::for_client(channels, high_quality);
+		if opus_config.channels != channels {
+			tracing::warn!("Client asked for {channels} audio channels, which isn't supported. Streaming stereo instead.");
+		}
+
 		let audio_stream_context = AudioStreamContext {
 			packet_duration,
 			qos: audio_qos_type != "0",
+			opus_config,
+			loopback: false,
+			encrypted: audio_encrypted,
 		};
 
-		if self.session_manager.set_stream_context(video_stream_context, audio_stream_context).await.is_err() {
+		if let Err(e) = self.session_manager.set_stream_context(video_stream_context, audio_stream_context, client_address).await {
+			tracing::error!("Failed to set up the streams: {e}.");
 			return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::InternalServerError)
 		}
 
@@ -289,8 +362,13 @@ impl RtspServer {
 		request: &rtsp_types::Request<Vec<u8>>,
 		cseq: i32,
 	) -> rtsp_types::Response<Vec<u8>> {
-		if self.session_manager.start_session().await.is_err() {
-			return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::InternalServerError)
+		if let Err(e) = self.session_manager.start_session().await {
+			tracing::error!("Failed to start the streams: {e}.");
+			let status = match e {
+				MoonshineError::NoSession | MoonshineError::StreamNotSetUp => rtsp_types::StatusCode::BadRequest,
+				_ => rtsp_types::StatusCode::InternalServerError,
+			};
+			return rtsp_response(cseq, request.version(), status)
 		}
 
 		rtsp_types::Response::builder(request.version(), rtsp_types::StatusCode::Ok)
@@ -303,7 +381,7 @@ impl RtspServer {
 		mut connection: TcpStream,
 		address: SocketAddr,
 	) -> Result<(), ()> {
-		let mut message_buffer = String::new();
+		let mut message_buffer = Vec::new();
 
 		let message = loop {
 			let mut buffer = [0u8; 2048];
@@ -313,27 +391,20 @@ impl RtspServer {
 				tracing::warn!("Received empty RTSP request.");
 				return Ok(());
 			}
-			message_buffer.push_str(std::str::from_utf8(&buffer[..bytes_read])
-				.map_err(|e| tracing::error!("Failed to convert message to string: {e}"))?);
 
-			// Hacky workaround to fix rtsp_types parsing SETUP/PLAY requests from Moonlight.
-			let message_buffer = message_buffer.replace("streamid", "rtsp://localhost?streamid");
-			let message_buffer = message_buffer.replace("PLAY /", "PLAY rtsp://localhost/");
-
-			tracing::trace!("Request: {}", message_buffer);
-			let result = rtsp_types::Message::parse(&message_buffer);
+			message_buffer.extend_from_slice(&buffer[..bytes_read]);
+			if message_buffer.len() > MAX_REQUEST_SIZE {
+				tracing::error!("Received RTSP request of more than {MAX_REQUEST_SIZE} bytes from '{address}'.");
+				return Err(());
+			}
 
-			break match result {
-				Ok((message, _consumed)) => message,
-				Err(rtsp_types::ParseError::Incomplete(_)) => {
+			match parse_message(&message_buffer)? {
+				Some(message) => break message,
+				None => {
 					tracing::debug!("Incomplete RTSP message received, waiting for more data.");
 					continue;
 				},
-				Err(e) => {
-					tracing::error!("Failed to parse request as RTSP message: {}", e);
-					return Err(());
-				}
-			};
+			}
 		};
 
 		// tracing::trace!("Consumed {} bytes into RTSP request: {:#?}", consumed, message);
@@ -349,10 +420,10 @@ impl RtspServer {
 					.map_err(|e| tracing::error!("Failed to parse CSeq header: {}", e))?;
 
 				match request.method() {
-					Method::Announce => self.handle_announce_request(request, cseq).await,
+					Method::Announce => self.handle_announce_request(request, cseq, address.ip()).await,
 					Method::Describe => self.handle_describe_request(request, cseq).await,
 					Method::Options => self.handle_options_request(request, cseq),
-					Method::Setup => self.handle_setup_request(request, cseq),
+					Method::Setup => self.handle_setup_request(request, cseq).await,
 					Method::Play => self.handle_play_request(request, cseq).await,
 					method => {
 						tracing::warn!("Received request with unsupported method {:?}", method);
@@ -385,12 +456,116 @@ impl RtspServer {
 	}
 }
 
+/// Parse a (partially) received RTSP message, returns `None` if the message is incomplete.
+fn parse_message(buffer: &[u8]) -> Result<Option<rtsp_types::Message<Vec<u8>>>, ()> {
+	let message_buffer = match std::str::from_utf8(buffer) {
+		Ok(message_buffer) => message_buffer,
+		// The last character might not be received completely yet.
+		Err(e) if e.error_len().is_none() => return Ok(None),
+		Err(e) => {
+			tracing::error!("Failed to convert message to string: {e}");
+			return Err(());
+		},
+	};
+
+	// Hacky workaround to fix rtsp_types parsing SETUP/PLAY requests from Moonlight.
+	let message_buffer = message_buffer.replace("streamid", "rtsp://localhost?streamid");
+	let message_buffer = message_buffer.replace("PLAY /", "PLAY rtsp://localhost/");
+
+	tracing::trace!("Request: {}", message_buffer);
+	match rtsp_types::Message::parse(&message_buffer) {
+		Ok((message, _consumed)) => Ok(Some(message)),
+		Err(rtsp_types::ParseError::Incomplete(_)) => Ok(None),
+		Err(e) => {
+			tracing::error!("Failed to parse request as RTSP message: {}", e);
+			Err(())
+		}
+	}
+}
+
+/// Parse data as if a client sent it as an RTSP request, for the fuzz targets.
+pub fn fuzz_message(data: &[u8]) {
+	let _ = parse_message(data);
+}
+
 fn rtsp_response(cseq: i32, version: rtsp_types::Version, status: rtsp_types::StatusCode) -> rtsp_types::Response<Vec<u8>> {
 	rtsp_types::Response::builder(version, status)
 		.header(headers::CSEQ, cseq.to_string())
 		.build(Vec::new())
 }
 
+/// Build the SDP description of the streams from the probed encoders and the configuration.
+///
+/// Moonlight doesn't parse this as SDP, it only looks for the attributes it knows.
+fn session_description(config: &Config, encoder_capabilities: &EncoderCapabilities) -> String {
+	// H264 is always streamed, Moonlight requires it.
+	let mut lines = vec!["a=fmtp:96 packetization-mode=1".to_string()];
+
+	// Moonlight only streams HEVC if it finds this (truncated) parameter set.
+	// AV1 would be advertised with "a=rtpmap:98 AV1/90000", but the stream only encodes H264 and HEVC.
+	if encoder_capabilities.hevc_supported() {
+		lines.insert(0, "sprop-parameter-sets=AAAAAU".to_string());
+	}
+
+	// Moonlight takes the layout of the Opus streams for the number of channels it wants from these.
+	for opus_config in OpusStreamConfig::ADVERTISED {
+		lines.push(format!("a=fmtp:97 surround-params={}", opus_config.surround_params()));
+	}
+
+	// Invalidated reference frames are handled by encoding the next frame as an IDR frame.
+	lines.push("a=x-nv-video[0].refPicInvalidation:1".to_string());
+
+	// Pen and controller touch events aren't supported, so no feature flags are set.
+	lines.push("a=x-ss-general.featureFlags:0".to_string());
+
+	// Audio is always encrypted for clients that don't negotiate encryption, so it is always supported.
+	// The control stream is encrypted the way GeForce Experience does it, so that flag (0x01) isn't set.
+	let (supported, requested) = match config.stream.encryption.video {
+		EncryptionMode::Disabled => (ENCRYPT_AUDIO, ENCRYPT_AUDIO),
+		EncryptionMode::Optional => (ENCRYPT_AUDIO | ENCRYPT_VIDEO, ENCRYPT_AUDIO),
+		EncryptionMode::Required => (ENCRYPT_AUDIO | ENCRYPT_VIDEO, ENCRYPT_AUDIO | ENCRYPT_VIDEO),
+	};
+	lines.push(format!("a=x-ss-general.encryptionSupported:{supported}"));
+	lines.push(format!("a=x-ss-general.encryptionRequested:{requested}"));
+
+	lines.join("\n")
+}
+
+/// Lower the framerate and the bitrate (in bps) to the limits that are configured for `device`.
+///
+/// If the device is in more than one list of limits, the lowest limits apply.
+fn apply_client_limits(limits: &[ClientLimitsConfig], device: &ConnectedDevice, fps: &mut u32, bitrate: &mut usize) {
+	for limits in limits.iter().filter(|limits| device.is_listed(&limits.clients)) {
+		if let Some(max_fps) = limits.max_fps.filter(|&max_fps| max_fps > 0 && *fps > max_fps) {
+			tracing::info!("Limiting the framerate of device '{}' from {} to {max_fps}.", device.name.as_deref().unwrap_or("unknown"), *fps);
+			*fps = max_fps;
+		}
+
+		let max_bitrate = limits.max_bitrate.map(|max_bitrate| max_bitrate.saturating_mul(1000));
+		if let Some(max_bitrate) = max_bitrate.filter(|&max_bitrate| *bitrate > max_bitrate) {
+			tracing::info!(
+				"Limiting the bitrate of device '{}' from {} kbps to {} kbps.",
+				device.name.as_deref().unwrap_or("unknown"), *bitrate / 1000, max_bitrate / 1000,
+			);
+			*bitrate = max_bitrate;
+		}
+	}
+}
+
+/// Decide whether video and audio are encrypted, from the encryption flags that the client enabled (if it sent them).
+///
+/// Fails if video encryption is required, but the client didn't enable it.
+fn negotiate_encryption(video_mode: EncryptionMode, enabled: Option<u32>) -> Result<(bool, bool), ()> {
+	let video_encrypted = video_mode != EncryptionMode::Disabled
+		&& enabled.is_some_and(|flags| flags & ENCRYPT_VIDEO != 0);
+	if video_mode == EncryptionMode::Required && !video_encrypted {
+		return Err(());
+	}
+
+	let audio_encrypted = !enabled.is_some_and(|flags| flags & ENCRYPT_AUDIO == 0);
+	Ok((video_encrypted, audio_encrypted))
+}
+
 fn get_sdp_attribute<F: FromStr>(sdp_session: &sdp_types::Session, attribute: &str) -> Result<F, ()> {
 	sdp_session.get_first_attribute_value(attribute)
 		.map_err(|e| tracing::warn!("Failed to attribute {attribute} from request: {e}"))?
@@ -399,3 +574,108 @@ fn get_sdp_attribute<F: FromStr>(sdp_session: &sdp_types::Session, attribute: &s
 		.parse()
 		.map_err(|_| tracing::warn!("Attribute {attribute} can't be parsed."))
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::testing::Rng;
+
+	use super::*;
+
+	/// SETUP request as sent by Moonlight.
+	const SETUP_REQUEST: &str = "SETUP streamid=video/0/0 RTSP/1.0\r\n\
+		CSeq: 3\r\n\
+		X-GS-ClientVersion: 14\r\n\
+		Host: 192.168.1.2\r\n\
+		Transport: unicast;X-GS-ClientPort=50000-50001\r\n\
+		If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n\
+		\r\n";
+
+	#[test]
+	fn setup_request_is_parsed() {
+		let Ok(Some(rtsp_types::Message::Request(request))) = parse_message(SETUP_REQUEST.as_bytes()) else {
+			panic!("failed to parse SETUP request");
+		};
+		assert_eq!(request.method(), Method::Setup);
+	}
+
+	#[test]
+	fn description_follows_capabilities() {
+		let mut config = Config::default();
+		let description = session_description(&config, &EncoderCapabilities::default());
+		assert!(description.starts_with("a=fmtp:96 packetization-mode=1\n"));
+		assert!(!description.contains("sprop-parameter-sets"));
+		assert_eq!(description.matches("surround-params=").count(), OpusStreamConfig::ADVERTISED.len());
+		assert!(description.contains("a=x-ss-general.encryptionSupported:6\n"));
+		assert!(description.ends_with("a=x-ss-general.encryptionRequested:4"));
+
+		config.stream.encryption.video = EncryptionMode::Required;
+		let description = session_description(&config, &EncoderCapabilities::default());
+		assert!(description.ends_with("a=x-ss-general.encryptionRequested:6"));
+	}
+
+	#[test]
+	fn lowest_client_limits_apply() {
+		let limits = vec![
+			ClientLimitsConfig { clients: vec!["Phone".to_string()], max_bitrate: Some(20_000), max_fps: None },
+			ClientLimitsConfig { clients: vec!["AB:CD".to_string()], max_bitrate: Some(10_000), max_fps: Some(30) },
+			ClientLimitsConfig { clients: vec!["Tablet".to_string()], max_bitrate: Some(1_000), max_fps: Some(1) },
+		];
+		let phone = ConnectedDevice { fingerprint: "abcd".to_string(), name: Some("Phone".to_string()) };
+
+		let (mut fps, mut bitrate) = (60, 50_000_000);
+		apply_client_limits(&limits, &phone, &mut fps, &mut bitrate);
+		assert_eq!((fps, bitrate), (30, 10_000_000));
+
+		// Limits only lower what the client asks for.
+		let (mut fps, mut bitrate) = (24, 5_000_000);
+		apply_client_limits(&limits, &phone, &mut fps, &mut bitrate);
+		assert_eq!((fps, bitrate), (24, 5_000_000));
+	}
+
+	#[test]
+	fn encryption_is_negotiated() {
+		// Clients that don't negotiate encryption get encrypted audio only.
+		assert_eq!(negotiate_encryption(EncryptionMode::Optional, None), Ok((false, true)));
+		assert_eq!(negotiate_encryption(EncryptionMode::Optional, Some(ENCRYPT_AUDIO | ENCRYPT_VIDEO)), Ok((true, true)));
+		assert_eq!(negotiate_encryption(EncryptionMode::Optional, Some(0)), Ok((false, false)));
+		assert_eq!(negotiate_encryption(EncryptionMode::Disabled, Some(ENCRYPT_VIDEO)), Ok((false, false)));
+		assert_eq!(negotiate_encryption(EncryptionMode::Required, Some(ENCRYPT_VIDEO)), Ok((true, false)));
+		assert_eq!(negotiate_encryption(EncryptionMode::Required, None), Err(()));
+	}
+
+	#[test]
+	fn truncated_requests_dont_panic() {
+		for length in 0..SETUP_REQUEST.len() {
+			let _ = parse_message(&SETUP_REQUEST.as_bytes()[..length]);
+		}
+	}
+
+	#[test]
+	fn incomplete_character_waits_for_more_data() {
+		let request = "OPTIONS rtsp://localhost RTSP/1.0\r\nX-Name: é".as_bytes();
+		assert!(matches!(parse_message(&request[..request.len() - 1]), Ok(None)));
+	}
+
+	#[test]
+	fn invalid_utf8_is_rejected() {
+		assert!(parse_message(b"OPTIONS \xFF\xFF RTSP/1.0\r\n\r\n").is_err());
+	}
+
+	/// Parse mutated requests and random data, which must never panic.
+	#[test]
+	fn random_requests_dont_panic() {
+		let mut rng = Rng(0x5EED_1234_ABCD_0004);
+		for _ in 0..10_000 {
+			let mut request = SETUP_REQUEST.as_bytes().to_vec();
+			for _ in 0..rng.range(1, 8) {
+				let index = rng.range(0, request.len());
+				request[index] = rng.next() as u8;
+			}
+			let _ = parse_message(&request);
+		}
+
+		for _ in 0..10_000 {
+			let _ = parse_message(&rng.bytes(256));
+		}
+	}
+}
@@ -0,0 +1,145 @@
+use std::{process::{Command, Stdio}, sync::mpsc::{self, RecvTimeoutError}, thread::JoinHandle, time::Duration};
+
+use crate::config::{BlankMethod, HostDisplayConfig};
+
+/// Interval at which displays are turned off again when using DPMS, since input wakes them up.
+const DPMS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Blanks the physical display of the host, the display is restored when this is dropped.
+pub struct BlankedDisplay {
+	restore: Restore,
+}
+
+enum Restore {
+	/// Brightness of each output before it was blanked.
+	Brightness(Vec<(String, String)>),
+
+	/// Thread that keeps the displays turned off, which stops when the sender is dropped.
+	Dpms(Option<(mpsc::Sender<()>, JoinHandle<()>)>),
+}
+
+impl BlankedDisplay {
+	pub fn new(config: &HostDisplayConfig) -> Result<Self, ()> {
+		let restore = match config.blank_method {
+			BlankMethod::Brightness => {
+				let outputs = output_brightness()?;
+				for (output, _) in &outputs {
+					let _ = run("xrandr", &["--output", output, "--brightness", "0"]);
+				}
+
+				Restore::Brightness(outputs)
+			},
+			BlankMethod::Dpms => {
+				let (stop_tx, stop_rx) = mpsc::channel::<()>();
+				let thread = std::thread::Builder::new().name("host-display".to_string()).spawn(move || {
+					let _ = run("xset", &["dpms", "force", "off"]);
+					while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(DPMS_INTERVAL) {
+						let _ = run("xset", &["dpms", "force", "off"]);
+					}
+				})
+					.map_err(|e| tracing::error!("Failed to start host display thread: {e}"))?;
+
+				Restore::Dpms(Some((stop_tx, thread)))
+			},
+		};
+
+		tracing::info!("Blanked the display of the host while streaming.");
+		Ok(Self { restore })
+	}
+}
+
+impl Drop for BlankedDisplay {
+	fn drop(&mut self) {
+		match &mut self.restore {
+			Restore::Brightness(outputs) => {
+				for (output, brightness) in outputs.iter() {
+					let _ = run("xrandr", &["--output", output, "--brightness", brightness]);
+				}
+			},
+			Restore::Dpms(thread) => {
+				if let Some((stop_tx, thread)) = thread.take() {
+					// Wait for the thread to stop, so it doesn't turn the displays off after we turn them on.
+					drop(stop_tx);
+					let _ = thread.join();
+				}
+				let _ = run("xset", &["dpms", "force", "on"]);
+			},
+		}
+
+		tracing::info!("Restored the display of the host.");
+	}
+}
+
+/// Resolution and refresh rate of the screen of the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DisplayMode {
+	pub width: u32,
+	pub height: u32,
+	pub refresh_rate: u32,
+}
+
+/// Get the current display mode of the host, with the refresh rate of the first active output.
+pub fn current_display_mode() -> Result<DisplayMode, ()> {
+	let output = run("xrandr", &["--current"])?;
+
+	// The first line describes the X screen, ie. "Screen 0: minimum 8 x 8, current 1920 x 1080, maximum 32767 x 32767".
+	let (width, height) = output.lines()
+		.next()
+		.and_then(|line| line.split(", ").find_map(|part| part.strip_prefix("current ")))
+		.and_then(|size| size.split_once(" x "))
+		.and_then(|(width, height)| Some((width.trim().parse().ok()?, height.trim().parse().ok()?)))
+		.ok_or_else(|| tracing::warn!("Couldn't find the size of the screen in the output of 'xrandr'."))?;
+
+	// The active mode of an output is marked with a '*', ie. "   1920x1080     60.00*+  59.94".
+	let refresh_rate = output.lines()
+		.flat_map(str::split_whitespace)
+		.filter(|rate| rate.contains('*'))
+		.find_map(|rate| rate.trim_end_matches(['*', '+']).parse::<f64>().ok())
+		.map(|rate| rate.round() as u32)
+		.ok_or_else(|| tracing::warn!("Couldn't find the refresh rate of the screen in the output of 'xrandr'."))?;
+
+	Ok(DisplayMode { width, height, refresh_rate })
+}
+
+/// Get the name and brightness of all active outputs.
+fn output_brightness() -> Result<Vec<(String, String)>, ()> {
+	let output = run("xrandr", &["--verbose"])?;
+
+	let mut outputs = Vec::new();
+	let mut current_output = None;
+	for line in output.lines() {
+		if !line.starts_with(char::is_whitespace) {
+			// Outputs are listed as "<name> connected ...", followed by indented properties.
+			let mut parts = line.split_whitespace();
+			current_output = match (parts.next(), parts.next()) {
+				(Some(name), Some("connected")) => Some(name.to_string()),
+				_ => None,
+			};
+		} else if let (Some(name), Some(brightness)) = (&current_output, line.trim().strip_prefix("Brightness:")) {
+			outputs.push((name.clone(), brightness.trim().to_string()));
+		}
+	}
+
+	if outputs.is_empty() {
+		tracing::warn!("Couldn't find any active outputs to blank.");
+	}
+
+	Ok(outputs)
+}
+
+/// Run a program and return its output.
+pub(super) fn run(program: &str, args: &[&str]) -> Result<String, ()> {
+	let output = Command::new(program)
+		.args(args)
+		.stdin(Stdio::null())
+		.stderr(Stdio::null())
+		.output()
+		.map_err(|e| tracing::error!("Failed to run '{program}': {e}"))?;
+
+	if !output.status.success() {
+		tracing::warn!("Command '{program} {}' failed with {}.", args.join(" "), output.status);
+		return Err(());
+	}
+
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
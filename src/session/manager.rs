@@ -1,19 +1,25 @@
+use std::{net::IpAddr, time::{Duration, Instant}};
+
 use async_shutdown::{TriggerShutdownToken, ShutdownManager};
 use enet::Enet;
 use tokio::sync::{mpsc, oneshot};
 
-use crate::config::Config;
+use crate::{config::Config, error::MoonshineError, events::{Event, Events}, ffmpeg::encoder::VideoCodec, state::{unix_time, ActiveSession, SessionRecord, State, StreamSettings}};
+
+use super::{Session, SessionShutdownReason, stream::{AudioStreamContext, VideoStreamContext}, SessionContext, SessionKeys, SessionTimings};
 
-use super::{Session, stream::{AudioStreamContext, VideoStreamContext}, SessionContext, SessionKeys};
+/// Interval at which is checked whether the client of a launched session ever started streaming.
+const ORPHAN_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
 pub enum SessionManagerCommand {
-	SetStreamContext(VideoStreamContext, AudioStreamContext),
+	SetStreamContext(VideoStreamContext, AudioStreamContext, IpAddr),
 	GetSessionContext(oneshot::Sender<Option<SessionContext>>),
-	InitializeSession(SessionContext),
+	InitializeSession(SessionContext, oneshot::Sender<Result<(), MoonshineError>>),
 	// GetCurrentSession(oneshot::Sender<Option<Session>>),
-	StartSession,
-	StopSession,
-	UpdateKeys(SessionKeys),
+	StartSession(oneshot::Sender<Result<(), MoonshineError>>),
+	StopSession(SessionShutdownReason, oneshot::Sender<Result<(), MoonshineError>>),
+	SetPaused(bool, oneshot::Sender<Result<(), MoonshineError>>),
+	UpdateKeys(SessionKeys, oneshot::Sender<Result<(), MoonshineError>>),
 }
 
 #[derive(Clone)]
@@ -31,46 +37,52 @@ struct SessionManagerInner {
 
 	/// The context within which the next audio stream will be created.
 	audio_stream_context: Option<AudioStreamContext>,
+
+	/// Address of the client that set up the next stream, streams are only sent to this address.
+	client_address: Option<IpAddr>,
+
+	/// Time at which the active session was launched, until the client starts streaming.
+	launched_at: Option<Instant>,
+
+	/// Events about sessions starting and stopping are sent here.
+	events: Events,
 }
 
 impl SessionManager {
-	#[allow(clippy::result_unit_err)]
-	pub fn new(config: Config, shutdown_token: TriggerShutdownToken<i32>) -> Result<Self, ()> {
+	pub fn new(config: Config, state: State, events: Events, shutdown_token: TriggerShutdownToken<i32>) -> Result<Self, MoonshineError> {
 		// Preferably this gets constructed in control.rs, however it needs to stay
 		// alive throughout the entire application runtime.
 		// Once dropped, it cannot be initialized again.
 		let enet = Enet::new()
-			.map_err(|e| tracing::error!("Failed to initialize Enet session: {e}"))?;
+			.map_err(|e| MoonshineError::Network(format!("failed to initialize Enet: {e}")))?;
 
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner: SessionManagerInner = Default::default();
-		tokio::spawn(async move { inner.run(config, command_rx, enet).await; drop(shutdown_token); });
+		let inner = SessionManagerInner { events, ..Default::default() };
+		tokio::spawn(async move { inner.run(config, state, command_rx, enet).await; drop(shutdown_token); });
 		Ok(Self { command_tx })
 	}
 
 	pub async fn set_stream_context(
 		&self,
 		video_stream_context: VideoStreamContext,
-		audio_stream_context: AudioStreamContext
-	) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context)).await
-			.map_err(|e| tracing::error!("Failed to send SetStreamContext command: {e}"))
+		audio_stream_context: AudioStreamContext,
+		client_address: IpAddr,
+	) -> Result<(), MoonshineError> {
+		self.command_tx.send(SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context, client_address)).await
+			.map_err(|_| MoonshineError::SessionManagerStopped)
 	}
 
-	pub async fn get_session_context(&self) -> Result<Option<SessionContext>, ()> {
+	pub async fn get_session_context(&self) -> Result<Option<SessionContext>, MoonshineError> {
 		let (session_context_tx, session_context_rx) = oneshot::channel();
 		self.command_tx.send(SessionManagerCommand::GetSessionContext(session_context_tx))
 			.await
-			.map_err(|e| tracing::error!("Failed to get session context: {e}"))?;
+			.map_err(|_| MoonshineError::SessionManagerStopped)?;
 		session_context_rx.await
-			.map_err(|e| tracing::error!("Failed to wait for GetCurrentSession response: {e}"))
+			.map_err(|_| MoonshineError::SessionManagerStopped)
 	}
 
-	pub async fn initialize_session(&self, context: SessionContext) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::InitializeSession(context))
-			.await
-			.map_err(|e| tracing::error!("Failed to initialize session: {e}"))?;
-		Ok(())
+	pub async fn initialize_session(&self, context: SessionContext) -> Result<(), MoonshineError> {
+		self.request(|result_tx| SessionManagerCommand::InitializeSession(context, result_tx)).await
 	}
 
 	// pub async fn current_session(&self) -> Result<Option<Session>, ()> {
@@ -82,22 +94,40 @@ impl SessionManager {
 	// 		.map_err(|e| tracing::error!("Failed to wait for GetCurrentSession response: {e}"))
 	// }
 
-	pub async fn start_session(&self) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::StartSession)
-			.await
-			.map_err(|e| tracing::error!("Failed to start session: {e}"))
+	pub async fn start_session(&self) -> Result<(), MoonshineError> {
+		self.request(SessionManagerCommand::StartSession).await
 	}
 
-	pub async fn stop_session(&self) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::StopSession)
-			.await
-			.map_err(|e| tracing::error!("Failed to stop session: {e}"))
+	/// Stop the active session because of `reason`, fails with `NoSession` if there is none.
+	pub async fn stop_session(&self, reason: SessionShutdownReason) -> Result<(), MoonshineError> {
+		self.request(|result_tx| SessionManagerCommand::StopSession(reason, result_tx)).await
+	}
+
+	/// Stop capturing and encoding the stream of the active session, without stopping the session.
+	pub async fn pause_session(&self) -> Result<(), MoonshineError> {
+		self.request(|result_tx| SessionManagerCommand::SetPaused(true, result_tx)).await
+	}
+
+	/// Continue the stream of the active session after it was paused.
+	pub async fn resume_session(&self) -> Result<(), MoonshineError> {
+		self.request(|result_tx| SessionManagerCommand::SetPaused(false, result_tx)).await
+	}
+
+	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), MoonshineError> {
+		self.request(|result_tx| SessionManagerCommand::UpdateKeys(keys, result_tx)).await
 	}
 
-	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::UpdateKeys(keys))
+	/// Send a command and wait for its result.
+	async fn request(
+		&self,
+		command: impl FnOnce(oneshot::Sender<Result<(), MoonshineError>>) -> SessionManagerCommand,
+	) -> Result<(), MoonshineError> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx.send(command(result_tx))
 			.await
-			.map_err(|e| tracing::error!("Failed to stop session: {e}"))
+			.map_err(|_| MoonshineError::SessionManagerStopped)?;
+		result_rx.await
+			.map_err(|_| MoonshineError::SessionManagerStopped)?
 	}
 }
 
@@ -105,6 +135,7 @@ impl SessionManagerInner {
 	async fn run(
 		mut self,
 		config: Config,
+		state: State,
 		mut command_rx: mpsc::Receiver<SessionManagerCommand>,
 		enet: Enet,
 	) {
@@ -112,11 +143,51 @@ impl SessionManagerInner {
 
 		let mut stop_signal = ShutdownManager::new();
 
+		self.session = restore_session(&config, &state, &enet, &stop_signal).await;
+
+		let launch_timeout = Duration::from_secs(config.idle.launch_timeout);
+		let mut orphan_check = tokio::time::interval(ORPHAN_CHECK_INTERVAL);
 		loop {
 			tokio::select! {
-				_ = stop_signal.wait_shutdown_triggered() => {
-					tracing::debug!("Closing session.");
+				_ = orphan_check.tick() => {
+					// A client that launched an application but never set up the stream would keep the host busy forever.
+					if self.launched_at.is_some_and(|launched_at| launched_at.elapsed() >= launch_timeout) {
+						tracing::warn!("Client didn't start streaming within {} seconds after launching, stopping session.", launch_timeout.as_secs());
+						let _ = self.stop_session(&state, SessionShutdownReason::ClientTimeout).await;
+					}
+				},
+
+				reason = stop_signal.wait_shutdown_triggered() => {
+					if reason.is_failure() {
+						tracing::warn!("Closing session because {reason}.");
+					} else {
+						tracing::info!("Closing session because {reason}.");
+					}
+
+					if let Some(session) = &self.session {
+						self.events.send(Event::SessionEnded {
+							application: session.get_context().application.title.clone(),
+							reason,
+						});
+					}
+					if reason.is_failure() {
+						self.events.send(Event::Error { message: format!("Session stopped because {reason}.") });
+					}
+
+					// Nobody is playing anymore, so treat this like the client quitting the application.
+					if reason == SessionShutdownReason::Idle {
+						if let Some(session) = &mut self.session {
+							if session.get_context().application.terminate_on_quit {
+								session.terminate_application();
+							}
+							if !config.idle.run_after {
+								session.skip_run_after();
+							}
+						}
+					}
 					self.session = None;
+					self.launched_at = None;
+					let _ = state.set_active_session(None).await;
 					stop_signal = ShutdownManager::new();
 				},
 
@@ -130,15 +201,21 @@ impl SessionManagerInner {
 					};
 
 					match command {
-						SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context) =>  {
+						SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context, client_address) =>  {
 							if self.session.is_none() {
 								// Well we can, but it is not expected.
 								tracing::warn!("Can't set stream context without an active session.");
 								continue;
 							}
 
+							// Failing to remember the settings of the stream shouldn't prevent it from starting.
+							if let Some(fingerprint) = self.session.as_ref().and_then(|session| session.get_context().device_fingerprint.clone()) {
+								let _ = state.set_last_stream(fingerprint, stream_settings(&video_stream_context, &audio_stream_context)).await;
+							}
+
 							self.video_stream_context = Some(video_stream_context);
 							self.audio_stream_context = Some(audio_stream_context);
+							self.client_address = Some(client_address);
 						},
 
 						SessionManagerCommand::GetSessionContext(session_context_tx) => {
@@ -148,16 +225,49 @@ impl SessionManagerInner {
 							}
 						},
 
-						SessionManagerCommand::InitializeSession(session_context) => {
+						SessionManagerCommand::InitializeSession(session_context, result_tx) => {
 							if self.session.is_some() {
 								tracing::warn!("Can't initialize a session, there is already an active session.");
+								let _ = result_tx.send(Err(MoonshineError::SessionActive));
 								continue;
 							}
 
+							let record = SessionRecord {
+								application_id: session_context.application_id,
+								application: session_context.application.title.clone(),
+								started_at: unix_time(),
+							};
+
+							tracing::info!(
+								"Device '{}' launched '{}'.",
+								session_context.device_name.as_deref().unwrap_or("unknown"),
+								session_context.application.title,
+							);
+
+							let active_session = active_session(&session_context);
+							let started = Event::SessionStarted {
+								client_id: session_context.client_id.clone(),
+								device_name: session_context.device_name.clone(),
+								application_id: session_context.application_id,
+								application: session_context.application.title.clone(),
+							};
+							let title = session_context.application.title.clone();
 							self.session = match Session::new(config.clone(), session_context, enet.clone(), stop_signal.clone()) {
 								Ok(session) => Some(session),
-								Err(()) => continue,
+								Err(()) => {
+									self.events.send(Event::Error { message: format!("Failed to launch '{title}'.") });
+									let _ = result_tx.send(Err(MoonshineError::Logged));
+									continue;
+								},
 							};
+
+							self.launched_at = Some(Instant::now());
+							self.events.send(started);
+
+							// Failing to store the session history shouldn't prevent the session from starting.
+							let _ = state.add_session(record).await;
+							let _ = state.set_active_session(Some(active_session)).await;
+							let _ = result_tx.send(Ok(()));
 						},
 
 						// SessionManagerCommand::GetCurrentSession(session_tx) => {
@@ -166,45 +276,72 @@ impl SessionManagerInner {
 						// 	}
 						// }
 
-						SessionManagerCommand::StartSession => {
+						SessionManagerCommand::StartSession(result_tx) => {
 							let Some(session) = &mut self.session else {
 								tracing::warn!("Can't launch a session, there is no session created yet.");
+								let _ = result_tx.send(Err(MoonshineError::NoSession));
 								continue;
 							};
 
 							if session.is_running() {
 								tracing::info!("Can't start session, it is already running.");
+								let _ = result_tx.send(Ok(()));
 								continue;
 							}
 
-							let Some(video_stream_context) = self.video_stream_context.clone() else {
-								tracing::warn!("Can't start a stream without a video stream context.");
-								continue;
-							};
-							let Some(audio_stream_context) = self.audio_stream_context.clone() else {
-								tracing::warn!("Can't start a stream without a audio stream context.");
+							let (Some(video_stream_context), Some(audio_stream_context), Some(client_address)) =
+								(self.video_stream_context.clone(), self.audio_stream_context.clone(), self.client_address)
+							else {
+								tracing::warn!("Can't start a stream before the client set up the streams.");
+								let _ = result_tx.send(Err(MoonshineError::StreamNotSetUp));
 								continue;
 							};
 
-							let _ = session.start_stream(video_stream_context, audio_stream_context).await;
+							let result = session.start_stream(video_stream_context, audio_stream_context, client_address).await;
+							if result.is_ok() {
+								self.launched_at = None;
+								self.events.send(Event::ClientConnected {
+									device_name: session.get_context().device_name.clone(),
+									address: client_address.to_string(),
+								});
+							} else {
+								self.events.send(Event::Error { message: "Failed to start the stream.".to_string() });
+							}
+							let _ = result_tx.send(result.map_err(MoonshineError::from));
 						},
 
-						SessionManagerCommand::StopSession => {
-							if let Some(session) = &mut self.session {
-								let _ = session.stop_stream().await;
-								self.session = None;
-							} else {
-								tracing::debug!("Trying to stop session, but no session is currently active.");
+						SessionManagerCommand::StopSession(reason, result_tx) => {
+							let _ = result_tx.send(self.stop_session(&state, reason).await);
+						},
+
+						SessionManagerCommand::SetPaused(paused, result_tx) => {
+							let Some(session) = &self.session else {
+								tracing::debug!("Can't pause or resume the stream, there is no active session.");
+								let _ = result_tx.send(Err(MoonshineError::NoSession));
+								continue;
+							};
+							if !session.is_running() {
+								tracing::debug!("Can't pause or resume the stream, the session isn't streaming.");
+								let _ = result_tx.send(Err(MoonshineError::NotStreaming));
+								continue;
 							}
+
+							let result = if paused { session.pause_stream().await } else { session.resume_stream().await };
+							let _ = result_tx.send(result.map_err(MoonshineError::from));
 						},
 
-						SessionManagerCommand::UpdateKeys(keys) => {
+						SessionManagerCommand::UpdateKeys(keys, result_tx) => {
 							let Some(session) = &mut self.session else {
 								tracing::warn!("Can't update session keys, there is no session created yet.");
+								let _ = result_tx.send(Err(MoonshineError::NoSession));
 								continue;
 							};
 
-							let _ = session.update_keys(keys).await;
+							let result = session.update_keys(keys);
+							if result.is_ok() {
+								let _ = state.set_active_session(Some(active_session(session.get_context()))).await;
+							}
+							let _ = result_tx.send(result.map_err(MoonshineError::from));
 						},
 					};
 				}
@@ -212,3 +349,115 @@ impl SessionManagerInner {
 		}
 	}
 }
+
+impl SessionManagerInner {
+	/// Stop the stream and the application of the active session, as if the client quit it.
+	async fn stop_session(&mut self, state: &State, reason: SessionShutdownReason) -> Result<(), MoonshineError> {
+		self.launched_at = None;
+		let Some(session) = &mut self.session else {
+			tracing::debug!("Trying to stop session, but no session is currently active.");
+			return Err(MoonshineError::NoSession);
+		};
+
+		let stop_start = Instant::now();
+		let _ = session.stop_stream().await;
+		if session.get_context().application.terminate_on_quit {
+			session.terminate_application();
+		}
+		self.events.send(Event::SessionEnded {
+			application: session.get_context().application.title.clone(),
+			reason,
+		});
+		self.session = None;
+		let _ = state.set_active_session(None).await;
+		tracing::info!("Stopped session in {} ms.", stop_start.elapsed().as_millis());
+		Ok(())
+	}
+}
+
+/// Describe the stream that the client negotiated, to remember it for the device.
+fn stream_settings(video: &VideoStreamContext, audio: &AudioStreamContext) -> StreamSettings {
+	let codec = match video.codec() {
+		VideoCodec::H264 => "H264",
+		VideoCodec::Hevc => "HEVC",
+	};
+
+	StreamSettings {
+		width: video.width,
+		height: video.height,
+		fps: video.fps,
+		bitrate: video.bitrate / 1000,
+		codec: if video.yuv444 { format!("{codec} 4:4:4") } else { codec.to_string() },
+		audio_channels: audio.opus_config.channels,
+		started_at: unix_time(),
+	}
+}
+
+/// Describe a session, so that it can be restored when moonshine restarts.
+fn active_session(context: &SessionContext) -> ActiveSession {
+	ActiveSession {
+		client_id: context.client_id.clone(),
+		device_name: context.device_name.clone(),
+		device_fingerprint: context.device_fingerprint.clone(),
+		application_id: context.application_id,
+		resolution: context.resolution,
+		refresh_rate: context.refresh_rate,
+		host_audio: context.host_audio,
+		hdr: context.hdr,
+	}
+}
+
+/// Restore the session that was active when moonshine stopped, so that the client can resume it.
+///
+/// The stored session is forgotten if it can't be restored, so that clients see that no application is running.
+async fn restore_session(
+	config: &Config,
+	state: &State,
+	enet: &Enet,
+	stop_signal: &ShutdownManager<SessionShutdownReason>,
+) -> Option<Session> {
+	let active_session = state.get_active_session().await.ok()??;
+
+	let Some(context) = restore_context(config, active_session) else {
+		let _ = state.set_active_session(None).await;
+		return None;
+	};
+
+	tracing::info!(
+		"Restored session of device '{}' for application '{}', waiting for the client to resume it.",
+		context.device_name.as_deref().unwrap_or("unknown"),
+		context.application.title,
+	);
+	Some(Session::restore(config.clone(), context, enet.clone(), stop_signal.clone()))
+}
+
+fn restore_context(config: &Config, active_session: ActiveSession) -> Option<SessionContext> {
+	let Some(application) = config.applications.iter().find(|a| a.id() == active_session.application_id) else {
+		tracing::info!("Not restoring previous session, application with ID {} no longer exists.", active_session.application_id);
+		return None;
+	};
+
+	// The client sends new keys when it resumes the session, until then nothing can be decrypted with these.
+	let mut remote_input_key = vec![0u8; 16];
+	openssl::rand::rand_bytes(&mut remote_input_key)
+		.map_err(|e| tracing::warn!("Not restoring previous session, failed to create a placeholder remote input key: {e}"))
+		.ok()?;
+
+	Some(SessionContext {
+		client_id: active_session.client_id,
+		device_name: active_session.device_name,
+		device_fingerprint: active_session.device_fingerprint,
+		application: application.clone(),
+		application_id: active_session.application_id,
+		resolution: active_session.resolution,
+		refresh_rate: active_session.refresh_rate,
+		host_audio: active_session.host_audio,
+		hdr: active_session.hdr,
+		launch_arguments: Vec::new(),
+		keys: SessionKeys {
+			remote_input_key,
+			remote_input_key_id: 0,
+		},
+		timings: SessionTimings::new(),
+	})
+}
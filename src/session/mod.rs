@@ -1,16 +1,29 @@
-use std::process::Stdio;
+use std::{net::IpAddr, os::unix::process::CommandExt, process::Stdio, sync::{Arc, RwLock}};
 
 use async_shutdown::ShutdownManager;
 use enet::Enet;
 use tokio::sync::mpsc;
+use tracing::Instrument;
 
-use crate::{config::{Config, ApplicationConfig}, session::stream::{VideoStream, AudioStream, ControlStream}};
+use crate::{clients::ConnectedDevice, config::{Config, ApplicationConfig, LaunchArgumentsConfig, SandboxConfig}, ffmpeg::encoder::EncoderProfile, session::stream::{bind_stream_sockets, VideoStream, AudioStream, ControlStream, StreamPause}};
 
-use self::stream::{VideoStreamContext, AudioStreamContext};
+use self::{host_display::BlankedDisplay, sandbox::sandbox_command, stream::{VideoStreamContext, AudioStreamContext}, virtual_sink::VirtualSink};
+pub use host_display::current_display_mode;
 pub use manager::SessionManager;
+pub use ready::wait_until_ready;
+pub use recorder::Recorder;
+pub use shutdown::SessionShutdownReason;
+pub use timings::{Milestone, SessionTimings};
 
+mod host_display;
 pub mod manager;
+mod ready;
+mod recorder;
+mod sandbox;
+mod shutdown;
 pub mod stream;
+mod timings;
+mod virtual_sink;
 
 #[derive(Clone, Debug)]
 pub struct SessionKeys {
@@ -21,9 +34,77 @@ pub struct SessionKeys {
 	pub remote_input_key_id: i64,
 }
 
+/// Session keys shared by all streams of a session.
+///
+/// Every update of the keys starts a new epoch, which all streams observe at the same moment.
+/// The keys of the previous epoch are kept, so that messages that were encrypted with them
+/// while the keys were being updated can still be decrypted.
+#[derive(Clone)]
+pub struct SharedSessionKeys {
+	epochs: Arc<RwLock<KeyEpochs>>,
+}
+
+struct KeyEpochs {
+	/// Number of the current epoch, incremented with every update.
+	epoch: u32,
+
+	/// Keys of the current epoch.
+	current: SessionKeys,
+
+	/// Keys of the previous epoch, if the keys were updated.
+	previous: Option<SessionKeys>,
+}
+
+impl SharedSessionKeys {
+	pub fn new(keys: SessionKeys) -> Self {
+		Self { epochs: Arc::new(RwLock::new(KeyEpochs { epoch: 0, current: keys, previous: None })) }
+	}
+
+	/// Get the epoch and keys to use for encrypting a message.
+	pub fn current(&self) -> Result<(u32, SessionKeys), ()> {
+		let epochs = self.epochs.read()
+			.map_err(|e| tracing::error!("Failed to lock session keys: {e}"))?;
+		Ok((epochs.epoch, epochs.current.clone()))
+	}
+
+	/// Get the keys to try when decrypting a message, the keys of the current epoch come first.
+	pub fn decryption_keys(&self) -> Result<Vec<(u32, SessionKeys)>, ()> {
+		let epochs = self.epochs.read()
+			.map_err(|e| tracing::error!("Failed to lock session keys: {e}"))?;
+
+		let mut keys = vec![(epochs.epoch, epochs.current.clone())];
+		if let Some(previous) = &epochs.previous {
+			keys.push((epochs.epoch.wrapping_sub(1), previous.clone()));
+		}
+
+		Ok(keys)
+	}
+
+	/// Replace the keys for all streams at once, starting a new epoch.
+	pub fn update(&self, keys: SessionKeys) -> Result<u32, ()> {
+		let mut epochs = self.epochs.write()
+			.map_err(|e| tracing::error!("Failed to lock session keys: {e}"))?;
+
+		let previous = std::mem::replace(&mut epochs.current, keys);
+		epochs.previous = Some(previous);
+		epochs.epoch = epochs.epoch.wrapping_add(1);
+
+		Ok(epochs.epoch)
+	}
+}
+
 /// Launch a session for a client.
 #[derive(Clone, Debug)]
 pub struct SessionContext {
+	/// Unique id of the client that launched the session.
+	pub client_id: String,
+
+	/// Name of the device that launched the session, if it is known.
+	pub device_name: Option<String>,
+
+	/// SHA-256 fingerprint of the certificate of the device that launched the session, if it is known.
+	pub device_fingerprint: Option<String>,
+
 	/// Application to launch.
 	pub application: ApplicationConfig,
 
@@ -36,20 +117,50 @@ pub struct SessionContext {
 	/// Refresh rate of the video stream.
 	pub refresh_rate: u32,
 
+	/// Whether the client asked to keep playing audio on the host.
+	pub host_audio: bool,
+
+	/// Whether the client asked for an HDR stream.
+	pub hdr: bool,
+
+	/// Arguments that the client passed when launching the application, appended to the last `run_before` command.
+	pub launch_arguments: Vec<String>,
+
 	/// Encryption keys for encoding traffic.
 	pub keys: SessionKeys,
+
+	/// Time it took to reach the milestones of this session, starting from the launch request.
+	pub timings: SessionTimings,
+}
+
+impl SessionContext {
+	/// The device that launched the session, if its certificate is known.
+	pub fn device(&self) -> Option<ConnectedDevice> {
+		self.device_fingerprint.clone().map(|fingerprint| ConnectedDevice { fingerprint, name: self.device_name.clone() })
+	}
 }
 
 enum SessionCommand {
-	StartStream(VideoStreamContext, AudioStreamContext),
+	StartStream(VideoStreamContext, AudioStreamContext, IpAddr),
 	StopStream,
-	UpdateKeys(SessionKeys),
+
+	/// Stop capturing and encoding, but keep the streams, the RTSP session and the application alive.
+	Pause,
+	Resume,
 }
 
 #[derive(Clone)]
 pub struct Session {
 	command_tx: mpsc::Sender<SessionCommand>,
 	context: SessionContext,
+	keys: SharedSessionKeys,
+
+	/// Ids of the processes started by the `run_before` commands, which are also the ids of their process groups.
+	processes: Vec<u32>,
+
+	/// Whether to run the `run_after` commands when the session ends.
+	run_after: bool,
+
 	running: bool,
 }
 
@@ -59,27 +170,66 @@ impl Session {
 		config: Config,
 		context: SessionContext,
 		enet: Enet,
-		stop_signal: ShutdownManager<()>,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
 	) -> Result<Self, ()> {
+		let mut processes = Vec::new();
 		if let Some(run_before) = &context.application.run_before {
-			for command in run_before {
-				run_command(command, &context);
+			for (index, command) in run_before.iter().enumerate() {
+				let arguments: &[String] = if index + 1 == run_before.len() { &context.launch_arguments } else { &[] };
+				processes.extend(run_command(command, arguments, context.application.sandbox.as_ref(), &context));
 			}
 		}
 
+		let mut session = Self::restore(config, context, enet, stop_signal);
+		session.processes = processes;
+		Ok(session)
+	}
+
+	/// Recreate a session that was active before moonshine restarted.
+	///
+	/// The application was already prepared for the session, so the `run_before` commands aren't run again.
+	/// The processes they started are no longer known, so they can't be terminated when the client quits.
+	pub fn restore(
+		config: Config,
+		context: SessionContext,
+		enet: Enet,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
+	) -> Self {
+		let keys = SharedSessionKeys::new(context.keys.clone());
+
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = SessionInner { config, video_stream: None, audio_stream: None, control_stream: None };
-		tokio::spawn(inner.run(command_rx, context.clone(), enet, stop_signal));
-		Ok(Self { command_tx, context, running: false })
+		let inner = SessionInner {
+			config,
+			application: context.application.title.clone(),
+			encoder_profile: context.application.encoder_profile,
+			host_audio: context.host_audio,
+			video_stream: None,
+			audio_stream: None,
+			control_stream: None,
+			pause: StreamPause::default(),
+			blanked_display: None,
+			virtual_sink: None,
+		};
+		// Everything the session and its streams log is correlated with the client and application.
+		let span = tracing::info_span!(
+			"session",
+			client_id = %context.client_id,
+			device = context.device_name.as_deref().unwrap_or("unknown"),
+			app_id = context.application_id,
+			app = %context.application.title,
+		);
+		tokio::spawn(inner.run(command_rx, keys.clone(), context.timings.clone(), enet, stop_signal).instrument(span));
+		Self { command_tx, context, keys, processes: Vec::new(), run_after: true, running: false }
 	}
 
 	pub async fn start_stream(
 		&mut self,
 		video_stream_context: VideoStreamContext,
 		audio_stream_context: AudioStreamContext,
+		client_address: IpAddr,
 	) -> Result <(), ()> {
 		self.running = true;
-		self.command_tx.send(SessionCommand::StartStream(video_stream_context, audio_stream_context))
+		self.command_tx.send(SessionCommand::StartStream(video_stream_context, audio_stream_context, client_address))
 			.await
 			.map_err(|e| tracing::error!("Failed to send StartStream command: {e}"))
 	}
@@ -91,6 +241,18 @@ impl Session {
 			.map_err(|e| tracing::error!("Failed to send StopStream command: {e}"))
 	}
 
+	pub async fn pause_stream(&self) -> Result<(), ()> {
+		self.command_tx.send(SessionCommand::Pause)
+			.await
+			.map_err(|e| tracing::error!("Failed to send Pause command: {e}"))
+	}
+
+	pub async fn resume_stream(&self) -> Result<(), ()> {
+		self.command_tx.send(SessionCommand::Resume)
+			.await
+			.map_err(|e| tracing::error!("Failed to send Resume command: {e}"))
+	}
+
 	pub fn get_context(&self) -> &SessionContext {
 		&self.context
 	}
@@ -99,17 +261,49 @@ impl Session {
 		self.running
 	}
 
-	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
-		self.command_tx.send(SessionCommand::UpdateKeys(keys)).await
-			.map_err(|e| tracing::error!("Failed to send UpdateKeys command: {e}"))
+	/// Terminate the processes started by the `run_before` commands, along with the processes they started.
+	pub fn terminate_application(&self) {
+		for &pid in &self.processes {
+			tracing::info!("Terminating process group {pid}.");
+			if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) } != 0 {
+				// Most likely the processes already stopped.
+				tracing::debug!("Failed to terminate process group {pid}: {}", std::io::Error::last_os_error());
+			}
+		}
+	}
+
+	/// Don't run the `run_after` commands when the session ends.
+	pub fn skip_run_after(&mut self) {
+		self.run_after = false;
+	}
+
+	pub fn update_keys(&mut self, keys: SessionKeys) -> Result<(), ()> {
+		self.context.keys = keys.clone();
+		let epoch = self.keys.update(keys)?;
+		tracing::debug!("Updated session keys, now at key epoch {epoch}.");
+
+		Ok(())
 	}
 }
 
 impl Drop for Session {
 	fn drop(&mut self) {
-		if let Some(run_after) = &self.context.application.run_after {
+		let timings = &self.context.timings;
+		if timings.get(Milestone::FirstFrameSent).is_some() {
+			tracing::info!("Session ended after {} s ({}).", timings.elapsed().as_secs(), timings.summary());
+		} else {
+			tracing::warn!(
+				"Session ended after {} s without sending video to the client ({}).",
+				timings.elapsed().as_secs(),
+				timings.summary(),
+			);
+		}
+
+		if !self.run_after {
+			tracing::info!("Not running the commands to run after the application.");
+		} else if let Some(run_after) = &self.context.application.run_after {
 			for command in run_after {
-				run_command(command, &self.context);
+				run_command(command, &[], None, &self.context);
 			}
 		}
 	}
@@ -117,31 +311,108 @@ impl Drop for Session {
 
 struct SessionInner {
 	config: Config,
+
+	/// Title of the application, used to name recordings.
+	application: String,
+
+	/// How the application wants to be encoded, if it differs from the configured default.
+	encoder_profile: Option<EncoderProfile>,
+
 	video_stream: Option<VideoStream>,
 	audio_stream: Option<AudioStream>,
 	control_stream: Option<ControlStream>,
+
+	/// Whether capturing and encoding of the streams is paused.
+	pause: StreamPause,
+
+	/// Whether the client asked to keep playing audio on the host.
+	host_audio: bool,
+
+	/// Keeps the display of the host blanked while streaming, if enabled.
+	blanked_display: Option<BlankedDisplay>,
+
+	/// Keeps the audio of the host off its speakers while streaming, if enabled.
+	virtual_sink: Option<VirtualSink>,
 }
 
 impl SessionInner {
 	async fn run(
 		mut self,
 		mut command_rx: mpsc::Receiver<SessionCommand>,
-		mut session_context: SessionContext,
+		keys: SharedSessionKeys,
+		timings: SessionTimings,
 		enet: Enet,
-		stop_signal: ShutdownManager<()>,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
 	) {
 		while let Some(command) = command_rx.recv().await {
 			match command {
-				SessionCommand::StartStream(video_stream_context, audio_stream_context) => {
-					let video_stream = VideoStream::new(self.config.clone(), video_stream_context, stop_signal.clone());
-					let audio_stream = AudioStream::new(self.config.clone(), audio_stream_context, stop_signal.clone());
+				SessionCommand::StartStream(mut video_stream_context, mut audio_stream_context, client_address) => {
+					timings.record(Milestone::StreamStarted);
+					self.pause.set(false);
+					if let Some(encoder_profile) = self.encoder_profile {
+						video_stream_context.profile = encoder_profile;
+					}
+					let loopback = self.config.stream.loopback.enabled && client_address.to_canonical().is_loopback();
+					video_stream_context.loopback = loopback;
+					audio_stream_context.loopback = loopback;
+
+					// Audio capture follows the default sink, so the virtual sink has to be the default before it starts.
+					if self.config.stream.audio.virtual_sink && !self.host_audio && self.virtual_sink.is_none() {
+						self.virtual_sink = VirtualSink::new().ok();
+					}
+
+					let recorder = self.config.recording.enabled
+						.then(|| Recorder::new(&self.config.recording, &self.application).ok())
+						.flatten();
+					let input_recording = self.config.recording.input_path.as_deref()
+						.map(|template| recorder::recording_path(template, &self.application));
+
+					let Ok((_, current_keys)) = keys.current() else {
+						tracing::error!("Failed to get session keys, killing session.");
+						continue;
+					};
+					let bind_result = bind_stream_sockets(
+						&self.config,
+						video_stream_context.qos,
+						audio_stream_context.qos,
+						client_address,
+						&current_keys,
+					).await;
+					let (video_socket, audio_socket) = match bind_result {
+						Ok(sockets) => sockets,
+						Err(()) => {
+							tracing::error!("Failed to bind stream sockets, killing session.");
+							continue;
+						},
+					};
+
+					let video_stream = VideoStream::new(
+						self.config.clone(),
+						video_stream_context,
+						video_socket,
+						recorder.clone(),
+						timings.clone(),
+						self.pause.clone(),
+						stop_signal.clone(),
+					);
+					let audio_stream = AudioStream::new(
+						self.config.clone(),
+						audio_stream_context,
+						audio_socket,
+						recorder,
+						self.pause.clone(),
+						stop_signal.clone(),
+					);
 					let control_stream = match ControlStream::new(
 						self.config.clone(),
 						video_stream.clone(),
 						audio_stream.clone(),
-						session_context.clone(),
+						keys.clone(),
+						timings.clone(),
 						enet.clone(),
-						stop_signal.clone()
+						stop_signal.clone(),
+						input_recording,
+						self.pause.clone(),
 					) {
 						Ok(control_stream) => control_stream,
 						Err(()) => {
@@ -153,58 +424,220 @@ impl SessionInner {
 					self.video_stream = Some(video_stream);
 					self.audio_stream = Some(audio_stream);
 					self.control_stream = Some(control_stream);
+
+					if self.config.host_display.blank_while_streaming && self.blanked_display.is_none() {
+						self.blanked_display = BlankedDisplay::new(&self.config.host_display).ok();
+					}
 				},
 
 				SessionCommand::StopStream => {
-					let _ = stop_signal.trigger_shutdown(());
+					self.blanked_display = None;
+					self.virtual_sink = None;
+					let _ = stop_signal.trigger_shutdown(SessionShutdownReason::ClientStopped);
 				},
 
-				SessionCommand::UpdateKeys(keys) => {
-					let Some(audio_stream) = &self.audio_stream else {
-						tracing::warn!("Can't update session keys without an audio stream.");
-						continue;
-					};
-					let Some(control_stream) = &self.control_stream else {
-						tracing::warn!("Can't update session keys without an control stream.");
-						continue;
-					};
+				SessionCommand::Pause => {
+					if !self.pause.set(true) {
+						tracing::debug!("Stream is already paused.");
+					}
+				},
 
-					session_context.keys = keys.clone();
-					let _ = audio_stream.update_keys(keys.clone()).await;
-					let _ = control_stream.update_keys(keys).await;
+				SessionCommand::Resume => {
+					if !self.pause.set(false) {
+						tracing::debug!("Stream is not paused.");
+					}
 				},
 			}
 		}
 
-		let _ = stop_signal.trigger_shutdown(());
+		let _ = stop_signal.trigger_shutdown(SessionShutdownReason::HostStopped);
 		tracing::debug!("Command channel closed.");
 	}
 }
 
-fn run_command(command: &[String], context: &SessionContext) {
+/// Run a command for a session with `arguments` appended to it, returning the id of the started process.
+///
+/// The appended arguments come from the client, so they are passed as they are instead of being expanded.
+/// If `sandbox` is provided, the command runs in that sandbox.
+fn run_command(command: &[String], arguments: &[String], sandbox: Option<&SandboxConfig>, context: &SessionContext) -> Option<u32> {
 	if command.is_empty() {
 		tracing::warn!("Can't run an empty command.");
-		return;
+		return None;
 	}
 
-	let command: Vec<String> = command.to_vec()
-		.iter_mut()
+	// Environment variables are expanded first, so that values from the client are never expanded.
+	let variables = command_variables(context);
+	let command: Vec<String> = command.iter()
 		.map(|c| {
-			let c = c
-				.replace("{width}", &context.resolution.0.to_string())
-				.replace("{height}", &context.resolution.1.to_string());
-			shellexpand::full(&c).map(|c| c.into()).unwrap_or(c)
+			let c = shellexpand::full(c).map(|c| c.into()).unwrap_or_else(|_| c.clone());
+			replace_variables(&c, &variables)
 		})
+		.chain(arguments.iter().cloned())
 		.collect();
+	let command = match sandbox {
+		Some(sandbox) => sandbox_command(sandbox, command),
+		None => command,
+	};
 
 	tracing::info!("Running command: {command:?}");
 
+	let mut process = std::process::Command::new(&command[0]);
+	process.envs(variables.iter().map(|(name, value)| (format!("MOONSHINE_{}", name.to_uppercase()), value)));
+	if context.application.limit_framerate {
+		process.envs(frame_limit_environment(context.refresh_rate, std::env::var("MANGOHUD_CONFIG").ok()));
+	}
+
 	// Now run the command.
-	let _ = std::process::Command::new(&command[0])
+	// It gets its own process group, so that it can be terminated together with the processes it starts.
+	process
 		.args(&command[1..])
 		.stdout(Stdio::null())
 		.stderr(Stdio::null())
 		.stdin(Stdio::null())
+		.process_group(0)
 		.spawn()
-		.map_err(|e| tracing::error!("Failed to run command: {e}"));
+		.map(|child| child.id())
+		.map_err(|e| tracing::error!("Failed to run command: {e}"))
+		.ok()
+}
+
+/// Values that are replaced in the commands of an application (as `{name}`), which are also passed as `MOONSHINE_<NAME>` environment variables.
+fn command_variables(context: &SessionContext) -> Vec<(&'static str, String)> {
+	vec![
+		("client_width", context.resolution.0.to_string()),
+		("client_height", context.resolution.1.to_string()),
+		("client_fps", context.refresh_rate.to_string()),
+		("hdr", (context.hdr as u8).to_string()),
+
+		// The name is chosen by the client, so it is replaced last to keep it from introducing other variables.
+		("client_name", context.device_name.as_deref().map(safe_client_name).unwrap_or_default()),
+	]
+}
+
+/// Restrict the name of a device to characters that are safe to use in commands, even when they are run by a shell.
+///
+/// Other characters are replaced with `_`, as is a leading `-` so that the name can't be taken for an option.
+fn safe_client_name(name: &str) -> String {
+	name.chars()
+		.enumerate()
+		.map(|(index, c)| match c {
+			'-' if index == 0 => '_',
+			c if c.is_ascii_alphanumeric() || "-_.".contains(c) => c,
+			_ => '_',
+		})
+		.collect()
+}
+
+/// Replace the variables in an argument of a command, including `{width}`, `{height}` and `{fps}` which are short for the values of the client.
+fn replace_variables(argument: &str, variables: &[(&str, String)]) -> String {
+	let mut argument = argument.replace("{width}", "{client_width}")
+		.replace("{height}", "{client_height}")
+		.replace("{fps}", "{client_fps}");
+	for (name, value) in variables {
+		argument = argument.replace(&format!("{{{name}}}"), value);
+	}
+
+	argument
+}
+
+/// Split the arguments that a client passed when launching an application, and check them against the configuration of the application.
+///
+/// Returns a message for the client if the arguments aren't accepted.
+pub fn parse_launch_arguments(config: Option<&LaunchArgumentsConfig>, arguments: &str) -> Result<Vec<String>, String> {
+	let arguments: Vec<String> = arguments.split_whitespace().map(str::to_string).collect();
+	if arguments.is_empty() {
+		return Ok(arguments);
+	}
+
+	let Some(config) = config else {
+		return Err("This application doesn't accept launch arguments.".to_string());
+	};
+
+	if arguments.len() > config.max_count {
+		return Err(format!("Expected at most {} launch arguments, got {}.", config.max_count, arguments.len()));
+	}
+
+	for argument in &arguments {
+		if let Some(allowed) = &config.allowed {
+			if !allowed.contains(argument) {
+				return Err(format!("Launch argument '{argument}' is not allowed."));
+			}
+			continue;
+		}
+
+		if argument.starts_with('-') && !config.allow_options {
+			return Err(format!("Launch argument '{argument}' looks like an option, which is not allowed."));
+		}
+
+		let valid_character = |c: char| c.is_ascii_alphanumeric() || "-_.,:=+@".contains(c);
+		if !argument.chars().all(valid_character) || argument.contains("..") {
+			return Err(format!("Launch argument '{argument}' contains characters that are not allowed."));
+		}
+	}
+
+	Ok(arguments)
+}
+
+/// Environment variables that cap the framerate of an application to `fps` with the frame limiters that games commonly run with.
+///
+/// Options in `mangohud_config` (the current `MANGOHUD_CONFIG`) are kept, MangoHud itself still has to be enabled by the command.
+fn frame_limit_environment(fps: u32, mangohud_config: Option<String>) -> Vec<(&'static str, String)> {
+	let mangohud_config = match mangohud_config.filter(|config| !config.is_empty()) {
+		Some(config) => format!("{config},fps_limit={fps}"),
+		None => format!("fps_limit={fps}"),
+	};
+
+	vec![
+		("MANGOHUD_CONFIG", mangohud_config),
+		("STRANGLE_FPS", fps.to_string()),
+		("DXVK_FRAME_RATE", fps.to_string()),
+	]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn frame_limit_keeps_mangohud_options() {
+		let environment = frame_limit_environment(60, Some("no_display".to_string()));
+		assert_eq!(environment, [
+			("MANGOHUD_CONFIG", "no_display,fps_limit=60".to_string()),
+			("STRANGLE_FPS", "60".to_string()),
+			("DXVK_FRAME_RATE", "60".to_string()),
+		]);
+		assert_eq!(frame_limit_environment(30, None)[0], ("MANGOHUD_CONFIG", "fps_limit=30".to_string()));
+	}
+
+	#[test]
+	fn variables_are_replaced() {
+		let variables = [("client_width", "2560".to_string()), ("client_height", "1440".to_string()), ("client_fps", "120".to_string()), ("hdr", "1".to_string())];
+		assert_eq!(replace_variables("--mode={width}x{height}@{fps}", &variables), "--mode=2560x1440@120");
+		assert_eq!(replace_variables("{client_width}x{client_height} hdr={hdr}", &variables), "2560x1440 hdr=1");
+		assert_eq!(replace_variables("{unknown}", &variables), "{unknown}");
+	}
+
+	#[test]
+	fn client_name_is_safe_for_shells() {
+		assert_eq!(safe_client_name("Living-room.TV_2"), "Living-room.TV_2");
+		assert_eq!(safe_client_name("Pixel 7; rm -rf ~"), "Pixel_7__rm_-rf__");
+		assert_eq!(safe_client_name("$(reboot)"), "__reboot_");
+		assert_eq!(safe_client_name("--help"), "_-help");
+	}
+
+	#[test]
+	fn launch_arguments_follow_policy() {
+		let config = LaunchArgumentsConfig::default();
+		assert_eq!(parse_launch_arguments(None, " "), Ok(Vec::new()));
+		assert!(parse_launch_arguments(None, "1245620").is_err());
+		assert_eq!(parse_launch_arguments(Some(&config), "1245620  fullscreen"), Ok(vec!["1245620".to_string(), "fullscreen".to_string()]));
+		assert!(parse_launch_arguments(Some(&config), "--config=/etc/passwd").is_err());
+		assert!(parse_launch_arguments(Some(&config), "$HOME").is_err());
+		assert!(parse_launch_arguments(Some(&config), "a..b").is_err());
+		assert!(parse_launch_arguments(Some(&config), &"a ".repeat(9)).is_err());
+
+		let config = LaunchArgumentsConfig { allowed: Some(vec!["roms/zelda.z64".to_string()]), ..config };
+		assert_eq!(parse_launch_arguments(Some(&config), "roms/zelda.z64"), Ok(vec!["roms/zelda.z64".to_string()]));
+		assert!(parse_launch_arguments(Some(&config), "1245620").is_err());
+	}
 }
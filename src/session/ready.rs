@@ -0,0 +1,69 @@
+use std::{process::{Command, Stdio}, time::{Duration, Instant}};
+
+use crate::config::ReadyCheckConfig;
+
+/// Interval at which the checks are repeated until they succeed.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Wait until the checks of an application succeed, or until the timeout of the checks expires.
+///
+/// Returns whether the application is ready.
+pub async fn wait_until_ready(config: ReadyCheckConfig) -> bool {
+	let span = tracing::Span::current();
+	tokio::task::spawn_blocking(move || {
+		let _span = span.enter();
+		let start = Instant::now();
+		let timeout = Duration::from_secs(config.timeout);
+		tracing::info!("Waiting up to {} s for the application to be ready.", config.timeout);
+
+		loop {
+			if is_ready(&config) {
+				tracing::info!("Application is ready after {} ms.", start.elapsed().as_millis());
+				return true;
+			}
+
+			if start.elapsed() >= timeout {
+				tracing::warn!("Application is not ready after {} s, continuing anyway.", config.timeout);
+				return false;
+			}
+
+			std::thread::sleep(CHECK_INTERVAL);
+		}
+	}).await.unwrap_or_else(|e| {
+		tracing::error!("Failed to wait for the application to be ready: {e}");
+		false
+	})
+}
+
+/// Check if all configured checks succeed.
+fn is_ready(config: &ReadyCheckConfig) -> bool {
+	config.window.iter().all(|window| succeeds("xdotool", &["search", "--onlyvisible", "--name", window.as_str()]))
+		&& config.process.iter().all(|process| succeeds("pgrep", &["--full", process.as_str()]))
+		&& config.command.iter().all(|command| match command.split_first() {
+			Some((program, args)) => {
+				let args: Vec<String> = args.iter()
+					.map(|arg| shellexpand::full(arg).map(|arg| arg.into_owned()).unwrap_or_else(|_| arg.clone()))
+					.collect();
+				succeeds(program, &args)
+			},
+			None => {
+				tracing::warn!("Can't run an empty ready check command.");
+				false
+			},
+		})
+}
+
+/// Run a command, returning whether it exited successfully.
+fn succeeds(program: &str, args: &[impl AsRef<std::ffi::OsStr>]) -> bool {
+	Command::new(program)
+		.args(args)
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.stdin(Stdio::null())
+		.status()
+		.map(|status| status.success())
+		.unwrap_or_else(|e| {
+			tracing::debug!("Failed to run '{program}': {e}");
+			false
+		})
+}
@@ -0,0 +1,352 @@
+use std::{net::SocketAddr, path::PathBuf, sync::mpsc::{self, Receiver, SyncSender, TrySendError}, time::{Instant, SystemTime, UNIX_EPOCH}};
+
+use ffmpeg::{codec::{self, packet::flag::Flags}, format, Packet, Rational};
+
+use crate::{config::RecordingConfig, session::stream::OpusStreamConfig};
+
+/// Time base of the timestamps of recorded packets (microseconds).
+const TIME_BASE: Rational = Rational(1, 1_000_000);
+
+/// Number of packets that can be queued for writing, packets are dropped when the queue is full.
+const QUEUE_SIZE: usize = 512;
+
+enum RecorderCommand {
+	StartVideo(codec::Parameters),
+	StartAudio {
+		sample_rate: u32,
+		opus_config: OpusStreamConfig,
+	},
+	Video {
+		data: Vec<u8>,
+		key_frame: bool,
+		time: Instant,
+	},
+	Audio {
+		data: Vec<u8>,
+		time: Instant,
+	},
+}
+
+/// Writes the encoded video and audio of a session to a file, without encoding it a second time.
+///
+/// Packets are written on a separate thread, so a slow disk never delays the stream.
+#[derive(Clone)]
+pub struct Recorder {
+	command_tx: SyncSender<RecorderCommand>,
+}
+
+impl Recorder {
+	/// Record to the file configured in `config`.
+	pub fn new(config: &RecordingConfig, application: &str) -> Result<Self, ()> {
+		let path = recording_path(&config.path, application);
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)
+				.map_err(|e| tracing::error!("Failed to create recording directory {parent:?}: {e}"))?;
+		}
+
+		let output = format::output(&path)
+			.map_err(|e| tracing::error!("Failed to create recording {path:?}: {e}"))?;
+
+		Self::start(output, path.display().to_string(), config.max_size.map(|megabytes| megabytes * 1024 * 1024))
+	}
+
+	/// Serve the stream as MPEG-TS over HTTP to a single player, blocks until a player connects.
+	pub fn serve(address: SocketAddr) -> Result<Self, ()> {
+		// Formatting a `SocketAddr` puts IPv6 addresses in brackets, as URLs require.
+		let url = format!("http://{address}");
+		let mut options = ffmpeg::Dictionary::new();
+		options.set("listen", "1");
+
+		let output = format::output_as_with(&url, "mpegts", options)
+			.map_err(|e| tracing::error!("Failed to serve stream on {url}: {e}"))?;
+
+		Self::start(output, url, None)
+	}
+
+	fn start(output: format::context::Output, name: String, max_size: Option<u64>) -> Result<Self, ()> {
+		let (command_tx, command_rx) = mpsc::sync_channel(QUEUE_SIZE);
+		let inner = RecorderInner { output, name, max_size };
+		let span = tracing::Span::current();
+		std::thread::Builder::new().name("recorder".to_string()).spawn(move || {
+			let _span = span.enter();
+			inner.run(command_rx)
+		})
+			.map_err(|e| tracing::error!("Failed to start recorder thread: {e}"))?;
+
+		Ok(Self { command_tx })
+	}
+
+	/// Announce the video stream, `parameters` are taken from the video encoder.
+	pub fn start_video(&self, parameters: codec::Parameters) {
+		self.send(RecorderCommand::StartVideo(parameters));
+	}
+
+	/// Announce the audio stream, which is encoded with Opus.
+	pub fn start_audio(&self, sample_rate: u32, opus_config: OpusStreamConfig) {
+		self.send(RecorderCommand::StartAudio { sample_rate, opus_config });
+	}
+
+	pub fn video(&self, data: &[u8], key_frame: bool) {
+		self.send(RecorderCommand::Video { data: data.to_vec(), key_frame, time: Instant::now() });
+	}
+
+	pub fn audio(&self, data: &[u8]) {
+		self.send(RecorderCommand::Audio { data: data.to_vec(), time: Instant::now() });
+	}
+
+	fn send(&self, command: RecorderCommand) {
+		match self.command_tx.try_send(command) {
+			// The recording stopped, for example because it reached its maximum size.
+			Ok(()) | Err(TrySendError::Disconnected(_)) => {},
+			Err(TrySendError::Full(_)) => tracing::warn!("Recorder can't keep up, dropping a packet."),
+		}
+	}
+}
+
+struct RecorderInner {
+	output: format::context::Output,
+
+	/// Path or URL of the recording, for logging.
+	name: String,
+
+	/// Maximum size of the recording in bytes.
+	max_size: Option<u64>,
+}
+
+impl RecorderInner {
+	fn run(self, command_rx: Receiver<RecorderCommand>) {
+		let mut output = Some(self.output);
+		let mut video = None;
+		let mut audio = None;
+		let mut recording: Option<Recording> = None;
+
+		while let Ok(command) = command_rx.recv() {
+			let result = match command {
+				RecorderCommand::StartVideo(parameters) => {
+					video = Some(parameters);
+					Ok(())
+				},
+				RecorderCommand::StartAudio { sample_rate, opus_config } => {
+					audio = Some((sample_rate, opus_config));
+					Ok(())
+				},
+				RecorderCommand::Video { data, key_frame, time } => {
+					if recording.is_none() {
+						// The recording can only start with a key frame, since other frames can't be decoded on their own.
+						let Some(parameters) = video.take().filter(|_| key_frame) else {
+							continue;
+						};
+						let Some(output) = output.take() else {
+							break;
+						};
+
+						match Recording::new(output, &self.name, parameters, &data, audio.take(), time) {
+							Ok(new_recording) => recording = Some(new_recording),
+							Err(()) => break,
+						}
+					}
+
+					match &mut recording {
+						Some(recording) => recording.write_video(&data, key_frame, time),
+						None => Ok(()),
+					}
+				},
+				RecorderCommand::Audio { data, time } => {
+					match &mut recording {
+						Some(recording) => recording.write_audio(&data, time),
+						None => Ok(()),
+					}
+				},
+			};
+
+			if result.is_err() {
+				tracing::warn!("Stopping recording {}.", self.name);
+				break;
+			}
+
+			if let (Some(recording), Some(max_size)) = (&recording, self.max_size) {
+				if recording.size >= max_size {
+					tracing::info!("Recording reached its maximum size of {} MB, stopping the recording.", max_size / 1024 / 1024);
+					break;
+				}
+			}
+		}
+
+		if let Some(recording) = recording {
+			recording.finish(&self.name);
+		}
+	}
+}
+
+struct Recording {
+	output: format::context::Output,
+	video_stream: usize,
+	audio_stream: Option<usize>,
+
+	/// Moment of the first video frame, timestamps are relative to this moment.
+	started: Instant,
+
+	/// Number of bytes of video and audio that were written.
+	size: u64,
+}
+
+impl Recording {
+	fn new(
+		mut output: format::context::Output,
+		name: &str,
+		mut video: codec::Parameters,
+		key_frame: &[u8],
+		audio: Option<(u32, OpusStreamConfig)>,
+		started: Instant,
+	) -> Result<Self, ()> {
+		// The encoder sends its parameter sets in the key frames, the muxer extracts them from the first key frame.
+		set_extradata(&mut video, key_frame)?;
+		let video_stream = add_stream(&mut output, video)?;
+
+		let audio_stream = match audio {
+			Some((sample_rate, opus_config)) => Some(add_stream(&mut output, opus_parameters(sample_rate, &opus_config)?)?),
+			None => {
+				tracing::info!("No audio stream started before the first video frame, recording without audio.");
+				None
+			},
+		};
+
+		output.write_header()
+			.map_err(|e| tracing::error!("Failed to write header of recording: {e}"))?;
+
+		tracing::info!("Recording session to {name}.");
+		Ok(Self { output, video_stream, audio_stream, started, size: 0 })
+	}
+
+	fn write_video(&mut self, data: &[u8], key_frame: bool, time: Instant) -> Result<(), ()> {
+		self.write(self.video_stream, data, key_frame, time)
+	}
+
+	fn write_audio(&mut self, data: &[u8], time: Instant) -> Result<(), ()> {
+		match self.audio_stream {
+			Some(audio_stream) => self.write(audio_stream, data, true, time),
+			None => Ok(()),
+		}
+	}
+
+	fn write(&mut self, stream_index: usize, data: &[u8], key_frame: bool, time: Instant) -> Result<(), ()> {
+		// Audio that was captured before the first video frame is not recorded.
+		let Some(timestamp) = time.checked_duration_since(self.started) else {
+			return Ok(());
+		};
+		let time_base = self.output.stream(stream_index)
+			.map(|stream| stream.time_base())
+			.ok_or_else(|| tracing::error!("Recording has no stream with index {stream_index}."))?;
+
+		let mut packet = Packet::copy(data);
+		packet.set_stream(stream_index);
+		packet.set_pts(Some(timestamp.as_micros() as i64));
+		packet.set_dts(Some(timestamp.as_micros() as i64));
+		if key_frame {
+			packet.set_flags(Flags::KEY);
+		}
+		packet.rescale_ts(TIME_BASE, time_base);
+
+		// A write fails when the disk is full or when the player of a served stream disconnects.
+		packet.write_interleaved(&mut self.output)
+			.map_err(|e| tracing::warn!("Failed to write packet to recording: {e}"))?;
+		self.size += data.len() as u64;
+
+		Ok(())
+	}
+
+	fn finish(mut self, name: &str) {
+		match self.output.write_trailer() {
+			Ok(()) => tracing::info!("Finished recording {name} ({} MB).", self.size / 1024 / 1024),
+			Err(e) => tracing::error!("Failed to finish recording {name}: {e}"),
+		}
+	}
+}
+
+/// Fill in the recording path template.
+pub(super) fn recording_path(template: &str, application: &str) -> PathBuf {
+	// Make sure the application title can't add directories to the path.
+	let application: String = application.chars()
+		.map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' { c } else { '_' })
+		.collect();
+	let timestamp = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|duration| duration.as_secs())
+		.unwrap_or_default();
+
+	let path = template
+		.replace("{application}", &application)
+		.replace("{timestamp}", &timestamp.to_string());
+	shellexpand::full(&path).map(|path| path.into_owned()).unwrap_or(path).into()
+}
+
+fn add_stream(output: &mut format::context::Output, parameters: codec::Parameters) -> Result<usize, ()> {
+	let mut stream = output.add_stream(None::<ffmpeg::Codec>)
+		.map_err(|e| tracing::error!("Failed to add stream to recording: {e}"))?;
+	stream.set_parameters(parameters);
+	stream.set_time_base(TIME_BASE);
+
+	Ok(stream.index())
+}
+
+/// Parameters of the Opus audio stream.
+fn opus_parameters(sample_rate: u32, opus_config: &OpusStreamConfig) -> Result<codec::Parameters, ()> {
+	let channels = opus_config.channels;
+
+	// The identification header of Opus, required by Matroska and MP4 (https://www.rfc-editor.org/rfc/rfc7845#section-5.1).
+	let mut header = b"OpusHead".to_vec();
+	header.push(1); // Version.
+	header.push(channels);
+	header.extend(0u16.to_le_bytes()); // Pre-skip.
+	header.extend(sample_rate.to_le_bytes());
+	header.extend(0i16.to_le_bytes()); // Output gain.
+	if channels <= 2 {
+		header.push(0); // Channel mapping family, mono or stereo.
+	} else {
+		header.push(1); // Channel mapping family, surround in the channel order of Vorbis.
+		header.push(opus_config.streams);
+		header.push(opus_config.coupled_streams);
+		header.extend(vorbis_order(channels).iter().map(|&channel| opus_config.mapping[channel]));
+	}
+
+	let mut parameters = codec::Parameters::new();
+	unsafe {
+		let raw = parameters.as_mut_ptr();
+		(*raw).codec_type = ffmpeg::sys::AVMediaType::AVMEDIA_TYPE_AUDIO;
+		(*raw).codec_id = ffmpeg::sys::AVCodecID::AV_CODEC_ID_OPUS;
+		(*raw).sample_rate = sample_rate as i32;
+		ffmpeg::sys::av_channel_layout_default(&mut (*raw).ch_layout, channels as i32);
+	}
+	set_extradata(&mut parameters, &header)?;
+
+	Ok(parameters)
+}
+
+/// Captured channels in the channel order of Vorbis, which the channel mapping family of surround Opus streams uses.
+fn vorbis_order(channels: u8) -> &'static [usize] {
+	match channels {
+		6 => &[0, 2, 1, 4, 5, 3],
+		8 => &[0, 2, 1, 6, 7, 4, 5, 3],
+		_ => &[0, 1],
+	}
+}
+
+fn set_extradata(parameters: &mut codec::Parameters, data: &[u8]) -> Result<(), ()> {
+	unsafe {
+		let raw = parameters.as_mut_ptr();
+		ffmpeg::sys::av_freep(&mut (*raw).extradata as *mut *mut u8 as *mut std::ffi::c_void);
+
+		// FFmpeg requires padding after the extradata and releases it when the parameters are dropped.
+		let extradata = ffmpeg::sys::av_mallocz(data.len() + ffmpeg::sys::AV_INPUT_BUFFER_PADDING_SIZE as usize) as *mut u8;
+		if extradata.is_null() {
+			tracing::error!("Failed to allocate extradata for recording.");
+			return Err(());
+		}
+
+		std::ptr::copy_nonoverlapping(data.as_ptr(), extradata, data.len());
+		(*raw).extradata = extradata;
+		(*raw).extradata_size = data.len() as i32;
+	}
+
+	Ok(())
+}
@@ -0,0 +1,102 @@
+use crate::config::{SandboxConfig, SandboxTool};
+
+/// Wrap `command` in the sandbox tool of `config`, so that it runs with the configured restrictions.
+///
+/// The sandbox tool stays in the process group of the command, and the sandboxed command is stopped when the tool stops,
+/// so it is still terminated when the client quits.
+pub fn sandbox_command(config: &SandboxConfig, command: Vec<String>) -> Vec<String> {
+	wrap_command(config, &sandbox_devices(), command)
+}
+
+/// Devices that applications in a bubblewrap sandbox can use: the GPU and the sound cards.
+///
+/// The NVIDIA driver uses its own devices instead of the render nodes of the GPU.
+fn sandbox_devices() -> Vec<String> {
+	let mut devices = Vec::new();
+	for (directory, prefix) in [("/dev/dri", "renderD"), ("/dev", "nvidia")] {
+		let Ok(entries) = std::fs::read_dir(directory) else {
+			continue;
+		};
+
+		let mut found: Vec<String> = entries
+			.filter_map(|entry| entry.ok())
+			.filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+			.map(|entry| entry.path().to_string_lossy().into_owned())
+			.collect();
+		found.sort();
+		devices.extend(found);
+	}
+
+	if std::path::Path::new("/dev/snd").exists() {
+		devices.push("/dev/snd".to_string());
+	}
+
+	devices
+}
+
+fn wrap_command(config: &SandboxConfig, devices: &[String], command: Vec<String>) -> Vec<String> {
+	let writable = config.writable.iter()
+		.map(|path| shellexpand::full(path).map(|path| path.into()).unwrap_or_else(|_| path.clone()));
+
+	let mut sandboxed: Vec<String> = Vec::new();
+	match config.tool {
+		SandboxTool::Bubblewrap => {
+			// Only the devices that applications need are available, in a new /dev with the basic devices.
+			sandboxed.extend(["bwrap", "--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"].map(String::from));
+			for device in devices {
+				sandboxed.extend(["--dev-bind".to_string(), device.clone(), device.clone()]);
+			}
+			for path in writable {
+				sandboxed.extend(["--bind".to_string(), path.clone(), path]);
+			}
+			if !config.network {
+				sandboxed.push("--unshare-net".to_string());
+			}
+
+			// A new session keeps the command from injecting input into the terminal of Moonshine (TIOCSTI).
+			// That takes the command out of the process group of bubblewrap, so it runs in its own PID namespace instead,
+			// of which all processes are stopped when bubblewrap stops.
+			sandboxed.extend(["--unshare-pid", "--die-with-parent", "--new-session"].map(String::from));
+
+			// Bubblewrap needs a compiled seccomp filter, so `seccomp` is rejected for it when the configuration is loaded.
+		},
+		SandboxTool::Firejail => {
+			sandboxed.extend(["firejail", "--quiet", "--read-only=/"].map(String::from));
+			sandboxed.extend(writable.map(|path| format!("--read-write={path}")));
+			if !config.network {
+				sandboxed.push("--net=none".to_string());
+			}
+			if config.seccomp {
+				sandboxed.push("--seccomp".to_string());
+			}
+		},
+	}
+
+	sandboxed.extend(config.extra_arguments.iter().cloned());
+	sandboxed.push("--".to_string());
+	sandboxed.extend(command);
+	sandboxed
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn network_and_writable_paths_are_passed_to_the_tool() {
+		let command = vec!["game".to_string(), "--fullscreen".to_string()];
+		let devices = ["/dev/dri/renderD128".to_string(), "/dev/snd".to_string()];
+		let config = SandboxConfig { network: false, writable: vec!["/tmp".to_string()], ..Default::default() };
+		assert_eq!(wrap_command(&config, &devices, command.clone()), [
+			"bwrap", "--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc",
+			"--dev-bind", "/dev/dri/renderD128", "/dev/dri/renderD128", "--dev-bind", "/dev/snd", "/dev/snd",
+			"--bind", "/tmp", "/tmp", "--unshare-net", "--unshare-pid", "--die-with-parent", "--new-session",
+			"--", "game", "--fullscreen",
+		]);
+
+		let config = SandboxConfig { tool: SandboxTool::Firejail, seccomp: true, ..config };
+		assert_eq!(wrap_command(&config, &devices, command), [
+			"firejail", "--quiet", "--read-only=/", "--read-write=/tmp", "--net=none", "--seccomp", "--", "game", "--fullscreen",
+		]);
+	}
+}
@@ -0,0 +1,108 @@
+use std::future::Future;
+
+use async_shutdown::ShutdownManager;
+use serde::Serialize;
+
+/// Termination code that Moonlight treats as a regular end of the stream.
+const GRACEFUL_TERMINATION_CODE: u32 = 0x8003_0023;
+
+/// Why a session stopped.
+///
+/// The first reason that stops a session is kept, later reasons (for example streams that stop
+/// because the session is stopping) are ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionShutdownReason {
+	/// The client asked to stop the session.
+	ClientStopped,
+
+	/// The client stopped sending pings.
+	ClientTimeout,
+
+	/// The client didn't send any input for the configured idle timeout.
+	Idle,
+
+	/// The host stopped the session, for example because moonshine is shutting down.
+	HostStopped,
+
+	/// Frames couldn't be captured.
+	CaptureFailed,
+
+	/// The video stream failed, for example because the encoder failed.
+	VideoStreamFailed,
+
+	/// The audio stream failed.
+	AudioStreamFailed,
+
+	/// The control stream failed.
+	ControlStreamFailed,
+}
+
+impl SessionShutdownReason {
+	/// Whether the session stopped because something failed on the host.
+	pub fn is_failure(&self) -> bool {
+		!matches!(self, Self::ClientStopped | Self::ClientTimeout | Self::Idle | Self::HostStopped)
+	}
+
+	/// Code that is sent to the client in a termination message.
+	///
+	/// Moonlight shows codes it doesn't recognize to the user, so every failure has its own code.
+	pub fn termination_code(&self) -> u32 {
+		match self {
+			Self::ClientStopped | Self::ClientTimeout | Self::HostStopped => GRACEFUL_TERMINATION_CODE,
+			// Not a failure, but the user should know why the stream ended.
+			Self::Idle => 0x8004_0005,
+			Self::CaptureFailed => 0x8004_0001,
+			Self::VideoStreamFailed => 0x8004_0002,
+			Self::AudioStreamFailed => 0x8004_0003,
+			Self::ControlStreamFailed => 0x8004_0004,
+		}
+	}
+
+	/// Exit code of moonshine when a command stops because of this reason.
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			Self::ClientStopped | Self::ClientTimeout | Self::Idle | Self::HostStopped => 0,
+			Self::CaptureFailed => 10,
+			Self::VideoStreamFailed => 11,
+			Self::AudioStreamFailed => 12,
+			Self::ControlStreamFailed => 13,
+		}
+	}
+}
+
+impl std::fmt::Display for SessionShutdownReason {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let description = match self {
+			Self::ClientStopped => "the client stopped the session",
+			Self::ClientTimeout => "the client stopped responding",
+			Self::Idle => "the client was idle for too long",
+			Self::HostStopped => "the host stopped the session",
+			Self::CaptureFailed => "frame capture failed",
+			Self::VideoStreamFailed => "the video stream failed",
+			Self::AudioStreamFailed => "the audio stream failed",
+			Self::ControlStreamFailed => "the control stream failed",
+		};
+
+		write!(f, "{description}")
+	}
+}
+
+/// Stop the session when a stream stops, with `failure` as the reason if the stream failed or panicked.
+pub fn stop_session_after<F>(
+	stop_signal: &ShutdownManager<SessionShutdownReason>,
+	failure: SessionShutdownReason,
+	stream: F,
+) -> impl Future<Output = ()>
+where
+	F: Future<Output = Result<(), ()>>,
+{
+	let trigger = stop_signal.clone();
+	stop_signal.wrap_trigger_shutdown(failure, async move {
+		let reason = match stream.await {
+			Ok(()) => SessionShutdownReason::HostStopped,
+			Err(()) => failure,
+		};
+		let _ = trigger.trigger_shutdown(reason);
+	})
+}
@@ -1,180 +1,230 @@
-use std::{cell::RefCell, mem::MaybeUninit, ops::Deref, rc::Rc};
+use std::{cell::RefCell, mem::MaybeUninit, ops::Deref, rc::Rc, time::{Duration, Instant}};
 
 use pulse::{
 	context::{Context, FlagSet},
 	def::BufferAttr,
 	mainloop::standard::{IterateResult, Mainloop},
 	proplist::Proplist,
+	channelmap::{Map, MapDef},
 	sample::Spec
 };
 use tokio::sync::mpsc::Sender;
 
-fn get_default_sink_name() -> Result<String, ()> {
-	// Create a new PulseAudio context
-	let mainloop = Rc::new(RefCell::new(Mainloop::new()
-
-		.ok_or_else(|| tracing::error!("Failed to create pulseaudio client."))?));
-
-	let mut proplist = Proplist::new()
-		.ok_or_else(|| tracing::error!("Failed to create pulseaudio proplist."))?;
-    proplist.set_str(pulse::proplist::properties::APPLICATION_NAME, "Moonshine")
-        .map_err(|()| tracing::error!("Failed to set pulseaudio application name."))?;
-	let context = Rc::new(RefCell::new(
-		Context::new_with_proplist(mainloop.borrow().deref(), "Moonshine context", &proplist)
-			.ok_or_else(|| tracing::error!("Failed to create pulseaudio context."))?
-	));
-
-	context.borrow_mut().connect(None, FlagSet::NOFLAGS, None)
-		.map_err(|e| tracing::error!("Failed to connect to pulseaudio server: {e}"))?;
-
-	// Wait for context to be ready.
-	loop {
-		match mainloop.borrow_mut().iterate(false) {
-			IterateResult::Quit(_) | IterateResult::Err(_) => {
-				tracing::error!("Failed to run pulseaudio main loop.");
-				return Err(());
-			},
-			IterateResult::Success(_) => {}
-		}
+mod null;
+
+/// Interval at which the default sink is checked, to follow it when it changes.
+const DEFAULT_SINK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connection to the PulseAudio server, used to query the default sink.
+struct PulseConnection {
+	mainloop: Rc<RefCell<Mainloop>>,
+	context: Rc<RefCell<Context>>,
+}
+
+impl PulseConnection {
+	fn new() -> Result<Self, ()> {
+		// Create a new PulseAudio context
+		let mainloop = Rc::new(RefCell::new(Mainloop::new()
+
+			.ok_or_else(|| tracing::error!("Failed to create pulseaudio client."))?));
+
+		let mut proplist = Proplist::new()
+			.ok_or_else(|| tracing::error!("Failed to create pulseaudio proplist."))?;
+		proplist.set_str(pulse::proplist::properties::APPLICATION_NAME, "Moonshine")
+			.map_err(|()| tracing::error!("Failed to set pulseaudio application name."))?;
+		let context = Rc::new(RefCell::new(
+			Context::new_with_proplist(mainloop.borrow().deref(), "Moonshine context", &proplist)
+				.ok_or_else(|| tracing::error!("Failed to create pulseaudio context."))?
+		));
 
-		match context.borrow().get_state() {
-			pulse::context::State::Unconnected
-			| pulse::context::State::Connecting
-			| pulse::context::State::Authorizing
-			| pulse::context::State::SettingName => {}
-			pulse::context::State::Failed | pulse::context::State::Terminated => {
-				tracing::error!("Failed to run context.");
-				return Err(());
+		context.borrow_mut().connect(None, FlagSet::NOFLAGS, None)
+			.map_err(|e| tracing::error!("Failed to connect to pulseaudio server: {e}"))?;
+
+		// Wait for context to be ready.
+		loop {
+			match mainloop.borrow_mut().iterate(false) {
+				IterateResult::Quit(_) | IterateResult::Err(_) => {
+					tracing::error!("Failed to run pulseaudio main loop.");
+					return Err(());
+				},
+				IterateResult::Success(_) => {}
 			}
-			pulse::context::State::Ready => break
-		}
-	}
 
-	// Start operation to get server info.
-	let result = Rc::new(RefCell::new(None));
-	let operation = {
-		let result = result.clone();
-		context.borrow().introspect().get_server_info(move |info| {
-			let name = match info.default_sink_name.as_ref() {
-				Some(name) => name,
-				None => {
-					tracing::error!("Failed to receive default sink name.");
-					return;
+			match context.borrow().get_state() {
+				pulse::context::State::Unconnected
+				| pulse::context::State::Connecting
+				| pulse::context::State::Authorizing
+				| pulse::context::State::SettingName => {}
+				pulse::context::State::Failed | pulse::context::State::Terminated => {
+					tracing::error!("Failed to run context.");
+					return Err(());
 				}
-			};
-			*result.borrow_mut() = Some(name.to_string());
-		})
-	};
+				pulse::context::State::Ready => break
+			}
+		}
 
-	// Wait for operation to finish.
-	loop {
-		match mainloop.borrow_mut().iterate(false) {
-			IterateResult::Quit(_) | IterateResult::Err(_) => {
-				tracing::error!("Failed to run pulseaudio main loop.");
-				return Err(());
-			},
-			IterateResult::Success(_) => {}
+		Ok(Self { mainloop, context })
+	}
+
+	fn default_sink_name(&self) -> Result<String, ()> {
+		// Start operation to get server info.
+		let result = Rc::new(RefCell::new(None));
+		let operation = {
+			let result = result.clone();
+			self.context.borrow().introspect().get_server_info(move |info| {
+				let name = match info.default_sink_name.as_ref() {
+					Some(name) => name,
+					None => {
+						tracing::error!("Failed to receive default sink name.");
+						return;
+					}
+				};
+				*result.borrow_mut() = Some(name.to_string());
+			})
 		};
-		match operation.get_state() {
-			pulse::operation::State::Running => {}
-			pulse::operation::State::Cancelled => {
-				tracing::error!("Failed to get default sink name.");
-				return Err(());
+
+		// Wait for operation to finish.
+		loop {
+			match self.mainloop.borrow_mut().iterate(false) {
+				IterateResult::Quit(_) | IterateResult::Err(_) => {
+					tracing::error!("Failed to run pulseaudio main loop.");
+					return Err(());
+				},
+				IterateResult::Success(_) => {}
+			};
+			match operation.get_state() {
+				pulse::operation::State::Running => {}
+				pulse::operation::State::Cancelled => {
+					tracing::error!("Failed to get default sink name.");
+					return Err(());
+				}
+				pulse::operation::State::Done => break
 			}
-			pulse::operation::State::Done => break
 		}
+
+		result.take().ok_or_else(|| tracing::error!("Failed to get default sink name result."))
 	}
+}
+
+/// Open a recording stream on a PulseAudio source.
+fn open_stream(source: &str, sample_rate: u32, channels: u8, sample_time_ms: u32) -> Result<pulse_simple::Simple, ()> {
+	let sample_spec = Spec {
+		format: pulse::sample::Format::F32le,
+		channels,
+		rate: sample_rate,
+	};
+
+	// The default channel map of PulseAudio doesn't put surround channels in the order that the encoder expects.
+	let mut channel_map = Map::default();
+	channel_map.init_auto(channels, MapDef::WAVEEx)
+		.ok_or_else(|| tracing::error!("No channel map for capturing {channels} audio channels."))?;
 
-	result.take().ok_or_else(|| tracing::error!("Failed to get default sink name result."))
+	// Connect to the PulseAudio server.
+	let stream = pulse_simple::Simple::new(
+		None,                             // Use default server.
+		"Moonshine audio capture",        // Stream description.
+		pulse::stream::Direction::Record, // Direction of audio (recording vs playback).
+		Some(source),                     // Specify input device.
+		"moonshine",                      // Stream name.
+		&sample_spec,                     // Sample specification.
+		Some(&channel_map),               // Channel map.
+		Some(&BufferAttr {
+			maxlength: u32::MAX,
+			tlength: u32::MAX,
+			prebuf: u32::MAX,
+			minreq: u32::MAX,
+			fragsize: std::mem::size_of::<f32>() as u32 * sample_rate * channels as u32 * sample_time_ms / 1000,
+		}),
+	).map_err(|e| tracing::error!("Failed to create audio capture device for source '{source}': {e}"))?;
+
+	tracing::info!("Recording from source: {source}");
+	Ok(stream)
 }
 
 pub struct AudioCapture {
 	sample_rate: u32,
-	channels: u8,
 }
 
 impl AudioCapture {
-	pub async fn new(audio_tx: Sender<Vec<f32>>) -> Result<Self, ()> {
+	/// Capture `channels` interleaved channels, in the order front left, front right, front center, low frequency, back left, back right, side left, side right.
+	pub async fn new(audio_tx: Sender<Vec<f32>>, null_audio: bool, device: Option<String>, channels: u8) -> Result<Self, ()> {
 		// TODO: Make configurable.
-		let channels = 2u8;
 		let sample_rate = 48000u32;
 		let sample_time_ms = 5;
 
-		let default_sink_name = match get_default_sink_name() {
-			Ok(name) => name,
-			Err(()) => {
-				return Err(());
-			}
-		};
-		let monitor_name = format!("{default_sink_name}.monitor");
-
-		let sample_spec = Spec {
-			format: pulse::sample::Format::F32le,
-			channels,
-			rate: sample_rate,
-		};
+		if null_audio && !crate::headless::has_audio_server() {
+			tracing::info!("No audio server available, streaming silence.");
+			null::spawn(audio_tx, sample_rate, channels)?;
+			return Ok(Self { sample_rate });
+		}
 
-		// Connect to the PulseAudio server.
-		let stream = pulse_simple::Simple::new(
-			None,                             // Use default server.
-			"Moonshine audio capture",        // Stream description.
-			pulse::stream::Direction::Record, // Direction of audio (recording vs playback).
-			Some(&monitor_name),              // Specify input device.
-			"moonshine",                      // Stream name.
-			&sample_spec,                     // Sample specification.
-			None,                             // Use default channel map.
-			Some(&BufferAttr {
-				maxlength: u32::MAX,
-				tlength: u32::MAX,
-				prebuf: u32::MAX,
-				minreq: u32::MAX,
-				fragsize: std::mem::size_of::<f32>() as u32 * sample_rate * channels as u32 * sample_time_ms / 1000,
-			}),
-		).map_err(|e| tracing::error!("Failed to create audio capture device: {e}"));
-
-		let stream = match stream {
-			Ok(stream) => stream,
-			Err(()) => {
-				return Err(());
+		// Without a configured device, capture the monitor of the default sink and follow it when it changes.
+		let (source, default_sink) = match device {
+			Some(device) => (device, None),
+			None => {
+				let default_sink = PulseConnection::new()?.default_sink_name()?;
+				(format!("{default_sink}.monitor"), Some(default_sink))
 			},
 		};
+		let stream = open_stream(&source, sample_rate, channels, sample_time_ms)?;
 
-		tracing::info!("Recording from source: {monitor_name}");
-
-		let inner = AudioCaptureInner { audio_tx };
-		std::thread::Builder::new().name("audio-capture".to_string()).spawn(move ||
-			inner.run(stream)
-		)
+		let inner = AudioCaptureInner { audio_tx, sample_rate, channels, sample_time_ms };
+		let span = tracing::Span::current();
+		std::thread::Builder::new().name("audio-capture".to_string()).spawn(move || {
+			let _span = span.enter();
+			inner.run(stream, default_sink)
+		})
 			.map_err(|e| tracing::error!("Failed to start audio capture thread: {e}"))?;
 
-		Ok(Self { sample_rate, channels })
+		Ok(Self { sample_rate })
 	}
 
 	pub fn sample_rate(&self) -> u32 {
 		self.sample_rate
 	}
-
-	pub fn channels(&self) -> u8 {
-		self.channels
-	}
 }
 
 struct AudioCaptureInner {
 	/// Channel to communicate audio fragments over.
 	audio_tx: Sender<Vec<f32>>,
+
+	sample_rate: u32,
+	channels: u8,
+	sample_time_ms: u32,
 }
 
 impl AudioCaptureInner {
-	fn run(self, stream: pulse_simple::Simple) -> Result<(), ()> {
+	/// Record from `stream` until the receiving end is dropped.
+	///
+	/// If `default_sink` is set, the stream is moved to the monitor of the default sink whenever the default sink changes.
+	fn run(self, mut stream: pulse_simple::Simple, mut default_sink: Option<String>) -> Result<(), ()> {
 		// TODO: Make configurable.
 		const SAMPLE_RATE: usize = 48000;
 		const SAMPLE_TIME_MS: usize = 5;
-		const FRAME_SIZE: usize = std::mem::size_of::<f32>() * SAMPLE_RATE * SAMPLE_TIME_MS / 1000;
+		let frame_size = std::mem::size_of::<f32>() * SAMPLE_RATE * self.channels as usize * SAMPLE_TIME_MS / 1000;
+
+		// The connection is only used to query the default sink, so failing to create it only means we don't follow it.
+		let connection = match default_sink {
+			Some(_) => PulseConnection::new()
+				.map_err(|()| tracing::warn!("Failed to connect to pulseaudio, audio won't follow changes of the default sink."))
+				.ok(),
+			None => None,
+		};
+		let mut last_check = Instant::now();
 
 		// Start recording.
 		loop {
+			if let (Some(connection), Some(current_sink)) = (&connection, &mut default_sink) {
+				if last_check.elapsed() >= DEFAULT_SINK_CHECK_INTERVAL {
+					last_check = Instant::now();
+					if let Some(new_stream) = self.follow_default_sink(connection, current_sink) {
+						stream = new_stream;
+					}
+				}
+			}
+
 			// Allocate uninitialized buffer for recording.
-			let buffer: Vec<MaybeUninit<u8>> = vec![MaybeUninit::uninit(); FRAME_SIZE];
+			let buffer: Vec<MaybeUninit<u8>> = vec![MaybeUninit::uninit(); frame_size];
 			let mut buffer = unsafe {
 				std::mem::transmute::<std::vec::Vec<std::mem::MaybeUninit<u8>>, std::vec::Vec<u8>>(buffer)
 			};
@@ -209,4 +259,18 @@ impl AudioCaptureInner {
 			}
 		}
 	}
+
+	/// Open a stream on the monitor of the default sink, if the default sink changed.
+	fn follow_default_sink(&self, connection: &PulseConnection, current_sink: &mut String) -> Option<pulse_simple::Simple> {
+		let default_sink = connection.default_sink_name().ok()?;
+		if default_sink == *current_sink {
+			return None;
+		}
+
+		// Remember the new sink even if we fail to open it, so we don't retry (and log an error) every interval.
+		tracing::info!("Default sink changed from '{current_sink}' to '{default_sink}'.");
+		let monitor_name = format!("{default_sink}.monitor");
+		*current_sink = default_sink;
+		open_stream(&monitor_name, self.sample_rate, self.channels, self.sample_time_ms).ok()
+	}
 }
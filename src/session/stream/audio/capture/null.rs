@@ -0,0 +1,35 @@
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::Sender;
+
+/// Duration of each audio fragment, matching the fragments captured from PulseAudio.
+const SAMPLE_TIME_MS: usize = 5;
+
+/// Start a thread that sends silent audio fragments, at the rate real audio would be captured.
+///
+/// Used when there is no audio server available, for example when running in a container.
+pub fn spawn(audio_tx: Sender<Vec<f32>>, sample_rate: u32, channels: u8) -> Result<(), ()> {
+	// Use the same fragment size as the PulseAudio capture, so the encoder sees no difference.
+	let fragment_size = sample_rate as usize * channels as usize * SAMPLE_TIME_MS / 1000;
+	let fragment_duration = Duration::from_millis(SAMPLE_TIME_MS as u64);
+
+	let span = tracing::Span::current();
+	std::thread::Builder::new().name("audio-capture".to_string()).spawn(move || {
+		let _span = span.enter();
+		let mut next_fragment = Instant::now();
+		loop {
+			next_fragment += fragment_duration;
+			if let Some(remaining) = next_fragment.checked_duration_since(Instant::now()) {
+				std::thread::sleep(remaining);
+			}
+
+			if audio_tx.blocking_send(vec![0f32; fragment_size]).is_err() {
+				tracing::info!("Closing null audio capture because the receiving end was dropped.");
+				break;
+			}
+		}
+	})
+		.map_err(|e| tracing::error!("Failed to start null audio capture thread: {e}"))?;
+
+	Ok(())
+}
@@ -2,7 +2,9 @@ use openssl::cipher::Cipher;
 use reed_solomon_erasure::{galois_8, ReedSolomon};
 use tokio::sync::mpsc;
 
-use crate::{crypto::encrypt, session::{stream::RtpHeader, SessionKeys}};
+use crate::{crypto::encrypt, session::{stream::{nonce::audio_iv, RtpHeader, StreamPause}, Recorder, SharedSessionKeys}};
+
+use super::multistream::{MultistreamEncoder, OpusStreamConfig};
 
 #[derive(Debug)]
 #[repr(C)]
@@ -14,66 +16,62 @@ struct AudioFecHeader {
 	pub ssrc: u32,
 }
 
-enum AudioEncoderCommand {
-	UpdateKeys(SessionKeys),
-}
-
 pub struct AudioEncoder {
-	command_tx: mpsc::Sender<AudioEncoderCommand>,
+	/// Stops the encoder when dropped.
+	_stop_tx: mpsc::Sender<()>,
 }
 
 impl AudioEncoder {
 	pub fn new(
 		sample_rate: u32,
-		channels: u8,
+		stream_config: OpusStreamConfig,
 		audio_rx: mpsc::Receiver<Vec<f32>>,
-		keys: SessionKeys,
-		packet_tx: mpsc::Sender<Vec<u8>>
+		keys: Option<SharedSessionKeys>,
+		packet_tx: mpsc::Sender<Vec<u8>>,
+		recorder: Option<Recorder>,
+		fec: bool,
+		pause: StreamPause,
 	) -> Result<Self, ()> {
-		// TODO: Make this configurable.
-		let audio_bitrate = 512000;
-
-		tracing::debug!("Creating audio encoder with sample rate {} and {} channels.", sample_rate, channels);
-		let mut encoder = opus::Encoder::new(
-			sample_rate,
-			if channels > 1 { opus::Channels::Stereo } else { opus::Channels::Mono },
-			opus::Application::LowDelay,
-		)
-			.map_err(|e| tracing::error!("Failed to create audio encoder: {e}"))?;
-
-		// Moonlight expects a constant bitrate.
-		encoder.set_vbr(false)
-			.map_err(|e| tracing::error!("Failed to disable variable bitrate: {e}"))?;
-		encoder.set_bitrate(opus::Bitrate::Bits(audio_bitrate))
-			.map_err(|e| tracing::error!("Failed to set audio bitrate: {e}"))?;
-
-		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = AudioEncoderInner { };
+		tracing::debug!(
+			"Creating audio encoder with sample rate {}, {} channels in {} streams of which {} coupled.",
+			sample_rate, stream_config.channels, stream_config.streams, stream_config.coupled_streams,
+		);
+		let encoder = MultistreamEncoder::new(sample_rate, &stream_config)?;
+
+		if let Some(recorder) = &recorder {
+			recorder.start_audio(sample_rate, stream_config);
+		}
+
+		let (stop_tx, stop_rx) = mpsc::channel(1);
+		let inner = AudioEncoderInner { fec, pause };
+		let span = tracing::Span::current();
 		std::thread::Builder::new().name("audio-encode".to_string()).spawn(move || {
-			inner.run(command_rx, audio_rx, encoder, keys, packet_tx)
+			let _span = span.enter();
+			inner.run(stop_rx, audio_rx, encoder, keys, packet_tx, recorder)
 		})
 			.map_err(|e| tracing::error!("Failed to start audio encode thread: {e}"))?;
 
-		Ok(Self { command_tx })
-	}
-
-	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
-		self.command_tx.send(AudioEncoderCommand::UpdateKeys(keys)).await
-			.map_err(|e| tracing::error!("Failed to send UpdateKeys command: {e}"))
+		Ok(Self { _stop_tx: stop_tx })
 	}
 }
 
 struct AudioEncoderInner {
+	/// Whether parity packets are sent after every block of audio packets.
+	fec: bool,
+
+	/// Captured audio is dropped while the stream is paused.
+	pause: StreamPause,
 }
 
 impl AudioEncoderInner {
 	fn run(
 		self,
-		mut command_rx: mpsc::Receiver<AudioEncoderCommand>,
+		mut stop_rx: mpsc::Receiver<()>,
 		mut audio_rx: mpsc::Receiver<Vec<f32>>,
-		mut encoder: opus::Encoder,
-		mut keys: SessionKeys,
+		mut encoder: MultistreamEncoder,
+		keys: Option<SharedSessionKeys>,
 		packet_tx: mpsc::Sender<Vec<u8>>,
+		recorder: Option<Recorder>,
 	) -> Result<(), ()> {
 		let mut sequence_number = 0u16;
 		let stream_start_time = std::time::Instant::now();
@@ -105,23 +103,19 @@ impl AudioEncoderInner {
 		// TODO: Decide the correct size for this buffer.
 		let mut encoded_audio = vec![0u8; 1400];
 
-		loop {
-			// Check if there's a command.
-			match command_rx.try_recv() {
-				Ok(command) => {
-					match command {
-						AudioEncoderCommand::UpdateKeys(new_keys) => {
-							tracing::debug!("Updating session keys.");
-							keys = new_keys;
-						}
-					}
-				},
-				Err(mpsc::error::TryRecvError::Disconnected) => {
-					tracing::debug!("Command channel closed.");
-					break;
-				},
-				Err(mpsc::error::TryRecvError::Empty) => { },
-			};
+		if !self.fec {
+			tracing::debug!("Sending audio without parity packets.");
+		}
+		if keys.is_none() {
+			tracing::info!("Client didn't ask for encrypted audio, sending audio unencrypted.");
+		}
+
+		'encode: loop {
+			// Check if the encoder was dropped.
+			if let Err(mpsc::error::TryRecvError::Disconnected) = stop_rx.try_recv() {
+				tracing::debug!("Audio encoder dropped.");
+				break;
+			}
 
 			let audio_fragment = audio_rx.blocking_recv();
 			let Some(audio_fragment) = audio_fragment else {
@@ -129,6 +123,10 @@ impl AudioEncoderInner {
 				break;
 			};
 
+			if self.pause.is_paused() {
+				continue;
+			}
+
 			// TODO: Figure out the 1000 / 90 value.
 			let timestamp = ((std::time::Instant::now() - stream_start_time).as_micros() / (1000 / 90)) as u32;
 			let encoded_size = match encoder.encode_float(&audio_fragment, &mut encoded_audio) {
@@ -140,18 +138,27 @@ impl AudioEncoderInner {
 				}
 			};
 
+			if let Some(recorder) = &recorder {
+				recorder.audio(&encoded_audio[..encoded_size]);
+			}
 
-			// Encrypt the audio data.
-			// TODO: Check if we should, some clients (ie. Steam Link) don't support this.
-			let iv = keys.remote_input_key_id as u32 + sequence_number as u32;
-			let mut iv = iv.to_be_bytes().to_vec();
-			iv.extend([0u8; 12]);
-			let payload = match encrypt(Cipher::aes_128_cbc(), &encoded_audio[..encoded_size], Some(&keys.remote_input_key), Some(&iv), true) {
-				Ok(payload) => payload,
-				Err(e) => {
-					tracing::error!("Failed to encrypt audio: {e}");
-					continue;
+			// Encrypt the audio data, unless the client asked for unencrypted audio.
+			let payload = match &keys {
+				Some(keys) => {
+					// The key and key id are taken from the same epoch, so a concurrent key update can't mix them.
+					let Ok((_, keys)) = keys.current() else {
+						break;
+					};
+					let iv = audio_iv(keys.remote_input_key_id, sequence_number);
+					match encrypt(Cipher::aes_128_cbc(), &encoded_audio[..encoded_size], Some(&keys.remote_input_key), Some(&iv), true) {
+						Ok(payload) => payload,
+						Err(e) => {
+							tracing::error!("Failed to encrypt audio: {e}");
+							continue;
+						},
+					}
 				},
+				None => encoded_audio[..encoded_size].to_vec(),
 			};
 
 			let shard = &mut shards[sequence_number as usize % NR_DATA_SHARDS];
@@ -174,6 +181,10 @@ impl AudioEncoderInner {
 			}
 
 			sequence_number = sequence_number.wrapping_add(1);
+			if sequence_number == 0 {
+				// The initialization vector is derived from the 16-bit sequence number, so the protocol repeats them from here on.
+				tracing::debug!("Audio sequence number wrapped around, initialization vectors repeat until the keys are updated.");
+			}
 
 			// Copy the payload to the shard.
 			unsafe {
@@ -193,6 +204,10 @@ impl AudioEncoderInner {
 				break;
 			}
 
+			if !self.fec {
+				continue;
+			}
+
 			{
 				// Create a view of just the data itself for encoding.
 				let mut shards: Vec<&mut [u8]> = shards.iter_mut().map(|s| &mut s[..data_shard_size]).collect();
@@ -212,7 +227,7 @@ impl AudioEncoderInner {
 				for (shard_index, shard) in shards[NR_DATA_SHARDS..].iter_mut().enumerate() {
 					{
 						let rtp_header = unsafe { &mut *(shard.as_mut_ptr() as *mut RtpHeader) };
-						rtp_header.sequence_number = (sequence_number + shard_index as u16).to_be();
+						rtp_header.sequence_number = sequence_number.wrapping_add(shard_index as u16).to_be();
 						rtp_header.packet_type = 127u8.to_be();
 						rtp_header.timestamp = 0u32.to_be();
 						rtp_header.ssrc = 0u32.to_be();
@@ -241,7 +256,7 @@ impl AudioEncoderInner {
 
 					if packet_tx.blocking_send(parity_shard).is_err() {
 						tracing::debug!("Failed to send packet over channel, channel is likely closed.");
-						break;
+						break 'encode;
 					}
 				}
 			}
@@ -1,22 +1,35 @@
 use async_shutdown::ShutdownManager;
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::sync::mpsc;
+use tracing::Instrument;
 
-use crate::{config::Config, session::SessionKeys};
+use crate::{config::Config, session::{shutdown::stop_session_after, Recorder, SessionShutdownReason, SharedSessionKeys}};
+use super::{StreamPause, StreamSocket};
+
+pub use self::multistream::OpusStreamConfig;
 
 use self::{capture::AudioCapture, encoder::AudioEncoder};
 
 mod capture;
 mod encoder;
+mod multistream;
 
 #[derive(Clone, Default)]
 pub struct AudioStreamContext {
 	pub packet_duration: u32,
 	pub qos: bool,
+
+	/// Layout of the Opus streams, following the number of channels the client asked for.
+	pub opus_config: OpusStreamConfig,
+
+	/// Whether the client runs on the host itself, in which case no parity packets are sent.
+	pub loopback: bool,
+
+	/// Whether the packets are encrypted, as negotiated with the client.
+	pub encrypted: bool,
 }
 
 enum AudioStreamCommand {
-	Start(SessionKeys),
-	UpdateKeys(SessionKeys),
+	Start(SharedSessionKeys),
 }
 
 #[derive(Clone)]
@@ -35,29 +48,30 @@ impl AudioStream {
 	pub fn new(
 		config: Config,
 		context: AudioStreamContext,
-		stop_signal: ShutdownManager<()>,
+		socket: StreamSocket,
+		recorder: Option<Recorder>,
+		pause: StreamPause,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
 	) -> Self {
 		let (command_tx, command_rx) = mpsc::channel(10);
 		let inner = AudioStreamInner { capture: None, encoder: None };
-		tokio::spawn(stop_signal.wrap_cancel(stop_signal.wrap_trigger_shutdown((), inner.run(
+		tokio::spawn(stop_signal.wrap_cancel(stop_session_after(&stop_signal, SessionShutdownReason::AudioStreamFailed, inner.run(
 			config,
 			context,
 			command_rx,
+			socket,
+			recorder,
+			pause,
 			stop_signal.clone(),
-		))));
+		))).instrument(tracing::info_span!("audio_stream")));
 
 		AudioStream { command_tx }
 	}
 
-	pub async fn start(&self, keys: SessionKeys) -> Result<(), ()> {
+	pub async fn start(&self, keys: SharedSessionKeys) -> Result<(), ()> {
 		self.command_tx.send(AudioStreamCommand::Start(keys)).await
 			.map_err(|e| tracing::error!("Failed to send Start command: {e}"))
 	}
-
-	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
-		self.command_tx.send(AudioStreamCommand::UpdateKeys(keys)).await
-			.map_err(|e| tracing::error!("Failed to send UpdateKeys command: {e}"))
-	}
 }
 
 impl AudioStreamInner {
@@ -66,66 +80,19 @@ impl AudioStreamInner {
 		config: Config,
 		audio_stream_context: AudioStreamContext,
 		mut command_rx: mpsc::Receiver<AudioStreamCommand>,
-		_stop_signal: ShutdownManager<()>,
+		mut socket: StreamSocket,
+		recorder: Option<Recorder>,
+		pause: StreamPause,
+		_stop_signal: ShutdownManager<SessionShutdownReason>,
 	) -> Result<(), ()> {
-		let socket = UdpSocket::bind((config.address, config.stream.audio.port)).await
-			.map_err(|e| tracing::error!("Failed to bind to UDP socket: {e}"))?;
-
-		if audio_stream_context.qos {
-			// TODO: Check this value 224, what does it mean exactly?
-			tracing::debug!("Enabling QoS on audio socket.");
-			socket.set_tos(224)
-				.map_err(|e| tracing::error!("Failed to set QoS on the audio socket: {e}"))?;
-		}
-
-		tracing::debug!(
-			"Listening for audio messages on {}",
-			socket.local_addr()
-			.map_err(|e| tracing::error!("Failed to get local address associated with control socket: {e}"))?
-		);
-
 		let (packet_tx, mut packet_rx) = mpsc::channel::<Vec<u8>>(10);
 		tokio::spawn(async move {
-			let mut buf = [0; 1024];
-			let mut client_address = None;
-
-			loop {
-				tokio::select! {
-					packet = packet_rx.recv() => {
-						match packet {
-							Some(packet) => {
-								if let Some(client_address) = client_address {
-									if let Err(e) = socket.send_to(packet.as_slice(), client_address).await {
-										tracing::warn!("Failed to send packet to client: {e}");
-									}
-								}
-							},
-							None => {
-								tracing::debug!("Packet channel closed.");
-								break;
-							},
-						}
-					},
-
-					message = socket.recv_from(&mut buf) => {
-						let (len, address) = match message {
-							Ok((len, address)) => (len, address),
-							Err(e) => {
-								tracing::warn!("Failed to receive message: {e}");
-								break;
-							},
-						};
-
-						if &buf[..len] == b"PING" {
-							tracing::trace!("Received video stream PING message from {address}.");
-							client_address = Some(address);
-						} else {
-							tracing::warn!("Received unknown message on video stream of length {len}.");
-						}
-					},
-				}
+			while let Some(packet) = packet_rx.recv().await {
+				let _ = socket.send(&packet).await;
 			}
-		});
+
+			tracing::debug!("Packet channel closed.");
+		}.in_current_span());
 
 		while let Some(command) = command_rx.recv().await {
 			match command {
@@ -133,17 +100,25 @@ impl AudioStreamInner {
 					tracing::info!("Starting audio stream.");
 
 					let (audio_tx, audio_rx) = mpsc::channel(10);
-					let capture = match AudioCapture::new(audio_tx).await {
+					let capture = match AudioCapture::new(
+						audio_tx,
+						config.headless.null_audio,
+						config.stream.audio.device.clone(),
+						audio_stream_context.opus_config.channels,
+					).await {
 						Ok(capture) => capture,
 						Err(()) => continue,
 					};
 
 					let encoder = match AudioEncoder::new(
 						capture.sample_rate(),
-						capture.channels(),
+						audio_stream_context.opus_config,
 						audio_rx,
-						keys.clone(),
-						packet_tx.clone()
+						audio_stream_context.encrypted.then(|| keys.clone()),
+						packet_tx.clone(),
+						recorder.clone(),
+						config.stream.audio.fec.enabled && !audio_stream_context.loopback,
+						pause.clone(),
 					) {
 						Ok(encoder) => encoder,
 						Err(()) => continue,
@@ -152,15 +127,6 @@ impl AudioStreamInner {
 					self.capture = Some(capture);
 					self.encoder = Some(encoder);
 				},
-
-				AudioStreamCommand::UpdateKeys(keys) => {
-					let Some(encoder) = &self.encoder else {
-						tracing::error!("Can't update session keys, there is no encoder to update.");
-						continue;
-					};
-
-					let _ = encoder.update_keys(keys).await;
-				},
 			}
 		}
 
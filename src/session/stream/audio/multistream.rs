@@ -0,0 +1,170 @@
+use std::{ffi::CStr, os::raw::c_int, ptr::NonNull};
+
+use audiopus_sys as sys;
+
+/// Layout of the Opus streams that encode the channels of the audio stream.
+///
+/// Channels are captured in the order front left, front right, front center, low frequency, back left, back right, side left, side right.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpusStreamConfig {
+	pub channels: u8,
+
+	/// Number of Opus streams, of which the first `coupled_streams` encode two channels and the others one.
+	pub streams: u8,
+	pub coupled_streams: u8,
+
+	/// Coded channel of each captured channel.
+	pub mapping: &'static [u8],
+
+	/// Bitrate of all streams together, in bits per second.
+	pub bitrate: i32,
+}
+
+impl OpusStreamConfig {
+	pub const STEREO: Self = Self { channels: 2, streams: 1, coupled_streams: 1, mapping: &[0, 1], bitrate: 512_000 };
+	pub const SURROUND_51: Self = Self { channels: 6, streams: 4, coupled_streams: 2, mapping: &[0, 1, 2, 3, 4, 5], bitrate: 256_000 };
+	pub const HIGH_SURROUND_51: Self = Self { channels: 6, streams: 6, coupled_streams: 0, mapping: &[0, 1, 2, 3, 4, 5], bitrate: 1_536_000 };
+	pub const SURROUND_71: Self = Self { channels: 8, streams: 5, coupled_streams: 3, mapping: &[0, 1, 2, 3, 4, 5, 6, 7], bitrate: 450_000 };
+	pub const HIGH_SURROUND_71: Self = Self { channels: 8, streams: 8, coupled_streams: 0, mapping: &[0, 1, 2, 3, 4, 5, 6, 7], bitrate: 2_048_000 };
+
+	/// Layouts that are advertised to clients, the high quality layouts are derived from these by the client.
+	pub const ADVERTISED: [Self; 3] = [Self::STEREO, Self::SURROUND_51, Self::SURROUND_71];
+
+	/// Layout for the number of channels the client asked for, falling back to stereo for unsupported channel counts.
+	pub fn for_client(channels: u8, high_quality: bool) -> Self {
+		match (channels, high_quality) {
+			(6, false) => Self::SURROUND_51,
+			(6, true) => Self::HIGH_SURROUND_51,
+			(8, false) => Self::SURROUND_71,
+			(8, true) => Self::HIGH_SURROUND_71,
+			_ => Self::STEREO,
+		}
+	}
+
+	/// Value of the `surround-params` SDP attribute that describes this layout to the client.
+	pub fn surround_params(&self) -> String {
+		// GameStream advertises the surround mappings with the low frequency channel moved behind the back channels,
+		// which Moonlight undoes, so the mapping has to be advertised the same way.
+		let mut mapping = self.mapping.to_vec();
+		if self.channels > 2 {
+			mapping[3..6].rotate_left(1);
+		}
+
+		let mut params = format!("{}{}{}", self.channels, self.streams, self.coupled_streams);
+		params.extend(mapping.iter().map(|channel| char::from(b'0' + channel)));
+		params
+	}
+}
+
+impl Default for OpusStreamConfig {
+	fn default() -> Self {
+		Self::STEREO
+	}
+}
+
+/// Encodes the channels of the audio stream into multiple Opus streams, as Moonlight expects for surround audio.
+pub struct MultistreamEncoder {
+	encoder: NonNull<sys::OpusMSEncoder>,
+	channels: usize,
+}
+
+// The encoder state is only accessed through `&mut self`.
+unsafe impl Send for MultistreamEncoder { }
+
+impl MultistreamEncoder {
+	pub fn new(sample_rate: u32, config: &OpusStreamConfig) -> Result<Self, ()> {
+		let mut error = 0;
+		let encoder = unsafe {
+			sys::opus_multistream_encoder_create(
+				sample_rate as i32,
+				config.channels as c_int,
+				config.streams as c_int,
+				config.coupled_streams as c_int,
+				config.mapping.as_ptr(),
+				sys::OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+				&mut error,
+			)
+		};
+		let encoder = NonNull::new(encoder)
+			.filter(|_| error == sys::OPUS_OK)
+			.ok_or_else(|| tracing::error!("Failed to create audio encoder: {}", error_message(error)))?;
+		let mut encoder = Self { encoder, channels: config.channels as usize };
+
+		encoder.ctl(sys::OPUS_SET_BITRATE_REQUEST, config.bitrate)
+			.map_err(|e| tracing::error!("Failed to set audio bitrate: {e}"))?;
+
+		// Moonlight expects a constant bitrate.
+		encoder.ctl(sys::OPUS_SET_VBR_REQUEST, 0)
+			.map_err(|e| tracing::error!("Failed to disable variable bitrate: {e}"))?;
+
+		Ok(encoder)
+	}
+
+	/// Encode interleaved samples of all channels into `output`, returning the size of the encoded packet.
+	pub fn encode_float(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, String> {
+		let frame_size = input.len() / self.channels;
+		let result = unsafe {
+			sys::opus_multistream_encode_float(
+				self.encoder.as_ptr(),
+				input.as_ptr(),
+				frame_size as c_int,
+				output.as_mut_ptr(),
+				output.len() as i32,
+			)
+		};
+
+		if result < 0 {
+			Err(error_message(result))
+		} else {
+			Ok(result as usize)
+		}
+	}
+
+	pub fn reset_state(&mut self) -> Result<(), String> {
+		let result = unsafe { sys::opus_multistream_encoder_ctl(self.encoder.as_ptr(), sys::OPUS_RESET_STATE) };
+		if result == sys::OPUS_OK {
+			Ok(())
+		} else {
+			Err(error_message(result))
+		}
+	}
+
+	fn ctl(&mut self, request: c_int, value: i32) -> Result<(), String> {
+		let result = unsafe { sys::opus_multistream_encoder_ctl(self.encoder.as_ptr(), request, value) };
+		if result == sys::OPUS_OK {
+			Ok(())
+		} else {
+			Err(error_message(result))
+		}
+	}
+}
+
+impl Drop for MultistreamEncoder {
+	fn drop(&mut self) {
+		unsafe { sys::opus_multistream_encoder_destroy(self.encoder.as_ptr()) };
+	}
+}
+
+fn error_message(error: c_int) -> String {
+	unsafe { CStr::from_ptr(sys::opus_strerror(error)) }.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn surround_params_match_gamestream() {
+		assert_eq!(OpusStreamConfig::STEREO.surround_params(), "21101");
+		assert_eq!(OpusStreamConfig::SURROUND_51.surround_params(), "642012453");
+		assert_eq!(OpusStreamConfig::SURROUND_71.surround_params(), "85301245367");
+	}
+
+	#[test]
+	fn unsupported_channel_counts_fall_back_to_stereo() {
+		assert_eq!(OpusStreamConfig::for_client(6, true), OpusStreamConfig::HIGH_SURROUND_51);
+		assert_eq!(OpusStreamConfig::for_client(8, false), OpusStreamConfig::SURROUND_71);
+		assert_eq!(OpusStreamConfig::for_client(4, false), OpusStreamConfig::STEREO);
+		assert_eq!(OpusStreamConfig::for_client(1, true), OpusStreamConfig::STEREO);
+	}
+}
@@ -0,0 +1,156 @@
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+
+use enet::{
+	Address,
+	BandwidthLimit,
+	ChannelLimit,
+	Enet,
+	Event,
+	Host,
+	Packet,
+	PacketMode,
+	PeerState,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::SchedulingConfig;
+use super::super::scheduling::{configure_current_thread, StreamThread};
+
+/// Longest time that the enet host sleeps without activity.
+///
+/// Enet only resends lost packets and pings the client while it is serviced, so it needs to wake up regularly.
+const SERVICE_INTERVAL_MS: u32 = 100;
+
+/// Runs the enet host of the control stream on its own thread.
+///
+/// The thread sleeps until the client sends a packet or a packet needs to be sent to the client,
+/// received packets are forwarded over a channel so they can be awaited together with other events.
+pub struct Connection {
+	outgoing_tx: std::sync::mpsc::Sender<Vec<u8>>,
+
+	/// Socket used to wake up the enet host when a packet needs to be sent.
+	waker: UdpSocket,
+
+	/// Address of the enet host, as reachable by the waker.
+	host_address: SocketAddrV4,
+}
+
+impl Connection {
+	/// Listen for a client on `address`, received packets are sent to `incoming_tx`.
+	pub async fn new(
+		enet: Enet,
+		address: Ipv4Addr,
+		port: u16,
+		scheduling: SchedulingConfig,
+		incoming_tx: mpsc::Sender<Vec<u8>>,
+	) -> Result<Self, ()> {
+		let (outgoing_tx, outgoing_rx) = std::sync::mpsc::channel();
+		let (ready_tx, ready_rx) = oneshot::channel();
+
+		let span = tracing::Span::current();
+		std::thread::Builder::new().name("control-stream".to_string()).spawn(move || {
+			let _span = span.enter();
+			configure_current_thread(&scheduling, StreamThread::Control);
+
+			// The enet host can't be moved between threads, so it is created on the thread that services it.
+			let host = enet.create_host::<()>(
+				Some(&Address::new(address, port)),
+				10,
+				ChannelLimit::Maximum,
+				BandwidthLimit::Unlimited,
+				BandwidthLimit::Unlimited,
+			);
+			let host = match host {
+				Ok(host) => {
+					tracing::debug!("Listening for control messages on {:?}", host.address());
+					let _ = ready_tx.send(Ok(()));
+					host
+				},
+				Err(e) => {
+					let _ = ready_tx.send(Err(e));
+					return;
+				},
+			};
+
+			run(host, outgoing_rx, incoming_tx);
+		})
+			.map_err(|e| tracing::error!("Failed to start control stream thread: {e}"))?;
+
+		ready_rx.await
+			.map_err(|e| tracing::error!("Failed to wait for control stream thread: {e}"))?
+			.map_err(|e| tracing::error!("Failed to create Enet host: {e}"))?;
+
+		let waker = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+			.map_err(|e| tracing::error!("Failed to create socket to wake up control stream: {e}"))?;
+		let host_ip = if address.is_unspecified() { Ipv4Addr::LOCALHOST } else { address };
+
+		Ok(Self { outgoing_tx, waker, host_address: SocketAddrV4::new(host_ip, port) })
+	}
+
+	/// Send a packet to the connected client.
+	pub fn send(&self, buffer: Vec<u8>) -> Result<(), ()> {
+		self.outgoing_tx.send(buffer)
+			.map_err(|_| tracing::error!("Failed to send control packet, the control stream thread stopped."))?;
+		self.wake();
+		Ok(())
+	}
+
+	/// Interrupt the enet host while it waits for packets.
+	///
+	/// Enet discards datagrams that are too short to have a protocol header, so this only wakes it up.
+	fn wake(&self) {
+		if let Err(e) = self.waker.send_to(&[0], self.host_address) {
+			tracing::warn!("Failed to wake up control stream: {e}");
+		}
+	}
+}
+
+impl Drop for Connection {
+	fn drop(&mut self) {
+		// The thread stops when it notices that the outgoing channel is closed.
+		self.wake();
+	}
+}
+
+fn run(mut host: Host<()>, outgoing_rx: std::sync::mpsc::Receiver<Vec<u8>>, incoming_tx: mpsc::Sender<Vec<u8>>) {
+	loop {
+		loop {
+			match outgoing_rx.try_recv() {
+				Ok(buffer) => { let _ = send_packet(&mut host, &buffer); },
+				Err(std::sync::mpsc::TryRecvError::Empty) => break,
+				Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+					// Send the last packets (like a termination message) before the host is destroyed.
+					host.flush();
+					tracing::debug!("Control stream connection closing.");
+					return;
+				},
+			}
+		}
+
+		match host.service(SERVICE_INTERVAL_MS) {
+			Ok(Some(Event::Receive { ref packet, .. })) => {
+				if incoming_tx.blocking_send(packet.data().to_vec()).is_err() {
+					tracing::debug!("Control stream stopped, closing connection.");
+					return;
+				}
+			},
+			Ok(Some(Event::Connect(_))) => tracing::debug!("Client connected to control stream."),
+			Ok(Some(Event::Disconnect(..))) => tracing::debug!("Client disconnected from control stream."),
+			Ok(None) => {},
+			Err(e) => {
+				tracing::error!("Failure in enet host: {e}");
+				return;
+			},
+		}
+	}
+}
+
+fn send_packet(host: &mut Host<()>, buffer: &[u8]) -> Result<(), ()> {
+	let packet = Packet::new(buffer, PacketMode::ReliableSequenced)
+		.map_err(|e| tracing::error!("Failed to create control packet: {e}"))?;
+	let mut peer = host.peers()
+		.find(|peer| matches!(peer.state(), PeerState::Connected))
+		.ok_or_else(|| tracing::warn!("Can't send control message, no client is connected."))?;
+	peer.send_packet(packet, 0)
+		.map_err(|e| tracing::error!("Failed to send control message: {e}"))
+}
@@ -0,0 +1,219 @@
+use std::{collections::VecDeque, sync::{Arc, Mutex}};
+
+use tokio::sync::Notify;
+
+use super::HostMessage;
+
+/// Number of messages for the client that can be queued, after which messages of the lowest priority are dropped.
+const FEEDBACK_QUEUE_SIZE: usize = 64;
+
+/// Priority of a message for the client, messages with a higher priority are sent first and dropped last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FeedbackPriority {
+	Normal,
+	High,
+}
+
+/// Messages for the client that are waiting to be sent.
+struct FeedbackQueue {
+	messages: VecDeque<HostMessage>,
+	capacity: usize,
+
+	/// Number of messages that were dropped because the queue was full.
+	dropped: u64,
+
+	/// Number of messages that replaced a queued message with the same target.
+	coalesced: u64,
+
+	/// Number of senders, the receiver stops once they are all dropped.
+	senders: usize,
+}
+
+impl FeedbackQueue {
+	fn push(&mut self, message: HostMessage) {
+		// Only the latest state matters, so a message replaces a queued message for the same target.
+		let key = message.coalesce_key();
+		if let Some(queued) = self.messages.iter_mut().find(|queued| key.is_some() && queued.coalesce_key() == key) {
+			*queued = message;
+			self.coalesced += 1;
+			return;
+		}
+
+		if self.messages.len() >= self.capacity {
+			// Make room by dropping the oldest message with the lowest priority, unless the new message has an even lower priority.
+			let lowest = self.messages.iter()
+				.enumerate()
+				.min_by_key(|(_, queued)| queued.priority())
+				.map(|(index, queued)| (index, queued.priority()));
+			self.dropped += 1;
+			match lowest {
+				Some((index, priority)) if priority <= message.priority() => {
+					if let Some(dropped) = self.messages.remove(index) {
+						tracing::warn!("Feedback queue is full, dropping message for the client: {dropped:?}");
+					}
+				},
+				_ => {
+					tracing::warn!("Feedback queue is full, dropping message for the client: {message:?}");
+					return;
+				},
+			}
+		}
+
+		self.messages.push_back(message);
+	}
+
+	/// Take the oldest message with the highest priority.
+	fn pop(&mut self) -> Option<HostMessage> {
+		let index = self.messages.iter()
+			.enumerate()
+			.max_by_key(|(index, queued)| (queued.priority(), std::cmp::Reverse(*index)))
+			.map(|(index, _)| index)?;
+		self.messages.remove(index)
+	}
+}
+
+/// Queues messages for the client without waiting, shared by everything that sends feedback to the client.
+pub struct FeedbackSender {
+	queue: Arc<Mutex<FeedbackQueue>>,
+	notify: Arc<Notify>,
+}
+
+impl FeedbackSender {
+	pub fn send(&self, message: HostMessage) {
+		match self.queue.lock() {
+			Ok(mut queue) => queue.push(message),
+			Err(e) => {
+				tracing::error!("Failed to lock feedback queue: {e}");
+				return;
+			},
+		}
+
+		self.notify.notify_one();
+	}
+}
+
+impl Clone for FeedbackSender {
+	fn clone(&self) -> Self {
+		if let Ok(mut queue) = self.queue.lock() {
+			queue.senders += 1;
+		}
+
+		Self { queue: self.queue.clone(), notify: self.notify.clone() }
+	}
+}
+
+impl Drop for FeedbackSender {
+	fn drop(&mut self) {
+		if let Ok(mut queue) = self.queue.lock() {
+			queue.senders -= 1;
+		}
+
+		// Wake up the receiver, so that it notices when the last sender is gone.
+		self.notify.notify_one();
+	}
+}
+
+/// Receives the queued messages for the client, in order of priority.
+pub struct FeedbackReceiver {
+	queue: Arc<Mutex<FeedbackQueue>>,
+	notify: Arc<Notify>,
+}
+
+impl FeedbackReceiver {
+	/// Wait for the next message, returns `None` when all senders are dropped and the queue is empty.
+	pub async fn recv(&mut self) -> Option<HostMessage> {
+		loop {
+			{
+				let mut queue = self.queue.lock()
+					.map_err(|e| tracing::error!("Failed to lock feedback queue: {e}"))
+					.ok()?;
+				if let Some(message) = queue.pop() {
+					return Some(message);
+				}
+
+				if queue.senders == 0 {
+					return None;
+				}
+			}
+
+			self.notify.notified().await;
+		}
+	}
+}
+
+impl Drop for FeedbackReceiver {
+	fn drop(&mut self) {
+		if let Ok(queue) = self.queue.lock() {
+			if queue.dropped > 0 || queue.coalesced > 0 {
+				tracing::info!(
+					"Dropped {} and coalesced {} message(s) for the client because the feedback queue was full or had a newer state.",
+					queue.dropped, queue.coalesced,
+				);
+			}
+		}
+	}
+}
+
+/// Create a bounded queue for messages to the client, that never blocks the sender.
+pub fn feedback_queue() -> (FeedbackSender, FeedbackReceiver) {
+	let queue = Arc::new(Mutex::new(FeedbackQueue {
+		messages: VecDeque::new(),
+		capacity: FEEDBACK_QUEUE_SIZE,
+		dropped: 0,
+		coalesced: 0,
+		senders: 1,
+	}));
+	let notify = Arc::new(Notify::new());
+
+	(
+		FeedbackSender { queue: queue.clone(), notify: notify.clone() },
+		FeedbackReceiver { queue, notify },
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::session::stream::control::input::MotionSensor;
+
+	use super::*;
+
+	fn queue(capacity: usize) -> FeedbackQueue {
+		FeedbackQueue { messages: VecDeque::new(), capacity, dropped: 0, coalesced: 0, senders: 1 }
+	}
+
+	fn enable_motion(gamepad: u16) -> HostMessage {
+		HostMessage::EnableMotion { gamepad, sensor: MotionSensor::Gyroscope }
+	}
+
+	#[test]
+	fn messages_for_the_same_target_are_coalesced() {
+		let mut queue = queue(4);
+		queue.push(enable_motion(0));
+		queue.push(enable_motion(1));
+		queue.push(enable_motion(0));
+		assert_eq!(queue.messages.len(), 2);
+		assert_eq!(queue.coalesced, 1);
+		assert!(matches!(queue.pop(), Some(HostMessage::EnableMotion { gamepad: 0, .. })));
+		assert!(matches!(queue.pop(), Some(HostMessage::EnableMotion { gamepad: 1, .. })));
+		assert!(queue.pop().is_none());
+	}
+
+	#[test]
+	fn high_priority_messages_are_sent_first_and_dropped_last() {
+		let mut queue = queue(2);
+		queue.push(enable_motion(0));
+		queue.push(HostMessage::Termination { code: 1 });
+		queue.push(HostMessage::Termination { code: 2 });
+		assert_eq!(queue.dropped, 1);
+		assert!(matches!(queue.pop(), Some(HostMessage::Termination { code: 1 })));
+		assert!(matches!(queue.pop(), Some(HostMessage::Termination { code: 2 })));
+		assert!(queue.pop().is_none());
+
+		// A full queue of high priority messages drops new messages of a lower priority.
+		queue.push(HostMessage::Termination { code: 3 });
+		queue.push(HostMessage::Termination { code: 4 });
+		queue.push(enable_motion(0));
+		assert_eq!(queue.dropped, 2);
+		assert!(queue.messages.iter().all(|message| message.priority() == FeedbackPriority::High));
+	}
+}
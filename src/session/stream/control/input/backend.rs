@@ -0,0 +1,112 @@
+use std::{fmt, os::unix::fs::MetadataExt};
+
+/// Device node used to create virtual input devices.
+const UINPUT_DEVICE: &str = "/dev/uinput";
+
+/// Reason why virtual input devices can't be created through uinput.
+#[derive(Debug)]
+pub enum InputBackendError {
+	/// The device node doesn't exist.
+	Missing,
+
+	/// The device node exists, but we are not allowed to write to it.
+	PermissionDenied {
+		/// Name of the group that owns the device, if it isn't owned by root.
+		group: Option<String>,
+
+		/// The user was added to the group, but the current process doesn't have the group yet.
+		pending_membership: bool,
+	},
+
+	/// Opening the device failed for another reason.
+	Other(std::io::Error),
+}
+
+impl fmt::Display for InputBackendError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Missing => write!(
+				f,
+				"Missing device '{UINPUT_DEVICE}'. Load the uinput kernel module (`modprobe uinput`), \
+				or when running in a container, pass the device with `--device {UINPUT_DEVICE}`."
+			),
+			Self::PermissionDenied { group: Some(group), pending_membership: true } => write!(
+				f,
+				"No permission to write to '{UINPUT_DEVICE}'. The user is a member of the '{group}' group that owns the device, \
+				but this only takes effect after logging in again (or restarting the service)."
+			),
+			Self::PermissionDenied { group: Some(group), pending_membership: false } => write!(
+				f,
+				"No permission to write to '{UINPUT_DEVICE}', which is owned by the '{group}' group. \
+				Add the user running Moonshine to this group (ie. `sudo usermod -aG {group} $USER`) and log in again."
+			),
+			Self::PermissionDenied { group: None, .. } => write!(
+				f,
+				"No permission to write to '{UINPUT_DEVICE}', only root can write to it. \
+				Add a udev rule that gives the 'input' group access (ie. `KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\"`) \
+				and add the user running Moonshine to the 'input' group."
+			),
+			Self::Other(e) => write!(f, "Failed to open '{UINPUT_DEVICE}': {e}"),
+		}
+	}
+}
+
+/// Check whether virtual input devices can be created, explaining what is missing if they can't.
+pub fn probe() -> Result<(), InputBackendError> {
+	match std::fs::OpenOptions::new().write(true).open(UINPUT_DEVICE) {
+		Ok(_) => Ok(()),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(InputBackendError::Missing),
+		Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+			let group_id = std::fs::metadata(UINPUT_DEVICE)
+				.map(|metadata| metadata.gid())
+				.map_err(InputBackendError::Other)?;
+
+			let group = (group_id != 0).then(|| find_group(group_id)).flatten();
+			let pending_membership = match &group {
+				Some((_, members)) => {
+					let user = std::env::var("USER").unwrap_or_default();
+					members.contains(&user) && !process_groups().contains(&group_id)
+				},
+				None => false,
+			};
+
+			Err(InputBackendError::PermissionDenied {
+				group: group.map(|(name, _)| name),
+				pending_membership,
+			})
+		},
+		Err(e) => Err(InputBackendError::Other(e)),
+	}
+}
+
+/// Find the name and members of a group in `/etc/group`.
+fn find_group(group_id: u32) -> Option<(String, Vec<String>)> {
+	let groups = std::fs::read_to_string("/etc/group").ok()?;
+	groups.lines().find_map(|line| {
+		// Each line has the format "name:password:id:member,member".
+		let mut fields = line.split(':');
+		let name = fields.next()?;
+		let id: u32 = fields.nth(1)?.parse().ok()?;
+		if id != group_id {
+			return None;
+		}
+
+		let members = fields.next()
+			.map(|members| members.split(',').filter(|m| !m.is_empty()).map(str::to_string).collect())
+			.unwrap_or_default();
+		Some((name.to_string(), members))
+	})
+}
+
+/// Group ids of the current process, including supplementary groups.
+fn process_groups() -> Vec<u32> {
+	let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+		return Vec::new();
+	};
+
+	status.lines()
+		.filter_map(|line| line.strip_prefix("Groups:").or_else(|| line.strip_prefix("Gid:")))
+		.flat_map(|ids| ids.split_whitespace())
+		.filter_map(|id| id.parse().ok())
+		.collect()
+}
@@ -1,3 +1,5 @@
+use std::time::Instant;
+
 use evdev::{
 	uinput::{
 		VirtualDevice,
@@ -5,6 +7,8 @@ use evdev::{
 	},
 	AttributeSet,
 	Key,
+	MiscType,
+	PropType,
 	UinputAbsSetup,
 	AbsoluteAxisType,
 	AbsInfo,
@@ -13,13 +17,31 @@ use evdev::{
 use strum::IntoEnumIterator;
 use strum_macros::{FromRepr, EnumIter};
 
-#[derive(Debug, FromRepr)]
+use super::DEVICE_WARMUP;
+
+/// Resolution of the virtual accelerometer, in units per g (matching the kernel driver of Sony controllers).
+const ACCELEROMETER_RESOLUTION: i32 = 8192;
+
+/// Range of the virtual accelerometer, in g.
+const ACCELEROMETER_RANGE: i32 = 4;
+
+/// Resolution of the virtual gyroscope, in units per degree per second.
+const GYROSCOPE_RESOLUTION: i32 = 1024;
+
+/// Range of the virtual gyroscope, in degrees per second.
+const GYROSCOPE_RANGE: i32 = 2048;
+
+/// Standard gravity in m/s², the client reports acceleration in m/s².
+const STANDARD_GRAVITY: f32 = 9.80665;
+
+#[derive(Clone, Copy, Debug, Default, FromRepr)]
 #[repr(u8)]
 enum GamepadKind {
-	_Unknown = 0x00,
-	_Xbox = 0x01,
-	_PlayStation = 0x02,
-	_Nintendo = 0x03,
+	#[default]
+	Unknown = 0x00,
+	Xbox = 0x01,
+	PlayStation = 0x02,
+	Nintendo = 0x03,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -38,10 +60,10 @@ enum GamepadCapability {
 	_Touchpad = 0x08,
 
 	/// Can report accelerometer events.
-	_Acceleration = 0x10,
+	Acceleration = 0x10,
 
 	/// Can report gyroscope events.
-	_Gyro = 0x20,
+	Gyro = 0x20,
 
 	/// Reports battery state.
 	_BatteryState = 0x40,
@@ -50,6 +72,44 @@ enum GamepadCapability {
 	_RgbLed = 0x80,
 }
 
+/// The controller that is emulated for a gamepad of the client.
+///
+/// Games and Steam Input pick their button prompts and features based on the vendor and product id,
+/// so we emulate the controller that is closest to the controller of the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EmulatedController {
+	Xbox,
+	DualShock4,
+	DualSense,
+}
+
+impl EmulatedController {
+	fn for_gamepad(info: &GamepadInfo) -> Self {
+		match info.kind {
+			// SDL reports the microphone button of a DualSense as misc button, a DualShock 4 doesn't have one.
+			GamepadKind::PlayStation if info.has_button(&GamepadButton::Misc) => Self::DualSense,
+			GamepadKind::PlayStation => Self::DualShock4,
+			GamepadKind::Xbox | GamepadKind::Nintendo | GamepadKind::Unknown => Self::Xbox,
+		}
+	}
+
+	fn name(&self) -> &'static str {
+		match self {
+			Self::Xbox => "Xbox 360 Controller",
+			Self::DualShock4 => "DualShock 4 Controller",
+			Self::DualSense => "DualSense Controller",
+		}
+	}
+
+	fn input_id(&self) -> InputId {
+		match self {
+			Self::Xbox => InputId::new(evdev::BusType::BUS_USB, 0x045E, 0x028E, 0x0110),
+			Self::DualShock4 => InputId::new(evdev::BusType::BUS_BLUETOOTH, 0x054C, 0x05C4, 0x8100),
+			Self::DualSense => InputId::new(evdev::BusType::BUS_USB, 0x054C, 0x0CE6, 0x8111),
+		}
+	}
+}
+
 #[derive(Copy, Clone, Debug, EnumIter, PartialEq)]
 #[repr(u32)]
 enum GamepadButton {
@@ -109,10 +169,10 @@ impl From<GamepadButton> for Key {
 
 #[derive(Debug)]
 pub struct GamepadInfo {
-	index: u8,
-	// kind: GamepadKind,
-	// capabilities: u16,
-	// supported_buttons: u32,
+	pub index: u8,
+	kind: GamepadKind,
+	capabilities: u16,
+	supported_buttons: u32,
 }
 
 impl GamepadInfo {
@@ -131,25 +191,83 @@ impl GamepadInfo {
 
 		Ok(Self {
 			index: buffer[0],
-			// kind: GamepadKind::from_repr(buffer[1]).ok_or_else(|| tracing::warn!("Unknown gamepad kind: {}", buffer[1]))?,
-			// capabilities: u16::from_le_bytes(buffer[2..4].try_into().unwrap()),
-			// supported_buttons: u32::from_le_bytes(buffer[4..8].try_into().unwrap()),
+			kind: GamepadKind::from_repr(buffer[1]).unwrap_or_else(|| {
+				tracing::warn!("Unknown gamepad kind: {}", buffer[1]);
+				GamepadKind::Unknown
+			}),
+			capabilities: u16::from_le_bytes(buffer[2..4].try_into().unwrap()),
+			supported_buttons: u32::from_le_bytes(buffer[4..8].try_into().unwrap()),
 		})
 	}
 
-	// fn has_capability(&self, capability: &GamepadCapability) -> bool {
-	// 	(self.capabilities & *capability as u16) != 0
-	// }
+	/// Info for a gamepad that the client sent updates for, without announcing it first.
+	///
+	/// Older clients don't announce their gamepads, so we assume a standard controller.
+	pub fn unannounced(index: u8) -> Self {
+		Self { index, kind: GamepadKind::Unknown, capabilities: 0, supported_buttons: 0 }
+	}
+
+	fn has_capability(&self, capability: &GamepadCapability) -> bool {
+		(self.capabilities & *capability as u16) != 0
+	}
+
+	fn has_button(&self, button: &GamepadButton) -> bool {
+		(self.supported_buttons & *button as u32) != 0
+	}
+}
+
+/// Motion sensor of a gamepad.
+#[derive(Clone, Copy, Debug, FromRepr)]
+#[repr(u8)]
+pub enum MotionSensor {
+	Accelerometer = 0x01,
+	Gyroscope = 0x02,
+}
+
+#[derive(Debug)]
+pub struct GamepadMotion {
+	pub index: u8,
+	sensor: MotionSensor,
+
+	/// Measurement of the sensor, in m/s² for the accelerometer and in degrees per second for the gyroscope.
+	values: (f32, f32, f32),
+}
+
+impl GamepadMotion {
+	pub fn from_bytes(buffer: &[u8]) -> Result<Self, ()> {
+		const EXPECTED_SIZE: usize =
+			std::mem::size_of::<u8>()    // index
+			+ std::mem::size_of::<u8>()  // sensor
+			+ std::mem::size_of::<u16>() // zero
+			+ std::mem::size_of::<f32>() // x
+			+ std::mem::size_of::<f32>() // y
+			+ std::mem::size_of::<f32>() // z
+		;
+
+		if buffer.len() < EXPECTED_SIZE {
+			tracing::warn!("Expected at least {EXPECTED_SIZE} bytes for GamepadMotion, got {} bytes.", buffer.len());
+			return Err(());
+		}
 
-	// fn has_button(&self, button: &GamepadButton) -> bool {
-	// 	(self.supported_buttons & *button as u32) != 0
-	// }
+		Ok(Self {
+			index: buffer[0],
+			sensor: MotionSensor::from_repr(buffer[1])
+				.ok_or_else(|| tracing::warn!("Unknown motion sensor: {}", buffer[1]))?,
+			values: (
+				f32::from_le_bytes(buffer[4..8].try_into().unwrap()),
+				f32::from_le_bytes(buffer[8..12].try_into().unwrap()),
+				f32::from_le_bytes(buffer[12..16].try_into().unwrap()),
+			),
+		})
+	}
 }
 
 #[derive(Debug)]
 pub struct GamepadUpdate {
 	pub index: u16,
-	_active_gamepad_mask: u16,
+
+	/// Bitmask of the gamepads that are connected to the client.
+	pub active_gamepad_mask: u16,
 	button_flags: u32,
 	left_trigger: u8,
 	right_trigger: u8,
@@ -183,7 +301,7 @@ impl GamepadUpdate {
 
 		Ok(Self {
 			index: u16::from_le_bytes(buffer[2..4].try_into().unwrap()),
-			_active_gamepad_mask: u16::from_le_bytes(buffer[4..6].try_into().unwrap()),
+			active_gamepad_mask: u16::from_le_bytes(buffer[4..6].try_into().unwrap()),
 			button_flags: u16::from_le_bytes(buffer[8..10].try_into().unwrap()) as u32 | (u16::from_le_bytes(buffer[22..24].try_into().unwrap()) as u32) << 16,
 			left_trigger: buffer[10],
 			right_trigger: buffer[11],
@@ -203,6 +321,91 @@ pub struct Gamepad {
 	_info: GamepadInfo,
 	device: VirtualDevice,
 	button_state: u32,
+	ready_at: Instant,
+
+	/// Separate device for the accelerometer and gyroscope, like the kernel creates for Sony controllers.
+	motion: Option<MotionDevice>,
+}
+
+struct MotionDevice {
+	device: VirtualDevice,
+	sensors: Vec<MotionSensor>,
+	created: Instant,
+}
+
+impl MotionDevice {
+	fn new(controller: EmulatedController, info: &GamepadInfo) -> Result<Option<Self>, ()> {
+		let sensors: Vec<_> = [
+			(GamepadCapability::Acceleration, MotionSensor::Accelerometer),
+			(GamepadCapability::Gyro, MotionSensor::Gyroscope),
+		]
+			.into_iter()
+			.filter(|(capability, _)| info.has_capability(capability))
+			.map(|(_, sensor)| sensor)
+			.collect();
+
+		if sensors.is_empty() {
+			return Ok(None);
+		}
+
+		let accelerometer_range = ACCELEROMETER_RANGE * ACCELEROMETER_RESOLUTION;
+		let gyroscope_range = GYROSCOPE_RANGE * GYROSCOPE_RESOLUTION;
+		let mut builder = VirtualDeviceBuilder::new()
+			.map_err(|e| tracing::error!("Failed to initiate virtual motion sensors: {e}"))?
+			.input_id(controller.input_id())
+			// SDL and Steam match the motion sensors to the gamepad based on this suffix.
+			.name(format!("Moonshine {} {} Motion Sensors", controller.name(), info.index).as_str())
+			.with_properties(&AttributeSet::from_iter([PropType::ACCELEROMETER]))
+			.map_err(|e| tracing::error!("Failed to set motion sensor properties: {e}"))?
+			.with_msc(&AttributeSet::from_iter([MiscType::MSC_TIMESTAMP]))
+			.map_err(|e| tracing::error!("Failed to enable motion sensor timestamps: {e}"))?;
+
+		for axis in [AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y, AbsoluteAxisType::ABS_Z] {
+			builder = builder.with_absolute_axis(&UinputAbsSetup::new(
+				axis,
+				AbsInfo::new(0, -accelerometer_range, accelerometer_range, 16, 0, ACCELEROMETER_RESOLUTION)
+			))
+				.map_err(|e| tracing::error!("Failed to enable accelerometer axis: {e}"))?;
+		}
+		for axis in [AbsoluteAxisType::ABS_RX, AbsoluteAxisType::ABS_RY, AbsoluteAxisType::ABS_RZ] {
+			builder = builder.with_absolute_axis(&UinputAbsSetup::new(
+				axis,
+				AbsInfo::new(0, -gyroscope_range, gyroscope_range, 16, 0, GYROSCOPE_RESOLUTION)
+			))
+				.map_err(|e| tracing::error!("Failed to enable gyroscope axis: {e}"))?;
+		}
+
+		let device = builder.build()
+			.map_err(|e| tracing::error!("Failed to create virtual motion sensors: {e}"))?;
+
+		Ok(Some(Self { device, sensors, created: Instant::now() }))
+	}
+
+	fn update(&mut self, motion: GamepadMotion) -> Result<(), ()> {
+		let (x, y, z) = motion.values;
+		let (axes, scale) = match motion.sensor {
+			MotionSensor::Accelerometer => (
+				[AbsoluteAxisType::ABS_X, AbsoluteAxisType::ABS_Y, AbsoluteAxisType::ABS_Z],
+				ACCELEROMETER_RESOLUTION as f32 / STANDARD_GRAVITY,
+			),
+			MotionSensor::Gyroscope => (
+				[AbsoluteAxisType::ABS_RX, AbsoluteAxisType::ABS_RY, AbsoluteAxisType::ABS_RZ],
+				GYROSCOPE_RESOLUTION as f32,
+			),
+		};
+
+		// The timestamp is in microseconds and is allowed to wrap around.
+		let timestamp = self.created.elapsed().as_micros() as i32;
+		let events = [
+			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, axes[0].0, (x * scale) as i32),
+			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, axes[1].0, (y * scale) as i32),
+			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, axes[2].0, (z * scale) as i32),
+			evdev::InputEvent::new_now(evdev::EventType::MISC, MiscType::MSC_TIMESTAMP.0, timestamp),
+		];
+
+		self.device.emit(&events)
+			.map_err(|e| tracing::error!("Failed to send motion events: {e}"))
+	}
 }
 
 impl Gamepad {
@@ -225,10 +428,13 @@ impl Gamepad {
 			evdev::Key::BTN_MODE,
 		]);
 
+		let controller = EmulatedController::for_gamepad(&info);
+		tracing::info!("Creating virtual {} for gamepad {} ({:?}).", controller.name(), info.index, info.kind);
+
 		let device = VirtualDeviceBuilder::new()
 			.map_err(|e| tracing::error!("Failed to initiate virtual gamepad: {e}"))?
-			.input_id(InputId::new(evdev::BusType::BUS_BLUETOOTH, 0x54C, 0x5C4, 0x8100))
-			.name(format!("Moonshine Gamepad {}", info.index).as_str())
+			.input_id(controller.input_id())
+			.name(format!("Moonshine {} {}", controller.name(), info.index).as_str())
 			.with_keys(&buttons)
 			.map_err(|e| tracing::error!("Failed to add keys to virtual gamepad: {e}"))?
 			// Dpad.
@@ -289,7 +495,30 @@ impl Gamepad {
 			.build()
 			.map_err(|e| tracing::error!("Failed to create virtual gamepad: {e}"))?;
 
-		Ok(Self { _info: info, device, button_state: 0 })
+		// Motion sensors are optional, the gamepad is still usable without them.
+		let motion = MotionDevice::new(controller, &info).unwrap_or(None);
+
+		Ok(Self { _info: info, device, button_state: 0, ready_at: Instant::now() + DEVICE_WARMUP, motion })
+	}
+
+	/// Motion sensors for which the client should send events.
+	pub fn motion_sensors(&self) -> &[MotionSensor] {
+		self.motion.as_ref().map(|motion| motion.sensors.as_slice()).unwrap_or_default()
+	}
+
+	pub fn update_motion(&mut self, motion: GamepadMotion) -> Result<(), ()> {
+		match &mut self.motion {
+			Some(device) => device.update(motion),
+			None => {
+				tracing::trace!("Ignoring motion event for gamepad without motion sensors.");
+				Ok(())
+			},
+		}
+	}
+
+	/// Moment at which the virtual gamepad is expected to be picked up by the system.
+	pub fn ready_at(&self) -> Instant {
+		self.ready_at
 	}
 
 	fn button_changed(&self, button: &GamepadButton, new_state: u32) -> bool {
@@ -342,9 +571,9 @@ impl Gamepad {
 		// Send analog triggers.
 		events.extend([
 			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, update.left_stick.0 as i32),
-			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, -update.left_stick.1 as i32),
+			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, update.left_stick.1.saturating_neg() as i32),
 			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_RX.0, update.right_stick.0 as i32),
-			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_RY.0, -update.right_stick.1 as i32),
+			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_RY.0, update.right_stick.1.saturating_neg() as i32),
 			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_Z.0, update.left_trigger as i32),
 			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_RZ.0, update.right_trigger as i32),
 		]);
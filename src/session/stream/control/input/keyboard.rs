@@ -2,6 +2,11 @@ use evdev::{uinput::{VirtualDevice, VirtualDeviceBuilder}, AttributeSet};
 use strum::IntoEnumIterator;
 use strum_macros::{FromRepr, EnumIter};
 
+/// Flags for the modifier keys that the client held while pressing or releasing a key.
+pub const MODIFIER_SHIFT: u8 = 0x01;
+pub const MODIFIER_CONTROL: u8 = 0x02;
+pub const MODIFIER_ALT: u8 = 0x04;
+
 #[derive(Debug, Eq, PartialEq, FromRepr, EnumIter)]
 #[repr(u8)]
 pub enum Key {
@@ -150,7 +155,12 @@ impl Key {
 		}
 
 
-		Key::from_repr(buffer[1]).ok_or_else(|| tracing::warn!("Unknown keycode: {}", buffer[5]))
+		Key::from_repr(buffer[1]).ok_or_else(|| tracing::warn!("Unknown keycode: {}", buffer[1]))
+	}
+
+	/// Modifier keys that were held, see the `MODIFIER_*` flags.
+	pub fn modifiers_from_bytes(buffer: &[u8]) -> u8 {
+		buffer.get(3).copied().unwrap_or(0)
 	}
 }
 
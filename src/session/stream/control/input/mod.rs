@@ -1,7 +1,11 @@
+use std::{path::Path, time::{Duration, Instant}};
+
 use strum_macros::FromRepr;
 use tokio::sync::mpsc;
+use tracing::Instrument;
 
-use crate::session::stream::control::input::gamepad::Gamepad;
+use crate::session::{current_display_mode, stream::{control::input::gamepad::Gamepad, video::{CapturedArea, SharedCapturedArea}}};
+use super::{feedback::{feedback_queue, FeedbackSender}, HostMessage};
 
 use self::{
 	mouse::{
@@ -12,13 +16,49 @@ use self::{
 		MouseScrollVertical,
 		MouseScrollHorizontal,
 	},
-	keyboard::{Keyboard, Key},
-	gamepad::{GamepadInfo, GamepadUpdate}
+	keyboard::{Keyboard, Key, MODIFIER_ALT, MODIFIER_CONTROL, MODIFIER_SHIFT},
+	gamepad::{GamepadInfo, GamepadMotion, GamepadUpdate},
+	queue::InputQueue,
 };
 
+pub use self::backend::probe as probe_input;
+pub use self::gamepad::MotionSensor;
+pub use self::recording::InputRecorder;
+
+mod backend;
 mod keyboard;
 mod mouse;
 mod gamepad;
+mod queue;
+mod recording;
+
+/// Time it takes for a newly created virtual device to be picked up by the system (and applications like Steam).
+///
+/// Events for a device are queued until this time has passed, since they would otherwise be lost.
+const DEVICE_WARMUP: Duration = Duration::from_millis(500);
+
+/// Maximum number of gamepads, the client reports active gamepads in a 16 bit mask.
+const MAX_GAMEPADS: usize = 16;
+
+/// Actions that are triggered by pressing a key with Ctrl+Alt+Shift, like the shortcuts of Moonlight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shortcut {
+	/// Show or hide the performance overlay (Ctrl+Alt+Shift+P).
+	ToggleOverlay,
+
+	/// Pause or resume the stream (Ctrl+Alt+Shift+Pause).
+	TogglePause,
+}
+
+impl Shortcut {
+	fn from_key(key: Key) -> Option<Self> {
+		match key {
+			Key::P => Some(Self::ToggleOverlay),
+			Key::Pause => Some(Self::TogglePause),
+			_ => None,
+		}
+	}
+}
 
 #[derive(FromRepr)]
 #[repr(u32)]
@@ -33,6 +73,7 @@ enum InputEventType {
 	MouseScrollHorizontal = 0x55000001,
 	GamepadInfo = 0x55000004, // Called ControllerArrival in Moonlight.
 	GamepadUpdate = 0x0000000C,
+	GamepadMotion = 0x55000006,
 }
 
 #[derive(Debug)]
@@ -48,6 +89,7 @@ enum InputEvent {
 	MouseScrollHorizontal(MouseScrollHorizontal),
 	GamepadInfo(GamepadInfo),
 	GamepadUpdate(GamepadUpdate),
+	GamepadMotion(GamepadMotion),
 }
 
 impl InputEvent {
@@ -69,6 +111,7 @@ impl InputEvent {
 			Some(InputEventType::MouseScrollHorizontal) => Ok(InputEvent::MouseScrollHorizontal(MouseScrollHorizontal::from_bytes(&buffer[4..])?)),
 			Some(InputEventType::GamepadInfo) => Ok(InputEvent::GamepadInfo(GamepadInfo::from_bytes(&buffer[4..])?)),
 			Some(InputEventType::GamepadUpdate) => Ok(InputEvent::GamepadUpdate(GamepadUpdate::from_bytes(&buffer[4..])?)),
+			Some(InputEventType::GamepadMotion) => Ok(InputEvent::GamepadMotion(GamepadMotion::from_bytes(&buffer[4..])?)),
 			None => {
 				tracing::warn!("Received unknown event type: {event_type}");
 				Err(())
@@ -82,13 +125,28 @@ pub struct InputHandler {
 }
 
 impl InputHandler {
-	pub fn new() -> Result<Self, ()> {
+	/// Create virtual input devices, `host_message_tx` is used to send messages to the client (like enabling motion events).
+	///
+	/// `captured_area` is used to map absolute mouse positions to the part of the screen that is streamed.
+	pub fn new(captured_area: SharedCapturedArea, host_message_tx: FeedbackSender) -> Result<Self, ()> {
+		// Explain why input can't work, instead of failing on the first device that can't be created.
+		probe_input()
+			.map_err(|e| tracing::error!("Can't create virtual input devices: {e}"))?;
+
 		let mouse = Mouse::new()?;
 		let keyboard = Keyboard::new()?;
 
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = InputHandlerInner { mouse, keyboard };
-		tokio::spawn(inner.run(command_rx));
+		let inner = InputHandlerInner {
+			mouse,
+			keyboard,
+			ready_at: Instant::now() + DEVICE_WARMUP,
+			captured_area,
+			gamepads: std::array::from_fn(|_| None),
+			queue: InputQueue::default(),
+			host_message_tx,
+		};
+		tokio::spawn(inner.run(command_rx).in_current_span());
 
 		Ok(Self { command_tx })
 	}
@@ -104,67 +162,359 @@ impl InputHandler {
 	}
 }
 
+/// The shortcut that `event` presses (`true`) or releases (`false`), if any.
+///
+/// Events of shortcuts aren't passed on to the host.
+pub fn shortcut(event: &[u8]) -> Option<(Shortcut, bool)> {
+	// Only look at the bytes that matter, parsing the event would warn twice about events that can't be parsed.
+	let event_type = InputEventType::from_repr(u32::from_le_bytes(event.get(..4)?.try_into().ok()?));
+	let pressed = match event_type {
+		Some(InputEventType::KeyDown) => true,
+		Some(InputEventType::KeyUp) => false,
+		_ => return None,
+	};
+
+	let payload = &event[4..];
+	let modifiers = MODIFIER_CONTROL | MODIFIER_ALT | MODIFIER_SHIFT;
+	if Key::modifiers_from_bytes(payload) & modifiers != modifiers {
+		return None;
+	}
+
+	let shortcut = Shortcut::from_key(Key::from_repr(*payload.get(1)?)?)?;
+	Some((shortcut, pressed))
+}
+
+/// Parse data as if a client sent it as an input event, for the fuzz targets.
+pub fn fuzz_input_event(data: &[u8]) {
+	let _ = shortcut(data);
+	let _ = InputEvent::from_bytes(data);
+}
+
+/// Replay the input events of a recording, with the timing they were received with.
+///
+/// With `parse_only` the events are only parsed and logged, without creating virtual input devices.
+/// Returns an error if the recording can't be read or if any of its events can't be parsed.
+pub async fn replay_input(path: &Path, parse_only: bool) -> Result<(), ()> {
+	let events = recording::read_recording(path)?;
+	tracing::info!("Replaying {} input event(s) from {path:?}.", events.len());
+
+	let mut input_handler = None;
+	if !parse_only {
+		// Map absolute mouse positions to the whole screen, like a stream of the whole screen would.
+		let captured_area = SharedCapturedArea::default();
+		match current_display_mode() {
+			Ok(mode) => captured_area.set(CapturedArea::whole_screen(mode.width, mode.height)),
+			Err(()) => tracing::warn!("Failed to get the size of the screen, absolute mouse movements are ignored."),
+		}
+
+		let (host_message_tx, mut host_message_rx) = feedback_queue();
+		tokio::spawn(async move {
+			while let Some(message) = host_message_rx.recv().await {
+				tracing::info!("Message for the client: {message:?}");
+			}
+		});
+
+		input_handler = Some(InputHandler::new(captured_area, host_message_tx)?);
+	}
+
+	let start = tokio::time::Instant::now();
+	let mut failed = 0;
+	for (index, recorded) in events.iter().enumerate() {
+		match &input_handler {
+			Some(input_handler) => {
+				tokio::time::sleep_until(start + recorded.offset).await;
+				if input_handler.handle_raw_input(&recorded.event).await.is_err() {
+					tracing::warn!("Failed to replay event {index}: {:02x?}", recorded.event);
+					failed += 1;
+				}
+			},
+			None => match InputEvent::from_bytes(&recorded.event) {
+				Ok(event) => tracing::info!("Event {index} at {} ms: {event:?}", recorded.offset.as_millis()),
+				Err(()) => {
+					tracing::warn!("Failed to parse event {index}: {:02x?}", recorded.event);
+					failed += 1;
+				},
+			},
+		}
+	}
+
+	if input_handler.is_some() {
+		// Give the input handler time to handle the last events (including those queued during device warmup).
+		tokio::time::sleep(DEVICE_WARMUP * 2).await;
+	}
+
+	if failed > 0 {
+		tracing::error!("Failed to handle {failed} of {} input event(s).", events.len());
+		return Err(());
+	}
+
+	tracing::info!("Replayed all input events.");
+	Ok(())
+}
+
 struct InputHandlerInner {
 	mouse: Mouse,
 	keyboard: Keyboard,
+
+	/// Moment at which the mouse and keyboard are expected to be picked up by the system.
+	ready_at: Instant,
+
+	/// Part of the screen that is streamed, absolute mouse positions are relative to this area.
+	captured_area: SharedCapturedArea,
+
+	/// Virtual gamepads, indexed by the slot of the gamepad on the client.
+	gamepads: [Option<Gamepad>; MAX_GAMEPADS],
+
+	/// Events received before the device they target was ready.
+	queue: InputQueue,
+
+	/// Messages for the client.
+	host_message_tx: FeedbackSender,
 }
 
 impl InputHandlerInner {
 	pub async fn run(mut self, mut command_rx: mpsc::Receiver<InputEvent>) {
-		let mut gamepads = Vec::new();
-
-		while let Some(command) = command_rx.recv().await {
-			match command {
-				InputEvent::KeyDown(key) => {
-					tracing::trace!("Pressing key: {key:?}");
-					let _ = self.keyboard.key_down(key);
-				},
-				InputEvent::KeyUp(key) => {
-					tracing::trace!("Releasing key: {key:?}");
-					let _ = self.keyboard.key_up(key);
-				},
-				InputEvent::MouseMoveAbsolute(event) => {
-					tracing::trace!("Absolute mouse movement: {event:?}");
-					let _ = self.mouse.move_absolute(event.x as i32, event.y as i32);
-				},
-				InputEvent::MouseMoveRelative(event) => {
-					tracing::trace!("Moving mouse relative: {event:?}");
-					let _ = self.mouse.move_relative(event.x as i32, event.y as i32);
-				},
-				InputEvent::MouseButtonDown(button) => {
-					tracing::trace!("Pressing mouse button: {button:?}");
-					let _ = self.mouse.button_down(button);
-				},
-				InputEvent::MouseButtonUp(button) => {
-					tracing::trace!("Releasing mouse button: {button:?}");
-					let _ = self.mouse.button_up(button);
-				},
-				InputEvent::MouseScrollVertical(event) => {
-					tracing::trace!("Scrolling vertically: {event:?}");
-					let _ = self.mouse.scroll_vertical(event.amount);
-				},
-				InputEvent::MouseScrollHorizontal(event) => {
-					tracing::trace!("Scrolling horizontally: {event:?}");
-					let _ = self.mouse.scroll_horizontal(event.amount);
-				},
-				InputEvent::GamepadInfo(gamepad) => {
-					tracing::debug!("Gamepad info: {gamepad:?}");
-					if let Ok(gamepad) = Gamepad::new(gamepad) {
-						gamepads.push(gamepad);
+		loop {
+			// If events are queued, wake up when the first of them can be replayed.
+			let command = match self.queue.front().and_then(|event| self.ready_at(event)) {
+				Some(ready_at) => {
+					match tokio::time::timeout_at(ready_at.into(), command_rx.recv()).await {
+						Ok(command) => command,
+						Err(_) => {
+							self.replay_queue();
+							continue;
+						},
 					}
 				},
-				InputEvent::GamepadUpdate(gamepad_update) => {
-					tracing::trace!("Gamepad update: {gamepad_update:?}");
-					if gamepad_update.index as usize >= gamepads.len() {
-						tracing::warn!("Received update for gamepad {}, but we only have {} gamepads.", gamepad_update.index, gamepads.len());
-						continue;
-					}
+				None => command_rx.recv().await,
+			};
 
-					let _ = gamepads[gamepad_update.index as usize].update(gamepad_update);
-				},
+			let Some(command) = command else {
+				break;
+			};
+
+			self.replay_queue();
+
+			// Preserve the order of events by queueing everything after the first queued event.
+			let waiting = self.ready_at(&command).is_some_and(|ready_at| ready_at > Instant::now());
+			if waiting || !self.queue.is_empty() {
+				tracing::trace!("Device not ready yet, queueing input event: {command:?}");
+				self.queue.push(command);
+			} else {
+				self.handle_event(command);
 			}
 		}
 
 		tracing::debug!("Input handler closing.");
 	}
+
+	/// Remove the virtual gamepads that are no longer connected to the client.
+	fn remove_inactive_gamepads(&mut self, active_gamepad_mask: u16) {
+		for (index, slot) in self.gamepads.iter_mut().enumerate() {
+			if slot.is_some() && active_gamepad_mask & (1 << index) == 0 {
+				tracing::info!("Gamepad {index} was disconnected, removing its virtual device.");
+				*slot = None;
+			}
+		}
+	}
+
+	/// Moment at which the device targeted by this event is ready, if it targets a device.
+	fn ready_at(&self, event: &InputEvent) -> Option<Instant> {
+		match event {
+			InputEvent::GamepadInfo(_) => None,
+			InputEvent::GamepadUpdate(gamepad_update) => {
+				self.gamepads.get(gamepad_update.index as usize)
+					.and_then(Option::as_ref)
+					.map(|gamepad| gamepad.ready_at())
+			},
+			InputEvent::GamepadMotion(gamepad_motion) => {
+				self.gamepads.get(gamepad_motion.index as usize)
+					.and_then(Option::as_ref)
+					.map(|gamepad| gamepad.ready_at())
+			},
+			_ => Some(self.ready_at),
+		}
+	}
+
+	/// Handle all queued events whose device has become ready, in the order they were received.
+	fn replay_queue(&mut self) {
+		let now = Instant::now();
+		while let Some(event) = self.queue.front() {
+			if self.ready_at(event).is_some_and(|ready_at| ready_at > now) {
+				break;
+			}
+
+			if let Some(event) = self.queue.pop() {
+				self.handle_event(event);
+			}
+		}
+	}
+
+	fn handle_event(&mut self, event: InputEvent) {
+		match event {
+			InputEvent::KeyDown(key) => {
+				tracing::trace!("Pressing key: {key:?}");
+				let _ = self.keyboard.key_down(key);
+			},
+			InputEvent::KeyUp(key) => {
+				tracing::trace!("Releasing key: {key:?}");
+				let _ = self.keyboard.key_up(key);
+			},
+			InputEvent::MouseMoveAbsolute(event) => {
+				tracing::trace!("Absolute mouse movement: {event:?}");
+				let Some(area) = self.captured_area.get() else {
+					tracing::debug!("Ignoring absolute mouse movement, the stream hasn't started yet.");
+					return;
+				};
+
+				let Some((x, y)) = area.to_screen(event.x, event.y, event.width, event.height) else {
+					tracing::warn!("Received absolute mouse movement with invalid reference size {}x{}.", event.width, event.height);
+					return;
+				};
+
+				let _ = self.mouse.move_absolute(x, y);
+			},
+			InputEvent::MouseMoveRelative(event) => {
+				tracing::trace!("Moving mouse relative: {event:?}");
+				let _ = self.mouse.move_relative(event.x as i32, event.y as i32);
+			},
+			InputEvent::MouseButtonDown(button) => {
+				tracing::trace!("Pressing mouse button: {button:?}");
+				let _ = self.mouse.button_down(button);
+			},
+			InputEvent::MouseButtonUp(button) => {
+				tracing::trace!("Releasing mouse button: {button:?}");
+				let _ = self.mouse.button_up(button);
+			},
+			InputEvent::MouseScrollVertical(event) => {
+				tracing::trace!("Scrolling vertically: {event:?}");
+				let _ = self.mouse.scroll_vertical(event.amount);
+			},
+			InputEvent::MouseScrollHorizontal(event) => {
+				tracing::trace!("Scrolling horizontally: {event:?}");
+				let _ = self.mouse.scroll_horizontal(event.amount);
+			},
+			InputEvent::GamepadInfo(gamepad) => {
+				tracing::debug!("Gamepad info: {gamepad:?}");
+				let index = gamepad.index as usize;
+				let Some(slot) = self.gamepads.get_mut(index) else {
+					tracing::warn!("Ignoring gamepad {index}, at most {MAX_GAMEPADS} gamepads are supported.");
+					return;
+				};
+
+				// The client may re-announce a gamepad in the same slot, so replace whatever was there.
+				*slot = None;
+				*slot = Gamepad::new(gamepad).ok();
+
+				// The client only sends motion events after we ask for them.
+				if let Some(gamepad) = slot {
+					for sensor in gamepad.motion_sensors() {
+						self.host_message_tx.send(HostMessage::EnableMotion { gamepad: index as u16, sensor: *sensor });
+					}
+				}
+			},
+			InputEvent::GamepadUpdate(gamepad_update) => {
+				tracing::trace!("Gamepad update: {gamepad_update:?}");
+				self.remove_inactive_gamepads(gamepad_update.active_gamepad_mask);
+
+				let index = gamepad_update.index as usize;
+				let Some(slot) = self.gamepads.get_mut(index) else {
+					tracing::warn!("Received update for gamepad {index}, but at most {MAX_GAMEPADS} gamepads are supported.");
+					return;
+				};
+
+				if gamepad_update.active_gamepad_mask & (1 << index) == 0 {
+					// This update tells us the gamepad was disconnected, which is handled above.
+					return;
+				}
+
+				if slot.is_none() {
+					tracing::debug!("Received update for gamepad {index} before it was announced, assuming a standard gamepad.");
+					*slot = Gamepad::new(GamepadInfo::unannounced(index as u8)).ok();
+				}
+
+				if let Some(gamepad) = slot {
+					let _ = gamepad.update(gamepad_update);
+				}
+			},
+			InputEvent::GamepadMotion(gamepad_motion) => {
+				tracing::trace!("Gamepad motion: {gamepad_motion:?}");
+				let index = gamepad_motion.index as usize;
+				let Some(gamepad) = self.gamepads.get_mut(index).and_then(Option::as_mut) else {
+					tracing::debug!("Received motion for unknown gamepad {index}.");
+					return;
+				};
+
+				let _ = gamepad.update_motion(gamepad_motion);
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::testing::Rng;
+
+	use super::*;
+
+	/// A valid event of every type, as sent by the client.
+	fn valid_events() -> Vec<Vec<u8>> {
+		let event = |event_type: InputEventType, payload: &[u8]| [&(event_type as u32).to_le_bytes()[..], payload].concat();
+		vec![
+			event(InputEventType::KeyDown, &[0, 0x0D, 0, 0, 0, 0]),
+			event(InputEventType::KeyUp, &[0, 0x0D, 0, 0, 0, 0]),
+			event(InputEventType::MouseMoveAbsolute, &[0, 10, 0, 20, 0, 0, 0x07, 0x80, 0x04, 0x38]),
+			event(InputEventType::MouseMoveRelative, &[0xFF, 0xFF, 0, 1]),
+			event(InputEventType::MouseButtonDown, &[1]),
+			event(InputEventType::MouseButtonUp, &[3]),
+			event(InputEventType::MouseScrollVertical, &[0, 120]),
+			event(InputEventType::MouseScrollHorizontal, &[0xFF, 0x88]),
+			event(InputEventType::GamepadInfo, &[0, 1, 0, 0, 0, 0, 0, 0]),
+			event(InputEventType::GamepadUpdate, &[0x1A, 0, 0, 0, 1, 0, 0x14, 0, 0, 0x10, 0xFF, 0, 0, 0x80, 0xFF, 0x7F, 0, 0, 0, 0, 0x9C, 0, 0, 0, 0x55, 0]),
+			event(InputEventType::GamepadMotion, &[0, 1, 0, 0, 0, 0, 0x80, 0x3F, 0, 0, 0, 0, 0, 0, 0x80, 0xBF]),
+		]
+	}
+
+	#[test]
+	fn valid_events_are_parsed() {
+		for event in valid_events() {
+			assert!(InputEvent::from_bytes(&event).is_ok(), "failed to parse {event:02x?}");
+		}
+	}
+
+	#[test]
+	fn truncated_events_are_rejected() {
+		for event in valid_events() {
+			for length in 0..event.len() {
+				assert!(InputEvent::from_bytes(&event[..length]).is_err(), "accepted truncated event {:02x?}", &event[..length]);
+			}
+		}
+	}
+
+	#[test]
+	fn shortcuts_need_all_modifiers() {
+		let event = |event_type: InputEventType, payload: &[u8]| [&(event_type as u32).to_le_bytes()[..], payload].concat();
+		assert_eq!(shortcut(&event(InputEventType::KeyDown, &[0, 0x50, 0, 0x07, 0, 0])), Some((Shortcut::ToggleOverlay, true)));
+		assert_eq!(shortcut(&event(InputEventType::KeyUp, &[0, 0x50, 0, 0x07, 0, 0])), Some((Shortcut::ToggleOverlay, false)));
+		assert_eq!(shortcut(&event(InputEventType::KeyDown, &[0, 0x13, 0, 0x07, 0, 0])), Some((Shortcut::TogglePause, true)));
+		assert_eq!(shortcut(&event(InputEventType::KeyDown, &[0, 0x50, 0, 0x03, 0, 0])), None);
+		assert_eq!(shortcut(&event(InputEventType::KeyDown, &[0, 0x51, 0, 0x07, 0, 0])), None);
+		assert_eq!(shortcut(&event(InputEventType::MouseButtonDown, &[1])), None);
+	}
+
+	/// Parse events with a known type and random contents, which must never panic.
+	#[test]
+	fn random_events_dont_panic() {
+		let mut rng = Rng(0x5EED_1234_ABCD_0002);
+		let events = valid_events();
+		for _ in 0..100_000 {
+			let mut event = events[rng.range(0, events.len())][..4].to_vec();
+			event.extend(rng.bytes(40));
+			let _ = InputEvent::from_bytes(&event);
+		}
+
+		for _ in 0..10_000 {
+			let _ = InputEvent::from_bytes(&rng.bytes(40));
+		}
+	}
 }
@@ -1,12 +1,20 @@
 use strum_macros::FromRepr;
 use evdev::{uinput::{VirtualDeviceBuilder, VirtualDevice}, AttributeSet, RelativeAxisType, Key, AbsoluteAxisType, UinputAbsSetup, AbsInfo};
 
+/// Maximum value of the absolute axes, the X server maps this range to the whole X screen.
+const ABSOLUTE_AXIS_MAX: i32 = u16::MAX as i32;
+
+/// High resolution scroll amount of a single wheel click, as defined by the kernel.
+const WHEEL_CLICK: i32 = 120;
+
 #[derive(Debug)]
 pub struct MouseMoveAbsolute {
 	pub x: i16,
 	pub y: i16,
-	// width: i16,
-	// height: i16,
+
+	/// Size of the view of the stream on the client, which `x` and `y` are relative to.
+	pub width: i16,
+	pub height: i16,
 }
 
 impl MouseMoveAbsolute {
@@ -27,8 +35,8 @@ impl MouseMoveAbsolute {
 		Ok(Self {
 			x: i16::from_be_bytes(buffer[0..2].try_into().unwrap()),
 			y: i16::from_be_bytes(buffer[2..4].try_into().unwrap()),
-			// width: i16::from_be_bytes(buffer[6..8].try_into().unwrap()),
-			// height: i16::from_be_bytes(buffer[8..10].try_into().unwrap()),
+			width: i16::from_be_bytes(buffer[6..8].try_into().unwrap()),
+			height: i16::from_be_bytes(buffer[8..10].try_into().unwrap()),
 		})
 	}
 }
@@ -126,6 +134,10 @@ impl MouseScrollHorizontal {
 
 pub struct Mouse {
 	device: VirtualDevice,
+
+	/// High resolution scroll amounts that didn't add up to a full wheel click yet.
+	vertical_scroll: i32,
+	horizontal_scroll: i32,
 }
 
 impl Mouse {
@@ -136,16 +148,18 @@ impl Mouse {
 			.with_relative_axes(&AttributeSet::from_iter([
 				RelativeAxisType::REL_X,
 				RelativeAxisType::REL_Y,
+				RelativeAxisType::REL_WHEEL,
+				RelativeAxisType::REL_HWHEEL,
 				RelativeAxisType::REL_WHEEL_HI_RES,
 				RelativeAxisType::REL_HWHEEL_HI_RES,
 			]))
 			.map_err(|e| tracing::error!("Failed to enable relative axes for virtual mouse: {e}"))?
 			.with_absolute_axis(&UinputAbsSetup::new(
-				AbsoluteAxisType::ABS_X, AbsInfo::new(0, 0, 3000, 0, 0, 1)
+				AbsoluteAxisType::ABS_X, AbsInfo::new(0, 0, ABSOLUTE_AXIS_MAX, 0, 0, 1)
 			))
 			.map_err(|e| tracing::error!("Failed to enable absolute axis for virtual mouse: {e}"))?
 			.with_absolute_axis(&UinputAbsSetup::new(
-				AbsoluteAxisType::ABS_Y, AbsInfo::new(0, 0, 3000, 0, 0, 1)
+				AbsoluteAxisType::ABS_Y, AbsInfo::new(0, 0, ABSOLUTE_AXIS_MAX, 0, 0, 1)
 			))
 			.map_err(|e| tracing::error!("Failed to enable absolute axis for virtual mouse: {e}"))?
 			.with_keys(&AttributeSet::from_iter([
@@ -159,7 +173,7 @@ impl Mouse {
 			.build()
 			.map_err(|e| tracing::error!("Failed to create virtual mouse: {e}"))?;
 
-		Ok(Self { device })
+		Ok(Self { device, vertical_scroll: 0, horizontal_scroll: 0 })
 	}
 
 	pub fn move_relative(&mut self, x: i32, y: i32) -> Result<(), ()> {
@@ -171,8 +185,10 @@ impl Mouse {
 			.map_err(|e| tracing::error!("Failed to make relative mouse movement: {e}"))
 	}
 
-	pub fn move_absolute(&mut self, x: i32, y: i32) -> Result<(), ()> {
-		tracing::info!("x: {x}, y: {y}");
+	/// Move the mouse to a position on the X screen, given as a fraction (between 0 and 1) of the screen size.
+	pub fn move_absolute(&mut self, x: f64, y: f64) -> Result<(), ()> {
+		let x = (x * ABSOLUTE_AXIS_MAX as f64).round() as i32;
+		let y = (y * ABSOLUTE_AXIS_MAX as f64).round() as i32;
 		let events = [
 			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, x),
 			evdev::InputEvent::new_now(evdev::EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, y),
@@ -203,19 +219,54 @@ impl Mouse {
 			.map_err(|e| tracing::error!("Failed to release mouse button: {e}"))
 	}
 
+	/// Scroll vertically, `amount` is in high resolution units (120 per wheel click).
 	pub fn scroll_vertical(&mut self, amount: i16) -> Result<(), ()> {
-		let events = [
-			evdev::InputEvent::new_now(evdev::EventType::RELATIVE, RelativeAxisType::REL_WHEEL_HI_RES.0, amount as i32),
-		];
+		let events = scroll_events(
+			amount as i32,
+			&mut self.vertical_scroll,
+			RelativeAxisType::REL_WHEEL_HI_RES,
+			RelativeAxisType::REL_WHEEL,
+		);
 		self.device.emit(&events)
 			.map_err(|e| tracing::error!("Failed to scroll vertically: {e}"))
 	}
 
+	/// Scroll horizontally, `amount` is in high resolution units (120 per wheel click).
 	pub fn scroll_horizontal(&mut self, amount: i16) -> Result<(), ()> {
-		let events = [
-			evdev::InputEvent::new_now(evdev::EventType::RELATIVE, RelativeAxisType::REL_HWHEEL_HI_RES.0, amount as i32),
-		];
+		let events = scroll_events(
+			amount as i32,
+			&mut self.horizontal_scroll,
+			RelativeAxisType::REL_HWHEEL_HI_RES,
+			RelativeAxisType::REL_HWHEEL,
+		);
 		self.device.emit(&events)
 			.map_err(|e| tracing::error!("Failed to scroll horizontally: {e}"))
 	}
 }
+
+/// Create the events for a high resolution scroll, like a physical mouse with a high resolution wheel.
+///
+/// Applications that support high resolution scrolling use the high resolution axis,
+/// others only see a wheel click once enough small scroll amounts (for example from a trackpad) add up to one.
+fn scroll_events(
+	amount: i32,
+	accumulated: &mut i32,
+	high_resolution_axis: RelativeAxisType,
+	axis: RelativeAxisType,
+) -> Vec<evdev::InputEvent> {
+	// Start over when changing direction, so scrolling back doesn't first have to undo the accumulated amount.
+	if amount.signum() != accumulated.signum() {
+		*accumulated = 0;
+	}
+	*accumulated += amount;
+
+	let mut events = vec![evdev::InputEvent::new_now(evdev::EventType::RELATIVE, high_resolution_axis.0, amount)];
+
+	let clicks = *accumulated / WHEEL_CLICK;
+	if clicks != 0 {
+		*accumulated -= clicks * WHEEL_CLICK;
+		events.push(evdev::InputEvent::new_now(evdev::EventType::RELATIVE, axis.0, clicks));
+	}
+
+	events
+}
@@ -0,0 +1,60 @@
+use std::{collections::VecDeque, time::Instant};
+
+use super::{InputEvent, mouse::MouseMoveRelative};
+
+/// Maximum number of events to hold on to while waiting for devices to become ready.
+const MAX_QUEUED_EVENTS: usize = 256;
+
+struct QueuedEvent {
+	received: Instant,
+	event: InputEvent,
+}
+
+/// Queue for input events that arrive before the device they target is ready.
+///
+/// Mouse movements are merged while queued, so that replaying the queue doesn't
+/// move the cursor through a trail of stale positions.
+#[derive(Default)]
+pub struct InputQueue {
+	events: VecDeque<QueuedEvent>,
+}
+
+impl InputQueue {
+	pub fn is_empty(&self) -> bool {
+		self.events.is_empty()
+	}
+
+	pub fn front(&self) -> Option<&InputEvent> {
+		self.events.front().map(|queued| &queued.event)
+	}
+
+	pub fn push(&mut self, event: InputEvent) {
+		let last = self.events.back_mut().map(|queued| &mut queued.event);
+		match (last, event) {
+			(Some(InputEvent::MouseMoveRelative(last)), InputEvent::MouseMoveRelative(event)) => {
+				*last = MouseMoveRelative {
+					x: last.x.saturating_add(event.x),
+					y: last.y.saturating_add(event.y),
+				};
+			},
+			(Some(InputEvent::MouseMoveAbsolute(last)), InputEvent::MouseMoveAbsolute(event)) => {
+				*last = event;
+			},
+			(_, event) => {
+				if self.events.len() >= MAX_QUEUED_EVENTS {
+					if let Some(dropped) = self.events.pop_front() {
+						tracing::warn!("Input queue is full, dropping oldest event: {:?}", dropped.event);
+					}
+				}
+
+				self.events.push_back(QueuedEvent { received: Instant::now(), event });
+			},
+		}
+	}
+
+	pub fn pop(&mut self) -> Option<InputEvent> {
+		let queued = self.events.pop_front()?;
+		tracing::trace!("Replaying input event queued for {:?}: {:?}", queued.received.elapsed(), queued.event);
+		Some(queued.event)
+	}
+}
@@ -0,0 +1,128 @@
+use std::{fs::File, io::{BufWriter, Write}, path::Path, time::{Duration, Instant}};
+
+/// Identifies a file with recorded input, including the version of the format.
+const MAGIC: &[u8; 8] = b"MSINPUT1";
+
+/// Size of the header of a recorded event: the time since the start of the recording and the length of the event.
+const EVENT_HEADER_SIZE: usize = std::mem::size_of::<u64>() + std::mem::size_of::<u32>();
+
+/// Writes the raw input events of the client to a file, so that they can be replayed with `moonshine replay-input`.
+///
+/// Every event is stored as the time since the start of the recording in microseconds (u64),
+/// the length of the event (u32) and the event as it was received, all little endian.
+pub struct InputRecorder {
+	writer: BufWriter<File>,
+	start: Instant,
+}
+
+impl InputRecorder {
+	pub fn new(path: &Path) -> Result<Self, ()> {
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)
+				.map_err(|e| tracing::error!("Failed to create input recording directory {parent:?}: {e}"))?;
+		}
+
+		let file = File::create(path)
+			.map_err(|e| tracing::error!("Failed to create input recording {path:?}: {e}"))?;
+		let mut writer = BufWriter::new(file);
+		writer.write_all(MAGIC)
+			.map_err(|e| tracing::error!("Failed to write input recording {path:?}: {e}"))?;
+
+		tracing::info!("Recording input of the client to {path:?}.");
+		Ok(Self { writer, start: Instant::now() })
+	}
+
+	pub fn record(&mut self, event: &[u8]) {
+		let offset = self.start.elapsed().as_micros() as u64;
+		let result = self.writer.write_all(&offset.to_le_bytes())
+			.and_then(|()| self.writer.write_all(&(event.len() as u32).to_le_bytes()))
+			.and_then(|()| self.writer.write_all(event));
+
+		if let Err(e) = result {
+			tracing::warn!("Failed to record input event: {e}");
+		}
+	}
+}
+
+/// An input event read from a recording.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RecordedInput {
+	/// Time since the start of the recording at which the event was received.
+	pub offset: Duration,
+
+	/// The event as it was received from the client.
+	pub event: Vec<u8>,
+}
+
+/// Read all events from an input recording.
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedInput>, ()> {
+	let data = std::fs::read(path)
+		.map_err(|e| tracing::error!("Failed to read input recording {path:?}: {e}"))?;
+	parse_recording(&data)
+}
+
+fn parse_recording(data: &[u8]) -> Result<Vec<RecordedInput>, ()> {
+	let mut data = data.strip_prefix(MAGIC.as_slice())
+		.ok_or_else(|| tracing::error!("File is not an input recording."))?;
+
+	let mut events = Vec::new();
+	while !data.is_empty() {
+		// A recording that was interrupted can end in the middle of an event, keep the events before it.
+		if data.len() < EVENT_HEADER_SIZE {
+			tracing::warn!("Input recording ends in the middle of an event, ignoring the last {} bytes.", data.len());
+			break;
+		}
+
+		let offset = u64::from_le_bytes(data[..8].try_into().unwrap());
+		let length = u32::from_le_bytes(data[8..EVENT_HEADER_SIZE].try_into().unwrap()) as usize;
+		data = &data[EVENT_HEADER_SIZE..];
+		if data.len() < length {
+			tracing::warn!("Input recording ends in the middle of an event, ignoring the last {} bytes.", data.len() + EVENT_HEADER_SIZE);
+			break;
+		}
+
+		events.push(RecordedInput { offset: Duration::from_micros(offset), event: data[..length].to_vec() });
+		data = &data[length..];
+	}
+
+	Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Create a recording with the given events, in the format written by `InputRecorder`.
+	fn recording(events: &[(u64, &[u8])]) -> Vec<u8> {
+		let mut data = MAGIC.to_vec();
+		for (offset, event) in events {
+			data.extend_from_slice(&offset.to_le_bytes());
+			data.extend_from_slice(&(event.len() as u32).to_le_bytes());
+			data.extend_from_slice(event);
+		}
+
+		data
+	}
+
+	#[test]
+	fn events_are_read_back() {
+		let data = recording(&[(0, &[1, 2, 3]), (1500, &[]), (20_000, &[4; 20])]);
+		assert_eq!(parse_recording(&data), Ok(vec![
+			RecordedInput { offset: Duration::ZERO, event: vec![1, 2, 3] },
+			RecordedInput { offset: Duration::from_micros(1500), event: vec![] },
+			RecordedInput { offset: Duration::from_millis(20), event: vec![4; 20] },
+		]));
+	}
+
+	#[test]
+	fn truncated_event_is_ignored() {
+		let mut data = recording(&[(0, &[1, 2, 3]), (10, &[4, 5, 6])]);
+		data.pop();
+		assert_eq!(parse_recording(&data), Ok(vec![RecordedInput { offset: Duration::ZERO, event: vec![1, 2, 3] }]));
+	}
+
+	#[test]
+	fn other_files_are_rejected() {
+		assert_eq!(parse_recording(b"not a recording"), Err(()));
+	}
+}
@@ -1,29 +1,34 @@
+use std::path::PathBuf;
+
 use async_shutdown::ShutdownManager;
-use enet::{
-	Address,
-	BandwidthLimit,
-	ChannelLimit,
-	Enet,
-	Event,
-};
+use enet::Enet;
 use openssl::symm::Cipher;
-use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+use crate::{session::{shutdown::stop_session_after, Milestone, SessionShutdownReason, SessionTimings, SharedSessionKeys}, config::Config};
+use self::{connection::Connection, feedback::{feedback_queue, FeedbackPriority, FeedbackReceiver}, input::{shortcut, InputHandler, InputRecorder, MotionSensor, Shortcut}};
+use super::{nonce::{control_iv, ControlSequence, ReplayWindow, CONTROL_IV_COUNT}, VideoStream, AudioStream, StreamPause};
 
-use crate::{session::{SessionContext, SessionKeys}, config::Config};
-use self::input::InputHandler;
-use super::{VideoStream, AudioStream};
+pub use self::input::{fuzz_input_event, probe_input, replay_input};
 
+mod connection;
+mod feedback;
 mod input;
 
 const ENCRYPTION_TAG_LENGTH: usize = 16;
 // Sequence number + tag + control message id
 const MINIMUM_ENCRYPTED_LENGTH: usize = 4 + ENCRYPTION_TAG_LENGTH + 4;
 
+/// Rate (in Hz) at which we ask the client to report motion events of gamepads.
+const MOTION_REPORT_RATE: u16 = 100;
+
 #[repr(u16)]
 enum ControlMessageType {
 	Encrypted = 0x0001,
 	Ping = 0x0200,
-	Termination = 0x0100,
+	/// Type of termination messages of the encrypted control stream, the unencrypted control stream uses 0x0100.
+	Termination = 0x0109,
 	RumbleData = 0x010b,
 	LossStats = 0x0201,
 	FrameStats = 0x0204,
@@ -32,6 +37,7 @@ enum ControlMessageType {
 	RequestIdrFrame = 0x0302,
 	StartA = 0x0305,
 	StartB = 0x0307,
+	EnableMotion = 0x5501,
 }
 
 impl TryFrom<u16> for ControlMessageType {
@@ -61,10 +67,10 @@ enum ControlMessage<'a> {
 	Ping,
 	Termination,
 	RumbleData,
-	LossStats,
+	LossStats(&'a [u8]),
 	FrameStats,
 	InputData(&'a [u8]),
-	InvalidateReferenceFrames,
+	InvalidateReferenceFrames(&'a [u8]),
 	RequestIdrFrame,
 	StartA,
 	StartB,
@@ -108,9 +114,14 @@ impl<'a> ControlMessage<'a> {
 			ControlMessageType::Ping => Ok(Self::Ping),
 			ControlMessageType::Termination => Ok(Self::Termination),
 			ControlMessageType::RumbleData => Ok(Self::RumbleData),
-			ControlMessageType::LossStats => Ok(Self::LossStats),
+			ControlMessageType::LossStats => Ok(Self::LossStats(&buffer[4..])),
 			ControlMessageType::FrameStats => Ok(Self::FrameStats),
 			ControlMessageType::InputData => {
+				if buffer.len() < 8 {
+					tracing::info!("Expected input event message of at least 8 bytes, got {} bytes.", buffer.len());
+					return Err(());
+				}
+
 				// Length of the input event, excluding the length itself.
 				let length = u32::from_be_bytes(buffer[4..8].try_into().unwrap());
 				if length as usize != buffer.len() - 8 {
@@ -120,7 +131,7 @@ impl<'a> ControlMessage<'a> {
 
 				Ok(Self::InputData(&buffer[8..]))
 			},
-			ControlMessageType::InvalidateReferenceFrames => Ok(Self::InvalidateReferenceFrames),
+			ControlMessageType::InvalidateReferenceFrames => Ok(Self::InvalidateReferenceFrames(&buffer[4..])),
 			ControlMessageType::RequestIdrFrame => Ok(Self::RequestIdrFrame),
 			ControlMessageType::StartA => Ok(Self::StartA),
 			ControlMessageType::StartB => Ok(Self::StartB),
@@ -128,6 +139,58 @@ impl<'a> ControlMessage<'a> {
 	}
 }
 
+/// Messages that are sent from the host to the client.
+#[derive(Debug)]
+pub enum HostMessage {
+	/// Ask the client to send events for a motion sensor of a gamepad.
+	EnableMotion {
+		gamepad: u16,
+		sensor: MotionSensor,
+	},
+
+	/// Tell the client that the session stopped, and why.
+	Termination {
+		code: u32,
+	},
+}
+
+impl HostMessage {
+	/// Priority of the message in the feedback queue.
+	fn priority(&self) -> FeedbackPriority {
+		match self {
+			Self::EnableMotion { .. } => FeedbackPriority::Normal,
+			Self::Termination { .. } => FeedbackPriority::High,
+		}
+	}
+
+	/// Message type, gamepad and sensor that the message applies to, a queued message is replaced by a newer one for the same target.
+	fn coalesce_key(&self) -> Option<(u16, u16, u8)> {
+		match self {
+			Self::EnableMotion { gamepad, sensor } => Some((ControlMessageType::EnableMotion as u16, *gamepad, *sensor as u8)),
+			Self::Termination { .. } => None,
+		}
+	}
+
+	fn to_bytes(&self) -> Vec<u8> {
+		let (message_type, payload) = match self {
+			Self::EnableMotion { gamepad, sensor } => {
+				let mut payload = Vec::with_capacity(5);
+				payload.extend(gamepad.to_le_bytes());
+				payload.extend(MOTION_REPORT_RATE.to_le_bytes());
+				payload.push(*sensor as u8);
+				(ControlMessageType::EnableMotion, payload)
+			},
+			Self::Termination { code } => (ControlMessageType::Termination, code.to_be_bytes().to_vec()),
+		};
+
+		let mut buffer = Vec::with_capacity(4 + payload.len());
+		buffer.extend((message_type as u16).to_le_bytes());
+		buffer.extend((payload.len() as u16).to_le_bytes());
+		buffer.extend(payload);
+		buffer
+	}
+}
+
 #[derive(Debug)]
 struct EncryptedControlMessage {
 	_length: u16,
@@ -136,50 +199,48 @@ struct EncryptedControlMessage {
 	payload: Vec<u8>,
 }
 
-enum ControlStreamCommand {
-	UpdateKeys(SessionKeys),
-}
-
 pub struct ControlStream {
-	command_tx: mpsc::Sender<ControlStreamCommand>,
+	/// Stops the control stream when dropped.
+	_stop_tx: mpsc::Sender<()>,
 }
 
 impl ControlStream {
-	#[allow(clippy::result_unit_err)]
+	#[allow(clippy::result_unit_err, clippy::too_many_arguments)]
 	pub fn new(
 		config: Config,
 		video_stream: VideoStream,
 		audio_stream: AudioStream,
-		context: SessionContext,
+		keys: SharedSessionKeys,
+		timings: SessionTimings,
 		enet: Enet,
-		stop_signal: ShutdownManager<()>,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
+		input_recording: Option<PathBuf>,
+		pause: StreamPause,
 	) -> Result<Self, ()> {
-		let input_handler = InputHandler::new()?;
+		let (host_message_tx, host_message_rx) = feedback_queue();
+		let input_handler = InputHandler::new(video_stream.captured_area(), host_message_tx)?;
+		let input_recorder = input_recording.and_then(|path| InputRecorder::new(&path).ok());
 
-		let (command_tx, command_rx) = mpsc::channel(10);
+		let (stop_tx, stop_rx) = mpsc::channel(1);
 		let inner = ControlStreamInner { };
-		tokio::task::spawn_blocking({
-			move || {
-				tokio::runtime::Handle::current().block_on(
-					stop_signal.wrap_cancel(stop_signal.wrap_trigger_shutdown((), inner.run(
-						config,
-						command_rx,
-						video_stream,
-						audio_stream,
-						context,
-						enet,
-						input_handler,
-					)))
-				)
-			}
-		});
-
-		Ok(Self { command_tx })
-	}
-
-	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
-		self.command_tx.send(ControlStreamCommand::UpdateKeys(keys)).await
-			.map_err(|e| tracing::error!("Failed to send UpdateKeys command: {e}"))
+		let span = tracing::info_span!("control_stream");
+		// The control stream isn't cancelled when the session stops, so that it can tell the client why it stopped.
+		tokio::spawn(stop_session_after(&stop_signal, SessionShutdownReason::ControlStreamFailed, inner.run(
+			config,
+			stop_rx,
+			video_stream,
+			audio_stream,
+			keys,
+			timings,
+			enet,
+			input_handler,
+			input_recorder,
+			host_message_rx,
+			pause,
+			stop_signal.clone(),
+		)).instrument(span));
+
+		Ok(Self { _stop_tx: stop_tx })
 	}
 }
 
@@ -189,125 +250,360 @@ struct ControlStreamInner {
 impl ControlStreamInner {
 	#[allow(clippy::too_many_arguments)] // TODO: Problem for later..
 	pub async fn run(
-		&self,
+		self,
 		config: Config,
-		mut command_rx: mpsc::Receiver<ControlStreamCommand>,
+		mut stop_rx: mpsc::Receiver<()>,
 		video_stream: VideoStream,
 		audio_stream: AudioStream,
-		mut context: SessionContext,
+		keys: SharedSessionKeys,
+		timings: SessionTimings,
 		enet: Enet,
 		input_handler: InputHandler,
+		mut input_recorder: Option<InputRecorder>,
+		mut host_message_rx: FeedbackReceiver,
+		pause: StreamPause,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
 	) -> Result<(), ()> {
-		let local_addr = Address::new(
-			config.address.parse()
-				.map_err(|e| tracing::error!("Failed to parse address: {e}"))?,
-			config.stream.control.port,
-		);
-		let mut host = enet
-			.create_host::<()>(
-				Some(&local_addr),
-				10,
-				ChannelLimit::Maximum,
-				BandwidthLimit::Unlimited,
-				BandwidthLimit::Unlimited,
-			)
-			.map_err(|e| tracing::error!("Failed to create Enet host: {e}"))?;
-
-		tracing::debug!("Listening for control messages on {:?}", host.address());
+		let address = config.address.parse()
+			.map_err(|e| tracing::error!("Failed to parse address: {e}"))?;
+		let (incoming_tx, mut incoming_rx) = mpsc::channel(100);
+		let connection = Connection::new(enet, address, config.stream.control.port, config.stream.scheduling.clone(), incoming_tx).await?;
 
-		let mut stop_deadline = std::time::Instant::now() + std::time::Duration::from_secs(config.stream_timeout);
+		let mut stop_deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(config.stream_timeout);
+		let idle_timeout = config.idle.timeout.map(|minutes| std::time::Duration::from_secs(minutes * 60));
+		let mut idle_deadline = idle_timeout.map(|idle_timeout| tokio::time::Instant::now() + idle_timeout);
+		let mut sequence = ControlSequence::default();
+		let mut replay_window = ReplayWindow::default();
 
 		loop {
-			// Check if we received a command.
-			let command = command_rx.try_recv();
-			match command {
-				Ok(command) => {
-					match command {
-						ControlStreamCommand::UpdateKeys(keys) => {
-							tracing::debug!("Updating session keys.");
-							context.keys = keys;
-						},
-					}
+			let packet = tokio::select! {
+				_ = stop_rx.recv() => {
+					tracing::debug!("Control stream dropped.");
+					break;
 				},
-				Err(TryRecvError::Disconnected) => {
-					tracing::debug!("Command channel closed.");
+				reason = stop_signal.wait_shutdown_triggered() => {
+					let message = HostMessage::Termination { code: reason.termination_code() };
+					tracing::debug!("Session stopped because {reason}, sending message to client: {message:?}");
+					let _ = send_host_message(&connection, &message, &keys, &mut sequence);
 					break;
 				},
-				Err(TryRecvError::Empty) => { },
-			}
-
-			// Check if the timeout has passed.
-			if std::time::Instant::now() > stop_deadline {
-				tracing::info!("Stopping because we haven't received a ping for {} seconds.", config.stream_timeout);
-				break;
+				_ = tokio::time::sleep_until(stop_deadline) => {
+					tracing::info!("Stopping because we haven't received a ping for {} seconds.", config.stream_timeout);
+					let _ = stop_signal.trigger_shutdown(SessionShutdownReason::ClientTimeout);
+					break;
+				},
+				// The deadline is only used if the idle timeout is enabled, `stop_deadline` is just a placeholder.
+				_ = tokio::time::sleep_until(idle_deadline.unwrap_or(stop_deadline)), if idle_deadline.is_some() => {
+					tracing::info!("Stopping because we haven't received input for {} minute(s).", config.idle.timeout.unwrap_or_default());
+					// Stopping the session sends the termination message to the client.
+					let _ = stop_signal.trigger_shutdown(SessionShutdownReason::Idle);
+					idle_deadline = None;
+					continue;
+				},
+				Some(message) = host_message_rx.recv() => {
+					tracing::debug!("Sending message to client: {message:?}");
+					let _ = send_host_message(&connection, &message, &keys, &mut sequence);
+					continue;
+				},
+				packet = incoming_rx.recv() => match packet {
+					Some(packet) => packet,
+					None => {
+						tracing::debug!("Control stream connection closed.");
+						break;
+					},
+				},
+			};
+
+			let Some(mut control_message) = parse_control_message(&packet) else {
+				continue;
+			};
+			tracing::trace!("Received control message: {control_message:?}");
+
+			// First check for encrypted control messages and decrypt them.
+			let decrypted;
+			if let ControlMessage::Encrypted(message) = control_message {
+				decrypted = match decrypt_control_message(&message, &keys) {
+					Ok((epoch, decrypted)) => {
+						// Only authenticated messages update the window, so forged messages can't push out real ones.
+						if !replay_window.accept(epoch, message.sequence_number) {
+							tracing::warn!("Dropping replayed control message with sequence number {}.", message.sequence_number);
+							continue;
+						}
+
+						decrypted
+					},
+					Err(()) => continue,
+				};
+
+				control_message = match parse_control_message(&decrypted) {
+					Some(decrypted_message) => decrypted_message,
+					None => continue,
+				};
+
+				tracing::trace!("Decrypted control message: {control_message:?}");
 			}
 
-			match host.service(1000).map_err(|e| tracing::error!("Failure in enet host: {e}"))? {
-				Some(Event::Connect(_)) => {},
-				Some(Event::Disconnect(..)) => {},
-				Some(Event::Receive {
-					ref packet,
-					..
-				}) => {
-					let mut control_message = ControlMessage::from_bytes(packet.data())?;
-					tracing::trace!("Received control message: {control_message:?}");
-
-					// First check for encrypted control messages and decrypt them.
-					let decrypted;
-					if let ControlMessage::Encrypted(message) = control_message {
-						let mut initialization_vector = [0u8; 16];
-						initialization_vector[0] = message.sequence_number as u8;
-
-						let decrypted_result = openssl::symm::decrypt_aead(
-							Cipher::aes_128_gcm(),
-							&context.keys.remote_input_key,
-							Some(&initialization_vector),
-							&[],
-							&message.payload,
-							&message.tag,
-						);
-
-						decrypted = match decrypted_result {
-							Ok(decrypted) => decrypted,
-							Err(e) => {
-								tracing::error!("Failed to decrypt control message: {:?}", e.errors());
-								continue;
-							}
-						};
-
-						control_message = match ControlMessage::from_bytes(&decrypted) {
-							Ok(decrypted_message) => decrypted_message,
-							Err(()) => continue,
-						};
-
-						tracing::trace!("Decrypted control message: {control_message:?}");
+			match control_message {
+				ControlMessage::Encrypted(_) => unreachable!("Encrypted control messages should be decrypted already."),
+				ControlMessage::RequestIdrFrame => {
+					video_stream.request_idr_frame().await?;
+				},
+				ControlMessage::InvalidateReferenceFrames(payload) => {
+					match invalidated_frames(payload) {
+						Some((lost_frames, last_frame)) => video_stream.invalidate_reference_frames(lost_frames, last_frame).await?,
+						None => video_stream.request_idr_frame().await?,
 					}
-
-					match control_message {
-						ControlMessage::Encrypted(_) => unreachable!("Encrypted control messages should be decrypted already."),
-						ControlMessage::RequestIdrFrame | ControlMessage::InvalidateReferenceFrames => {
-							video_stream.request_idr_frame().await?;
-						},
-						ControlMessage::StartB => {
-							audio_stream.start(context.keys.clone()).await?;
-							video_stream.start().await?;
-						},
-						ControlMessage::Ping => {
-							stop_deadline = std::time::Instant::now() + std::time::Duration::from_secs(config.stream_timeout);
-						},
-						ControlMessage::InputData(event) => {
-							let _ = input_handler.handle_raw_input(event).await;
-						},
-						skipped_message => {
-							tracing::trace!("Skipped control message: {skipped_message:?}");
-						},
-					};
-				}
-				_ => (),
-			}
+				},
+				ControlMessage::LossStats(payload) => {
+					if let Some((lost_frames, last_good_frame)) = loss_statistics(payload) {
+						video_stream.report_loss(lost_frames, last_good_frame).await?;
+					}
+				},
+				ControlMessage::StartB => {
+					audio_stream.start(keys.clone()).await?;
+					video_stream.start(Some(keys.clone())).await?;
+				},
+				ControlMessage::Ping => {
+					timings.record(Milestone::FirstControlPing);
+					stop_deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(config.stream_timeout);
+				},
+				ControlMessage::InputData(event) => {
+					idle_deadline = idle_timeout.map(|idle_timeout| tokio::time::Instant::now() + idle_timeout);
+					if let Some(input_recorder) = &mut input_recorder {
+						input_recorder.record(event);
+					}
+					match shortcut(event) {
+						Some((Shortcut::ToggleOverlay, true)) => video_stream.toggle_overlay().await?,
+						Some((Shortcut::TogglePause, true)) => { pause.set(!pause.is_paused()); },
+						Some((_, false)) => {},
+						None => { let _ = input_handler.handle_raw_input(event).await; },
+					}
+				},
+				skipped_message => {
+					tracing::trace!("Skipped control message: {skipped_message:?}");
+				},
+			};
 		}
 
 		tracing::debug!("Control stream closing.");
 		Ok(())
 	}
 }
+
+/// Parse a packet that the client sent on the control stream.
+///
+/// Packets that can't be parsed, like messages that newer clients send but aren't known yet, are skipped instead of stopping the stream.
+fn parse_control_message(packet: &[u8]) -> Option<ControlMessage<'_>> {
+	match ControlMessage::from_bytes(packet) {
+		Ok(message) => Some(message),
+		Err(()) => {
+			tracing::debug!("Skipping control message of {} bytes that could not be parsed.", packet.len());
+			None
+		},
+	}
+}
+
+/// Parse data as if a client sent it on the control stream, for the fuzz targets.
+pub fn fuzz_control_message(data: &[u8]) {
+	let _ = parse_control_message(data);
+}
+
+/// Number of frames that the client lost since its previous loss statistics, and the last frame it received in full.
+///
+/// The statistics start with the number of lost frames, followed by the interval at which they are sent,
+/// the interval they are measured over and the last good frame.
+fn loss_statistics(payload: &[u8]) -> Option<(u32, u64)> {
+	let lost_frames = i32::from_le_bytes(payload.get(..4)?.try_into().ok()?);
+	let last_good_frame = u64::from_le_bytes(payload.get(12..20)?.try_into().ok()?);
+	Some((u32::try_from(lost_frames).ok()?, last_good_frame))
+}
+
+/// Number of frames that the client asks to invalidate, because it lost (part of) them, and the last of those frames.
+fn invalidated_frames(payload: &[u8]) -> Option<(u32, u64)> {
+	let first_frame = u64::from_le_bytes(payload.get(..8)?.try_into().ok()?);
+	let last_frame = u64::from_le_bytes(payload.get(8..16)?.try_into().ok()?);
+	let count = last_frame.checked_sub(first_frame)?.saturating_add(1);
+	Some((count.min(u32::MAX as u64) as u32, last_frame))
+}
+
+/// Encrypt a message for the client and send it to the connected peer.
+fn send_host_message(
+	connection: &Connection,
+	message: &HostMessage,
+	keys: &SharedSessionKeys,
+	sequence: &mut ControlSequence,
+) -> Result<(), ()> {
+	let (epoch, current_keys) = keys.current()?;
+
+	// Encrypting more messages would reuse an initialization vector, which breaks AES-GCM.
+	let sequence_number = sequence.next(epoch)
+		.ok_or_else(|| tracing::error!("Sent {CONTROL_IV_COUNT} control messages with the keys of epoch {epoch}, dropping message until the keys are updated."))?;
+	let initialization_vector = control_iv(sequence_number);
+
+	let mut tag = [0u8; ENCRYPTION_TAG_LENGTH];
+	let encrypted = openssl::symm::encrypt_aead(
+		Cipher::aes_128_gcm(),
+		&current_keys.remote_input_key,
+		Some(&initialization_vector),
+		&[],
+		&message.to_bytes(),
+		&mut tag,
+	)
+		.map_err(|e| tracing::error!("Failed to encrypt control message: {e}"))?;
+
+	// Sequence number + tag + encrypted message.
+	let length = 4 + ENCRYPTION_TAG_LENGTH + encrypted.len();
+	let mut buffer = Vec::with_capacity(4 + length);
+	buffer.extend((ControlMessageType::Encrypted as u16).to_le_bytes());
+	buffer.extend((length as u16).to_le_bytes());
+	buffer.extend(sequence_number.to_le_bytes());
+	buffer.extend(tag);
+	buffer.extend(encrypted);
+
+	connection.send(buffer)
+}
+
+/// Decrypt a control message with the keys of the current epoch.
+///
+/// While the keys are being updated the client might still send messages encrypted with the previous keys,
+/// so those are tried as well. Returns the epoch of the keys that decrypted the message, along with the message.
+fn decrypt_control_message(message: &EncryptedControlMessage, keys: &SharedSessionKeys) -> Result<(u32, Vec<u8>), ()> {
+	let initialization_vector = control_iv(message.sequence_number);
+
+	let mut errors = Vec::new();
+	for (epoch, keys) in keys.decryption_keys()? {
+		let decrypted_result = openssl::symm::decrypt_aead(
+			Cipher::aes_128_gcm(),
+			&keys.remote_input_key,
+			Some(&initialization_vector),
+			&[],
+			&message.payload,
+			&message.tag,
+		);
+
+		match decrypted_result {
+			Ok(decrypted) => return Ok((epoch, decrypted)),
+			Err(e) => errors.push((epoch, e)),
+		}
+	}
+
+	for (epoch, e) in errors {
+		tracing::error!("Failed to decrypt control message with keys of epoch {epoch}: {:?}", e.errors());
+	}
+
+	Err(())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::testing::Rng;
+
+	use super::*;
+
+	/// Prefix a payload with the type and length of a control message.
+	fn message(message_type: ControlMessageType, payload: &[u8]) -> Vec<u8> {
+		[&(message_type as u16).to_le_bytes()[..], &(payload.len() as u16).to_le_bytes(), payload].concat()
+	}
+
+	#[test]
+	fn input_data_is_parsed() {
+		let buffer = message(ControlMessageType::InputData, &[0, 0, 0, 3, 1, 2, 3]);
+		assert!(matches!(ControlMessage::from_bytes(&buffer), Ok(ControlMessage::InputData(&[1, 2, 3]))));
+	}
+
+	#[test]
+	fn input_data_without_length_is_rejected() {
+		for length in 0..4 {
+			let buffer = message(ControlMessageType::InputData, &vec![0; length]);
+			assert!(ControlMessage::from_bytes(&buffer).is_err());
+		}
+	}
+
+	#[test]
+	fn input_data_with_wrong_length_is_rejected() {
+		let buffer = message(ControlMessageType::InputData, &[0, 0, 0, 4, 1, 2, 3]);
+		assert!(ControlMessage::from_bytes(&buffer).is_err());
+	}
+
+	#[test]
+	fn encrypted_message_is_parsed() {
+		let mut payload = 7u32.to_le_bytes().to_vec();
+		payload.extend([0xAA; ENCRYPTION_TAG_LENGTH]);
+		payload.extend([1, 2, 3, 4]);
+
+		let buffer = message(ControlMessageType::Encrypted, &payload);
+		let Ok(ControlMessage::Encrypted(encrypted)) = ControlMessage::from_bytes(&buffer) else {
+			panic!("failed to parse encrypted message");
+		};
+		assert_eq!(encrypted.sequence_number, 7);
+		assert_eq!(encrypted.tag, [0xAA; ENCRYPTION_TAG_LENGTH]);
+		assert_eq!(encrypted.payload, [1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn short_encrypted_message_is_rejected() {
+		for length in 0..MINIMUM_ENCRYPTED_LENGTH - 4 {
+			let buffer = message(ControlMessageType::Encrypted, &vec![0; length]);
+			assert!(ControlMessage::from_bytes(&buffer).is_err());
+		}
+	}
+
+	#[test]
+	fn unknown_messages_are_skipped() {
+		let unknown = [&0xFFFFu16.to_le_bytes()[..], &0u16.to_le_bytes()].concat();
+		let malformed = message(ControlMessageType::InputData, &[0, 0]);
+		let ping = message(ControlMessageType::Ping, &[]);
+
+		// The control stream continues with the next packet when a packet can't be parsed.
+		let parsed: Vec<_> = [&unknown, &malformed, &ping].into_iter().filter_map(|packet| parse_control_message(packet)).collect();
+		assert!(matches!(parsed[..], [ControlMessage::Ping]));
+	}
+
+	#[test]
+	fn loss_is_counted() {
+		let statistics = |lost_frames: i32, last_good_frame: u64| {
+			[&lost_frames.to_le_bytes()[..], &50u32.to_le_bytes(), &1000u32.to_le_bytes(), &last_good_frame.to_le_bytes()].concat()
+		};
+		assert_eq!(loss_statistics(&statistics(3, 120)), Some((3, 120)));
+		assert_eq!(loss_statistics(&statistics(-1, 120)), None);
+		assert_eq!(loss_statistics(&statistics(3, 120)[..16]), None);
+
+		let range = |first: u64, last: u64| [first.to_le_bytes(), last.to_le_bytes(), [0; 8]].concat();
+		assert_eq!(invalidated_frames(&range(10, 12)), Some((3, 12)));
+		assert_eq!(invalidated_frames(&range(12, 10)), None);
+		assert_eq!(invalidated_frames(&range(0, u64::MAX)), Some((u32::MAX, u64::MAX)));
+		assert_eq!(invalidated_frames(&range(10, 12)[..12]), None);
+	}
+
+	/// Parse messages with a known type and random contents, which must never panic.
+	#[test]
+	fn random_messages_dont_panic() {
+		let message_types = [
+			ControlMessageType::Encrypted,
+			ControlMessageType::Ping,
+			ControlMessageType::Termination,
+			ControlMessageType::InputData,
+			ControlMessageType::RequestIdrFrame,
+			ControlMessageType::StartB,
+		].map(|message_type| message_type as u16);
+
+		let mut rng = Rng(0x5EED_1234_ABCD_0003);
+		for _ in 0..100_000 {
+			let message_type = message_types[rng.range(0, message_types.len())];
+			let payload = rng.bytes(64);
+
+			// Mostly use the correct length, otherwise the message is rejected before looking at the payload.
+			let length = match rng.range(0, 4) {
+				0 => rng.next() as u16,
+				_ => payload.len() as u16,
+			};
+
+			let buffer = [&message_type.to_le_bytes()[..], &length.to_le_bytes(), &payload].concat();
+			let _ = ControlMessage::from_bytes(&buffer);
+		}
+
+		for _ in 0..10_000 {
+			let _ = ControlMessage::from_bytes(&rng.bytes(64));
+		}
+	}
+}
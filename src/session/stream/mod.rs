@@ -1,11 +1,17 @@
 pub use self::{
-	audio::{AudioStreamContext, AudioStream},
-	video::{VideoStreamContext, VideoStream},
-	control::ControlStream,
+	audio::{AudioStreamContext, AudioStream, OpusStreamConfig},
+	video::{find_gpu, gpu_name, open_gpu, probe_capture, EncoderCapabilities, VideoStreamContext, VideoStream},
+	control::{fuzz_control_message, fuzz_input_event, probe_input, replay_input, ControlStream},
+	pause::StreamPause,
+	socket::{bind_stream_sockets, ping_payload, StreamSocket, AUDIO_STREAM, VIDEO_STREAM},
 };
 
 mod audio;
 mod control;
+mod nonce;
+mod pause;
+mod scheduling;
+mod socket;
 mod video;
 
 #[derive(Debug)]
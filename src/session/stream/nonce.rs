@@ -0,0 +1,274 @@
+/// Length of the initialization vectors of audio packets and control messages.
+pub const IV_LENGTH: usize = 16;
+
+/// Length of the initialization vectors of encrypted video packets.
+pub const VIDEO_IV_LENGTH: usize = 12;
+
+/// Number of unique initialization vectors for control messages under the same keys,
+/// since only the lowest byte of the sequence number ends up in the initialization vector.
+pub const CONTROL_IV_COUNT: u32 = 256;
+
+/// Number of sequence numbers below the highest one that are remembered to detect replayed control messages.
+const REPLAY_WINDOW_SIZE: u32 = u64::BITS;
+
+/// Initialization vector of an audio packet: the key id plus the RTP sequence number (big endian), followed by zeros.
+///
+/// Moonlight computes this in 32 bits, so the sum wraps around instead of overflowing.
+pub fn audio_iv(key_id: i64, sequence_number: u16) -> [u8; IV_LENGTH] {
+	let mut iv = [0u8; IV_LENGTH];
+	iv[..4].copy_from_slice(&(key_id as u32).wrapping_add(sequence_number as u32).to_be_bytes());
+	iv
+}
+
+/// Initialization vector of an encrypted control message.
+///
+/// Only the lowest byte of the sequence number is used, this is how GeForce Experience does it and Moonlight expects it.
+pub fn control_iv(sequence_number: u32) -> [u8; IV_LENGTH] {
+	let mut iv = [0u8; IV_LENGTH];
+	iv[0] = sequence_number as u8;
+	iv
+}
+
+/// Initialization vector of an encrypted video packet: a counter (little endian) that continues over frames, ending with 'V'.
+///
+/// The counter is never reset during a session, so that no initialization vector is used twice under the same keys.
+/// The client reads the initialization vector from the packet, the trailing 'V' only keeps it apart from other streams.
+pub fn video_iv(counter: u64) -> [u8; VIDEO_IV_LENGTH] {
+	let mut iv = [0u8; VIDEO_IV_LENGTH];
+	iv[..8].copy_from_slice(&counter.to_le_bytes());
+	iv[VIDEO_IV_LENGTH - 1] = b'V';
+	iv
+}
+
+/// Hands out sequence numbers for control messages to the client, without reusing an initialization vector under the same keys.
+///
+/// Once all initialization vectors of an epoch are used, no more messages can be encrypted until the keys are updated.
+#[derive(Debug, Default)]
+pub struct ControlSequence {
+	/// Epoch of the keys that the sequence numbers are handed out for.
+	epoch: Option<u32>,
+
+	/// Next sequence number in this epoch.
+	next: u32,
+}
+
+impl ControlSequence {
+	/// Get the sequence number for the next message encrypted with the keys of `epoch`.
+	pub fn next(&mut self, epoch: u32) -> Option<u32> {
+		if self.epoch != Some(epoch) {
+			// New keys, so every initialization vector can be used again.
+			self.epoch = Some(epoch);
+			self.next = 0;
+		}
+
+		if self.next >= CONTROL_IV_COUNT {
+			return None;
+		}
+
+		let sequence_number = self.next;
+		self.next += 1;
+		Some(sequence_number)
+	}
+}
+
+/// Detects control messages from the client that are received more than once.
+///
+/// Sequence numbers are tracked per key epoch, for the current and the previous epoch,
+/// since the client might still send messages with the previous keys while the keys are updated.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+	/// Windows of the most recent epochs, oldest first.
+	epochs: Vec<(u32, SequenceWindow)>,
+}
+
+impl ReplayWindow {
+	/// Check a sequence number of a message that was authenticated with the keys of `epoch`,
+	/// returns false if the message was seen before or is too old to tell.
+	pub fn accept(&mut self, epoch: u32, sequence_number: u32) -> bool {
+		if !self.epochs.iter().any(|(window_epoch, _)| *window_epoch == epoch) {
+			self.epochs.push((epoch, SequenceWindow::default()));
+			if self.epochs.len() > 2 {
+				self.epochs.remove(0);
+			}
+		}
+
+		self.epochs.iter_mut()
+			.find(|(window_epoch, _)| *window_epoch == epoch)
+			.map(|(_, window)| window.accept(sequence_number))
+			.unwrap_or(false)
+	}
+}
+
+/// Sliding window over the sequence numbers of a single epoch.
+#[derive(Debug, Default)]
+struct SequenceWindow {
+	/// Highest sequence number that was accepted.
+	highest: Option<u32>,
+
+	/// Bit `n` is set if sequence number `highest - n` was accepted.
+	seen: u64,
+}
+
+impl SequenceWindow {
+	fn accept(&mut self, sequence_number: u32) -> bool {
+		let Some(highest) = self.highest else {
+			self.highest = Some(sequence_number);
+			self.seen = 1;
+			return true;
+		};
+
+		if sequence_number > highest {
+			let shift = sequence_number - highest;
+			self.seen = if shift >= REPLAY_WINDOW_SIZE { 0 } else { self.seen << shift };
+			self.seen |= 1;
+			self.highest = Some(sequence_number);
+			return true;
+		}
+
+		let age = highest - sequence_number;
+		if age >= REPLAY_WINDOW_SIZE {
+			return false;
+		}
+
+		let bit = 1u64 << age;
+		if self.seen & bit != 0 {
+			return false;
+		}
+
+		self.seen |= bit;
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use openssl::symm::{decrypt, decrypt_aead, encrypt_aead, Cipher};
+
+	use super::*;
+
+	/// Key used for the vectors below.
+	const KEY: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+	#[test]
+	fn audio_iv_adds_sequence_number_to_key_id() {
+		let iv = audio_iv(0x12345678, 0x0102);
+		assert_eq!(iv[..4], [0x12, 0x34, 0x57, 0x7a]);
+		assert!(iv[4..].iter().all(|&byte| byte == 0));
+	}
+
+	#[test]
+	fn audio_iv_wraps_around() {
+		assert_eq!(audio_iv(0xffffffff, 2)[..4], [0, 0, 0, 1]);
+		assert_eq!(audio_iv(-1, 0)[..4], [0xff, 0xff, 0xff, 0xff]);
+		assert_eq!(audio_iv(0x1_0000_0005, 0)[..4], [0, 0, 0, 5]);
+	}
+
+	#[test]
+	fn control_iv_uses_lowest_byte() {
+		let iv = control_iv(0x1234);
+		assert_eq!(iv[0], 0x34);
+		assert!(iv[1..].iter().all(|&byte| byte == 0));
+	}
+
+	#[test]
+	fn video_iv_uses_counter() {
+		let iv = video_iv(0x0102030405060708);
+		assert_eq!(iv[..8], [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+		assert_eq!(iv[8..], [0, 0, 0, b'V']);
+	}
+
+	#[test]
+	fn control_sequence_never_reuses_iv() {
+		let mut sequence = ControlSequence::default();
+		let mut ivs = std::collections::HashSet::new();
+		for expected in 0..CONTROL_IV_COUNT {
+			let sequence_number = sequence.next(0).unwrap();
+			assert_eq!(sequence_number, expected);
+			assert!(ivs.insert(control_iv(sequence_number)));
+		}
+
+		assert_eq!(sequence.next(0), None);
+		assert_eq!(sequence.next(0), None);
+	}
+
+	#[test]
+	fn control_sequence_restarts_with_new_keys() {
+		let mut sequence = ControlSequence::default();
+		for _ in 0..CONTROL_IV_COUNT {
+			sequence.next(0).unwrap();
+		}
+
+		assert_eq!(sequence.next(1), Some(0));
+		assert_eq!(sequence.next(1), Some(1));
+	}
+
+	#[test]
+	fn replay_window_rejects_duplicates() {
+		let mut window = ReplayWindow::default();
+		assert!(window.accept(0, 10));
+		assert!(!window.accept(0, 10));
+		assert!(window.accept(0, 11));
+		assert!(!window.accept(0, 11));
+	}
+
+	#[test]
+	fn replay_window_accepts_reordered_messages() {
+		let mut window = ReplayWindow::default();
+		assert!(window.accept(0, 5));
+		assert!(window.accept(0, 3));
+		assert!(window.accept(0, 4));
+		assert!(!window.accept(0, 3));
+		assert!(window.accept(0, 6));
+	}
+
+	#[test]
+	fn replay_window_rejects_old_messages() {
+		let mut window = ReplayWindow::default();
+		assert!(window.accept(0, 1));
+		assert!(window.accept(0, 1 + REPLAY_WINDOW_SIZE));
+		assert!(!window.accept(0, 0));
+		assert!(!window.accept(0, 1));
+		assert!(window.accept(0, 2));
+	}
+
+	#[test]
+	fn replay_window_tracks_epochs_separately() {
+		let mut window = ReplayWindow::default();
+		assert!(window.accept(0, 7));
+		assert!(window.accept(1, 7));
+		assert!(!window.accept(0, 7));
+		assert!(!window.accept(1, 7));
+
+		// Only the two most recent epochs are remembered, older epochs start over.
+		assert!(window.accept(2, 7));
+		assert!(!window.accept(1, 7));
+		assert!(window.accept(0, 7));
+	}
+
+	#[test]
+	fn control_message_matches_moonlight() {
+		// Encrypted ping (type 0x0200, no payload) with sequence number 0x0105, encrypted the way Moonlight does.
+		let ping = [0x00, 0x02, 0x00, 0x00];
+		let ciphertext = [0xfe, 0x2a, 0x7c, 0x8c];
+		let tag = [0x65, 0xbd, 0x3d, 0xaf, 0x26, 0xe4, 0xab, 0x87, 0x38, 0xa2, 0xc0, 0x36, 0xbc, 0x32, 0x8c, 0x39];
+
+		let iv = control_iv(0x0105);
+		let decrypted = decrypt_aead(Cipher::aes_128_gcm(), &KEY, Some(&iv), &[], &ciphertext, &tag).unwrap();
+		assert_eq!(decrypted, ping);
+
+		let mut encrypted_tag = [0u8; 16];
+		let encrypted = encrypt_aead(Cipher::aes_128_gcm(), &KEY, Some(&iv), &[], &ping, &mut encrypted_tag).unwrap();
+		assert_eq!(encrypted, ciphertext);
+		assert_eq!(encrypted_tag, tag);
+	}
+
+	#[test]
+	fn audio_packet_matches_moonlight() {
+		// Audio payload with key id 0x12345678 and sequence number 0x0102, encrypted the way Moonlight decrypts it.
+		let ciphertext = [0xe9, 0xc1, 0x2b, 0x96, 0xff, 0xa3, 0xe2, 0xdb, 0x2c, 0xff, 0xe1, 0x21, 0xca, 0xae, 0xc6, 0xba];
+
+		let iv = audio_iv(0x12345678, 0x0102);
+		let decrypted = decrypt(Cipher::aes_128_cbc(), &KEY, Some(&iv), &ciphertext).unwrap();
+		assert_eq!(decrypted, b"moonshine audio");
+	}
+}
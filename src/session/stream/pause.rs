@@ -0,0 +1,70 @@
+use std::{sync::{Arc, Condvar, Mutex}, time::Duration};
+
+use async_shutdown::ShutdownManager;
+
+use crate::session::SessionShutdownReason;
+
+/// Interval at which paused threads check whether the stream stopped.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Whether the stream is paused, shared between the session and the threads that capture and encode the stream.
+///
+/// While paused nothing is captured or encoded, but the sockets, the RTSP session and the application stay alive.
+#[derive(Clone, Default)]
+pub struct StreamPause(Arc<(Mutex<bool>, Condvar)>);
+
+impl StreamPause {
+	/// Pause or resume the stream, returns whether that changed anything.
+	pub fn set(&self, paused: bool) -> bool {
+		let (lock, condvar) = &*self.0;
+		let mut current = match lock.lock() {
+			Ok(current) => current,
+			Err(e) => {
+				tracing::error!("Failed to lock pause state: {e}");
+				return false;
+			},
+		};
+		if *current == paused {
+			return false;
+		}
+
+		*current = paused;
+		condvar.notify_all();
+		if paused {
+			tracing::info!("Paused the stream.");
+		} else {
+			tracing::info!("Resumed the stream.");
+		}
+
+		true
+	}
+
+	pub fn is_paused(&self) -> bool {
+		self.0.0.lock().is_ok_and(|paused| *paused)
+	}
+
+	/// Block the current thread while the stream is paused or until it stops, returns whether it was paused.
+	pub fn wait_while_paused(&self, stop_signal: &ShutdownManager<SessionShutdownReason>) -> bool {
+		let (lock, condvar) = &*self.0;
+		let mut paused = match lock.lock() {
+			Ok(paused) => paused,
+			Err(e) => {
+				tracing::error!("Failed to lock pause state: {e}");
+				return false;
+			},
+		};
+
+		let was_paused = *paused;
+		while *paused && !stop_signal.is_shutdown_triggered() {
+			paused = match condvar.wait_timeout(paused, STOP_CHECK_INTERVAL) {
+				Ok((paused, _)) => paused,
+				Err(e) => {
+					tracing::error!("Failed to wait for the stream to resume: {e}");
+					return was_paused;
+				},
+			};
+		}
+
+		was_paused
+	}
+}
@@ -0,0 +1,97 @@
+use crate::config::SchedulingConfig;
+
+/// Thread of the stream that can be pinned to cores and scheduled with realtime priority.
+#[derive(Clone, Copy, Debug)]
+pub enum StreamThread {
+	VideoCapture,
+	VideoEncode,
+	Control,
+}
+
+impl StreamThread {
+	fn cores(self, config: &SchedulingConfig) -> &[usize] {
+		match self {
+			Self::VideoCapture => &config.capture_cores,
+			Self::VideoEncode => &config.encode_cores,
+			Self::Control => &config.control_cores,
+		}
+	}
+}
+
+/// Pin the current thread to the cores that are configured for it, and switch it to the realtime policy if enabled.
+///
+/// Failing to do so is logged, but the thread keeps running with the default scheduling.
+pub fn configure_current_thread(config: &SchedulingConfig, thread: StreamThread) {
+	let cores = thread.cores(config);
+	if !cores.is_empty() && set_affinity(cores).is_ok() {
+		tracing::info!("Pinned {thread:?} thread to cores {cores:?}.");
+	}
+
+	if config.realtime {
+		if let Ok(priority) = set_realtime_priority(config.realtime_priority) {
+			tracing::info!("Scheduling {thread:?} thread with SCHED_FIFO at priority {priority}.");
+		}
+	}
+}
+
+fn set_affinity(cores: &[usize]) -> Result<(), ()> {
+	let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+	for &core in cores {
+		if core >= libc::CPU_SETSIZE as usize {
+			tracing::warn!("Can't pin a thread to core {core}, the highest core is {}.", libc::CPU_SETSIZE - 1);
+			return Err(());
+		}
+
+		unsafe { libc::CPU_SET(core, &mut set) };
+	}
+
+	// A pid of 0 refers to the calling thread.
+	let result = unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+	if result != 0 {
+		tracing::warn!("Failed to pin thread to cores {cores:?}: {}", std::io::Error::last_os_error());
+		return Err(());
+	}
+
+	Ok(())
+}
+
+/// Schedule the current thread with `SCHED_FIFO`, returning the priority it got.
+fn set_realtime_priority(priority: u8) -> Result<i32, ()> {
+	let (minimum, maximum) = unsafe { (libc::sched_get_priority_min(libc::SCHED_FIFO), libc::sched_get_priority_max(libc::SCHED_FIFO)) };
+	let priority = (priority as i32).clamp(minimum, maximum);
+	raise_realtime_priority_limit(priority);
+
+	let parameters = libc::sched_param { sched_priority: priority };
+	let result = unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &parameters) };
+	if result != 0 {
+		tracing::warn!(
+			"Failed to schedule thread with SCHED_FIFO at priority {priority}: {}. \
+			Raise the realtime priority limit (`LimitRTPRIO={priority}` in systemd or `rtprio` in limits.conf) or grant CAP_SYS_NICE.",
+			std::io::Error::from_raw_os_error(result),
+		);
+		return Err(());
+	}
+
+	Ok(priority)
+}
+
+/// Raise the soft limit of the realtime priority to `priority` if the hard limit allows it.
+///
+/// Unprivileged processes can only use realtime priorities up to this limit, which is 0 by default.
+fn raise_realtime_priority_limit(priority: i32) {
+	let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+	if unsafe { libc::getrlimit(libc::RLIMIT_RTPRIO, &mut limit) } != 0 {
+		tracing::debug!("Failed to get the realtime priority limit: {}", std::io::Error::last_os_error());
+		return;
+	}
+
+	let priority = priority as libc::rlim_t;
+	if limit.rlim_cur >= priority || limit.rlim_cur >= limit.rlim_max {
+		return;
+	}
+
+	limit.rlim_cur = priority.min(limit.rlim_max);
+	if unsafe { libc::setrlimit(libc::RLIMIT_RTPRIO, &limit) } != 0 {
+		tracing::debug!("Failed to raise the realtime priority limit to {}: {}", limit.rlim_cur, std::io::Error::last_os_error());
+	}
+}
@@ -0,0 +1,381 @@
+use std::{io, net::{IpAddr, SocketAddr}, os::fd::AsRawFd, sync::Arc};
+
+use tokio::{io::Interest, net::UdpSocket, sync::watch};
+use tracing::Instrument;
+
+use crate::{config::{Config, StreamSocketConfig}, session::SessionKeys};
+
+/// Length of the payload that the host gives to the client, for the client to repeat in its pings.
+pub const PING_PAYLOAD_LENGTH: usize = 16;
+
+/// Name of the video stream, used to derive its ping payload.
+pub const VIDEO_STREAM: &str = "video";
+
+/// Name of the audio stream, used to derive its ping payload.
+pub const AUDIO_STREAM: &str = "audio";
+
+/// Largest DSCP class, it is stored in the upper six bits of the type of service.
+const MAX_DSCP: u8 = 63;
+
+/// Argument of SO_TXTIME (`struct sock_txtime` in `linux/net_tstamp.h`).
+#[repr(C)]
+struct SockTxtime {
+	clockid: libc::clockid_t,
+	flags: u32,
+}
+
+/// Ping payload of a stream, which the host gives to the client in the RTSP SETUP response.
+///
+/// The payload is derived from the keys of the session, so only the client that launched the session knows it.
+/// Every stream has its own payload, which also tells the streams apart in single port mode.
+pub fn ping_payload(keys: &SessionKeys, stream: &str) -> String {
+	let digest = openssl::sha::sha256(&[keys.remote_input_key.as_slice(), stream.as_bytes()].concat());
+	hex::encode(&digest[..PING_PAYLOAD_LENGTH / 2])
+}
+
+/// The pings a stream accepts, pings that don't match are ignored.
+#[derive(Clone, Debug)]
+pub struct ExpectedPing {
+	/// Address of the client that set up the stream over RTSP.
+	pub client_address: IpAddr,
+
+	/// Payload that the client repeats in its pings, see [`ping_payload`].
+	pub payload: String,
+}
+
+impl ExpectedPing {
+	fn matches(&self, address: SocketAddr, payload: &[u8]) -> bool {
+		// Compare in constant time, so the payload can't be guessed byte by byte.
+		address.ip().to_canonical() == self.client_address.to_canonical()
+			&& payload.len() == self.payload.len()
+			&& openssl::memcmp::eq(payload, self.payload.as_bytes())
+	}
+}
+
+/// Socket that a stream sends its packets over.
+///
+/// The client pings the socket to tell the host where to send the stream, which is tracked in the background.
+pub struct StreamSocket {
+	socket: Arc<UdpSocket>,
+	client_address_rx: watch::Receiver<Option<SocketAddr>>,
+
+	/// Spreads packets over time, if pacing is enabled.
+	pacer: Option<Pacer>,
+}
+
+impl StreamSocket {
+	/// Bind a socket for a single stream.
+	///
+	/// Without an expected ping all pings are ignored, which is useful when the stream isn't sent to a client.
+	pub async fn bind(
+		address: &str,
+		port: u16,
+		dscp: Option<u8>,
+		config: &StreamSocketConfig,
+		expected_ping: Option<ExpectedPing>,
+	) -> Result<Self, ()> {
+		let socket = bind(address, port, dscp, config).await?;
+		let (client_address_tx, client_address_rx) = watch::channel(None);
+		tokio::spawn(receive_pings(socket.clone(), vec![(expected_ping, client_address_tx)]).in_current_span());
+
+		Ok(Self { socket, client_address_rx, pacer: None })
+	}
+
+	/// Address of the client, or `None` if the client didn't ping this stream yet.
+	pub fn client_address(&self) -> Option<SocketAddr> {
+		*self.client_address_rx.borrow()
+	}
+
+	/// Wait until the client pings this stream.
+	pub async fn pinged(&mut self) -> Result<(), ()> {
+		self.client_address_rx.changed().await
+			.map_err(|_| tracing::debug!("Stopped receiving pings."))
+	}
+
+	/// Send a packet to the client, if the client pinged this stream.
+	pub async fn send(&mut self, packet: &[u8]) -> Result<bool, ()> {
+		let Some(client_address) = self.client_address() else {
+			return Ok(false);
+		};
+
+		let result = match &mut self.pacer {
+			Some(pacer) => {
+				let transmit_time = pacer.transmit_time(packet.len());
+				self.socket.async_io(Interest::WRITABLE, || send_at(&self.socket, packet, client_address, transmit_time)).await
+			},
+			None => self.socket.send_to(packet, client_address).await,
+		};
+
+		result.map_err(|e| tracing::warn!("Failed to send packet to client: {e}"))?;
+		Ok(true)
+	}
+
+	/// Spread packets over time at `rate` megabits per second.
+	fn enable_pacing(&mut self, rate: u32) {
+		let clock = SockTxtime { clockid: libc::CLOCK_MONOTONIC, flags: 0 };
+		match set_option(&self.socket, libc::SOL_SOCKET, libc::SO_TXTIME, clock) {
+			Ok(()) => {
+				tracing::debug!("Pacing packets at {rate} Mbps.");
+				self.pacer = Some(Pacer::new(rate));
+			},
+			Err(e) => tracing::warn!("Failed to enable pacing, sending packets without pacing: {e}"),
+		}
+	}
+}
+
+/// Schedules when packets are sent, so that they leave at a fixed rate instead of in bursts.
+struct Pacer {
+	/// Time it takes to send a byte at the pacing rate, in nanoseconds.
+	nanoseconds_per_byte: f64,
+
+	/// Earliest time to send the next packet, in nanoseconds of the monotonic clock.
+	next_transmit_time: u64,
+}
+
+impl Pacer {
+	fn new(rate: u32) -> Self {
+		Self {
+			nanoseconds_per_byte: 8_000.0 / rate.max(1) as f64,
+			next_transmit_time: 0,
+		}
+	}
+
+	/// Time to send a packet of `length` bytes, in nanoseconds of the monotonic clock.
+	fn transmit_time(&mut self, length: usize) -> u64 {
+		let transmit_time = self.next_transmit_time.max(monotonic_time());
+		self.next_transmit_time = transmit_time + (length as f64 * self.nanoseconds_per_byte) as u64;
+		transmit_time
+	}
+}
+
+/// Bind the sockets of the video and audio streams, or a single socket for both in single port mode.
+///
+/// The streams are only sent to `client_address`, once it pings them with the payloads derived from `keys`.
+pub async fn bind_stream_sockets(
+	config: &Config,
+	video_qos: bool,
+	audio_qos: bool,
+	client_address: IpAddr,
+	keys: &SessionKeys,
+) -> Result<(StreamSocket, StreamSocket), ()> {
+	let socket_config = &config.stream.socket;
+	let video_dscp = video_qos.then_some(socket_config.video_dscp);
+	let audio_dscp = audio_qos.then_some(socket_config.audio_dscp);
+	let video_ping = ExpectedPing { client_address, payload: ping_payload(keys, VIDEO_STREAM) };
+	let audio_ping = ExpectedPing { client_address, payload: ping_payload(keys, AUDIO_STREAM) };
+	let (mut video, audio) = if config.stream.single_port {
+		bind_single_port(config, video_dscp.or(audio_dscp), video_ping, audio_ping).await?
+	} else {
+		(
+			StreamSocket::bind(&config.address, config.stream.video.port, video_dscp, socket_config, Some(video_ping)).await?,
+			StreamSocket::bind(&config.address, config.stream.audio.port, audio_dscp, socket_config, Some(audio_ping)).await?,
+		)
+	};
+
+	// Audio packets are small and evenly spaced already, so only video is paced.
+	// Packets to a client on the host itself don't cross a network that could drop a burst.
+	let loopback = config.stream.loopback.enabled && client_address.to_canonical().is_loopback();
+	if let Some(pacing_rate) = socket_config.pacing_rate.filter(|_| !loopback) {
+		video.enable_pacing(pacing_rate);
+	}
+
+	Ok((video, audio))
+}
+
+/// Bind a single socket for video and audio, pings are told apart by their payload.
+///
+/// Both streams share the DSCP class of video, since it can only be set per socket.
+async fn bind_single_port(
+	config: &Config,
+	dscp: Option<u8>,
+	video_ping: ExpectedPing,
+	audio_ping: ExpectedPing,
+) -> Result<(StreamSocket, StreamSocket), ()> {
+	let socket = bind(&config.address, config.stream.video.port, dscp, &config.stream.socket).await?;
+	let (video_address_tx, video_address_rx) = watch::channel(None);
+	let (audio_address_tx, audio_address_rx) = watch::channel(None);
+	tokio::spawn(receive_pings(socket.clone(), vec![
+		(Some(video_ping), video_address_tx),
+		(Some(audio_ping), audio_address_tx),
+	]).in_current_span());
+
+	Ok((
+		StreamSocket { socket: socket.clone(), client_address_rx: video_address_rx, pacer: None },
+		StreamSocket { socket, client_address_rx: audio_address_rx, pacer: None },
+	))
+}
+
+async fn bind(address: &str, port: u16, dscp: Option<u8>, config: &StreamSocketConfig) -> Result<Arc<UdpSocket>, ()> {
+	let socket = UdpSocket::bind((address, port)).await
+		.map_err(|e| tracing::error!("Failed to bind to UDP socket: {e}"))?;
+
+	if let Some(dscp) = dscp {
+		if dscp > MAX_DSCP {
+			tracing::error!("DSCP class {dscp} is invalid, it should be at most {MAX_DSCP}.");
+			return Err(());
+		}
+
+		tracing::debug!("Enabling QoS with DSCP class {dscp} on stream socket.");
+		socket.set_tos((dscp as u32) << 2)
+			.map_err(|e| tracing::error!("Failed to set QoS on stream socket: {e}"))?;
+	}
+
+	// The kernel doubles the requested size and limits it to net.core.wmem_max and net.core.rmem_max.
+	if let Some(size) = config.send_buffer_size {
+		set_option(&socket, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)
+			.map_err(|e| tracing::error!("Failed to set send buffer size of stream socket: {e}"))?;
+	}
+	if let Some(size) = config.receive_buffer_size {
+		set_option(&socket, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)
+			.map_err(|e| tracing::error!("Failed to set receive buffer size of stream socket: {e}"))?;
+	}
+
+	tracing::debug!(
+		"Listening for stream messages on {}",
+		socket.local_addr()
+			.map_err(|e| tracing::error!("Failed to get local address associated with stream socket: {e}"))?
+	);
+
+	Ok(Arc::new(socket))
+}
+
+/// Receive pings of the client and update the client address of the stream that they are meant for.
+///
+/// A stream only accepts pings from the address of the client that repeat its payload,
+/// so that nobody else can redirect the stream to themselves. A stream without an expected ping ignores all pings.
+/// This stops when all streams are dropped.
+async fn receive_pings(socket: Arc<UdpSocket>, streams: Vec<(Option<ExpectedPing>, watch::Sender<Option<SocketAddr>>)>) {
+	let mut buffer = [0; 1024];
+	let mut warned_plain_ping = false;
+	loop {
+		let (length, address) = tokio::select! {
+			message = socket.recv_from(&mut buffer) => match message {
+				Ok(message) => message,
+				Err(e) => {
+					tracing::warn!("Failed to receive message: {e}");
+					break;
+				},
+			},
+			_ = closed(&streams) => break,
+		};
+
+		let message = &buffer[..length];
+		if message == b"PING" {
+			if !warned_plain_ping {
+				tracing::warn!("Ignoring pings without payload from {address}, the client is too old to stream from Moonshine.");
+				warned_plain_ping = true;
+			}
+			continue;
+		}
+
+		if message.len() != PING_PAYLOAD_LENGTH + 4 {
+			tracing::warn!("Received unknown message on stream socket of length {length}.");
+			continue;
+		}
+
+		// The payload is followed by a sequence number.
+		let payload = &message[..PING_PAYLOAD_LENGTH];
+		let stream = streams.iter().find(|(expected_ping, _)| {
+			expected_ping.as_ref().is_some_and(|expected_ping| expected_ping.matches(address, payload))
+		});
+		match stream {
+			Some((_, client_address_tx)) => {
+				tracing::trace!("Received stream PING message from {address}.");
+				client_address_tx.send_replace(Some(address));
+			},
+			None => tracing::debug!("Ignoring PING message from {address} that doesn't match a stream."),
+		}
+	}
+
+	tracing::debug!("Stopped receiving pings.");
+}
+
+/// Wait until all streams of a socket are dropped.
+async fn closed(streams: &[(Option<ExpectedPing>, watch::Sender<Option<SocketAddr>>)]) {
+	for (_, client_address_tx) in streams {
+		client_address_tx.closed().await;
+	}
+}
+
+fn set_option<T>(socket: &UdpSocket, level: libc::c_int, name: libc::c_int, value: T) -> io::Result<()> {
+	let result = unsafe {
+		libc::setsockopt(
+			socket.as_raw_fd(),
+			level,
+			name,
+			&value as *const T as *const libc::c_void,
+			std::mem::size_of::<T>() as libc::socklen_t,
+		)
+	};
+
+	if result < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(())
+}
+
+/// Send a packet that the kernel holds back until `transmit_time` (in nanoseconds of the monotonic clock).
+fn send_at(socket: &UdpSocket, packet: &[u8], address: SocketAddr, transmit_time: u64) -> io::Result<usize> {
+	let (mut storage, address_length) = socket_address(address);
+	let mut buffer = libc::iovec { iov_base: packet.as_ptr() as *mut libc::c_void, iov_len: packet.len() };
+
+	// Room for a single control message that holds the transmit time, as u64 to align it.
+	let mut control = [0u64; 8];
+	let control_length = unsafe { libc::CMSG_SPACE(std::mem::size_of::<u64>() as u32) } as usize;
+
+	let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+	message.msg_name = &mut storage as *mut libc::sockaddr_storage as *mut libc::c_void;
+	message.msg_namelen = address_length;
+	message.msg_iov = &mut buffer;
+	message.msg_iovlen = 1;
+	message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+	message.msg_controllen = control_length;
+
+	let sent = unsafe {
+		let header = libc::CMSG_FIRSTHDR(&message);
+		(*header).cmsg_level = libc::SOL_SOCKET;
+		(*header).cmsg_type = libc::SCM_TXTIME;
+		(*header).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u64>() as u32) as usize;
+		std::ptr::write_unaligned(libc::CMSG_DATA(header) as *mut u64, transmit_time);
+
+		libc::sendmsg(socket.as_raw_fd(), &message, 0)
+	};
+
+	if sent < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(sent as usize)
+}
+
+fn socket_address(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+	let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+	let length = match address {
+		SocketAddr::V4(address) => {
+			let raw = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+			raw.sin_family = libc::AF_INET as libc::sa_family_t;
+			raw.sin_port = address.port().to_be();
+			raw.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(address.ip().octets()) };
+			std::mem::size_of::<libc::sockaddr_in>()
+		},
+		SocketAddr::V6(address) => {
+			let raw = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+			raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+			raw.sin6_port = address.port().to_be();
+			raw.sin6_flowinfo = address.flowinfo();
+			raw.sin6_addr = libc::in6_addr { s6_addr: address.ip().octets() };
+			raw.sin6_scope_id = address.scope_id();
+			std::mem::size_of::<libc::sockaddr_in6>()
+		},
+	};
+
+	(storage, length as libc::socklen_t)
+}
+
+/// Current time of the monotonic clock in nanoseconds, the clock that SO_TXTIME is configured with.
+fn monotonic_time() -> u64 {
+	let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+	unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+	time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
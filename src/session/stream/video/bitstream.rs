@@ -0,0 +1,198 @@
+use crate::{config::BitstreamConfig, ffmpeg::encoder::VideoCodec};
+
+/// Access unit delimiter of H264 that allows any type of slice, including its start code.
+const H264_ACCESS_UNIT_DELIMITER: &[u8] = &[0, 0, 0, 1, 0x09, 0xf0];
+
+/// Access unit delimiter of HEVC that allows any type of slice, including its start code.
+const HEVC_ACCESS_UNIT_DELIMITER: &[u8] = &[0, 0, 0, 1, 0x46, 0x01, 0x50];
+
+/// Kind of a NAL unit, as far as the headers of a frame are concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NalKind {
+	AccessUnitDelimiter,
+
+	/// A parameter set, with its position among the parameter sets (VPS, SPS and PPS in that order).
+	ParameterSet(usize),
+
+	Other,
+}
+
+/// Adds the headers that clients need to the encoded frames, instead of relying on what the encoder adds by itself.
+///
+/// The latest parameter sets of the stream are cached, so that they can be repeated on IDR frames that lack them.
+pub struct BitstreamFilter {
+	codec: VideoCodec,
+	config: BitstreamConfig,
+
+	/// Latest parameter sets of the stream including their start code, in the order they are sent in.
+	parameter_sets: [Option<Vec<u8>>; 3],
+
+	/// Frames with added headers, reused between frames.
+	buffer: Vec<u8>,
+}
+
+impl BitstreamFilter {
+	pub fn new(codec: VideoCodec, config: BitstreamConfig) -> Self {
+		Self { codec, config, parameter_sets: Default::default(), buffer: Vec::new() }
+	}
+
+	/// Cache the parameter sets in `frame` and return it with the headers that are configured to be added.
+	///
+	/// The frame is returned as is when nothing has to be added.
+	pub fn filter<'a>(&'a mut self, frame: &'a [u8], key_frame: bool) -> &'a [u8] {
+		let units = nal_units(frame);
+		if units.is_empty() {
+			return frame;
+		}
+
+		let mut present = [false; 3];
+		let mut delimited = false;
+		for (index, unit) in units.iter().enumerate() {
+			match self.kind(unit) {
+				NalKind::ParameterSet(position) => {
+					self.parameter_sets[position] = Some(unit.to_vec());
+					present[position] = true;
+				},
+				NalKind::AccessUnitDelimiter => delimited |= index == 0,
+				NalKind::Other => {},
+			}
+		}
+
+		let add_delimiter = self.config.access_unit_delimiters && !delimited;
+		let repeat_parameter_sets = self.config.repeat_parameter_sets
+			&& key_frame
+			&& self.parameter_sets.iter().zip(present).any(|(cached, present)| cached.is_some() && !present);
+		if !add_delimiter && !repeat_parameter_sets {
+			return frame;
+		}
+
+		// An access unit starts with the delimiter, followed by the parameter sets and then the slices.
+		self.buffer.clear();
+		let mut units = units.as_slice();
+		if add_delimiter {
+			let delimiter = self.access_unit_delimiter();
+			self.buffer.extend_from_slice(delimiter);
+		} else if let Some((delimiter, rest)) = units.split_first().filter(|_| delimited) {
+			self.buffer.extend_from_slice(delimiter);
+			units = rest;
+		}
+
+		// The parameter sets of the frame are cached already, so all of them are sent in order from the cache.
+		if repeat_parameter_sets {
+			for parameter_set in self.parameter_sets.iter().flatten() {
+				self.buffer.extend_from_slice(parameter_set);
+			}
+		}
+
+		for unit in units {
+			if !repeat_parameter_sets || !matches!(self.kind(unit), NalKind::ParameterSet(_)) {
+				self.buffer.extend_from_slice(unit);
+			}
+		}
+
+		&self.buffer
+	}
+
+	fn kind(&self, unit: &[u8]) -> NalKind {
+		let Some(&header) = unit.get(start_code_length(unit)) else {
+			return NalKind::Other;
+		};
+
+		match self.codec {
+			VideoCodec::H264 => match header & 0x1f {
+				7 => NalKind::ParameterSet(1),
+				8 => NalKind::ParameterSet(2),
+				9 => NalKind::AccessUnitDelimiter,
+				_ => NalKind::Other,
+			},
+			VideoCodec::Hevc => match (header >> 1) & 0x3f {
+				32 => NalKind::ParameterSet(0),
+				33 => NalKind::ParameterSet(1),
+				34 => NalKind::ParameterSet(2),
+				35 => NalKind::AccessUnitDelimiter,
+				_ => NalKind::Other,
+			},
+		}
+	}
+
+	fn access_unit_delimiter(&self) -> &'static [u8] {
+		match self.codec {
+			VideoCodec::H264 => H264_ACCESS_UNIT_DELIMITER,
+			VideoCodec::Hevc => HEVC_ACCESS_UNIT_DELIMITER,
+		}
+	}
+}
+
+/// Split an Annex B stream in NAL units, each including the start code in front of it.
+///
+/// Returns no units if the stream doesn't start with a start code.
+fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+	let mut starts = Vec::new();
+	let mut index = 0;
+	while index + 3 <= data.len() {
+		if data[index..index + 3] == [0, 0, 1] {
+			// Include the leading zero of a four byte start code.
+			starts.push(if index > 0 && data[index - 1] == 0 { index - 1 } else { index });
+			index += 3;
+		} else {
+			index += 1;
+		}
+	}
+
+	if starts.first() != Some(&0) {
+		return Vec::new();
+	}
+
+	starts.iter()
+		.enumerate()
+		.map(|(index, &start)| &data[start..starts.get(index + 1).copied().unwrap_or(data.len())])
+		.collect()
+}
+
+fn start_code_length(unit: &[u8]) -> usize {
+	if unit.starts_with(&[0, 0, 0, 1]) { 4 } else { 3 }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SPS: &[u8] = &[0, 0, 0, 1, 0x67, 0x64, 0x00, 0x2a];
+	const PPS: &[u8] = &[0, 0, 0, 1, 0x68, 0xee, 0x3c, 0x80];
+	const IDR: &[u8] = &[0, 0, 0, 1, 0x65, 0x88, 0x84];
+	const SLICE: &[u8] = &[0, 0, 0, 1, 0x41, 0x9a, 0x02];
+
+	fn config(repeat_parameter_sets: bool, access_unit_delimiters: bool) -> BitstreamConfig {
+		BitstreamConfig { repeat_parameter_sets, access_unit_delimiters }
+	}
+
+	#[test]
+	fn parameter_sets_are_repeated_on_idr_frames() {
+		let mut filter = BitstreamFilter::new(VideoCodec::H264, config(true, false));
+		let first = [SPS, PPS, IDR].concat();
+		assert_eq!(filter.filter(&first, true), first.as_slice());
+		assert_eq!(filter.filter(SLICE, false), SLICE);
+		assert_eq!(filter.filter(IDR, true), [SPS, PPS, IDR].concat().as_slice());
+
+		// Parameter sets that are already in front of the frame aren't sent twice.
+		let partial = [PPS, IDR].concat();
+		assert_eq!(filter.filter(&partial, true), [SPS, PPS, IDR].concat().as_slice());
+	}
+
+	#[test]
+	fn access_unit_delimiters_start_every_frame() {
+		let mut filter = BitstreamFilter::new(VideoCodec::H264, config(true, true));
+		assert_eq!(filter.filter(&[SPS, PPS, IDR].concat(), true), [H264_ACCESS_UNIT_DELIMITER, SPS, PPS, IDR].concat().as_slice());
+		assert_eq!(filter.filter(SLICE, false), [H264_ACCESS_UNIT_DELIMITER, SLICE].concat().as_slice());
+
+		let delimited = [H264_ACCESS_UNIT_DELIMITER, IDR].concat();
+		assert_eq!(filter.filter(&delimited, true), [H264_ACCESS_UNIT_DELIMITER, SPS, PPS, IDR].concat().as_slice());
+	}
+
+	#[test]
+	fn frames_without_start_code_are_left_alone() {
+		let mut filter = BitstreamFilter::new(VideoCodec::Hevc, config(true, true));
+		let frame = [0x26, 0x01, 0xaf];
+		assert_eq!(filter.filter(&frame, true), frame.as_slice());
+	}
+}
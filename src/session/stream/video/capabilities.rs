@@ -0,0 +1,178 @@
+use ffmpeg::format::Pixel;
+
+use crate::{config::VideoStreamConfig, ffmpeg::encoder::{EncoderBuilder, VideoCodec}};
+
+use super::find_gpu;
+
+/// Name of the AV1 encoder to probe.
+const AV1_ENCODER: &str = "av1_nvenc";
+
+/// Resolutions to try when probing the largest resolution of the HEVC encoder, from large to small.
+const PROBE_RESOLUTIONS: [(u32, u32); 5] = [(8192, 8192), (8192, 4320), (4096, 4096), (3840, 2160), (1920, 1080)];
+
+/// Resolution used when probing everything other than the largest resolution.
+const PROBE_RESOLUTION: (u32, u32) = (1920, 1080);
+
+/// Codec mode bit for H264, GeForce Experience always reports the second bit as well.
+const CODEC_MODE_H264: u32 = 0x3;
+
+/// Codec mode bit for HEVC.
+const CODEC_MODE_HEVC: u32 = 0x100;
+
+/// Codec mode bit for H264 High 4:4:4 with 8 bits per color.
+const CODEC_MODE_H264_HIGH8_444: u32 = 0x40000;
+
+/// Codec mode bit for HEVC Range Extensions 4:4:4 with 8 bits per color.
+const CODEC_MODE_HEVC_REXT8_444: u32 = 0x80000;
+
+/// What the encoders on this system can do, probed by opening them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncoderCapabilities {
+	h264: bool,
+	hevc: bool,
+
+	/// Largest number of pixels in a frame that the HEVC encoder accepts, zero if HEVC is not available.
+	max_luma_pixels_hevc: u64,
+
+	/// HEVC with 10 bits per color (Main10).
+	hevc_main10: bool,
+
+	/// H264 without chroma subsampling (4:4:4), encoded from the packed RGB frames that are captured.
+	h264_yuv444: bool,
+
+	/// HEVC without chroma subsampling (4:4:4), encoded from the packed RGB frames that are captured.
+	hevc_yuv444: bool,
+
+	av1: bool,
+}
+
+impl EncoderCapabilities {
+	/// Probe the encoders configured for the video stream, this takes a moment since every check opens an encoder.
+	pub fn probe(config: &VideoStreamConfig) -> Self {
+		// Failing to open an encoder is expected here, so don't let FFmpeg log errors about it.
+		let log_level = ffmpeg::log::get_level().ok();
+		ffmpeg::log::set_level(ffmpeg::log::Level::Quiet);
+
+		// Probe the encoders of the GPU that is used for streaming.
+		let gpu = find_gpu(config.gpu.as_deref()).ok();
+		let can_open = |codec_name: &str, resolution: (u32, u32), pixel_format: Pixel, options: &[(&str, &str)]| can_open(codec_name, resolution, pixel_format, options, gpu);
+
+		// 4:4:4 is probed the way it is streamed: from packed RGB frames that the encoder doesn't subsample.
+		// Older versions of FFmpeg don't have the option for that, in which case 4:4:4 is not supported.
+		let yuv444_options = |profile: &'static str| [("profile", profile), ("rgb_mode", "yuv444")];
+
+		let h264 = can_open(&config.codec_h264, PROBE_RESOLUTION, Pixel::YUV420P, &[]);
+		let max_luma_pixels_hevc = PROBE_RESOLUTIONS.iter()
+			.find(|&&resolution| can_open(&config.codec_hevc, resolution, Pixel::YUV420P, &[]))
+			.map(|(width, height)| *width as u64 * *height as u64)
+			.unwrap_or(0);
+		let hevc = max_luma_pixels_hevc > 0;
+		let capabilities = Self {
+			h264,
+			hevc,
+			max_luma_pixels_hevc,
+			hevc_main10: hevc && can_open(&config.codec_hevc, PROBE_RESOLUTION, Pixel::P010LE, &[("profile", "main10")]),
+			h264_yuv444: h264 && can_open(&config.codec_h264, PROBE_RESOLUTION, Pixel::ZRGB32, &yuv444_options("high444p")),
+			hevc_yuv444: hevc && can_open(&config.codec_hevc, PROBE_RESOLUTION, Pixel::ZRGB32, &yuv444_options("rext")),
+			av1: can_open(AV1_ENCODER, PROBE_RESOLUTION, Pixel::YUV420P, &[]),
+		};
+
+		if let Some(log_level) = log_level {
+			ffmpeg::log::set_level(log_level);
+		}
+
+		if !capabilities.h264 {
+			tracing::warn!("Failed to open encoder '{}', streaming will likely fail.", config.codec_h264);
+		}
+		tracing::info!("Encoder capabilities: {}.", capabilities.summary());
+
+		capabilities
+	}
+
+	/// Codecs to offer to clients, as the bitmask of codec modes that Moonlight expects.
+	pub fn codec_mode_support(&self) -> u32 {
+		// Moonlight requires H264, so it is always offered.
+		let mut codec_modes = CODEC_MODE_H264;
+		if self.hevc {
+			codec_modes |= CODEC_MODE_HEVC;
+		}
+		if self.h264_yuv444 {
+			codec_modes |= CODEC_MODE_H264_HIGH8_444;
+		}
+		if self.hevc_yuv444 {
+			codec_modes |= CODEC_MODE_HEVC_REXT8_444;
+		}
+
+		// HEVC Main10 and AV1 are not offered, since frames are captured as 8-bit BGRA
+		// and the stream only encodes H264 and HEVC.
+		codec_modes
+	}
+
+	/// Whether frames of `codec` can be encoded without chroma subsampling.
+	pub fn yuv444_supported(&self, codec: VideoCodec) -> bool {
+		match codec {
+			VideoCodec::H264 => self.h264_yuv444,
+			VideoCodec::Hevc => self.hevc_yuv444,
+		}
+	}
+
+	/// Whether HEVC is offered to clients.
+	pub fn hevc_supported(&self) -> bool {
+		self.hevc
+	}
+
+	pub fn max_luma_pixels_hevc(&self) -> u64 {
+		self.max_luma_pixels_hevc
+	}
+
+	/// Whether HDR can be streamed.
+	///
+	/// Besides an encoder that supports HEVC Main10, this requires frames with 10 bits per color.
+	/// NvFBC captures 8-bit BGRA frames, so HDR is not supported even if the encoder supports it.
+	pub fn hdr_supported(&self) -> bool {
+		false
+	}
+
+	/// Describe the capabilities, for logging.
+	pub fn summary(&self) -> String {
+		let supported = |supported: bool| if supported { "yes" } else { "no" };
+		format!(
+			"H264: {}, HEVC: {} (up to {} megapixels), HEVC Main10: {}, H264 4:4:4: {}, HEVC 4:4:4: {}, AV1: {}",
+			supported(self.h264),
+			supported(self.hevc),
+			self.max_luma_pixels_hevc / 1_000_000,
+			supported(self.hevc_main10),
+			supported(self.h264_yuv444),
+			supported(self.hevc_yuv444),
+			supported(self.av1),
+		)
+	}
+}
+
+/// Check whether an encoder can be opened with the given settings.
+fn can_open(codec_name: &str, (width, height): (u32, u32), pixel_format: Pixel, options: &[(&str, &str)], gpu: Option<usize>) -> bool {
+	let Ok(builder) = EncoderBuilder::new(codec_name) else {
+		return false;
+	};
+
+	let builder = builder
+		.set_width(width)
+		.set_height(height)
+		.set_framerate(60)
+		.set_pixel_format(pixel_format);
+	let mut builder = match gpu {
+		Some(gpu) => match builder.set_option("gpu", gpu.to_string().as_str()) {
+			Ok(builder) => builder,
+			Err(_) => return false,
+		},
+		None => builder,
+	};
+	for &(name, value) in options {
+		builder = match builder.set_option(name, value) {
+			Ok(builder) => builder,
+			Err(_) => return false,
+		};
+	}
+
+	builder.build().is_ok()
+}
@@ -0,0 +1,426 @@
+use std::{fs::File, os::fd::FromRawFd, sync::{mpsc::Sender, Arc, RwLock}, time::{Duration, Instant}};
+
+use async_shutdown::ShutdownManager;
+use ffmpeg::Frame;
+use nvfbc::{CudaCapturer, BufferFormat, cuda::CaptureMethod};
+
+use crate::{config::{CaptureBackend, VideoStreamConfig}, ffmpeg::hwframe::{copy_device_to_frame, copy_host_to_frame}, metrics::{Stage, StageTimer}, session::SessionShutdownReason};
+
+use super::{super::StreamPause, encoder::EncoderCommand, memory::GpuMemoryMonitor, queue::FrameQueue, scaler::Scaler};
+
+mod test_pattern;
+pub use test_pattern::TestPattern;
+
+mod wlr_screencopy;
+pub use wlr_screencopy::WlrScreencopy;
+
+mod x11_shm;
+pub use x11_shm::X11Shm;
+
+/// Number of times we try to restart capturing after it failed, before ending the stream.
+const MAX_RECOVERY_ATTEMPTS: u32 = 5;
+
+/// Time to wait before trying to restart capturing, giving the driver or X server time to settle.
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest time without a new frame when nothing on the screen changes, so that the encoder keeps receiving frames.
+const MAX_IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Part of the X screen that is captured, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapturedArea {
+	/// Offset of the captured area within the X screen.
+	pub x: u32,
+	pub y: u32,
+	pub width: u32,
+	pub height: u32,
+
+	/// Size of the X screen, which spans all monitors.
+	pub screen_width: u32,
+	pub screen_height: u32,
+}
+
+impl CapturedArea {
+	/// The area when capturing the whole X screen.
+	pub fn whole_screen(screen_width: u32, screen_height: u32) -> Self {
+		Self { x: 0, y: 0, width: screen_width, height: screen_height, screen_width, screen_height }
+	}
+
+	/// Map a position on the video of the client to a position on the X screen.
+	///
+	/// The client reports positions relative to its own view of the stream (`width` by `height`), which has the aspect ratio
+	/// of the resolution it asked for. When the aspect ratio of the screen differs, the screen is letterboxed in that view
+	/// (by the scaler, or by the client if we stream the resolution of the screen), so positions on the black bars are
+	/// clamped to the edge of the screen.
+	/// Returns the position as a fraction (between 0 and 1) of the X screen size.
+	pub fn to_screen(&self, x: i16, y: i16, width: i16, height: i16) -> Option<(f64, f64)> {
+		if width <= 0 || height <= 0 || self.width == 0 || self.height == 0 || self.screen_width == 0 || self.screen_height == 0 {
+			return None;
+		}
+
+		// Size and offset of the video, scaled to fit the view of the client.
+		let (width, height) = (width as f64, height as f64);
+		let scale = (width / self.width as f64).min(height / self.height as f64);
+		let video_width = self.width as f64 * scale;
+		let video_height = self.height as f64 * scale;
+		let offset_x = (width - video_width) / 2.0;
+		let offset_y = (height - video_height) / 2.0;
+
+		let x = ((x as f64 - offset_x) / video_width).clamp(0.0, 1.0);
+		let y = ((y as f64 - offset_y) / video_height).clamp(0.0, 1.0);
+		Some((
+			(self.x as f64 + x * self.width as f64) / self.screen_width as f64,
+			(self.y as f64 + y * self.height as f64) / self.screen_height as f64,
+		))
+	}
+}
+
+/// Area that is currently being captured, shared with the input handler to map absolute mouse positions.
+#[derive(Clone, Debug, Default)]
+pub struct SharedCapturedArea(Arc<RwLock<Option<CapturedArea>>>);
+
+impl SharedCapturedArea {
+	/// The captured area, or `None` if we haven't started capturing yet.
+	pub fn get(&self) -> Option<CapturedArea> {
+		*self.0.read().ok()?
+	}
+
+	pub fn set(&self, area: CapturedArea) {
+		match self.0.write() {
+			Ok(mut lock) => *lock = Some(area),
+			Err(e) => tracing::error!("Failed to lock captured area: {e}"),
+		}
+	}
+}
+
+/// Source of the frames of the stream.
+pub enum Capturer {
+	/// Capture the X screen with NvFBC.
+	Nvfbc(FrameCapturer),
+
+	/// Generate a test pattern, which doesn't capture anything.
+	TestPattern(TestPattern),
+
+	/// Capture a Wayland output with wlr-screencopy.
+	WlrScreencopy(WlrScreencopy),
+
+	/// Capture the X screen through shared memory.
+	X11Shm(X11Shm),
+}
+
+impl Capturer {
+	/// Create the capturer that is configured, a test pattern is generated at `width`x`height`.
+	pub fn new(config: &VideoStreamConfig, width: u32, height: u32) -> Result<Self, ()> {
+		match config.capture {
+			CaptureBackend::Nvfbc => Ok(Self::Nvfbc(FrameCapturer::new()?)),
+			CaptureBackend::TestPattern => Ok(Self::TestPattern(TestPattern::new(width, height))),
+			CaptureBackend::WlrScreencopy => Ok(Self::WlrScreencopy(WlrScreencopy::new(config.output.as_deref())?)),
+			CaptureBackend::X11Shm => Ok(Self::X11Shm(X11Shm::new()?)),
+		}
+	}
+
+	/// Resolution of the captured frames.
+	pub fn screen_size(&self) -> Result<(u32, u32), ()> {
+		match self {
+			Self::Nvfbc(capturer) => {
+				let status = capturer.status()?;
+				Ok((status.screen_size.w, status.screen_size.h))
+			},
+			Self::TestPattern(pattern) => Ok((pattern.width(), pattern.height())),
+			Self::WlrScreencopy(capturer) => Ok((capturer.width(), capturer.height())),
+			Self::X11Shm(capturer) => Ok((capturer.width(), capturer.height())),
+		}
+	}
+
+	/// The area of the screen that is captured, which is the whole test pattern when it is generated.
+	///
+	/// A Wayland output is treated as the whole screen, so absolute mouse positions only match with a single output.
+	pub fn captured_area(&self) -> Result<CapturedArea, ()> {
+		match self {
+			Self::Nvfbc(capturer) => capturer.captured_area(),
+			Self::TestPattern(pattern) => Ok(CapturedArea::whole_screen(pattern.width(), pattern.height())),
+			Self::WlrScreencopy(capturer) => Ok(CapturedArea::whole_screen(capturer.width(), capturer.height())),
+			Self::X11Shm(capturer) => Ok(CapturedArea::whole_screen(capturer.width(), capturer.height())),
+		}
+	}
+
+	/// Produce frames until the stream stops, see [`FrameCapturer::run`].
+	///
+	/// A test pattern is generated at the resolution of the buffers, so it is never scaled.
+	#[allow(clippy::too_many_arguments)]
+	pub fn run(
+		self,
+		width: u32,
+		height: u32,
+		framerate: u32,
+		scaler: Option<Scaler>,
+		capture_buffer: Frame,
+		frame_queue: Arc<FrameQueue>,
+		encoder_command_tx: Sender<EncoderCommand>,
+		pause: StreamPause,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
+	) -> Result<(), ()> {
+		match self {
+			Self::Nvfbc(capturer) => capturer.run(
+				width,
+				height,
+				framerate,
+				scaler,
+				capture_buffer,
+				frame_queue,
+				encoder_command_tx,
+				pause,
+				stop_signal,
+			),
+			Self::TestPattern(pattern) => {
+				let result = pattern.run(framerate, capture_buffer, &frame_queue, &pause, &stop_signal);
+				if result.is_err() {
+					tracing::error!("Generating the test pattern failed, stopping stream.");
+					let _ = stop_signal.trigger_shutdown(SessionShutdownReason::CaptureFailed);
+				}
+
+				result
+			},
+			Self::WlrScreencopy(capturer) => {
+				let result = capturer.run(framerate, scaler, capture_buffer, &frame_queue, &pause, &stop_signal);
+				if result.is_err() {
+					tracing::error!("Frame capture failed, stopping stream.");
+					let _ = stop_signal.trigger_shutdown(SessionShutdownReason::CaptureFailed);
+				}
+
+				result
+			},
+			Self::X11Shm(capturer) => {
+				let result = capturer.run(framerate, scaler, capture_buffer, &frame_queue, &pause, &stop_signal);
+				if result.is_err() {
+					tracing::error!("Frame capture failed, stopping stream.");
+					let _ = stop_signal.trigger_shutdown(SessionShutdownReason::CaptureFailed);
+				}
+
+				result
+			},
+		}
+	}
+}
+
+/// Create an anonymous file of `size` bytes, to share captured frames with the compositor or X server.
+fn create_shared_memory(size: usize) -> Result<File, ()> {
+	let fd = unsafe { libc::memfd_create(b"moonshine-capture\0".as_ptr() as *const libc::c_char, libc::MFD_CLOEXEC) };
+	if fd < 0 {
+		tracing::error!("Failed to create shared memory: {}", std::io::Error::last_os_error());
+		return Err(());
+	}
+
+	let file = unsafe { File::from_raw_fd(fd) };
+	file.set_len(size as u64)
+		.map_err(|e| tracing::error!("Failed to resize shared memory to {size} bytes: {e}"))?;
+	Ok(file)
+}
+
+/// Upload a tightly packed frame in host memory to `frame`, scaling it if a scaler is given.
+fn upload_frame(image: &[u8], width: u32, height: u32, scaler: Option<&mut Scaler>, frame: &mut Frame) -> Result<(), ()> {
+	let _timer = StageTimer::start(Stage::Convert);
+	match scaler {
+		Some(scaler) => scaler.scale_host(image, width, height, frame),
+		None => copy_host_to_frame(image, width as usize * 4, height as usize, frame)
+			.map_err(|e| tracing::error!("Failed to upload captured frame: {e}")),
+	}
+}
+
+pub struct FrameCapturer {
+	capturer: CudaCapturer,
+}
+
+impl FrameCapturer {
+	pub fn new() -> Result<Self, ()> {
+		let capturer = CudaCapturer::new()
+			.map_err(|e| tracing::error!("Failed to create CUDA capture device: {e}"))?;
+		capturer.release_context()
+			.map_err(|e| tracing::error!("Failed to release frame capturer CUDA context: {e}"))?;
+
+		Ok(Self { capturer })
+	}
+
+	pub fn status(&self) -> Result<nvfbc::Status, ()>{
+		self.capturer.status()
+			.map_err(|e| tracing::error!("Failed to get NvFBC status: {e}"))
+	}
+
+	/// The area of the X screen that is captured.
+	///
+	/// NvFBC captures the whole X screen, so with multiple monitors this spans all of them.
+	pub fn captured_area(&self) -> Result<CapturedArea, ()> {
+		let status = self.status()?;
+		Ok(CapturedArea::whole_screen(status.screen_size.w, status.screen_size.h))
+	}
+
+	/// Capture frames until the stream stops.
+	///
+	/// If capturing fails mid-stream (for example because the X server reset the display), capturing is restarted.
+	/// The stream is stopped if capturing can't be restarted, or if the resolution of the screen changed.
+	///
+	/// `width` and `height` are the resolution of the screen. If a scaler is given,
+	/// frames are scaled to the size of the buffers, otherwise the buffers must have the resolution of the screen.
+	#[allow(clippy::too_many_arguments)]
+	pub fn run(
+		self,
+		width: u32,
+		height: u32,
+		framerate: u32,
+		scaler: Option<Scaler>,
+		capture_buffer: Frame,
+		frame_queue: Arc<FrameQueue>,
+		encoder_command_tx: Sender<EncoderCommand>,
+		pause: StreamPause,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
+	) -> Result<(), ()> {
+		let result = self.capture(
+			width,
+			height,
+			framerate,
+			scaler,
+			capture_buffer,
+			frame_queue,
+			encoder_command_tx,
+			&pause,
+			&stop_signal,
+		);
+
+		if result.is_err() {
+			tracing::error!("Frame capture failed, stopping stream.");
+			let _ = stop_signal.trigger_shutdown(SessionShutdownReason::CaptureFailed);
+		}
+
+		result
+	}
+
+	#[allow(clippy::too_many_arguments)]
+	fn capture(
+		mut self,
+		width: u32,
+		height: u32,
+		framerate: u32,
+		scaler: Option<Scaler>,
+		mut capture_buffer: Frame,
+		frame_queue: Arc<FrameQueue>,
+		encoder_command_tx: Sender<EncoderCommand>,
+		pause: &StreamPause,
+		stop_signal: &ShutdownManager<SessionShutdownReason>,
+	) -> Result<(), ()> {
+		self.start(framerate)?;
+		tracing::info!("Started frame capture.");
+
+		// Failing to monitor memory usage shouldn't stop the stream.
+		let mut memory_monitor = GpuMemoryMonitor::new().ok();
+
+		let expected_buffer_len = width as usize * height as usize * 4;
+		while !stop_signal.is_shutdown_triggered() {
+			pause.wait_while_paused(stop_signal);
+
+			let capture_timer = StageTimer::start(Stage::Capture);
+			let frame_info = self.capturer.next_frame(CaptureMethod::NoWaitIfNewFrame);
+			drop(capture_timer);
+
+			let (frame_info, captured_at) = match frame_info {
+				Ok(frame_info) => (frame_info, Instant::now()),
+				Err(e) => {
+					tracing::warn!("Failed to wait for new CUDA frame, restarting frame capture: {e}");
+					self = Self::recover(width, height, framerate, stop_signal)?;
+
+					// The client needs a new IDR frame to recover from the frames it missed.
+					let _ = encoder_command_tx.send(EncoderCommand::RequestIdrFrame);
+					continue;
+				},
+			};
+			tracing::trace!("Frame info: {:#?}", frame_info);
+
+			// Copying a larger frame would write past the end of our buffer.
+			if frame_info.device_buffer_len as usize > expected_buffer_len {
+				tracing::error!(
+					"Captured frame has {} bytes, but expected at most {expected_buffer_len} bytes for a resolution of {width}x{height}. \
+					Did the resolution of the screen change?",
+					frame_info.device_buffer_len,
+				);
+				return Err(());
+			}
+
+			let device_buffer = frame_info.device_buffer as cudarc::driver::sys::CUdeviceptr;
+			let convert_timer = StageTimer::start(Stage::Convert);
+			if let Some(scaler) = &scaler {
+				if scaler.scale(device_buffer, width, height, &mut capture_buffer).is_err() {
+					continue;
+				}
+			} else if let Err(e) = copy_device_to_frame(
+				// The captured frame is tightly packed, while the lines of our buffer may be padded.
+				device_buffer,
+				width as usize * 4,
+				frame_info.device_buffer_len as usize / (width as usize * 4),
+				&mut capture_buffer,
+			) {
+				tracing::error!("Failed to copy CUDA memory: {e}");
+				continue;
+			}
+			drop(convert_timer);
+
+			// Hand the frame to the encoder, continuing with a free buffer.
+			frame_queue.push(&mut capture_buffer, captured_at)?;
+			tracing::trace!("Current frame: {}", frame_info.current_frame);
+
+			if let Some(memory_monitor) = memory_monitor.as_mut() {
+				memory_monitor.on_frame();
+			}
+		}
+
+		tracing::debug!("Received stop signal.");
+
+		Ok(())
+	}
+
+	fn start(&self, framerate: u32) -> Result<(), ()> {
+		self.capturer.bind_context()
+			.map_err(|e| tracing::error!("Failed to bind frame capturer CUDA context: {e}"))?;
+		self.capturer.start(BufferFormat::Bgra, framerate)
+			.map_err(|e| tracing::error!("Failed to start CUDA capture device: {e}"))
+	}
+
+	/// Create and start a new capturer, after the previous one failed.
+	fn recover(width: u32, height: u32, framerate: u32, stop_signal: &ShutdownManager<SessionShutdownReason>) -> Result<Self, ()> {
+		for attempt in 1..=MAX_RECOVERY_ATTEMPTS {
+			std::thread::sleep(RECOVERY_INTERVAL);
+			if stop_signal.is_shutdown_triggered() {
+				return Err(());
+			}
+
+			let Ok(capturer) = Self::new() else {
+				tracing::warn!("Failed to restart frame capture (attempt {attempt}/{MAX_RECOVERY_ATTEMPTS}).");
+				continue;
+			};
+
+			// The driver can still be busy invalidating the previous session (for example after switching VTs), so this is retried too.
+			let Ok(status) = capturer.status() else {
+				tracing::warn!("Failed to restart frame capture (attempt {attempt}/{MAX_RECOVERY_ATTEMPTS}).");
+				continue;
+			};
+
+			// The encoder and its buffers are created for the original resolution, so we can't continue with another one.
+			if status.screen_size.w != width || status.screen_size.h != height {
+				tracing::error!(
+					"Resolution of the screen changed from {width}x{height} to {}x{}, can't continue the stream.",
+					status.screen_size.w, status.screen_size.h,
+				);
+				return Err(());
+			}
+
+			if capturer.start(framerate).is_err() {
+				tracing::warn!("Failed to restart frame capture (attempt {attempt}/{MAX_RECOVERY_ATTEMPTS}).");
+				continue;
+			}
+
+			tracing::info!("Restarted frame capture.");
+			return Ok(capturer);
+		}
+
+		tracing::error!("Failed to restart frame capture after {MAX_RECOVERY_ATTEMPTS} attempts.");
+		Err(())
+	}
+}
@@ -0,0 +1,165 @@
+use std::time::{Duration, Instant};
+
+use async_shutdown::ShutdownManager;
+use ffmpeg::Frame;
+
+use crate::{ffmpeg::hwframe::copy_host_to_frame, metrics::{Stage, StageTimer}, session::SessionShutdownReason};
+
+use super::super::{super::StreamPause, overlay::render, queue::FrameQueue};
+
+/// Colors of the bars from left to right (in BGRA): white, yellow, cyan, green, magenta, red and blue.
+const BARS: [[u8; 4]; 7] = [
+	[192, 192, 192, 255],
+	[0, 192, 192, 255],
+	[192, 192, 0, 255],
+	[0, 192, 0, 255],
+	[192, 0, 192, 255],
+	[0, 0, 192, 255],
+	[192, 0, 0, 255],
+];
+
+/// Time it takes for the bars to move across the whole width of the image.
+const SCROLL_PERIOD: Duration = Duration::from_secs(4);
+
+/// Generates moving color bars with a timestamp and frame counter, instead of capturing the screen.
+///
+/// The moving bars make it easy to spot stutter on the client, and the timestamp allows measuring latency
+/// by comparing it with a clock on the host.
+pub struct TestPattern {
+	width: u32,
+	height: u32,
+	started: Instant,
+
+	/// Image in host memory, packed 32 bit BGRA.
+	image: Vec<u8>,
+}
+
+impl TestPattern {
+	/// Resolution of the test pattern when no client asks for one, for example in the preview.
+	pub const DEFAULT_RESOLUTION: (u32, u32) = (1920, 1080);
+
+	pub fn new(width: u32, height: u32) -> Self {
+		Self {
+			width,
+			height,
+			started: Instant::now(),
+			image: vec![0; width as usize * height as usize * 4],
+		}
+	}
+
+	pub fn width(&self) -> u32 {
+		self.width
+	}
+
+	pub fn height(&self) -> u32 {
+		self.height
+	}
+
+	/// Generate frames at `framerate` until the stream stops.
+	pub fn run(
+		mut self,
+		framerate: u32,
+		mut capture_buffer: Frame,
+		frame_queue: &FrameQueue,
+		pause: &StreamPause,
+		stop_signal: &ShutdownManager<SessionShutdownReason>,
+	) -> Result<(), ()> {
+		tracing::info!("Started generating a test pattern of {}x{} at {framerate} fps.", self.width, self.height);
+
+		let frame_interval = Duration::from_secs(1) / framerate.max(1);
+		let mut next_frame = Instant::now();
+		let mut current_frame = 0u32;
+		while !stop_signal.is_shutdown_triggered() {
+			pause.wait_while_paused(stop_signal);
+
+			let now = Instant::now();
+			if next_frame > now {
+				std::thread::sleep(next_frame - now);
+			}
+
+			// Don't try to catch up on frames when we fell behind, like a real screen wouldn't.
+			next_frame = (next_frame + frame_interval).max(Instant::now());
+			current_frame = current_frame.wrapping_add(1);
+
+			let captured_at = Instant::now();
+			{
+				let _timer = StageTimer::start(Stage::Capture);
+				draw(&mut self.image, self.width as usize, self.height as usize, captured_at - self.started, current_frame);
+			}
+			{
+				let _timer = StageTimer::start(Stage::Convert);
+				copy_host_to_frame(&self.image, self.width as usize * 4, self.height as usize, &mut capture_buffer)
+					.map_err(|e| tracing::error!("Failed to upload test pattern: {e}"))?;
+			}
+
+			// Hand the frame to the encoder, continuing with a free buffer.
+			frame_queue.push(&mut capture_buffer, captured_at)?;
+		}
+
+		tracing::debug!("Received stop signal.");
+
+		Ok(())
+	}
+}
+
+/// Draw the test pattern of `frame` at `elapsed` time since the start of the stream.
+fn draw(image: &mut [u8], width: usize, height: usize, elapsed: Duration, frame: u32) {
+	if width == 0 || height == 0 {
+		return;
+	}
+
+	// Draw one line of bars, shifted to the left as time passes, and repeat it for the whole image.
+	let offset = (elapsed.as_secs_f64() / SCROLL_PERIOD.as_secs_f64() * width as f64) as usize % width;
+	let (first_line, other_lines) = image.split_at_mut(width * 4);
+	for (x, pixel) in first_line.chunks_exact_mut(4).enumerate() {
+		pixel.copy_from_slice(&BARS[(x + offset) % width * BARS.len() / width]);
+	}
+	for line in other_lines.chunks_exact_mut(width * 4) {
+		line.copy_from_slice(first_line);
+	}
+
+	// Show the time and frame number in the center, at the same size as the performance overlay.
+	let (mask, mask_width, mask_height) = render(&[timestamp(elapsed), format!("FRAME {frame}")]);
+	let scale = (height / 360).max(1);
+	let (text_width, text_height) = (mask_width as usize * scale, mask_height as usize * scale);
+	let left = width.saturating_sub(text_width) / 2;
+	let top = height.saturating_sub(text_height) / 2;
+	for y in 0..text_height.min(height) {
+		for x in 0..text_width.min(width) {
+			let value = mask[(y / scale) * mask_width as usize + x / scale];
+			let index = ((top + y) * width + left + x) * 4;
+			image[index..index + 4].copy_from_slice(&[value, value, value, 255]);
+		}
+	}
+}
+
+/// Time since the start of the stream as `HH:MM:SS.mmm`.
+fn timestamp(elapsed: Duration) -> String {
+	let seconds = elapsed.as_secs();
+	format!("{:02}:{:02}:{:02}.{:03}", seconds / 3600, seconds / 60 % 60, seconds % 60, elapsed.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn timestamp_is_formatted() {
+		assert_eq!(timestamp(Duration::from_millis(3_723_045)), "01:02:03.045");
+	}
+
+	#[test]
+	fn bars_move_over_time() {
+		let (width, height) = (700, 100);
+		let mut first = vec![0; width * height * 4];
+		let mut second = first.clone();
+		draw(&mut first, width, height, Duration::ZERO, 1);
+		draw(&mut second, width, height, SCROLL_PERIOD / 4, 2);
+
+		// After a quarter of the period the bars moved 175 pixels to the left, which puts the second bar at the left edge.
+		assert_eq!(first[..4], BARS[0]);
+		assert_eq!(second[..4], BARS[1]);
+		let last_line = (height - 1) * width * 4;
+		assert_eq!(first[last_line..last_line + 4], BARS[0]);
+	}
+}
@@ -0,0 +1,400 @@
+use std::{fs::File, io::ErrorKind, os::{fd::{AsFd, AsRawFd}, unix::fs::FileExt}, time::{Duration, Instant}};
+
+use async_shutdown::ShutdownManager;
+use ffmpeg::Frame;
+use wayland_client::{
+	delegate_noop,
+	globals::{registry_queue_init, GlobalListContents},
+	protocol::{wl_buffer::WlBuffer, wl_output::{self, WlOutput}, wl_registry::{self, WlRegistry}, wl_shm::{self, WlShm}, wl_shm_pool::WlShmPool},
+	backend::WaylandError,
+	Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols_wlr::screencopy::v1::client::{
+	zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+	zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+};
+
+use crate::{metrics::{Stage, StageTimer}, session::SessionShutdownReason};
+
+use super::{super::{super::StreamPause, queue::FrameQueue, scaler::Scaler}, create_shared_memory, upload_frame, MAX_IDLE_INTERVAL};
+
+/// Layout of the shared memory buffers that the compositor copies frames into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BufferInfo {
+	format: WEnum<wl_shm::Format>,
+	width: u32,
+	height: u32,
+	stride: u32,
+}
+
+/// Progress of copying a frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum FrameState {
+	#[default]
+	Pending,
+	Ready,
+	Failed,
+}
+
+/// State of the connection to the compositor, updated by its events.
+#[derive(Default)]
+struct State {
+	/// Outputs with their name, which the compositor only sends from version 4 of `wl_output`.
+	outputs: Vec<(WlOutput, Option<String>)>,
+
+	/// Layout of the buffer that the compositor asked for, for the frame that is being captured.
+	buffer_info: Option<BufferInfo>,
+
+	/// Whether the frame that is being captured is upside down.
+	y_invert: bool,
+
+	frame: FrameState,
+}
+
+/// Captures an output of a wlroots based compositor (ie. Sway or Hyprland) with the wlr-screencopy protocol.
+///
+/// Frames are copied by the compositor into shared memory and uploaded to the GPU from there.
+/// This needs no permission dialog, unlike capturing through the desktop portal.
+/// If the compositor supports it, frames are only copied when the output changed.
+pub struct WlrScreencopy {
+	queue: EventQueue<State>,
+	state: State,
+	manager: ZwlrScreencopyManagerV1,
+	output: WlOutput,
+
+	/// Layout of the frames of the output, which can't change during a stream.
+	buffer_info: BufferInfo,
+
+	/// Shared memory that the compositor copies frames into, and the buffer that refers to it.
+	file: File,
+	buffer: WlBuffer,
+
+	/// The last frame as it was copied by the compositor.
+	shared_image: Vec<u8>,
+
+	/// The last frame, tightly packed and the right way up.
+	image: Vec<u8>,
+}
+
+impl WlrScreencopy {
+	/// Connect to the compositor of `WAYLAND_DISPLAY` and prepare capturing the output named `output_name` (ie. "DP-1").
+	///
+	/// The first output is captured if no name is given.
+	pub fn new(output_name: Option<&str>) -> Result<Self, ()> {
+		let connection = Connection::connect_to_env()
+			.map_err(|e| tracing::error!("Failed to connect to the Wayland compositor: {e}"))?;
+		let (globals, mut queue) = registry_queue_init::<State>(&connection)
+			.map_err(|e| tracing::error!("Failed to list the globals of the Wayland compositor: {e}"))?;
+		let queue_handle = queue.handle();
+
+		let manager: ZwlrScreencopyManagerV1 = globals.bind(&queue_handle, 1..=3, ())
+			.map_err(|e| tracing::error!("The Wayland compositor doesn't support wlr-screencopy: {e}"))?;
+		let shm: WlShm = globals.bind(&queue_handle, 1..=1, ())
+			.map_err(|e| tracing::error!("The Wayland compositor doesn't support shared memory: {e}"))?;
+
+		let mut state = State::default();
+		let outputs = globals.contents().with_list(|globals| {
+			globals.iter()
+				.filter(|global| global.interface == "wl_output")
+				.map(|global| (global.name, global.version))
+				.collect::<Vec<_>>()
+		});
+		for (index, (name, version)) in outputs.into_iter().enumerate() {
+			let output = globals.registry().bind::<WlOutput, _, _>(name, version.min(4), &queue_handle, index);
+			state.outputs.push((output, None));
+		}
+		queue.roundtrip(&mut state)
+			.map_err(|e| tracing::error!("Failed to receive the outputs of the Wayland compositor: {e}"))?;
+
+		let names: Vec<_> = state.outputs.iter().filter_map(|(_, name)| name.as_deref()).collect();
+		tracing::debug!("Found Wayland outputs: {names:?}.");
+		let output = match output_name {
+			Some(output_name) => state.outputs.iter()
+				.find(|(_, name)| name.as_deref() == Some(output_name))
+				.map(|(output, _)| output.clone())
+				.ok_or_else(|| tracing::error!("No Wayland output named '{output_name}', available outputs are: {names:?}."))?,
+			None => state.outputs.first()
+				.map(|(output, _)| output.clone())
+				.ok_or_else(|| tracing::error!("The Wayland compositor has no outputs to capture."))?,
+		};
+
+		// Ask for a frame without copying it, to learn the layout of the buffers the compositor wants.
+		let frame = manager.capture_output(1, &output, &queue_handle, ());
+		queue.roundtrip(&mut state)
+			.map_err(|e| tracing::error!("Failed to receive the buffer layout of the Wayland output: {e}"))?;
+		frame.destroy();
+		let buffer_info = state.buffer_info
+			.ok_or_else(|| tracing::error!("The Wayland compositor didn't offer a shared memory buffer to capture into."))?;
+
+		// Both formats are BGRA in memory, which is what the encoder expects.
+		let format = match buffer_info.format {
+			WEnum::Value(format @ (wl_shm::Format::Xrgb8888 | wl_shm::Format::Argb8888)) => format,
+			format => {
+				tracing::error!("The Wayland compositor captures in {format:?}, only XRGB8888 and ARGB8888 are supported.");
+				return Err(());
+			},
+		};
+
+		let size = buffer_info.stride as usize * buffer_info.height as usize;
+		let file = create_shared_memory(size)?;
+		let pool = shm.create_pool(file.as_fd(), size as i32, &queue_handle, ());
+		let buffer = pool.create_buffer(
+			0,
+			buffer_info.width as i32,
+			buffer_info.height as i32,
+			buffer_info.stride as i32,
+			format,
+			&queue_handle,
+			(),
+		);
+		pool.destroy();
+
+		tracing::info!(
+			"Capturing Wayland output {} of {}x{} with wlr-screencopy.",
+			output_name.unwrap_or("(first output)"), buffer_info.width, buffer_info.height,
+		);
+
+		Ok(Self {
+			queue,
+			state,
+			manager,
+			output,
+			buffer_info,
+			file,
+			buffer,
+			shared_image: vec![0; size],
+			image: vec![0; buffer_info.width as usize * buffer_info.height as usize * 4],
+		})
+	}
+
+	pub fn width(&self) -> u32 {
+		self.buffer_info.width
+	}
+
+	pub fn height(&self) -> u32 {
+		self.buffer_info.height
+	}
+
+	/// Capture frames at most at `framerate` until the stream stops.
+	///
+	/// While the output doesn't change, the last frame is repeated every `MAX_IDLE_INTERVAL` so that the encoder keeps receiving frames.
+	/// If a scaler is given, frames are scaled to the size of the buffers, otherwise the buffers must have the resolution of the output.
+	#[allow(clippy::too_many_arguments)]
+	pub fn run(
+		mut self,
+		framerate: u32,
+		mut scaler: Option<Scaler>,
+		mut capture_buffer: Frame,
+		frame_queue: &FrameQueue,
+		pause: &StreamPause,
+		stop_signal: &ShutdownManager<SessionShutdownReason>,
+	) -> Result<(), ()> {
+		tracing::info!("Started frame capture.");
+
+		let frame_interval = Duration::from_secs(1) / framerate.max(1);
+		let mut next_frame = Instant::now();
+		while !stop_signal.is_shutdown_triggered() {
+			pause.wait_while_paused(stop_signal);
+
+			let now = Instant::now();
+			if next_frame > now {
+				std::thread::sleep(next_frame - now);
+			}
+			next_frame = (next_frame + frame_interval).max(Instant::now());
+
+			// The image still holds the previous frame if the output didn't change.
+			let captured_at = self.capture()?.unwrap_or_else(Instant::now);
+
+			let (width, height) = (self.buffer_info.width, self.buffer_info.height);
+			if upload_frame(&self.image, width, height, scaler.as_mut(), &mut capture_buffer).is_err() {
+				continue;
+			}
+
+			// Hand the frame to the encoder, continuing with a free buffer.
+			frame_queue.push(&mut capture_buffer, captured_at)?;
+		}
+
+		tracing::debug!("Received stop signal.");
+
+		Ok(())
+	}
+
+	/// Let the compositor copy the next frame of the output into `image`, returning when it was captured.
+	///
+	/// Compositors that support it only copy the frame once the output changed.
+	/// Returns `None` if that didn't happen within `MAX_IDLE_INTERVAL`, in which case `image` is left as it was.
+	fn capture(&mut self) -> Result<Option<Instant>, ()> {
+		// Waiting for the output to change isn't part of the latency of the stream, so it is only measured without damage tracking.
+		let wait_for_damage = self.manager.version() >= 2;
+		let _timer = (!wait_for_damage).then(|| StageTimer::start(Stage::Capture));
+		self.state.buffer_info = None;
+		self.state.y_invert = false;
+		self.state.frame = FrameState::Pending;
+
+		let queue_handle = self.queue.handle();
+		let frame = self.manager.capture_output(1, &self.output, &queue_handle, ());
+		self.queue.roundtrip(&mut self.state)
+			.map_err(|e| tracing::error!("Failed to receive the buffer layout of the Wayland output: {e}"))?;
+
+		// The buffers are created for the original layout, so we can't continue with another one.
+		if self.state.buffer_info != Some(self.buffer_info) {
+			tracing::error!(
+				"Layout of the Wayland output changed from {:?} to {:?}, can't continue the stream.",
+				self.buffer_info, self.state.buffer_info,
+			);
+			frame.destroy();
+			return Err(());
+		}
+
+		if wait_for_damage {
+			frame.copy_with_damage(&self.buffer);
+		} else {
+			frame.copy(&self.buffer);
+		}
+
+		let copied = self.wait_for_frame(Instant::now() + MAX_IDLE_INTERVAL);
+		frame.destroy();
+		if !copied? {
+			return Ok(None);
+		}
+		let captured_at = Instant::now();
+
+		if self.state.frame == FrameState::Failed {
+			tracing::error!("The Wayland compositor failed to copy a frame, was the output disabled?");
+			return Err(());
+		}
+
+		self.file.read_exact_at(&mut self.shared_image, 0)
+			.map_err(|e| tracing::error!("Failed to read captured frame from shared memory: {e}"))?;
+		pack_rows(
+			&self.shared_image,
+			self.buffer_info.stride as usize,
+			self.buffer_info.width as usize * 4,
+			self.state.y_invert,
+			&mut self.image,
+		);
+
+		Ok(Some(captured_at))
+	}
+
+	/// Dispatch the events of the compositor until it copied the frame or failed to, returning `false` if that didn't happen before `deadline`.
+	fn wait_for_frame(&mut self, deadline: Instant) -> Result<bool, ()> {
+		loop {
+			self.queue.dispatch_pending(&mut self.state)
+				.map_err(|e| tracing::error!("Failed to wait for a frame of the Wayland output: {e}"))?;
+			if self.state.frame != FrameState::Pending {
+				return Ok(true);
+			}
+
+			self.queue.flush()
+				.map_err(|e| tracing::error!("Failed to send requests to the Wayland compositor: {e}"))?;
+			let Some(guard) = self.queue.prepare_read() else {
+				// Events arrived in the meantime, dispatch them first.
+				continue;
+			};
+
+			let timeout = deadline.saturating_duration_since(Instant::now());
+			if timeout.is_zero() {
+				return Ok(false);
+			}
+
+			let mut fds = [libc::pollfd { fd: guard.connection_fd().as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+			let result = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout.as_millis().max(1) as libc::c_int) };
+			if result < 0 {
+				let error = std::io::Error::last_os_error();
+				if error.kind() == ErrorKind::Interrupted {
+					continue;
+				}
+
+				tracing::error!("Failed to wait for events of the Wayland compositor: {error}");
+				return Err(());
+			}
+
+			if result > 0 {
+				match guard.read() {
+					Ok(_) => {},
+					Err(WaylandError::Io(e)) if e.kind() == ErrorKind::WouldBlock => {},
+					Err(e) => {
+						tracing::error!("Failed to read events of the Wayland compositor: {e}");
+						return Err(());
+					},
+				}
+			}
+		}
+	}
+}
+
+/// Copy the lines of `source`, which are `stride` bytes apart, into `destination` without padding.
+///
+/// The order of the lines is reversed if `y_invert` is set.
+fn pack_rows(source: &[u8], stride: usize, line_length: usize, y_invert: bool, destination: &mut [u8]) {
+	let lines = source.chunks(stride).map(|line| &line[..line_length.min(line.len())]);
+	let destination_lines = destination.chunks_exact_mut(line_length);
+	if y_invert {
+		for (destination, source) in destination_lines.rev().zip(lines) {
+			destination[..source.len()].copy_from_slice(source);
+		}
+	} else {
+		for (destination, source) in destination_lines.zip(lines) {
+			destination[..source.len()].copy_from_slice(source);
+		}
+	}
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for State {
+	fn event(_: &mut Self, _: &WlRegistry, _: wl_registry::Event, _: &GlobalListContents, _: &Connection, _: &QueueHandle<Self>) {
+		// Outputs that are added during a stream are not captured, so changes to the globals are ignored.
+	}
+}
+
+impl Dispatch<WlOutput, usize> for State {
+	fn event(state: &mut Self, _: &WlOutput, event: wl_output::Event, index: &usize, _: &Connection, _: &QueueHandle<Self>) {
+		if let wl_output::Event::Name { name } = event {
+			if let Some((_, output_name)) = state.outputs.get_mut(*index) {
+				*output_name = Some(name);
+			}
+		}
+	}
+}
+
+impl Dispatch<ZwlrScreencopyFrameV1, ()> for State {
+	fn event(state: &mut Self, _: &ZwlrScreencopyFrameV1, event: zwlr_screencopy_frame_v1::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {
+		match event {
+			zwlr_screencopy_frame_v1::Event::Buffer { format, width, height, stride } => {
+				state.buffer_info = Some(BufferInfo { format, width, height, stride });
+			},
+			zwlr_screencopy_frame_v1::Event::Flags { flags } => {
+				state.y_invert = matches!(flags, WEnum::Value(flags) if flags.contains(zwlr_screencopy_frame_v1::Flags::YInvert));
+			},
+			zwlr_screencopy_frame_v1::Event::Ready { .. } => state.frame = FrameState::Ready,
+			zwlr_screencopy_frame_v1::Event::Failed => state.frame = FrameState::Failed,
+			_ => {},
+		}
+	}
+}
+
+delegate_noop!(State: ZwlrScreencopyManagerV1);
+delegate_noop!(State: WlShmPool);
+delegate_noop!(State: ignore WlShm);
+delegate_noop!(State: ignore WlBuffer);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn padding_is_removed() {
+		let source = [1, 2, 0, 3, 4, 0];
+		let mut destination = [0; 4];
+		pack_rows(&source, 3, 2, false, &mut destination);
+		assert_eq!(destination, [1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn inverted_lines_are_flipped() {
+		let source = [1, 2, 0, 3, 4, 0];
+		let mut destination = [0; 4];
+		pack_rows(&source, 3, 2, true, &mut destination);
+		assert_eq!(destination, [3, 4, 1, 2]);
+	}
+}
@@ -0,0 +1,207 @@
+use std::{fs::File, os::{fd::OwnedFd, unix::fs::FileExt}, time::{Duration, Instant}};
+
+use async_shutdown::ShutdownManager;
+use ffmpeg::Frame;
+use x11rb::{
+	connection::{Connection, RequestConnection},
+	protocol::{damage::{self, ConnectionExt as _}, shm::{self, ConnectionExt as _}, xproto::{ImageFormat, Window}, Event},
+	rust_connection::RustConnection,
+	NONE,
+};
+
+use crate::{metrics::{Stage, StageTimer}, session::SessionShutdownReason};
+
+use super::{super::{super::StreamPause, queue::FrameQueue, scaler::Scaler}, create_shared_memory, upload_frame, MAX_IDLE_INTERVAL};
+
+/// Captures the X screen by letting the X server copy it into shared memory.
+///
+/// This works with any X server and GPU, but is slower than NvFBC since every frame passes through host memory.
+/// Frames are only captured when the damage extension reports that the screen changed.
+pub struct X11Shm {
+	connection: RustConnection,
+	root: Window,
+	width: u16,
+	height: u16,
+
+	/// Shared memory that the X server copies the screen into, and the segment that refers to it.
+	file: File,
+	segment: shm::Seg,
+
+	/// Tracks which parts of the screen changed since the last frame.
+	damage: damage::Damage,
+
+	/// The last frame, packed 32 bit BGRX.
+	image: Vec<u8>,
+}
+
+impl X11Shm {
+	/// Connect to the X server of `DISPLAY` and prepare capturing its screen.
+	pub fn new() -> Result<Self, ()> {
+		let (connection, screen_number) = x11rb::connect(None)
+			.map_err(|e| tracing::error!("Failed to connect to the X server: {e}"))?;
+		let screen = &connection.setup().roots[screen_number];
+		let (root, width, height, depth) = (screen.root, screen.width_in_pixels, screen.height_in_pixels, screen.root_depth);
+
+		// The encoder expects 32 bits per pixel, which is what X servers use for a depth of 24 and 32 bits.
+		let bits_per_pixel = connection.setup().pixmap_formats.iter()
+			.find(|format| format.depth == depth)
+			.map(|format| format.bits_per_pixel);
+		if bits_per_pixel != Some(32) {
+			tracing::error!("The X screen has a depth of {depth} bits with {bits_per_pixel:?} bits per pixel, only 32 bits per pixel are supported.");
+			return Err(());
+		}
+
+		// Passing a file descriptor to the X server needs version 1.2 of the shared memory extension.
+		if connection.extension_information(shm::X11_EXTENSION_NAME).ok().flatten().is_none() {
+			tracing::error!("The X server doesn't support the shared memory extension (MIT-SHM).");
+			return Err(());
+		}
+		let version = connection.shm_query_version()
+			.map_err(|e| tracing::error!("Failed to query the shared memory extension: {e}"))?
+			.reply()
+			.map_err(|e| tracing::error!("Failed to query the shared memory extension: {e}"))?;
+		if (version.major_version, version.minor_version) < (1, 2) {
+			tracing::error!(
+				"The X server supports version {}.{} of the shared memory extension, at least 1.2 is required.",
+				version.major_version, version.minor_version,
+			);
+			return Err(());
+		}
+
+		let size = width as usize * height as usize * 4;
+		let file = create_shared_memory(size)?;
+		let shared_file = file.try_clone()
+			.map_err(|e| tracing::error!("Failed to share memory with the X server: {e}"))?;
+		let segment = connection.generate_id()
+			.map_err(|e| tracing::error!("Failed to allocate a shared memory segment: {e}"))?;
+		connection.shm_attach_fd(segment, OwnedFd::from(shared_file), false)
+			.map_err(|e| tracing::error!("Failed to share memory with the X server: {e}"))?
+			.check()
+			.map_err(|e| tracing::error!("Failed to share memory with the X server: {e}"))?;
+
+		if connection.extension_information(damage::X11_EXTENSION_NAME).ok().flatten().is_none() {
+			tracing::error!("The X server doesn't support the damage extension.");
+			return Err(());
+		}
+		connection.damage_query_version(1, 1)
+			.map_err(|e| tracing::error!("Failed to query the damage extension: {e}"))?
+			.reply()
+			.map_err(|e| tracing::error!("Failed to query the damage extension: {e}"))?;
+		let damage = connection.generate_id()
+			.map_err(|e| tracing::error!("Failed to allocate a damage object: {e}"))?;
+		connection.damage_create(damage, root, damage::ReportLevel::NON_EMPTY)
+			.map_err(|e| tracing::error!("Failed to track changes to the X screen: {e}"))?
+			.check()
+			.map_err(|e| tracing::error!("Failed to track changes to the X screen: {e}"))?;
+
+		Ok(Self {
+			connection,
+			root,
+			width,
+			height,
+			file,
+			segment,
+			damage,
+			image: vec![0; size],
+		})
+	}
+
+	pub fn width(&self) -> u32 {
+		self.width as u32
+	}
+
+	pub fn height(&self) -> u32 {
+		self.height as u32
+	}
+
+	/// Capture frames at most at `framerate` until the stream stops.
+	///
+	/// If a scaler is given, frames are scaled to the size of the buffers, otherwise the buffers must have the resolution of the screen.
+	#[allow(clippy::too_many_arguments)]
+	pub fn run(
+		mut self,
+		framerate: u32,
+		mut scaler: Option<Scaler>,
+		mut capture_buffer: Frame,
+		frame_queue: &FrameQueue,
+		pause: &StreamPause,
+		stop_signal: &ShutdownManager<SessionShutdownReason>,
+	) -> Result<(), ()> {
+		tracing::info!("Started frame capture of the X screen through shared memory ({}x{}).", self.width, self.height);
+
+		let frame_interval = Duration::from_secs(1) / framerate.max(1);
+		let mut next_frame = Instant::now();
+		let mut last_capture: Option<Instant> = None;
+		while !stop_signal.is_shutdown_triggered() {
+			pause.wait_while_paused(stop_signal);
+
+			let now = Instant::now();
+			if next_frame > now {
+				std::thread::sleep(next_frame - now);
+			}
+			next_frame = (next_frame + frame_interval).max(Instant::now());
+
+			// Skip capturing while nothing changed, unless the encoder hasn't received a frame for a while.
+			let damaged = self.take_damage()?;
+			let idle = !last_capture.is_some_and(|last_capture| last_capture.elapsed() < MAX_IDLE_INTERVAL);
+			if !damaged && !idle {
+				continue;
+			}
+
+			let captured_at = self.capture()?;
+			last_capture = Some(captured_at);
+
+			if upload_frame(&self.image, self.width(), self.height(), scaler.as_mut(), &mut capture_buffer).is_err() {
+				continue;
+			}
+
+			// Hand the frame to the encoder, continuing with a free buffer.
+			frame_queue.push(&mut capture_buffer, captured_at)?;
+		}
+
+		tracing::debug!("Received stop signal.");
+
+		Ok(())
+	}
+
+	/// Whether the screen changed since the last call, which resets the tracked damage.
+	fn take_damage(&self) -> Result<bool, ()> {
+		let mut damaged = false;
+		while let Some(event) = self.connection.poll_for_event()
+			.map_err(|e| tracing::error!("Lost the connection to the X server: {e}"))?
+		{
+			damaged |= matches!(event, Event::DamageNotify(_));
+		}
+
+		// The damage has to be reset, otherwise the X server doesn't report new changes.
+		if damaged {
+			self.connection.damage_subtract(self.damage, NONE, NONE)
+				.map_err(|e| tracing::error!("Failed to reset the damage of the X screen: {e}"))?;
+		}
+
+		Ok(damaged)
+	}
+
+	/// Let the X server copy the screen into `image`, returning when it was captured.
+	fn capture(&mut self) -> Result<Instant, ()> {
+		let _timer = StageTimer::start(Stage::Capture);
+		self.connection.shm_get_image(self.root, 0, 0, self.width, self.height, !0, ImageFormat::Z_PIXMAP.into(), self.segment, 0)
+			.map_err(|e| tracing::error!("Failed to capture the X screen: {e}"))?
+			.reply()
+			.map_err(|e| tracing::error!("Failed to capture the X screen, did its resolution change? {e}"))?;
+		let captured_at = Instant::now();
+
+		self.file.read_exact_at(&mut self.image, 0)
+			.map_err(|e| tracing::error!("Failed to read captured frame from shared memory: {e}"))?;
+
+		Ok(captured_at)
+	}
+}
+
+impl Drop for X11Shm {
+	fn drop(&mut self) {
+		let _ = self.connection.damage_destroy(self.damage);
+		let _ = self.connection.shm_detach(self.segment);
+		let _ = self.connection.flush();
+	}
+}
@@ -1,80 +1,59 @@
-use std::{collections::{hash_map::Entry, HashMap}, sync::{atomic::Ordering, Arc, Mutex}};
+use std::{sync::{atomic::Ordering, mpsc::{Receiver, TryRecvError}, Arc}, time::{Duration, Instant}};
 
 use async_shutdown::ShutdownManager;
 use cudarc::driver::CudaDevice;
-use ffmpeg::{
-	codec::packet::flag::Flags, format::Pixel, option::Settable, Frame, Packet
-};
-use reed_solomon_erasure::{galois_8, ReedSolomon};
+use ffmpeg::{codec::packet::flag::Flags, format::Pixel, Frame, Packet};
 
-use crate::{ffmpeg::{hwdevice::CudaDeviceContextBuilder, hwframe::{HwFrameContext, HwFrameContextBuilder}}, session::stream::RtpHeader};
+use crate::{config::BitstreamConfig, ffmpeg::{encoder::{EncoderBuilder, EncoderProfile, NvencPreset, NvencTune, VideoCodec}, hwdevice::CudaDeviceContextBuilder, hwframe::{HwFrameContextBuilder, HwFramePool}}, metrics::{self, Stage, StageTimer}, session::{Recorder, SessionShutdownReason}};
+use super::{super::StreamPause, bitstream::BitstreamFilter, encryption::VideoEncryption, fec::AdaptiveFec, overlay::PerformanceOverlay, packetizer::Packetizer, queue::FrameQueue};
 
-/// Maximum allowed number of shards in the encoder (data + parity).
-pub const MAX_SHARDS: usize = 255;
-
-#[repr(u8)]
-enum RtpFlag {
-	ContainsPicData = 0x1,
-	EndOfFrame = 0x2,
-	StartOfFrame = 0x4,
-}
+/// Clock rate of the timestamps of frames, as used by RTP for video.
+const TIMESTAMP_CLOCK_RATE: u32 = 90_000;
 
+/// Commands for the encoding thread, handled before encoding the next frame.
 #[derive(Debug)]
-#[repr(C)]
-struct VideoFrameHeader {
-	header_type: u8,
-	padding1: u16,
-	frame_type: u8,
-	padding2: u32,
-}
+pub enum EncoderCommand {
+	/// Encode the next frame as an IDR frame.
+	RequestIdrFrame,
 
-impl VideoFrameHeader {
-	fn serialize(&self, buffer: &mut Vec<u8>) {
-		buffer.extend(self.header_type.to_le_bytes());
-		buffer.extend(self.padding1.to_le_bytes());
-		buffer.extend(self.frame_type.to_le_bytes());
-		buffer.extend(self.padding2.to_le_bytes());
-	}
-}
+	/// Show the performance overlay if it is hidden, hide it otherwise.
+	ToggleOverlay,
 
-#[derive(Debug)]
-#[repr(C)]
-struct NvVideoPacket {
-	stream_packet_index: u32,
-	frame_index: u32,
-	flags: u8,
-	reserved: u8,
-	multi_fec_flags: u8,
-	multi_fec_blocks: u8,
-	fec_info: u32,
-}
+	/// The client reported that it lost frames, which is shown on the performance overlay.
+	ReportLoss(u32),
 
-impl NvVideoPacket {
-	fn serialize(&self, buffer: &mut Vec<u8>) {
-		buffer.extend(self.stream_packet_index.to_le_bytes());
-		buffer.extend(self.frame_index.to_le_bytes());
-		buffer.extend(self.flags.to_le_bytes());
-		buffer.extend(self.reserved.to_le_bytes());
-		buffer.extend(self.multi_fec_flags.to_le_bytes());
-		buffer.extend(self.multi_fec_blocks.to_le_bytes());
-		buffer.extend(self.fec_info.to_le_bytes());
-	}
+	/// Stop encoding, the encoder also stops when all senders are dropped.
+	Stop,
 }
 
 pub struct Encoder {
+	cuda_device: Arc<CudaDevice>,
 	encoder: ffmpeg::encoder::Video,
-	pub hw_frame_context: HwFrameContext,
-	fec_encoders: HashMap<(usize, usize), ReedSolomon<galois_8::Field>>,
+	pub frame_pool: HwFramePool,
+
+	/// Minimum time between encoded frames, or `None` to encode frames as soon as they are captured.
+	frame_interval: Option<Duration>,
+
+	/// Adds the headers that clients need to the encoded frames.
+	bitstream_filter: BitstreamFilter,
 }
 
 impl Encoder {
+	#[allow(clippy::too_many_arguments)]
 	pub fn new(
-		cuda_device: &CudaDevice,
+		cuda_device: &Arc<CudaDevice>,
 		codec_name: &str,
+		codec: VideoCodec,
+		yuv444: bool,
 		width: u32,
 		height: u32,
 		framerate: u32,
 		bitrate: usize,
+		preset: NvencPreset,
+		tune: NvencTune,
+		profile: EncoderProfile,
+		variable_refresh_rate: bool,
+		bitstream: BitstreamConfig,
 	) -> Result<Self, ()> {
 		let cuda_device_context = CudaDeviceContextBuilder::new()
 			.map_err(|e| tracing::error!("Failed to create CUDA device context: {e}"))?
@@ -83,7 +62,7 @@ impl Encoder {
 			.map_err(|e| tracing::error!("Failed to build CUDA device context: {e}"))?
 		;
 
-		let mut hw_frame_context = HwFrameContextBuilder::new(cuda_device_context)
+		let hw_frame_context = HwFrameContextBuilder::new(cuda_device_context)
 			.map_err(|e| tracing::error!("Failed to create CUDA frame context: {e}"))?
 			.set_width(width)
 			.set_height(height)
@@ -93,117 +72,163 @@ impl Encoder {
 			.map_err(|e| tracing::error!("Failed to build CUDA frame context: {e}"))?
 		;
 
-		tracing::info!("Using codec with name '{codec_name}'.");
-		let codec = ffmpeg::encoder::find_by_name(codec_name)
-			.ok_or_else(|| tracing::error!("Failed to find codec by name '{codec_name}'."))?;
-
-		let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
-			.encoder()
-			.video()
-			.map_err(|e| tracing::error!("Failed to create video encoder: {e}"))?;
-
-		encoder.set_width(width);
-		encoder.set_height(height);
-		encoder.set_frame_rate(Some((framerate as i32, 1)));
-		encoder.set_time_base((framerate as i32, 1));
-		encoder.set_max_b_frames(0);
-		encoder.set_bit_rate(bitrate);
-		encoder.set_gop(i32::MAX as u32);
-		unsafe {
-			(*encoder.as_mut_ptr()).pix_fmt = Pixel::CUDA.into();
-			(*encoder.as_mut_ptr()).hw_frames_ctx = hw_frame_context.as_raw_mut();
-			(*encoder.as_mut_ptr()).refs = 0;
-		}
-		encoder.set_str("preset", "fast")
-			.map_err(|e| tracing::error!("Failed to set preset for encoder: {e}"))?;
-		encoder.set_str("tune", "ull")
-			.map_err(|e| tracing::error!("Failed to set tuning option for encoder: {e}"))?;
-		encoder.set_str("forced-idr", "1")
-			.map_err(|e| tracing::error!("Failed to set forced-idr for encoder: {e}"))?;
+		tracing::info!(
+			"Using codec with name '{codec_name}' with the {profile:?} profile, in {}.",
+			if yuv444 { "4:4:4" } else { "4:2:0" },
+		);
+		let build = |profile: EncoderProfile| -> Result<ffmpeg::encoder::Video, ()> {
+			let mut encoder = EncoderBuilder::new(codec_name)
+				.map_err(|e| tracing::error!("Failed to create video encoder: {e}"))?
+				.set_width(width)
+				.set_height(height)
+				.set_framerate(framerate);
+
+			// Frames are timestamped with the time they were captured, instead of being numbered.
+			if variable_refresh_rate {
+				encoder = encoder.set_time_base(TIMESTAMP_CLOCK_RATE);
+			}
 
-		let encoder = encoder.open()
-			.map_err(|e| tracing::error!("Failed to start encoder: {e}"))?;
+			encoder
+				.set_bitrate(bitrate)
+				.set_codec_profile(codec, yuv444)
+				.map_err(|e| tracing::error!("Failed to set {codec:?} profile for encoder: {e}"))?
+				.set_level(codec, width, height, framerate, bitrate, yuv444)
+				.map_err(|e| tracing::error!("Failed to set {codec:?} level for encoder: {e}"))?
+				.set_low_latency()
+				.set_hw_frame_context(&hw_frame_context)
+				.map_err(|e| tracing::error!("Failed to set CUDA frame context for encoder: {e}"))?
+				.set_preset(preset)
+				.map_err(|e| tracing::error!("Failed to set preset for encoder: {e}"))?
+				.set_tune(tune)
+				.map_err(|e| tracing::error!("Failed to set tuning option for encoder: {e}"))?
+				.set_profile(profile)
+				.map_err(|e| tracing::error!("Failed to set options of the {profile:?} profile for encoder: {e}"))?
+				.set_forced_idr(true)
+				.map_err(|e| tracing::error!("Failed to set forced-idr for encoder: {e}"))?
+				.build()
+				.map_err(|e| tracing::error!("Failed to start encoder: {e}"))
+		};
+
+		// Not every GPU supports the options of every profile, those can still stream without them.
+		let encoder = match build(profile) {
+			Ok(encoder) => encoder,
+			Err(()) if profile != EncoderProfile::Game => {
+				tracing::warn!("Failed to start encoder with the {profile:?} profile, starting it without the options of the profile.");
+				build(EncoderProfile::Game)?
+			},
+			Err(()) => return Err(()),
+		};
+
+		let frame_interval = if variable_refresh_rate {
+			tracing::info!("Encoding frames as they are captured, at most {framerate} frames per second.");
+			None
+		} else {
+			tracing::info!("Encoding {framerate} frames per second.");
+			Some(Duration::from_secs(1) / framerate.max(1))
+		};
 
 		Ok(Self {
+			cuda_device: cuda_device.clone(),
 			encoder,
-			hw_frame_context,
-			fec_encoders: HashMap::new(),
+			frame_pool: HwFramePool::new(hw_frame_context),
+			frame_interval,
+			bitstream_filter: BitstreamFilter::new(codec, bitstream),
 		})
 	}
 
+	/// Parameters of the encoded stream, used to describe the stream in a recording.
+	pub fn parameters(&self) -> ffmpeg::codec::Parameters {
+		ffmpeg::codec::Parameters::from(&self.encoder)
+	}
+
 	#[allow(clippy::too_many_arguments)] // TODO: Problem for later..
 	pub fn run(
 		mut self,
 		packet_tx: tokio::sync::mpsc::Sender<Vec<u8>>,
-		mut idr_frame_request_rx: tokio::sync::broadcast::Receiver<()>,
+		recorder: Option<Recorder>,
+		command_rx: Receiver<EncoderCommand>,
 		packet_size: usize,
 		minimum_fec_packets: u32,
 		fec_percentage: u8,
+		adaptive_fec: Option<AdaptiveFec>,
+		mut encryption: Option<VideoEncryption>,
+		last_key_frame: Arc<std::sync::atomic::AtomicU32>,
 		mut encoder_buffer: Frame,
-		intermediate_buffer: Arc<Mutex<Frame>>,
-		captured_frame_number: Arc<std::sync::atomic::AtomicU32>,
-		frame_notifier: Arc<std::sync::Condvar>,
-		stop_signal: ShutdownManager<()>,
+		frame_queue: Arc<FrameQueue>,
+		pause: StreamPause,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
 	) {
 		let mut packet = Packet::empty();
 
-		// The last frame number we used.
-		let mut current_captured_frame_number = 0;
-
 		// The sequential frame number for sending to the client.
 		let mut frame_number = 0;
 
-		let mut sequence_number = 0u32;
-		let stream_start_time = std::time::Instant::now();
-		while !stop_signal.is_shutdown_triggered() {
-			// Swap the intermediate buffer with the output buffer.
-			// Note that the lock is only held while swapping buffers, to minimize wait time for others locking the buffer.
-			{
-				tracing::trace!("Checking for new frame.");
-
-				// Acquire the lock for a new frame.
-				let mut lock = match intermediate_buffer.lock() {
-					Ok(lock) => lock,
-					Err(e) => {
-						tracing::error!("Failed to acquire buffer lock: {e}");
-						continue;
-					},
-				};
-
-				// Check if we missed a frame, in that case we don't need to wait for a new frame notification.
-				let captured_frame_number = captured_frame_number.load(Ordering::Relaxed);
-				if captured_frame_number == 0 || captured_frame_number == current_captured_frame_number + 1 {
-					// Realistically we can wait indefinitely, but it feels safer to have a timeout just in case.
-					let mut lock = match frame_notifier.wait_timeout(lock, std::time::Duration::from_secs(5)) {
-						Ok(result) => result,
-						Err(e) => {
-							tracing::error!("Failed to wait for new frame: {e}");
-							continue;
-						},
-					};
+		// Time at which the frame that is being encoded was captured.
+		let mut captured_at = Instant::now();
 
-					// Didn't get a lock, let's check shutdown status and try again.
-					if lock.1.timed_out() {
-						tracing::warn!("Failed to acquire lock for frame buffer.");
-						continue;
-					}
+		// Time at which we started encoding the previous frame.
+		let mut previous_frame_at: Option<Instant> = None;
 
-					tracing::trace!("Received notification for a new frame.");
-					std::mem::swap(&mut *lock.0, &mut encoder_buffer);
-				} else {
-					tracing::debug!("We missed {} frame notification(s), continuing with newest frame.", captured_frame_number - current_captured_frame_number);
-					std::mem::swap(&mut *lock, &mut encoder_buffer);
-				}
+		// Timestamp of the previous frame, timestamps have to increase with every frame.
+		let mut previous_timestamp: Option<u32> = None;
+
+		// Shown when the client toggles it, created when it is first shown.
+		let mut overlay: Option<PerformanceOverlay> = None;
 
-				current_captured_frame_number = captured_frame_number;
+		// Number of captured frames that were dropped, as far as the overlay knows.
+		let mut dropped_frames = 0;
+
+		let mut packetizer = Packetizer::new(packet_size, minimum_fec_packets, fec_percentage);
+		let stream_start_time = Instant::now();
+		while !stop_signal.is_shutdown_triggered() {
+			// Nothing is captured while the stream is paused, the client gets a key frame to continue from when it resumes.
+			let resumed = pause.wait_while_paused(&stop_signal);
+
+			// Frames that are captured faster than the client asked for wait in the queue (or are dropped) until the next interval.
+			if let (Some(frame_interval), Some(previous_frame_at)) = (self.frame_interval, previous_frame_at) {
+				let next_frame_at = previous_frame_at + frame_interval;
+				let now = Instant::now();
+				if next_frame_at > now {
+					std::thread::sleep(next_frame_at - now);
+				}
 			}
 
+			// Take the next captured frame, the capture thread drops frames when we fall behind.
+			// Realistically we can wait indefinitely, but it feels safer to have a timeout just in case.
+			tracing::trace!("Checking for new frame.");
+			captured_at = match frame_queue.pop(&mut encoder_buffer, Duration::from_secs(5)) {
+				Ok(Some(captured_at)) => captured_at,
+				Ok(None) => {
+					if !pause.is_paused() {
+						tracing::warn!("No frame was captured for 5 seconds.");
+					}
+					continue;
+				},
+				Err(()) => continue,
+			};
+
 			frame_number += 1;
+			previous_frame_at = Some(Instant::now());
+
+			// Timestamp the frame with the time it was captured, so that the client can present frames at the right time.
+			let mut timestamp = (captured_at.saturating_duration_since(stream_start_time).as_micros()
+				* TIMESTAMP_CLOCK_RATE as u128 / 1_000_000) as u32;
+			if let Some(previous_timestamp) = previous_timestamp {
+				timestamp = timestamp.max(previous_timestamp + 1);
+			}
+			previous_timestamp = Some(timestamp);
 
-			tracing::trace!("Swapped new frame with old frame.");
-			encoder_buffer.set_pts(Some(frame_number as i64));
+			tracing::trace!("Took new frame from the queue.");
+			if self.frame_interval.is_some() {
+				encoder_buffer.set_pts(Some(frame_number as i64));
+			} else {
+				encoder_buffer.set_pts(Some(timestamp as i64));
+			}
 
 			tracing::trace!("Sending frame {} to encoder", frame_number);
+			if let Some(adaptive_fec) = &adaptive_fec {
+				packetizer.set_fec_percentage(adaptive_fec.percentage());
+			}
 
 			// TODO: Check if this is necessary?
 			// Reset possible previous request for keyframe.
@@ -212,25 +237,56 @@ impl Encoder {
 				(*encoder_buffer.as_mut_ptr()).key_frame = 0;
 			}
 
-			// Check if there was an IDR frame request.
-			match idr_frame_request_rx.try_recv() {
-				Ok(_) => {
-					tracing::debug!("Received request for IDR frame.");
-					unsafe {
-						(*encoder_buffer.as_mut_ptr()).pict_type = ffmpeg::picture::Type::I.into();
-						(*encoder_buffer.as_mut_ptr()).key_frame = 1;
-					}
-				},
-				Err(tokio::sync::broadcast::error::TryRecvError::Empty) => {},
-				Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => {},
-				Err(_) => {
-					tracing::debug!("Channel closed, quitting encoder task.");
-					return;
+			// Handle the commands that arrived since the previous frame.
+			let mut request_idr_frame = resumed;
+			loop {
+				match command_rx.try_recv() {
+					Ok(EncoderCommand::RequestIdrFrame) => request_idr_frame = true,
+					Ok(EncoderCommand::ToggleOverlay) => {
+						if overlay.take().is_some() {
+							tracing::info!("Hiding performance overlay.");
+						} else {
+							tracing::info!("Showing performance overlay.");
+							overlay = self.cuda_device.bind_to_thread()
+								.map_err(|e| tracing::error!("Failed to bind CUDA device to thread: {e}"))
+								.and_then(|()| PerformanceOverlay::new(self.cuda_device.clone()))
+								.ok();
+						}
+					},
+					Ok(EncoderCommand::ReportLoss(lost_frames)) => {
+						if let Some(overlay) = &mut overlay {
+							overlay.on_loss(lost_frames);
+						}
+					},
+					Ok(EncoderCommand::Stop) | Err(TryRecvError::Disconnected) => {
+						tracing::debug!("Encoder stopped, quitting encoder task.");
+						return;
+					},
+					Err(TryRecvError::Empty) => break,
+				}
+			}
+
+			if request_idr_frame {
+				tracing::debug!("Received request for IDR frame.");
+				unsafe {
+					(*encoder_buffer.as_mut_ptr()).pict_type = ffmpeg::picture::Type::I.into();
+					(*encoder_buffer.as_mut_ptr()).key_frame = 1;
+				}
+			}
+
+			if let Some(frame_overlay) = &mut overlay {
+				let statistics = frame_queue.statistics();
+				frame_overlay.on_dropped(statistics.dropped - dropped_frames);
+				dropped_frames = statistics.dropped;
+				if frame_overlay.draw(&mut encoder_buffer).is_err() {
+					tracing::warn!("Failed to draw performance overlay, hiding it.");
+					overlay = None;
 				}
 			}
 
 			// Send the frame to the encoder.
 			tracing::trace!("Sending frame {}", frame_number);
+			let encode_start = Instant::now();
 			if let Err(e) = self.encoder.send_frame(&encoder_buffer) {
 				tracing::error!("Error sending frame for encoding: {e}");
 				continue;
@@ -240,18 +296,26 @@ impl Encoder {
 				match self.encoder.receive_packet(&mut packet) {
 					Ok(()) => {
 						tracing::trace!("Received frame {} from encoder, converting frame to packets.", packet.pts().unwrap_or(-1));
-						if self.encode_packet(
+						metrics::record(Stage::Encode, encode_start.elapsed());
+						if send_packet(
 							&packet,
+							&mut self.bitstream_filter,
+							&mut packetizer,
+							encryption.as_mut(),
 							&packet_tx,
-							packet_size,
-							minimum_fec_packets,
-							fec_percentage,
+							recorder.as_ref(),
 							frame_number,
-							&mut sequence_number,
-							stream_start_time,
+							timestamp,
+							captured_at,
 						).is_err() {
 							continue;
 						}
+						if packet.flags().contains(Flags::KEY) {
+							last_key_frame.store(frame_number, Ordering::Relaxed);
+						}
+						if let Some(overlay) = &mut overlay {
+							overlay.on_frame_encoded(packet.size(), encode_start.elapsed());
+						}
 						tracing::trace!("Done converting frame {} to packets.", packet.pts().unwrap_or(-1));
 					},
 					Err(e) => {
@@ -276,203 +340,46 @@ impl Encoder {
 
 		tracing::debug!("Received stop signal.");
 	}
+}
 
-	#[allow(clippy::too_many_arguments)] // TODO: Problem for later..
-	fn encode_packet(
-		&mut self,
-		packet: &Packet,
-		packet_tx: &tokio::sync::mpsc::Sender<Vec<u8>>,
-		requested_packet_size: usize,
-		minimum_fec_packets: u32,
-		fec_percentage: u8,
-		frame_number: u32,
-		sequence_number: &mut u32,
-		stream_start_time: std::time::Instant,
-	) -> Result<(), ()> {
-		// Random padding, because we need it.
-		const PADDING: u32 = 0;
-
-		let timestamp = ((std::time::Instant::now() - stream_start_time).as_micros() / (1000 / 90)) as u32;
-
-		// TODO: Figure out what this header means?
-		let video_frame_header = VideoFrameHeader {
-			header_type: 0x01, // Always 0x01 for short headers. What is this exactly?
-			padding1: 0,
-			frame_type: if packet.flags().contains(Flags::KEY) { 2 } else { 1 },
-			padding2: 0,
-		};
-
-		// Prefix the frame with a VideoFrameHeader.
-		let mut buffer = Vec::with_capacity(std::mem::size_of::<VideoFrameHeader>());
-		video_frame_header.serialize(&mut buffer);
-		let packet_data = packet.data()
-			.ok_or_else(|| tracing::error!("Packet is empty, but we expected it to be full."))?;
-		let packet_data = [&buffer, packet_data].concat();
-
-		let requested_shard_payload_size = requested_packet_size - std::mem::size_of::<NvVideoPacket>();
-
-		// The total size of a shard.
-		let requested_shard_size =
-			std::mem::size_of::<RtpHeader>()
-			+ std::mem::size_of_val(&PADDING)
-			+ std::mem::size_of::<NvVideoPacket>()
-			+ requested_shard_payload_size;
-
-		// Determine how many data shards we will be sending.
-		let nr_data_shards = packet_data.len() / requested_shard_payload_size + (packet_data.len() % requested_shard_payload_size != 0) as usize; // TODO: Replace with div_ceil when it lands in stable (https://doc.rust-lang.org/std/primitive.i32.html#method.div_ceil).
-		assert!(nr_data_shards != 0);
-
-		// Determine how many parity and data shards are permitted per FEC block.
-		let nr_parity_shards_per_block = MAX_SHARDS * fec_percentage as usize / (100 + fec_percentage as usize);
-		let nr_data_shards_per_block = MAX_SHARDS - nr_parity_shards_per_block;
-
-		// We need to subtract number of data shards by 1, otherwise you can get a situation where
-		// there are for example 100 data shards allowed per block and also 100 data shards available.
-		// In this case, nr_blocks = 100 / 100 + 1 = 2, but we only need to send 1 block.
-		// Subtracting the value of nr_data_shards by 1 avoids this situation.
-		let nr_blocks = (nr_data_shards - 1) / nr_data_shards_per_block + 1;
-		let last_block_index = (nr_blocks.min(4) as u8 - 1) << 6; // TODO: Why the bit shift? To 'force' a limit of 4 blocks?
-
-		tracing::trace!("Sending a max of {nr_data_shards_per_block} data shards and {nr_parity_shards_per_block} parity shards per block.");
-		tracing::trace!("Sending {nr_blocks} blocks of video data.");
-
-		for block_index in 0..nr_blocks {
-			// Determine what data shards are in this block.
-			let start = block_index * nr_data_shards_per_block;
-			let mut end = ((block_index + 1) * nr_data_shards_per_block)
-				.min(nr_data_shards);
-
-			if block_index == 3 {
-				tracing::debug!("Trying to create {nr_blocks} blocks, but we are limited to 4 blocks so we are sending all remaining packets without FEC.");
-				end = nr_data_shards;
-			}
-
-			// Compute how many parity shards we will need (approximately) in this block.
-			let nr_data_shards = end - start;
-			assert!(nr_data_shards != 0);
-
-			let nr_parity_shards = (nr_data_shards * fec_percentage as usize / 100)
-				.max(minimum_fec_packets as usize) // Lower limit by the minimum number of parity shards.
-				.min(MAX_SHARDS.saturating_sub(nr_data_shards)); // But hard total upper limit in the number of shards.
-
-			// Create the FEC encoder for this amount of shards.
-			let encoder = if nr_parity_shards > 0 {
-				Some(self.get_fec_encoder(nr_data_shards, nr_parity_shards)?)
-			} else {
-				None
-			};
-
-			// Recompute the actual FEC percentage in case of a rounding error or when there are 0 parity shards.
-			let fec_percentage = nr_parity_shards * 100 / nr_data_shards;
-
-			tracing::trace!("Sending block {block_index} with {nr_data_shards} data shards and {nr_parity_shards} parity shards.");
-
-			let mut shards = Vec::with_capacity(nr_data_shards + nr_parity_shards);
-			for (block_shard_index, data_shard_index) in (start..end).enumerate() {
-				// Determine which part of the payload is in this shard.
-				let start = data_shard_index * requested_shard_payload_size;
-				let end = ((data_shard_index + 1) * requested_shard_payload_size).min(packet_data.len());
-
-				let mut shard = Vec::with_capacity(requested_shard_size);
-
-				let rtp_header = RtpHeader {
-					header: 0x90, // What is this?
-					packet_type: 0,
-					sequence_number: *sequence_number as u16,
-					timestamp,
-					ssrc: 0,
-				};
-				rtp_header.serialize(&mut shard);
-				shard.extend(PADDING.to_le_bytes());
-
-				let mut video_packet_header = NvVideoPacket {
-					stream_packet_index: *sequence_number << 8,
-					frame_index: frame_number,
-					flags: RtpFlag::ContainsPicData as u8,
-					reserved: 0,
-					multi_fec_flags: 0x10,
-					multi_fec_blocks: ((block_index as u8) << 4) | last_block_index,
-					fec_info: (block_shard_index << 12 | nr_data_shards << 22 | fec_percentage << 4) as u32,
-				};
-				if block_shard_index == 0 {
-					video_packet_header.flags |= RtpFlag::StartOfFrame as u8;
-				}
-				if block_shard_index == nr_data_shards - 1 {
-					video_packet_header.flags |= RtpFlag::EndOfFrame as u8;
-				}
-				video_packet_header.serialize(&mut shard);
-
-				// Append the payload.
-				shard.extend(&packet_data[start..end]);
-
-				// Pad with zeros at the end to make an equally sized shard.
-				if end - start < requested_shard_payload_size {
-					shard.extend(vec![0u8; requested_shard_payload_size - (end - start)]);
-				}
-
-				shards.push(shard);
-
-				*sequence_number += 1;
-			}
-
-			if let Some(encoder) = encoder {
-				for _ in 0..nr_parity_shards {
-					shards.push(vec![0u8; requested_shard_size]);
-				}
-
-				encoder.encode(&mut shards)
-					.map_err(|e| tracing::error!("Failed to encode packet as FEC shards: {e}"))?;
-
-				// Force these values for the parity shards, we don't need to reconstruct them, but Moonlight needs them to match with the frame they came from.
-				for (block_shard_index, shard) in shards[nr_data_shards..].iter_mut().enumerate() {
-					let rtp_header = unsafe { &mut *(shard.as_mut_ptr() as *mut RtpHeader) };
-					rtp_header.header = 0x90u8.to_be(); // The `.to_be` is redundant for u8, but is there to make it clear it should be big-endian.
-					rtp_header.sequence_number = (*sequence_number as u16).to_be();
-
-					let video_packet_header = unsafe {
-						&mut *(shard.as_mut_ptr().add(std::mem::size_of::<RtpHeader>() + std::mem::size_of_val(&PADDING)) as *mut NvVideoPacket)
-					};
-					video_packet_header.multi_fec_blocks = ((block_index as u8) << 4) | last_block_index;
-					video_packet_header.fec_info = ((nr_data_shards + block_shard_index) << 12 | nr_data_shards << 22 | fec_percentage << 4) as u32;
-					video_packet_header.frame_index = frame_number;
-
-					*sequence_number += 1;
-				}
-			}
-
-			for (index, shard) in shards.into_iter().enumerate() {
-				tracing::trace!("Sending shard {}/{} with size {} bytes.", index + 1, nr_data_shards + nr_parity_shards, shard.len());
-				if packet_tx.blocking_send(shard).is_err() {
-					tracing::info!("Channel closed, couldn't send packet.");
-					return Ok(());
-				}
-			}
-
-			tracing::trace!("Finished sending frame {frame_number}.");
+/// Split an encoded packet in shards and send them to the client, and to the recorder if the session is recorded.
+fn send_packet(
+	packet: &Packet,
+	bitstream_filter: &mut BitstreamFilter,
+	packetizer: &mut Packetizer,
+	mut encryption: Option<&mut VideoEncryption>,
+	packet_tx: &tokio::sync::mpsc::Sender<Vec<u8>>,
+	recorder: Option<&Recorder>,
+	frame_number: u32,
+	timestamp: u32,
+	captured_at: Instant,
+) -> Result<(), ()> {
+	let processing_latency = (captured_at.elapsed().as_micros() / 100).min(u16::MAX as u128) as u16;
+	let packet_data = packet.data()
+		.ok_or_else(|| tracing::error!("Packet is empty, but we expected it to be full."))?;
+
+	let key_frame = packet.flags().contains(Flags::KEY);
+	if let Some(recorder) = recorder {
+		recorder.video(packet_data, key_frame);
+	}
 
-			// At this point we should have sent all the data shards in the last block, so we can break the loop.
-			if block_index == 3 {
-				break;
-			}
+	let packet_data = bitstream_filter.filter(packet_data, key_frame);
+	let packetize_timer = StageTimer::start(Stage::Packetize);
+	let shards = packetizer.packetize(packet_data, key_frame, frame_number, timestamp, processing_latency)?;
+	drop(packetize_timer);
+	let nr_shards = shards.len();
+	for (index, shard) in shards.into_iter().enumerate() {
+		tracing::trace!("Sending shard {}/{nr_shards} with size {} bytes.", index + 1, shard.len());
+		let shard = match encryption.as_deref_mut() {
+			Some(encryption) => encryption.encrypt(&shard, frame_number)?,
+			None => shard,
+		};
+		if packet_tx.blocking_send(shard).is_err() {
+			tracing::info!("Channel closed, couldn't send packet.");
+			return Ok(());
 		}
-
-		Ok(())
 	}
 
-	fn get_fec_encoder(&mut self, nr_data_shards: usize, nr_parity_shards: usize) -> Result<&mut ReedSolomon<galois_8::Field>, ()> {
-		Ok(match self.fec_encoders.entry((nr_data_shards, nr_parity_shards)) {
-			Entry::Occupied(e) => {
-				tracing::trace!("Found a FEC encoder for this combination of shards.");
-				e.into_mut()
-			},
-			Entry::Vacant(e) => {
-				tracing::trace!("No FEC encoder for this combination of shards, creating a new one.");
-				let encoder = e.insert(ReedSolomon::<galois_8::Field>::new(nr_data_shards, nr_parity_shards)
-					.map_err(|e| tracing::error!("Couldn't create error correction encoder: {e}"))?);
-				tracing::trace!("Finished preparing FEC encoder.");
-
-				encoder
-			}
-		})
-	}
+	tracing::trace!("Finished sending frame {frame_number}.");
+	Ok(())
 }
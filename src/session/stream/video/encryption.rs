@@ -0,0 +1,80 @@
+use openssl::symm::Cipher;
+
+use crate::session::{stream::nonce::{video_iv, VIDEO_IV_LENGTH}, SharedSessionKeys};
+
+/// Length of the authentication tag of an encrypted video packet.
+const TAG_LENGTH: usize = 16;
+
+/// Length of the header in front of an encrypted video packet: the initialization vector, the frame number and the tag.
+pub const HEADER_LENGTH: usize = VIDEO_IV_LENGTH + 4 + TAG_LENGTH;
+
+/// Encrypts the packets of the video stream with AES-GCM, using the key that the client sent when it launched the session.
+///
+/// Every packet is prefixed with the initialization vector, the number of its frame and the tag.
+/// The frame number is sent in the clear, so that the client can drop packets of old frames without decrypting them.
+pub struct VideoEncryption {
+	keys: SharedSessionKeys,
+
+	/// Counter of the initialization vector of the next packet.
+	counter: u64,
+}
+
+impl VideoEncryption {
+	pub fn new(keys: SharedSessionKeys) -> Self {
+		Self { keys, counter: 0 }
+	}
+
+	/// Encrypt a packet of frame `frame_number`, including its RTP header.
+	pub fn encrypt(&mut self, packet: &[u8], frame_number: u32) -> Result<Vec<u8>, ()> {
+		let (_, keys) = self.keys.current()?;
+		let iv = video_iv(self.counter);
+		self.counter += 1;
+
+		let mut tag = [0u8; TAG_LENGTH];
+		let encrypted = openssl::symm::encrypt_aead(
+			Cipher::aes_128_gcm(),
+			&keys.remote_input_key,
+			Some(&iv),
+			&[],
+			packet,
+			&mut tag,
+		)
+			.map_err(|e| tracing::error!("Failed to encrypt video packet: {e}"))?;
+
+		let mut buffer = Vec::with_capacity(HEADER_LENGTH + encrypted.len());
+		buffer.extend(iv);
+		buffer.extend(frame_number.to_le_bytes());
+		buffer.extend(tag);
+		buffer.extend(encrypted);
+
+		Ok(buffer)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use openssl::symm::decrypt_aead;
+
+	use crate::session::SessionKeys;
+
+	use super::*;
+
+	#[test]
+	fn packet_is_prefixed_with_header() {
+		let key = vec![7u8; 16];
+		let mut encryption = VideoEncryption::new(SharedSessionKeys::new(SessionKeys { remote_input_key: key.clone(), remote_input_key_id: 0 }));
+		let packet = b"moonshine video packet";
+		let first = encryption.encrypt(packet, 0x01020304).unwrap();
+		let second = encryption.encrypt(packet, 0x01020304).unwrap();
+
+		assert_eq!(first.len(), HEADER_LENGTH + packet.len());
+		assert_eq!(first[..VIDEO_IV_LENGTH], video_iv(0));
+		assert_eq!(second[..VIDEO_IV_LENGTH], video_iv(1));
+		assert_eq!(first[VIDEO_IV_LENGTH..VIDEO_IV_LENGTH + 4], [0x04, 0x03, 0x02, 0x01]);
+
+		let (iv, rest) = first.split_at(VIDEO_IV_LENGTH);
+		let (tag, ciphertext) = rest[4..].split_at(TAG_LENGTH);
+		let decrypted = decrypt_aead(Cipher::aes_128_gcm(), &key, Some(iv), &[], ciphertext, tag).unwrap();
+		assert_eq!(decrypted, packet);
+	}
+}
@@ -0,0 +1,140 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::config::AdaptiveFecConfig;
+
+/// Percentage points the parity is raised by when the client reports loss.
+const INCREASE_STEP: f64 = 10.0;
+
+/// Loss that is reported within this time after an increase is assumed to be the same loss.
+///
+/// The client can report a single loss more than once (for example in its loss statistics and
+/// when asking to invalidate reference frames), which shouldn't raise the parity twice.
+const INCREASE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Percentage points per second that the parity is lowered by while the client reports no loss.
+const DECREASE_PER_SECOND: f64 = 2.0;
+
+/// Percentage of parity shards that follows the loss that the client reports.
+///
+/// Loss raises the percentage in steps, after which it slowly goes back down while the network is clean.
+/// Shared between the control stream, which reports loss, and the encoder, which packetizes the frames.
+#[derive(Clone)]
+pub struct AdaptiveFec(Arc<Mutex<AdaptiveFecState>>);
+
+struct AdaptiveFecState {
+	minimum: f64,
+	maximum: f64,
+
+	/// Current percentage of parity shards.
+	percentage: f64,
+
+	/// Moment at which the percentage was last lowered.
+	updated_at: Instant,
+
+	/// Moment at which the percentage was last raised.
+	increased_at: Option<Instant>,
+}
+
+impl AdaptiveFec {
+	/// Start at `fec_percentage`, moving between the bounds in `config`.
+	pub fn new(fec_percentage: u8, config: &AdaptiveFecConfig) -> Self {
+		let minimum = config.minimum_percentage.min(config.maximum_percentage) as f64;
+		let maximum = config.maximum_percentage as f64;
+		Self(Arc::new(Mutex::new(AdaptiveFecState {
+			minimum,
+			maximum,
+			percentage: (fec_percentage as f64).clamp(minimum, maximum),
+			updated_at: Instant::now(),
+			increased_at: None,
+		})))
+	}
+
+	/// The client reported that it lost frames.
+	pub fn report_loss(&self, lost_frames: u32) {
+		self.report_loss_at(lost_frames, Instant::now());
+	}
+
+	/// Percentage of parity shards to use for the next frame.
+	pub fn percentage(&self) -> u8 {
+		self.percentage_at(Instant::now())
+	}
+
+	fn report_loss_at(&self, lost_frames: u32, now: Instant) {
+		let Ok(mut state) = self.0.lock() else {
+			return;
+		};
+
+		if lost_frames == 0 || state.increased_at.is_some_and(|increased_at| now.saturating_duration_since(increased_at) < INCREASE_INTERVAL) {
+			return;
+		}
+
+		state.decay(now);
+		let previous = state.percentage;
+		state.percentage = (state.percentage + INCREASE_STEP).min(state.maximum);
+		state.increased_at = Some(now);
+		if state.percentage != previous {
+			tracing::debug!("Client lost {lost_frames} frame(s), raising FEC percentage to {:.0}%.", state.percentage);
+		}
+	}
+
+	fn percentage_at(&self, now: Instant) -> u8 {
+		let Ok(mut state) = self.0.lock() else {
+			return 0;
+		};
+
+		state.decay(now);
+		state.percentage.round() as u8
+	}
+}
+
+impl AdaptiveFecState {
+	/// Lower the percentage for the time that passed without loss.
+	fn decay(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.updated_at);
+		self.percentage = (self.percentage - elapsed.as_secs_f64() * DECREASE_PER_SECOND).max(self.minimum);
+		self.updated_at = now;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(minimum_percentage: u8, maximum_percentage: u8) -> AdaptiveFecConfig {
+		AdaptiveFecConfig { enabled: true, minimum_percentage, maximum_percentage }
+	}
+
+	#[test]
+	fn loss_raises_percentage_up_to_maximum() {
+		let fec = AdaptiveFec::new(20, &config(5, 35));
+		let start = Instant::now();
+		fec.report_loss_at(1, start);
+		assert_eq!(fec.percentage_at(start), 30);
+
+		fec.report_loss_at(3, start + INCREASE_INTERVAL);
+		assert_eq!(fec.percentage_at(start + INCREASE_INTERVAL), 35);
+	}
+
+	#[test]
+	fn repeated_reports_of_the_same_loss_raise_once() {
+		let fec = AdaptiveFec::new(20, &config(5, 50));
+		let start = Instant::now();
+		fec.report_loss_at(1, start);
+		fec.report_loss_at(1, start + Duration::from_millis(10));
+		assert_eq!(fec.percentage_at(start + Duration::from_millis(10)), 30);
+	}
+
+	#[test]
+	fn percentage_decays_to_minimum_without_loss() {
+		let fec = AdaptiveFec::new(20, &config(5, 50));
+		let start = Instant::now();
+		assert_eq!(fec.percentage_at(start + Duration::from_secs(5)), 10);
+		assert_eq!(fec.percentage_at(start + Duration::from_secs(60)), 5);
+	}
+
+	#[test]
+	fn initial_percentage_is_within_bounds() {
+		assert_eq!(AdaptiveFec::new(80, &config(5, 50)).percentage(), 50);
+		assert_eq!(AdaptiveFec::new(0, &config(5, 50)).percentage(), 5);
+	}
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use cudarc::driver::{result, sys::CUdevice_attribute, CudaDevice};
+
+/// Find the index of the GPU to use for capturing, scaling and encoding.
+///
+/// The selector is the index of the GPU, its UUID (ie. `GPU-5e4b7a8c-...`, as listed by `nvidia-smi -L`),
+/// its PCI bus ID (ie. `0000:01:00.0`) or part of its name.
+/// Without a selector the first GPU is used.
+pub fn find_gpu(selector: Option<&str>) -> Result<usize, ()> {
+	result::init()
+		.map_err(|e| tracing::error!("Failed to initialize CUDA ({e}), make sure the NVIDIA driver is installed and loaded."))?;
+	let count = result::device::get_count()
+		.map_err(|e| tracing::error!("Failed to get the number of GPUs: {e}"))?;
+	if count <= 0 {
+		tracing::error!("No GPUs found that support CUDA.");
+		return Err(());
+	}
+
+	let Some(selector) = selector.map(str::trim) else {
+		return Ok(0);
+	};
+
+	if let Ok(index) = selector.parse::<usize>() {
+		if index >= count as usize {
+			tracing::error!("Configured GPU index {index}, but only {count} GPU(s) are available.");
+			return Err(());
+		}
+		return Ok(index);
+	}
+
+	let bus_id = parse_pci_bus_id(selector);
+	let selector_lowercase = selector.to_lowercase();
+	let mut found = None;
+	for index in 0..count {
+		let device = result::device::get(index)
+			.map_err(|e| tracing::error!("Failed to get GPU {index}: {e}"))?;
+		let name = result::device::get_name(device).unwrap_or_default();
+
+		// Like `CUDA_VISIBLE_DEVICES`, the start of a UUID is enough to select a GPU.
+		let matches = if selector_lowercase.starts_with("gpu-") {
+			gpu_uuid(device).is_some_and(|uuid| uuid.starts_with(&selector_lowercase))
+		} else {
+			match bus_id {
+				Some(bus_id) => pci_bus_id(device) == Some(bus_id),
+				None => name.to_lowercase().contains(&selector_lowercase),
+			}
+		};
+		if !matches {
+			continue;
+		}
+
+		match found {
+			None => found = Some(index as usize),
+			Some(first) => tracing::warn!("GPU {index} ('{name}') also matches '{selector}', using GPU {first}."),
+		}
+	}
+
+	found.ok_or_else(|| {
+		let available = (0..count)
+			.filter_map(|index| {
+				let device = result::device::get(index).ok()?;
+				let name = result::device::get_name(device).ok()?;
+				Some(format!("{index}: '{name}'"))
+			})
+			.collect::<Vec<_>>()
+			.join(", ");
+		tracing::error!("No GPU matches '{selector}', available GPUs are {available}.");
+	})
+}
+
+/// Open the configured GPU.
+pub fn open_gpu(selector: Option<&str>) -> Result<Arc<CudaDevice>, ()> {
+	let index = find_gpu(selector)?;
+	let device = CudaDevice::new(index)
+		.map_err(|e| tracing::error!("Failed to initialize CUDA on GPU {index}: {e}"))?;
+	tracing::info!("Using GPU {index} ('{}').", device.name().unwrap_or_else(|_| "unknown".to_string()));
+
+	Ok(device)
+}
+
+/// Name of the configured GPU, if it can be found.
+pub fn gpu_name(selector: Option<&str>) -> Option<String> {
+	let index = find_gpu(selector).ok()?;
+	let device = result::device::get(index as i32).ok()?;
+	result::device::get_name(device).ok()
+}
+
+/// Parse a PCI bus ID in the format of `nvidia-smi` or `lspci` (ie. `00000000:01:00.0` or `01:00.0`),
+/// returning the domain, bus and device.
+fn parse_pci_bus_id(bus_id: &str) -> Option<(i32, i32, i32)> {
+	let (address, _function) = bus_id.rsplit_once('.')?;
+	let parts = address.split(':')
+		.map(|part| i32::from_str_radix(part, 16).ok())
+		.collect::<Option<Vec<_>>>()?;
+
+	match parts.as_slice() {
+		[domain, bus, device] => Some((*domain, *bus, *device)),
+		[bus, device] => Some((0, *bus, *device)),
+		_ => None,
+	}
+}
+
+/// UUID of a GPU in the format of `nvidia-smi -L` (ie. `gpu-5e4b7a8c-1f2d-4c3b-9a8e-0b1c2d3e4f50`), in lowercase.
+fn gpu_uuid(device: cudarc::driver::sys::CUdevice) -> Option<String> {
+	let mut uuid = cudarc::driver::sys::CUuuid { bytes: [0; 16] };
+	unsafe { cudarc::driver::sys::lib().cuDeviceGetUuid(&mut uuid, device) }.result().ok()?;
+
+	let bytes = uuid.bytes.map(|byte| byte as u8);
+	Some(format!(
+		"gpu-{}-{}-{}-{}-{}",
+		hex::encode(&bytes[0..4]),
+		hex::encode(&bytes[4..6]),
+		hex::encode(&bytes[6..8]),
+		hex::encode(&bytes[8..10]),
+		hex::encode(&bytes[10..16]),
+	))
+}
+
+/// Domain, bus and device of the PCI address of a GPU.
+fn pci_bus_id(device: cudarc::driver::sys::CUdevice) -> Option<(i32, i32, i32)> {
+	let attribute = |attribute| unsafe { result::device::get_attribute(device, attribute) }.ok();
+	Some((
+		attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_PCI_DOMAIN_ID)?,
+		attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_PCI_BUS_ID)?,
+		attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_PCI_DEVICE_ID)?,
+	))
+}
@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+const MIB: usize = 1024 * 1024;
+
+/// Interval at which GPU memory usage is checked and logged.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Growth in GPU memory usage (since the start of the stream) after which we warn about a possible leak.
+const LEAK_WARNING_THRESHOLD: usize = 256 * MIB;
+
+/// Keeps track of GPU memory usage during a stream, to detect memory leaks in capture and encoding.
+///
+/// Note that the usage is device wide, so other processes using the GPU are included.
+pub struct GpuMemoryMonitor {
+	/// Memory in use when the stream started.
+	baseline: usize,
+
+	/// Highest memory usage seen during the stream.
+	peak: usize,
+
+	/// Growth for which we last warned, to avoid warning about the same growth repeatedly.
+	warned_growth: usize,
+
+	/// Number of frames processed since the stream started.
+	frames: u64,
+
+	last_check: Instant,
+}
+
+impl GpuMemoryMonitor {
+	/// Create a monitor, this requires a CUDA context to be bound to the current thread.
+	pub fn new() -> Result<Self, ()> {
+		let used = used_memory()?;
+		tracing::debug!("GPU memory in use at the start of the stream: {} MiB.", used / MIB);
+
+		Ok(Self {
+			baseline: used,
+			peak: used,
+			warned_growth: 0,
+			frames: 0,
+			last_check: Instant::now(),
+		})
+	}
+
+	/// Register a processed frame, periodically checking memory usage.
+	pub fn on_frame(&mut self) {
+		self.frames += 1;
+		if self.last_check.elapsed() < CHECK_INTERVAL {
+			return;
+		}
+		self.last_check = Instant::now();
+
+		let Ok(used) = used_memory() else {
+			return;
+		};
+		self.peak = self.peak.max(used);
+
+		let growth = used.saturating_sub(self.baseline);
+		tracing::debug!(
+			"GPU memory in use after {} frames: {} MiB (started at {} MiB, peak {} MiB).",
+			self.frames, used / MIB, self.baseline / MIB, self.peak / MIB,
+		);
+
+		if growth >= self.warned_growth + LEAK_WARNING_THRESHOLD {
+			tracing::warn!(
+				"GPU memory usage grew by {} MiB over {} frames since the stream started, this might be a memory leak.",
+				growth / MIB, self.frames,
+			);
+			self.warned_growth = growth;
+		}
+	}
+}
+
+impl Drop for GpuMemoryMonitor {
+	fn drop(&mut self) {
+		tracing::debug!(
+			"Processed {} frames, GPU memory in use started at {} MiB with a peak of {} MiB.",
+			self.frames, self.baseline / MIB, self.peak / MIB,
+		);
+	}
+}
+
+fn used_memory() -> Result<usize, ()> {
+	let (free, total) = cudarc::driver::result::mem_get_info()
+		.map_err(|e| tracing::warn!("Failed to get GPU memory usage: {e}"))?;
+
+	Ok(total.saturating_sub(free))
+}
@@ -1,21 +1,53 @@
-use std::sync::{Arc, Mutex};
+use std::{sync::Arc, time::Instant};
 
 use async_shutdown::ShutdownManager;
-use ffmpeg::{format::Pixel, Frame};
-use tokio::{net::UdpSocket, sync::mpsc::{self, Sender}};
+use ffmpeg::Frame;
+use tokio::sync::mpsc::{self, Sender};
+use tracing::Instrument;
 
-use crate::{config::Config, ffmpeg::{check_ret, hwframe::HwFrameContext}};
+use crate::{config::{CaptureBackend, Config, VideoStreamConfig}, ffmpeg::{encoder::{EncoderProfile, VideoCodec}, hwframe::HwFramePool}, metrics::{self, Stage}, session::{shutdown::stop_session_after, Milestone, Recorder, SessionShutdownReason, SessionTimings, SharedSessionKeys}};
+use super::{scheduling::{configure_current_thread, StreamThread}, StreamPause, StreamSocket};
+
+mod bitstream;
+
+mod capabilities;
+pub use capabilities::EncoderCapabilities;
 
 mod capture;
-use capture::FrameCapturer;
+use capture::{Capturer, FrameCapturer, TestPattern, WlrScreencopy, X11Shm};
+pub use capture::{CapturedArea, SharedCapturedArea};
 
 mod encoder;
-use encoder::Encoder;
+use encoder::{Encoder, EncoderCommand};
+
+mod encryption;
+use encryption::VideoEncryption;
+
+mod fec;
+use fec::AdaptiveFec;
+
+mod gpu;
+pub use gpu::{find_gpu, gpu_name, open_gpu};
+
+mod memory;
+mod overlay;
+mod packetizer;
+mod queue;
+use queue::FrameQueue;
+mod recovery;
+use recovery::FrameRecovery;
+mod scaler;
+use scaler::Scaler;
+mod throughput;
+use throughput::ThroughputMonitor;
 
 #[derive(Debug)]
 enum VideoStreamCommand {
-	Start,
+	Start(Option<SharedSessionKeys>),
 	RequestIdrFrame,
+	InvalidateReferenceFrames { lost_frames: u32, last_frame: u64 },
+	ReportLoss { lost_frames: u32, last_good_frame: u64 },
+	ToggleOverlay,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -28,32 +60,72 @@ pub struct VideoStreamContext {
 	pub minimum_fec_packets: u32,
 	pub qos: bool,
 	pub video_format: u32,
+
+	/// Whether frames are encoded without chroma subsampling (4:4:4).
+	pub yuv444: bool,
+
+	/// What kind of content is streamed, which decides how it is encoded.
+	pub profile: EncoderProfile,
+
+	/// Whether the client runs on the host itself, in which case no parity packets are needed.
+	pub loopback: bool,
+
+	/// Whether the packets are encrypted, as negotiated with the client.
+	pub encrypted: bool,
+}
+
+impl VideoStreamContext {
+	/// Codec that the client asked for.
+	pub fn codec(&self) -> VideoCodec {
+		if self.video_format == 0 { VideoCodec::H264 } else { VideoCodec::Hevc }
+	}
 }
 
 #[derive(Clone)]
 pub struct VideoStream {
-	command_tx: Sender<VideoStreamCommand>
+	command_tx: Sender<VideoStreamCommand>,
+	captured_area: SharedCapturedArea,
 }
 
 struct VideoStreamInner {
 }
 
 impl VideoStream {
-	pub fn new(config: Config, context: VideoStreamContext, stop_signal: ShutdownManager<()>) -> Self {
+	pub fn new(
+		config: Config,
+		context: VideoStreamContext,
+		socket: StreamSocket,
+		recorder: Option<Recorder>,
+		timings: SessionTimings,
+		pause: StreamPause,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
+	) -> Self {
 		let (command_tx, command_rx) = mpsc::channel(10);
 		let inner = VideoStreamInner { };
-		tokio::spawn(stop_signal.wrap_cancel(stop_signal.wrap_trigger_shutdown((), inner.run(
+		let captured_area = SharedCapturedArea::default();
+		tokio::spawn(stop_signal.wrap_cancel(stop_session_after(&stop_signal, SessionShutdownReason::VideoStreamFailed, inner.run(
 			config,
 			context,
 			command_rx,
+			socket,
+			recorder,
+			timings,
+			captured_area.clone(),
+			pause,
 			stop_signal.clone()
-		))));
+		))).instrument(tracing::info_span!("video_stream")));
+
+		Self { command_tx, captured_area }
+	}
 
-		Self { command_tx }
+	/// Area of the screen that is streamed, available once the stream has started.
+	pub fn captured_area(&self) -> SharedCapturedArea {
+		self.captured_area.clone()
 	}
 
-	pub async fn start(&self) -> Result<(), ()> {
-		self.command_tx.send(VideoStreamCommand::Start).await
+	/// Start streaming, encrypting the packets with `keys` if the client asked for encrypted video.
+	pub async fn start(&self, keys: Option<SharedSessionKeys>) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::Start(keys)).await
 			.map_err(|e| tracing::warn!("Failed to send Start command: {e}"))
 	}
 
@@ -61,6 +133,24 @@ impl VideoStream {
 		self.command_tx.send(VideoStreamCommand::RequestIdrFrame).await
 			.map_err(|e| tracing::warn!("Failed to send RequestIdrFrame command: {e}"))
 	}
+
+	/// The client can't use the frames up to and including `last_frame` as reference, because it lost (part of) them.
+	pub async fn invalidate_reference_frames(&self, lost_frames: u32, last_frame: u64) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::InvalidateReferenceFrames { lost_frames, last_frame }).await
+			.map_err(|e| tracing::warn!("Failed to send InvalidateReferenceFrames command: {e}"))
+	}
+
+	/// The client reported that it lost frames since `last_good_frame`, which is used to adjust the amount of parity shards.
+	pub async fn report_loss(&self, lost_frames: u32, last_good_frame: u64) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::ReportLoss { lost_frames, last_good_frame }).await
+			.map_err(|e| tracing::warn!("Failed to send ReportLoss command: {e}"))
+	}
+
+	/// Show or hide the performance overlay on the stream.
+	pub async fn toggle_overlay(&self) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::ToggleOverlay).await
+			.map_err(|e| tracing::warn!("Failed to send ToggleOverlay command: {e}"))
+	}
 }
 
 impl VideoStreamInner {
@@ -69,39 +159,32 @@ impl VideoStreamInner {
 		config: Config,
 		mut context: VideoStreamContext,
 		mut command_rx: mpsc::Receiver<VideoStreamCommand>,
-		stop_signal: ShutdownManager<()>,
+		mut socket: StreamSocket,
+		recorder: Option<Recorder>,
+		timings: SessionTimings,
+		captured_area: SharedCapturedArea,
+		pause: StreamPause,
+		stop_signal: ShutdownManager<SessionShutdownReason>,
 	) -> Result<(), ()> {
-		let socket = UdpSocket::bind((config.address, config.stream.video.port))
-			.await
-			.map_err(|e| tracing::error!("Failed to bind to UDP socket: {e}"))?;
-
-		if context.qos {
-			// TODO: Check this value 160, what does it mean exactly?
-			tracing::debug!("Enabling QoS on video socket.");
-			socket.set_tos(160)
-				.map_err(|e| tracing::error!("Failed to set QoS on the video socket: {e}"))?;
-		}
-
-		tracing::debug!(
-			"Listening for video messages on {}",
-			socket.local_addr()
-				.map_err(|e| tracing::error!("Failed to get local address associated with control socket: {e}"))?
-		);
-
 		let (packet_tx, mut packet_rx) = mpsc::channel::<Vec<u8>>(1024);
+		let mut throughput_monitor = ThroughputMonitor::new(context.bitrate);
 		tokio::spawn(async move {
-			let mut buf = [0; 1024];
-			let mut client_address = None;
-
 			loop {
 				tokio::select! {
 					packet = packet_rx.recv() => {
 						match packet {
 							Some(packet) => {
-								if let Some(client_address) = client_address {
-									if let Err(e) = socket.send_to(packet.as_slice(), client_address).await {
-										tracing::warn!("Failed to send packet to client: {e}");
-									}
+								timings.record(Milestone::FirstFrameEncoded);
+								let send_started = Instant::now();
+								let result = socket.send(&packet).await;
+								metrics::record(Stage::Send, send_started.elapsed());
+								match result {
+									Ok(true) => {
+										timings.record(Milestone::FirstFrameSent);
+										throughput_monitor.on_sent(packet.len());
+									},
+									Ok(false) => {},
+									Err(()) => throughput_monitor.on_failed(),
 								}
 							},
 							None => {
@@ -111,88 +194,177 @@ impl VideoStreamInner {
 						}
 					},
 
-					message = socket.recv_from(&mut buf) => {
-						let (len, address) = match message {
-							Ok((len, address)) => (len, address),
-							Err(e) => {
-								tracing::warn!("Failed to receive message: {e}");
-								break;
-							},
-						};
-
-						if &buf[..len] == b"PING" {
-							tracing::trace!("Received video stream PING message from {address}.");
-							client_address = Some(address);
-						} else {
-							tracing::warn!("Received unknown message on video stream of length {len}.");
+					result = socket.pinged() => {
+						if result.is_err() {
+							break;
 						}
+						timings.record(Milestone::FirstVideoPing);
 					},
 				}
 			}
 
 			tracing::debug!("Stopping video stream.");
-		});
+		}.in_current_span());
 
 		let mut started_streaming = false;
-		let (idr_frame_request_tx, _idr_frame_request_rx) = tokio::sync::broadcast::channel(1);
+		let mut encoder_command_tx: Option<std::sync::mpsc::Sender<EncoderCommand>> = None;
+		let mut adaptive_fec: Option<AdaptiveFec> = None;
+		let mut recovery = FrameRecovery::new();
 		while let Some(command) = command_rx.recv().await {
 			match command {
 				VideoStreamCommand::RequestIdrFrame => {
+					let Some(encoder_command_tx) = &encoder_command_tx else {
+						tracing::debug!("Received request for IDR frame before the stream started, ignoring it.");
+						continue;
+					};
+
+					// Without reference frame invalidation, the client asks for an IDR frame when it lost a frame.
+					if let Some(adaptive_fec) = &adaptive_fec {
+						adaptive_fec.report_loss(1);
+					}
+
 					tracing::info!("Received request for IDR frame, next frame will be an IDR frame.");
-					idr_frame_request_tx.send(())
+					recovery.on_idr_request();
+					encoder_command_tx.send(EncoderCommand::RequestIdrFrame)
 						.map_err(|e| tracing::error!("Failed to send IDR frame request to encoder: {e}"))?;
 				},
-				VideoStreamCommand::Start => {
+				VideoStreamCommand::InvalidateReferenceFrames { lost_frames, last_frame } => {
+					let Some(encoder_command_tx) = &encoder_command_tx else {
+						tracing::debug!("Received request to invalidate reference frames before the stream started, ignoring it.");
+						continue;
+					};
+
+					if let Some(adaptive_fec) = &adaptive_fec {
+						adaptive_fec.report_loss(lost_frames);
+					}
+
+					if !recovery.on_invalidate_reference_frames(last_frame) {
+						tracing::debug!("Client invalidated frames up to {last_frame}, which a more recent key frame already covers.");
+						continue;
+					}
+
+					tracing::info!("Client invalidated frames up to {last_frame}, next frame will be an IDR frame.");
+					encoder_command_tx.send(EncoderCommand::RequestIdrFrame)
+						.map_err(|e| tracing::error!("Failed to send IDR frame request to encoder: {e}"))?;
+				},
+				VideoStreamCommand::ReportLoss { lost_frames, last_good_frame } => {
+					// Loss before the stream started (like the request for the first IDR frame) says nothing about the network.
+					let Some(encoder_command_tx) = &encoder_command_tx else {
+						continue;
+					};
+
+					if let Some(adaptive_fec) = &adaptive_fec {
+						adaptive_fec.report_loss(lost_frames);
+					}
+					if lost_frames > 0 {
+						let _ = encoder_command_tx.send(EncoderCommand::ReportLoss(lost_frames));
+					}
+
+					// The client can't decode anything after a lost frame until it receives a key frame, so don't wait for it to ask.
+					if recovery.on_loss_statistics(lost_frames, last_good_frame) {
+						tracing::info!("Client lost {lost_frames} frame(s) after frame {last_good_frame}, next frame will be an IDR frame.");
+						encoder_command_tx.send(EncoderCommand::RequestIdrFrame)
+							.map_err(|e| tracing::error!("Failed to send IDR frame request to encoder: {e}"))?;
+					}
+				},
+				VideoStreamCommand::ToggleOverlay => {
+					let Some(encoder_command_tx) = &encoder_command_tx else {
+						tracing::debug!("Received request to toggle the performance overlay before the stream started, ignoring it.");
+						continue;
+					};
+
+					encoder_command_tx.send(EncoderCommand::ToggleOverlay)
+						.map_err(|e| tracing::error!("Failed to send overlay toggle to encoder: {e}"))?;
+				},
+				VideoStreamCommand::Start(keys) => {
 					if started_streaming {
 						tracing::warn!("Can't start streaming twice.");
 						continue;
 					}
 
-					// TODO: Make the GPU index configurable.
-					let cuda_device = cudarc::driver::CudaDevice::new(0)
-						.map_err(|e| tracing::error!("Failed to initialize CUDA: {e}"))?;
-
-					let capturer = FrameCapturer::new()?;
-					let status = capturer.status()?;
-					if status.screen_size.w != context.width || status.screen_size.h != context.height {
-						// TODO: Resize the CUDA buffer to the requested size?
-						tracing::warn!(
-							"Client asked for resolution {}x{}, but we are generating a resolution of {}x{}.",
-							context.width, context.height, status.screen_size.w, status.screen_size.h
+					let encryption = match keys {
+						Some(keys) if context.encrypted => {
+							tracing::info!("Encrypting the video stream.");
+							Some(VideoEncryption::new(keys))
+						},
+						None if context.encrypted => {
+							tracing::error!("Client asked for encrypted video, but there are no keys to encrypt it with.");
+							return Err(());
+						},
+						_ => None,
+					};
+
+					let cuda_device = open_gpu(config.stream.video.gpu.as_deref())?;
+
+					let capturer = Capturer::new(&config.stream.video, context.width, context.height)?;
+					let (screen_width, screen_height) = capturer.screen_size()?;
+					let scaler = if screen_width != context.width || screen_height != context.height {
+						tracing::info!(
+							"Client asked for resolution {}x{}, scaling the screen from {screen_width}x{screen_height} using {:?} filtering.",
+							context.width, context.height, config.stream.video.scaling_filter,
 						);
-						context.width = status.screen_size.w;
-						context.height = status.screen_size.h;
-					}
+						match Scaler::new(cuda_device.clone(), config.stream.video.scaling_filter) {
+							Ok(scaler) => Some(scaler),
+							Err(()) => {
+								tracing::warn!("Failed to create scaler, streaming the resolution of the screen instead.");
+								context.width = screen_width;
+								context.height = screen_height;
+								None
+							},
+						}
+					} else {
+						None
+					};
+					captured_area.set(capturer.captured_area()?);
 
 					let mut encoder = Encoder::new(
 						&cuda_device,
 						if context.video_format == 0 { &config.stream.video.codec_h264 } else { &config.stream.video.codec_hevc },
+						context.codec(),
+						context.yuv444,
 						context.width, context.height,
 						context.fps,
 						context.bitrate,
+						config.stream.video.preset,
+						config.stream.video.tune,
+						context.profile,
+						config.stream.video.variable_refresh_rate,
+						config.stream.video.bitstream.clone(),
 					)?;
+					if let Some(recorder) = &recorder {
+						recorder.start_video(encoder.parameters());
+					}
 
-					let capture_buffer = create_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?;
-					let intermediate_buffer = Arc::new(Mutex::new(create_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?));
-					let encoder_buffer = create_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?;
-					let frame_number = Arc::new(std::sync::atomic::AtomicU32::new(0));
-					let frame_notifier = Arc::new(std::sync::Condvar::new());
+					let capture_buffer = create_frame(&mut encoder.frame_pool)?;
+					let encoder_buffer = create_frame(&mut encoder.frame_pool)?;
+					let queue_depth = config.stream.video.frame_queue.depth.max(1);
+					let frame_queue = Arc::new(FrameQueue::new(
+						(0..queue_depth).map(|_| create_frame(&mut encoder.frame_pool)).collect::<Result<_, ()>>()?
+					));
+					let (command_tx, encoder_command_rx) = std::sync::mpsc::channel();
 
 					let capture_thread = std::thread::Builder::new().name("video-capture".to_string()).spawn({
-						let intermediate_buffer = intermediate_buffer.clone();
-						let frame_notifier = frame_notifier.clone();
-						let frame_number = frame_number.clone();
+						let frame_queue = frame_queue.clone();
+						let encoder_command_tx = command_tx.clone();
 						let context = context.clone();
+						let scheduling = config.stream.scheduling.clone();
+						let pause = pause.clone();
 						let stop_signal = stop_signal.clone();
+						let span = tracing::Span::current();
 						move || {
+							let _span = span.enter();
+							configure_current_thread(&scheduling, StreamThread::VideoCapture);
 							cuda_device.bind_to_thread()
 								.map_err(|e| tracing::error!("Failed to bind CUDA device to thread: {e}"))?;
 							capturer.run(
+								screen_width,
+								screen_height,
 								context.fps,
+								scaler,
 								capture_buffer,
-								intermediate_buffer,
-								frame_number,
-								frame_notifier,
+								frame_queue,
+								encoder_command_tx,
+								pause,
 								stop_signal,
 							)
 						}
@@ -202,24 +374,41 @@ impl VideoStreamInner {
 						continue;
 					}
 
+					// Packets over loopback aren't lost, so only the parity packets that the client asks for are sent.
+					let fec_percentage = if context.loopback {
+						tracing::info!("Client runs on the host, sending video without parity packets.");
+						0
+					} else {
+						config.stream.video.fec_percentage
+					};
+					let fec = (config.stream.video.adaptive_fec.enabled && !context.loopback)
+						.then(|| AdaptiveFec::new(fec_percentage, &config.stream.video.adaptive_fec));
 					let encode_thread = std::thread::Builder::new().name("video-encode".to_string()).spawn({
 						let packet_tx = packet_tx.clone();
-						let frame_number = frame_number.clone();
-						let frame_notifier = frame_notifier.clone();
-						let idr_frame_request_rx = idr_frame_request_tx.subscribe();
+						let recorder = recorder.clone();
 						let context = context.clone();
+						let fec = fec.clone();
+						let last_key_frame = recovery.last_key_frame();
+						let scheduling = config.stream.scheduling.clone();
+						let pause = pause.clone();
 						let stop_signal = stop_signal.clone();
+						let span = tracing::Span::current();
 						move || {
+							let _span = span.enter();
+							configure_current_thread(&scheduling, StreamThread::VideoEncode);
 							encoder.run(
 								packet_tx,
-								idr_frame_request_rx,
+								recorder,
+								encoder_command_rx,
 								context.packet_size,
 								context.minimum_fec_packets,
-								config.stream.video.fec_percentage,
+								fec_percentage,
+								fec,
+								encryption,
+								last_key_frame,
 								encoder_buffer,
-								intermediate_buffer,
-								frame_number,
-								frame_notifier,
+								frame_queue,
+								pause,
 								stop_signal,
 							)
 						}
@@ -229,30 +418,44 @@ impl VideoStreamInner {
 						continue;
 					}
 
+					encoder_command_tx = Some(command_tx);
+					adaptive_fec = fec;
 					started_streaming = true;
 				},
 			}
 		}
 
 		tracing::debug!("Command channel closed.");
+		if let Some(encoder_command_tx) = encoder_command_tx {
+			let _ = encoder_command_tx.send(EncoderCommand::Stop);
+		}
 		Ok(())
 	}
 }
 
-fn create_frame(width: u32, height: u32, pixel_format: Pixel, context: &mut HwFrameContext) -> Result<Frame, ()> {
-	unsafe {
-		let mut frame = Frame::empty();
-		(*frame.as_mut_ptr()).format = pixel_format as i32;
-		(*frame.as_mut_ptr()).width = width as i32;
-		(*frame.as_mut_ptr()).height = height as i32;
-		(*frame.as_mut_ptr()).hw_frames_ctx = context.as_raw_mut();
-
-		check_ret(ffmpeg::sys::av_hwframe_get_buffer(context.as_raw_mut(), frame.as_mut_ptr(), 0))
-			.map_err(|e| tracing::error!("Failed to create CUDA frame: {e}"))?;
-		check_ret(ffmpeg::sys::av_hwframe_get_buffer(context.as_raw_mut(), frame.as_mut_ptr(), 0))
-			.map_err(|e| println!("Failed to allocate hardware frame: {e}"))?;
-		(*frame.as_mut_ptr()).linesize[0] = (*frame.as_ptr()).width * 4;
-
-		Ok(frame)
+/// Check if frames can be captured with the configured backend, returning the resolution of the screen if they can.
+///
+/// A test pattern can always be generated, at its default resolution since there is no client to ask for one.
+pub fn probe_capture(config: &VideoStreamConfig) -> Result<(u32, u32), ()> {
+	match config.capture {
+		CaptureBackend::Nvfbc => {
+			let capturer = FrameCapturer::new()?;
+			let status = capturer.status()?;
+			Ok((status.screen_size.w, status.screen_size.h))
+		},
+		CaptureBackend::TestPattern => Ok(TestPattern::DEFAULT_RESOLUTION),
+		CaptureBackend::WlrScreencopy => {
+			let capturer = WlrScreencopy::new(config.output.as_deref())?;
+			Ok((capturer.width(), capturer.height()))
+		},
+		CaptureBackend::X11Shm => {
+			let capturer = X11Shm::new()?;
+			Ok((capturer.width(), capturer.height()))
+		},
 	}
 }
+
+fn create_frame(frame_pool: &mut HwFramePool) -> Result<Frame, ()> {
+	frame_pool.get()
+		.map_err(|e| tracing::error!("Failed to allocate CUDA frame: {e}"))
+}
@@ -0,0 +1,253 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use cudarc::driver::{sys::CUdeviceptr, CudaDevice, CudaFunction, CudaSlice, LaunchAsync, LaunchConfig};
+use ffmpeg::Frame;
+
+/// Name under which the kernel is loaded in the CUDA device.
+const MODULE_NAME: &str = "overlay";
+
+/// Size of a block of threads, each thread computes one pixel of the overlay.
+const BLOCK_SIZE: u32 = 16;
+
+/// Interval at which the statistics on the overlay are updated.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size of a glyph of the font, and the space between glyphs and lines.
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const SPACING: usize = 1;
+
+/// Space around the text, in pixels of the font.
+const PADDING: usize = 2;
+
+/// Kernel that draws white text on a darkened background in the top left corner of a packed 32 bit image.
+const KERNELS: &str = r#"
+extern "C" __global__ void draw_overlay(
+	unsigned char* frame, int pitch, int width, int height,
+	const unsigned char* mask, int mask_width, int mask_height, int scale
+) {
+	int x = blockIdx.x * blockDim.x + threadIdx.x;
+	int y = blockIdx.y * blockDim.y + threadIdx.y;
+	if (x >= mask_width * scale || y >= mask_height * scale || x >= width || y >= height) {
+		return;
+	}
+
+	uchar4* pixel = (uchar4*)(frame + y * pitch + x * 4);
+	if (mask[(y / scale) * mask_width + x / scale]) {
+		*pixel = make_uchar4(255, 255, 255, 255);
+	} else {
+		*pixel = make_uchar4(pixel->x / 3, pixel->y / 3, pixel->z / 3, pixel->w);
+	}
+}
+"#;
+
+/// Draws statistics of the stream on the frames before they are encoded, so that they can be seen on the client.
+pub struct PerformanceOverlay {
+	cuda_device: Arc<CudaDevice>,
+	function: CudaFunction,
+
+	/// Rendered text in CUDA memory, with its width and height.
+	mask: Option<(CudaSlice<u8>, u32, u32)>,
+
+	/// Statistics since the overlay was last updated.
+	window_start: Instant,
+	frames: u32,
+	encoded_bytes: usize,
+	encode_time: Duration,
+	lost_frames: u32,
+	dropped_frames: u64,
+}
+
+impl PerformanceOverlay {
+	pub fn new(cuda_device: Arc<CudaDevice>) -> Result<Self, ()> {
+		if !cuda_device.has_func(MODULE_NAME, "draw_overlay") {
+			let ptx = cudarc::nvrtc::compile_ptx(KERNELS)
+				.map_err(|e| tracing::error!("Failed to compile overlay kernel: {e}"))?;
+			cuda_device.load_ptx(ptx, MODULE_NAME, &["draw_overlay"])
+				.map_err(|e| tracing::error!("Failed to load overlay kernel: {e}"))?;
+		}
+
+		let function = cuda_device.get_func(MODULE_NAME, "draw_overlay")
+			.ok_or_else(|| tracing::error!("Failed to find overlay kernel."))?;
+
+		Ok(Self {
+			cuda_device,
+			function,
+			mask: None,
+			window_start: Instant::now(),
+			frames: 0,
+			encoded_bytes: 0,
+			encode_time: Duration::ZERO,
+			lost_frames: 0,
+			dropped_frames: 0,
+		})
+	}
+
+	/// A frame of `size` bytes was encoded in `encode_time`.
+	pub fn on_frame_encoded(&mut self, size: usize, encode_time: Duration) {
+		self.frames += 1;
+		self.encoded_bytes += size;
+		self.encode_time += encode_time;
+	}
+
+	/// The client reported that it lost frames.
+	pub fn on_loss(&mut self, lost_frames: u32) {
+		self.lost_frames += lost_frames;
+	}
+
+	/// Captured frames were dropped because encoding fell behind.
+	pub fn on_dropped(&mut self, dropped_frames: u64) {
+		self.dropped_frames += dropped_frames;
+	}
+
+	/// Draw the overlay on a frame in CUDA memory, updating the statistics on it when they are due.
+	pub fn draw(&mut self, frame: &mut Frame) -> Result<(), ()> {
+		let elapsed = self.window_start.elapsed();
+		if self.mask.is_none() || elapsed >= UPDATE_INTERVAL {
+			let lines = summary(elapsed, self.frames, self.encoded_bytes, self.encode_time, self.lost_frames, self.dropped_frames);
+			let (mask, mask_width, mask_height) = render(&lines);
+			let mask = self.cuda_device.htod_sync_copy(&mask)
+				.map_err(|e| tracing::error!("Failed to upload performance overlay: {e}"))?;
+			self.mask = Some((mask, mask_width, mask_height));
+
+			self.window_start = Instant::now();
+			self.frames = 0;
+			self.encoded_bytes = 0;
+			self.encode_time = Duration::ZERO;
+			self.lost_frames = 0;
+			self.dropped_frames = 0;
+		}
+
+		let Some((mask, mask_width, mask_height)) = &self.mask else {
+			return Ok(());
+		};
+
+		let (destination, pitch, width, height) = unsafe {
+			let frame = &*frame.as_ptr();
+			(frame.data[0] as CUdeviceptr, frame.linesize[0], frame.width, frame.height)
+		};
+
+		// Keep the text readable at high resolutions, a 1080p stream shows the font at three times its size.
+		let scale = (height / 360).max(1);
+		let config = LaunchConfig {
+			grid_dim: ((mask_width * scale as u32).div_ceil(BLOCK_SIZE), (mask_height * scale as u32).div_ceil(BLOCK_SIZE), 1),
+			block_dim: (BLOCK_SIZE, BLOCK_SIZE, 1),
+			shared_mem_bytes: 0,
+		};
+
+		unsafe {
+			self.function.clone().launch(config, (
+				destination,
+				pitch,
+				width,
+				height,
+				mask,
+				*mask_width as i32,
+				*mask_height as i32,
+				scale,
+			))
+		}.map_err(|e| tracing::error!("Failed to draw performance overlay: {e}"))?;
+
+		// The encoder doesn't use the stream of the kernel, so wait until the frame is complete.
+		self.cuda_device.synchronize()
+			.map_err(|e| tracing::error!("Failed to wait for performance overlay: {e}"))
+	}
+}
+
+/// Describe the statistics of a window of `elapsed` time, one line per statistic.
+fn summary(elapsed: Duration, frames: u32, encoded_bytes: usize, encode_time: Duration, lost_frames: u32, dropped_frames: u64) -> Vec<String> {
+	let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+	let encode_ms = if frames > 0 { encode_time.as_secs_f64() * 1000.0 / frames as f64 } else { 0.0 };
+	vec![
+		format!("FPS {:.1}", frames as f64 / seconds),
+		format!("BITRATE {:.1} MBPS", encoded_bytes as f64 * 8.0 / 1_000_000.0 / seconds),
+		format!("ENCODE {encode_ms:.1} MS"),
+		format!("LOSS {lost_frames}"),
+		format!("DROPPED {dropped_frames}"),
+	]
+}
+
+/// Render lines of text with the built-in font, returning one byte per pixel with the width and height of the image.
+pub(super) fn render(lines: &[String]) -> (Vec<u8>, u32, u32) {
+	let columns = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+	let width = PADDING * 2 + columns * (GLYPH_WIDTH + SPACING) - SPACING.min(columns);
+	let height = PADDING * 2 + lines.len() * (GLYPH_HEIGHT + SPACING) - SPACING.min(lines.len());
+
+	let mut mask = vec![0u8; width * height];
+	for (row, line) in lines.iter().enumerate() {
+		for (column, character) in line.chars().enumerate() {
+			let left = PADDING + column * (GLYPH_WIDTH + SPACING);
+			let top = PADDING + row * (GLYPH_HEIGHT + SPACING);
+			for (y, bits) in glyph(character).iter().enumerate() {
+				for x in 0..GLYPH_WIDTH {
+					if bits & (1 << (GLYPH_WIDTH - 1 - x)) != 0 {
+						mask[(top + y) * width + left + x] = 255;
+					}
+				}
+			}
+		}
+	}
+
+	(mask, width as u32, height as u32)
+}
+
+/// Rows of a 5x7 glyph, the most significant of the five bits is the leftmost pixel.
+///
+/// Only the characters used by the overlay and the test pattern are included, others are drawn as a space.
+fn glyph(character: char) -> [u8; GLYPH_HEIGHT] {
+	match character {
+		'0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+		'1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+		'2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+		'3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+		'4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+		'5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+		'6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+		'7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+		'8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+		'9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+		'.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+		':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+		'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+		'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+		'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+		'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+		'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+		'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+		'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+		'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+		'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+		'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+		'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+		'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+		'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+		'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+		'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+		_ => [0x00; GLYPH_HEIGHT],
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn summary_uses_known_glyphs() {
+		let lines = summary(Duration::from_secs(1), 60, 2_500_000, Duration::from_millis(180), 2, 1);
+		assert_eq!(lines, ["FPS 60.0", "BITRATE 20.0 MBPS", "ENCODE 3.0 MS", "LOSS 2", "DROPPED 1"]);
+		for character in lines.concat().chars().filter(|&character| character != ' ') {
+			assert_ne!(glyph(character), [0x00; GLYPH_HEIGHT], "no glyph for '{character}'");
+		}
+	}
+
+	#[test]
+	fn text_is_rendered_inside_the_padding() {
+		let (mask, width, height) = render(&["1".to_string()]);
+		assert_eq!((width, height), (PADDING as u32 * 2 + 5, PADDING as u32 * 2 + 7));
+
+		// The top of the glyph for '1' has a single pixel in the middle.
+		let row = &mask[PADDING * width as usize..(PADDING + 1) * width as usize];
+		assert_eq!(row.iter().position(|&pixel| pixel != 0), Some(PADDING + 2));
+		assert_eq!(row.iter().filter(|&&pixel| pixel != 0).count(), 1);
+	}
+}
@@ -0,0 +1,494 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use reed_solomon_erasure::{galois_8, ReedSolomon};
+
+use crate::session::stream::RtpHeader;
+
+/// Maximum allowed number of shards in the encoder (data + parity).
+pub const MAX_SHARDS: usize = 255;
+
+// Random padding, because we need it.
+const PADDING: u32 = 0;
+
+#[repr(u8)]
+enum RtpFlag {
+	ContainsPicData = 0x1,
+	EndOfFrame = 0x2,
+	StartOfFrame = 0x4,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+struct VideoFrameHeader {
+	header_type: u8,
+
+	/// Time between capturing and encoding the frame in units of 0.1 ms, shown by Moonlight as host processing latency.
+	frame_processing_latency: u16,
+
+	frame_type: u8,
+	padding2: u32,
+}
+
+impl VideoFrameHeader {
+	fn serialize(&self, buffer: &mut Vec<u8>) {
+		buffer.extend(self.header_type.to_le_bytes());
+		buffer.extend(self.frame_processing_latency.to_le_bytes());
+		buffer.extend(self.frame_type.to_le_bytes());
+		buffer.extend(self.padding2.to_le_bytes());
+	}
+}
+
+#[derive(Debug)]
+#[repr(C)]
+struct NvVideoPacket {
+	stream_packet_index: u32,
+	frame_index: u32,
+	flags: u8,
+	reserved: u8,
+	multi_fec_flags: u8,
+	multi_fec_blocks: u8,
+	fec_info: u32,
+}
+
+impl NvVideoPacket {
+	fn serialize(&self, buffer: &mut Vec<u8>) {
+		buffer.extend(self.stream_packet_index.to_le_bytes());
+		buffer.extend(self.frame_index.to_le_bytes());
+		buffer.extend(self.flags.to_le_bytes());
+		buffer.extend(self.reserved.to_le_bytes());
+		buffer.extend(self.multi_fec_flags.to_le_bytes());
+		buffer.extend(self.multi_fec_blocks.to_le_bytes());
+		buffer.extend(self.fec_info.to_le_bytes());
+	}
+}
+
+/// Splits encoded frames in shards that are sent to the client, adding parity shards for error correction.
+pub struct Packetizer {
+	/// Size of a shard, excluding the RTP header and padding.
+	packet_size: usize,
+	minimum_fec_packets: u32,
+	fec_percentage: u8,
+
+	/// Sequence number of the next shard, continues over frames.
+	sequence_number: u32,
+
+	fec_encoders: HashMap<(usize, usize), ReedSolomon<galois_8::Field>>,
+}
+
+impl Packetizer {
+	pub fn new(packet_size: usize, minimum_fec_packets: u32, fec_percentage: u8) -> Self {
+		Self {
+			packet_size,
+			minimum_fec_packets,
+			fec_percentage,
+			sequence_number: 0,
+			fec_encoders: HashMap::new(),
+		}
+	}
+
+	/// Change the percentage of parity shards for the next frames.
+	pub fn set_fec_percentage(&mut self, fec_percentage: u8) {
+		self.fec_percentage = fec_percentage;
+	}
+
+	/// Split an encoded frame in shards, in the order in which they should be sent.
+	///
+	/// The `timestamp` is in units of 90kHz, as is common for RTP video streams.
+	/// The `processing_latency` is the time it took to capture and encode the frame, in units of 0.1 ms.
+	pub fn packetize(
+		&mut self,
+		frame: &[u8],
+		key_frame: bool,
+		frame_number: u32,
+		timestamp: u32,
+		processing_latency: u16,
+	) -> Result<Vec<Vec<u8>>, ()> {
+		if frame.is_empty() {
+			tracing::error!("Packet is empty, but we expected it to be full.");
+			return Err(());
+		}
+
+		// TODO: Figure out what this header means?
+		let video_frame_header = VideoFrameHeader {
+			header_type: 0x01, // Always 0x01 for short headers. What is this exactly?
+			frame_processing_latency: processing_latency,
+			frame_type: if key_frame { 2 } else { 1 },
+			padding2: 0,
+		};
+
+		// Prefix the frame with a VideoFrameHeader.
+		let mut buffer = Vec::with_capacity(std::mem::size_of::<VideoFrameHeader>());
+		video_frame_header.serialize(&mut buffer);
+		let packet_data = [&buffer, frame].concat();
+
+		let requested_shard_payload_size = self.packet_size - std::mem::size_of::<NvVideoPacket>();
+
+		// The total size of a shard.
+		let requested_shard_size =
+			std::mem::size_of::<RtpHeader>()
+			+ std::mem::size_of_val(&PADDING)
+			+ std::mem::size_of::<NvVideoPacket>()
+			+ requested_shard_payload_size;
+
+		// Determine how many data shards we will be sending.
+		let nr_data_shards = packet_data.len() / requested_shard_payload_size + (packet_data.len() % requested_shard_payload_size != 0) as usize; // TODO: Replace with div_ceil when it lands in stable (https://doc.rust-lang.org/std/primitive.i32.html#method.div_ceil).
+		assert!(nr_data_shards != 0);
+
+		// Determine how many parity and data shards are permitted per FEC block.
+		let nr_parity_shards_per_block = MAX_SHARDS * self.fec_percentage as usize / (100 + self.fec_percentage as usize);
+		let nr_data_shards_per_block = MAX_SHARDS - nr_parity_shards_per_block;
+
+		// We need to subtract number of data shards by 1, otherwise you can get a situation where
+		// there are for example 100 data shards allowed per block and also 100 data shards available.
+		// In this case, nr_blocks = 100 / 100 + 1 = 2, but we only need to send 1 block.
+		// Subtracting the value of nr_data_shards by 1 avoids this situation.
+		let nr_blocks = (nr_data_shards - 1) / nr_data_shards_per_block + 1;
+		let last_block_index = (nr_blocks.min(4) as u8 - 1) << 6; // TODO: Why the bit shift? To 'force' a limit of 4 blocks?
+
+		tracing::trace!("Sending a max of {nr_data_shards_per_block} data shards and {nr_parity_shards_per_block} parity shards per block.");
+		tracing::trace!("Sending {nr_blocks} blocks of video data.");
+
+		let mut all_shards = Vec::new();
+		for block_index in 0..nr_blocks {
+			// Determine what data shards are in this block.
+			let start = block_index * nr_data_shards_per_block;
+			let mut end = ((block_index + 1) * nr_data_shards_per_block)
+				.min(nr_data_shards);
+
+			if block_index == 3 {
+				tracing::debug!("Trying to create {nr_blocks} blocks, but we are limited to 4 blocks so we are sending all remaining packets without FEC.");
+				end = nr_data_shards;
+			}
+
+			// Compute how many parity shards we will need (approximately) in this block.
+			let nr_data_shards = end - start;
+			assert!(nr_data_shards != 0);
+
+			let nr_parity_shards = (nr_data_shards * self.fec_percentage as usize / 100)
+				.max(self.minimum_fec_packets as usize) // Lower limit by the minimum number of parity shards.
+				.min(MAX_SHARDS.saturating_sub(nr_data_shards)); // But hard total upper limit in the number of shards.
+
+			// Recompute the actual FEC percentage in case of a rounding error or when there are 0 parity shards.
+			let fec_percentage = nr_parity_shards * 100 / nr_data_shards;
+
+			tracing::trace!("Sending block {block_index} with {nr_data_shards} data shards and {nr_parity_shards} parity shards.");
+
+			let mut shards = Vec::with_capacity(nr_data_shards + nr_parity_shards);
+			for (block_shard_index, data_shard_index) in (start..end).enumerate() {
+				// Determine which part of the payload is in this shard.
+				let start = data_shard_index * requested_shard_payload_size;
+				let end = ((data_shard_index + 1) * requested_shard_payload_size).min(packet_data.len());
+
+				let mut shard = Vec::with_capacity(requested_shard_size);
+
+				let rtp_header = RtpHeader {
+					header: 0x90, // What is this?
+					packet_type: 0,
+					sequence_number: self.sequence_number as u16,
+					timestamp,
+					ssrc: 0,
+				};
+				rtp_header.serialize(&mut shard);
+				shard.extend(PADDING.to_le_bytes());
+
+				let mut video_packet_header = NvVideoPacket {
+					stream_packet_index: self.sequence_number << 8,
+					frame_index: frame_number,
+					flags: RtpFlag::ContainsPicData as u8,
+					reserved: 0,
+					multi_fec_flags: 0x10,
+					multi_fec_blocks: ((block_index as u8) << 4) | last_block_index,
+					fec_info: (block_shard_index << 12 | nr_data_shards << 22 | fec_percentage << 4) as u32,
+				};
+				if block_shard_index == 0 {
+					video_packet_header.flags |= RtpFlag::StartOfFrame as u8;
+				}
+				if block_shard_index == nr_data_shards - 1 {
+					video_packet_header.flags |= RtpFlag::EndOfFrame as u8;
+				}
+				video_packet_header.serialize(&mut shard);
+
+				// Append the payload.
+				shard.extend(&packet_data[start..end]);
+
+				// Pad with zeros at the end to make an equally sized shard.
+				if end - start < requested_shard_payload_size {
+					shard.extend(vec![0u8; requested_shard_payload_size - (end - start)]);
+				}
+
+				shards.push(shard);
+
+				self.sequence_number += 1;
+			}
+
+			if nr_parity_shards > 0 {
+				for _ in 0..nr_parity_shards {
+					shards.push(vec![0u8; requested_shard_size]);
+				}
+
+				self.get_fec_encoder(nr_data_shards, nr_parity_shards)?
+					.encode(&mut shards)
+					.map_err(|e| tracing::error!("Failed to encode packet as FEC shards: {e}"))?;
+
+				// Force these values for the parity shards, we don't need to reconstruct them, but Moonlight needs them to match with the frame they came from.
+				for (block_shard_index, shard) in shards[nr_data_shards..].iter_mut().enumerate() {
+					let rtp_header = unsafe { &mut *(shard.as_mut_ptr() as *mut RtpHeader) };
+					rtp_header.header = 0x90u8.to_be(); // The `.to_be` is redundant for u8, but is there to make it clear it should be big-endian.
+					rtp_header.sequence_number = (self.sequence_number as u16).to_be();
+
+					let video_packet_header = unsafe {
+						&mut *(shard.as_mut_ptr().add(std::mem::size_of::<RtpHeader>() + std::mem::size_of_val(&PADDING)) as *mut NvVideoPacket)
+					};
+					video_packet_header.multi_fec_blocks = ((block_index as u8) << 4) | last_block_index;
+					video_packet_header.fec_info = ((nr_data_shards + block_shard_index) << 12 | nr_data_shards << 22 | fec_percentage << 4) as u32;
+					video_packet_header.frame_index = frame_number;
+
+					self.sequence_number += 1;
+				}
+			}
+
+			all_shards.extend(shards);
+
+			// At this point we should have sent all the data shards in the last block, so we can break the loop.
+			if block_index == 3 {
+				break;
+			}
+		}
+
+		Ok(all_shards)
+	}
+
+	fn get_fec_encoder(&mut self, nr_data_shards: usize, nr_parity_shards: usize) -> Result<&mut ReedSolomon<galois_8::Field>, ()> {
+		Ok(match self.fec_encoders.entry((nr_data_shards, nr_parity_shards)) {
+			Entry::Occupied(e) => {
+				tracing::trace!("Found a FEC encoder for this combination of shards.");
+				e.into_mut()
+			},
+			Entry::Vacant(e) => {
+				tracing::trace!("No FEC encoder for this combination of shards, creating a new one.");
+				let encoder = e.insert(ReedSolomon::<galois_8::Field>::new(nr_data_shards, nr_parity_shards)
+					.map_err(|e| tracing::error!("Couldn't create error correction encoder: {e}"))?);
+				tracing::trace!("Finished preparing FEC encoder.");
+
+				encoder
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::testing::Rng;
+
+	use super::*;
+
+	/// Size of the RTP header, padding and video packet header in front of the payload of each shard.
+	const HEADER_SIZE: usize = 12 + 4 + 16;
+
+	/// Size of the video frame header that prefixes the frame data.
+	const FRAME_HEADER_SIZE: usize = 8;
+
+	/// Fields of a shard as Moonlight reads them.
+	#[derive(Debug)]
+	struct ParsedShard<'a> {
+		sequence_number: u16,
+		timestamp: u32,
+		stream_packet_index: u32,
+		frame_index: u32,
+		flags: u8,
+		multi_fec_blocks: u8,
+		shard_index: usize,
+		data_shards: usize,
+		fec_percentage: usize,
+		payload: &'a [u8],
+	}
+
+	fn parse(shard: &[u8]) -> ParsedShard<'_> {
+		let fec_info = u32::from_le_bytes(shard[28..32].try_into().unwrap()) as usize;
+		ParsedShard {
+			sequence_number: u16::from_be_bytes(shard[2..4].try_into().unwrap()),
+			timestamp: u32::from_be_bytes(shard[4..8].try_into().unwrap()),
+			stream_packet_index: u32::from_le_bytes(shard[16..20].try_into().unwrap()),
+			frame_index: u32::from_le_bytes(shard[20..24].try_into().unwrap()),
+			flags: shard[24],
+			multi_fec_blocks: shard[27],
+			shard_index: (fec_info >> 12) & 0x3FF,
+			data_shards: fec_info >> 22,
+			fec_percentage: (fec_info >> 4) & 0xFF,
+			payload: &shard[HEADER_SIZE..],
+		}
+	}
+
+	/// Reassemble a frame from its data shards, the way the client does when no shards are lost.
+	fn depacketize(shards: &[Vec<u8>]) -> Vec<u8> {
+		shards.iter()
+			.map(|shard| parse(shard))
+			.filter(|shard| shard.shard_index < shard.data_shards)
+			.flat_map(|shard| shard.payload.to_vec())
+			.collect()
+	}
+
+	#[test]
+	fn single_shard_matches_golden_vector() {
+		let mut packetizer = Packetizer::new(48, 0, 0);
+		let frame: Vec<u8> = (1..=10).collect();
+		let shards = packetizer.packetize(&frame, true, 7, 0x01020304, 0).unwrap();
+
+		#[rustfmt::skip]
+		let expected: Vec<u8> = [
+			// RTP header: header, packet type, sequence number, timestamp, ssrc.
+			&[0x90, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x00][..],
+			// Padding.
+			&[0x00, 0x00, 0x00, 0x00],
+			// Video packet header: stream packet index, frame index, flags, reserved, multi FEC flags, multi FEC blocks, FEC info.
+			&[0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x07, 0x00, 0x10, 0x00, 0x00, 0x00, 0x40, 0x00],
+			// Video frame header of a key frame.
+			&[0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00],
+			// Frame data, followed by zeros up to the shard size.
+			&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A],
+			&[0x00; 14],
+		].concat();
+
+		assert_eq!(shards, vec![expected]);
+	}
+
+	#[test]
+	fn frame_type_reflects_key_frames() {
+		let mut packetizer = Packetizer::new(48, 0, 0);
+		let key_frame = packetizer.packetize(&[0xFF], true, 1, 0, 0).unwrap();
+		let delta_frame = packetizer.packetize(&[0xFF], false, 2, 0, 0).unwrap();
+
+		assert_eq!(key_frame[0][HEADER_SIZE + 3], 2);
+		assert_eq!(delta_frame[0][HEADER_SIZE + 3], 1);
+	}
+
+	#[test]
+	fn processing_latency_is_in_frame_header() {
+		let mut packetizer = Packetizer::new(48, 0, 0);
+		let shards = packetizer.packetize(&[0xFF], false, 1, 0, 0x0123).unwrap();
+
+		assert_eq!(shards[0][HEADER_SIZE + 1..HEADER_SIZE + 3], [0x23, 0x01]);
+	}
+
+	#[test]
+	fn empty_frame_is_rejected() {
+		let mut packetizer = Packetizer::new(48, 0, 0);
+		assert!(packetizer.packetize(&[], false, 1, 0, 0).is_err());
+	}
+
+	#[test]
+	fn fec_shard_layout() {
+		// 5 data shards of 32 bytes, with 20% FEC but at least 2 parity shards.
+		let mut packetizer = Packetizer::new(48, 2, 20);
+		let frame = vec![0xAB; 5 * 32 - FRAME_HEADER_SIZE];
+		let shards = packetizer.packetize(&frame, false, 3, 1000, 0).unwrap();
+		assert_eq!(shards.len(), 7);
+
+		for (index, shard) in shards.iter().enumerate() {
+			assert_eq!(shard.len(), 64);
+
+			let parsed = parse(shard);
+			assert_eq!(shard[0], 0x90);
+			assert_eq!(parsed.sequence_number as usize, index);
+			assert_eq!(parsed.frame_index, 3);
+			assert_eq!(parsed.shard_index, index);
+			assert_eq!(parsed.data_shards, 5);
+			assert_eq!(parsed.fec_percentage, 2 * 100 / 5);
+			assert_eq!(parsed.multi_fec_blocks, 0);
+
+			if index < 5 {
+				assert_eq!(parsed.timestamp, 1000);
+				assert_eq!(parsed.stream_packet_index, (index as u32) << 8);
+				assert_ne!(parsed.flags & RtpFlag::ContainsPicData as u8, 0);
+				assert_eq!(parsed.flags & RtpFlag::StartOfFrame as u8 != 0, index == 0);
+				assert_eq!(parsed.flags & RtpFlag::EndOfFrame as u8 != 0, index == 4);
+			}
+		}
+	}
+
+	#[test]
+	fn lost_data_shards_can_be_recovered() {
+		let mut packetizer = Packetizer::new(48, 2, 20);
+		let frame: Vec<u8> = (0..5 * 32 - FRAME_HEADER_SIZE).map(|i| i as u8).collect();
+		let shards = packetizer.packetize(&frame, false, 1, 0, 0).unwrap();
+
+		let mut received: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+		received[1] = None;
+		received[3] = None;
+		ReedSolomon::<galois_8::Field>::new(5, 2).unwrap()
+			.reconstruct_data(&mut received)
+			.unwrap();
+
+		// Only the payload can be recovered, since the headers of the parity shards are overwritten after encoding.
+		for index in [1, 3] {
+			assert_eq!(&received[index].as_ref().unwrap()[HEADER_SIZE..], &shards[index][HEADER_SIZE..]);
+		}
+	}
+
+	#[test]
+	fn sequence_numbers_continue_across_frames() {
+		let mut packetizer = Packetizer::new(48, 1, 20);
+		let mut expected_sequence_number = 0u16;
+		for frame_number in 1..=5 {
+			let frame = vec![frame_number as u8; frame_number * 50];
+			for shard in packetizer.packetize(&frame, false, frame_number as u32, 0, 0).unwrap() {
+				let parsed = parse(&shard);
+				assert_eq!(parsed.sequence_number, expected_sequence_number);
+				assert_eq!(parsed.frame_index, frame_number as u32);
+				expected_sequence_number = expected_sequence_number.wrapping_add(1);
+			}
+		}
+	}
+
+	#[test]
+	fn large_frames_are_split_in_blocks() {
+		// With 100% FEC a block holds at most 128 data shards, so 300 data shards need 3 blocks.
+		let mut packetizer = Packetizer::new(48, 0, 100);
+		let frame = vec![0x55; 300 * 32 - FRAME_HEADER_SIZE];
+		let shards = packetizer.packetize(&frame, false, 1, 0, 0).unwrap();
+
+		let blocks: Vec<u8> = shards.iter().map(|shard| parse(shard).multi_fec_blocks).collect();
+		assert!(blocks.iter().all(|block| block & 0xC0 == 2 << 6), "last block index should be 2");
+		assert_eq!(blocks.first(), Some(&(2 << 6)));
+		assert_eq!(blocks.last(), Some(&((2 << 4) | (2 << 6))));
+
+		let payload = depacketize(&shards);
+		assert_eq!(&payload[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + frame.len()], frame.as_slice());
+	}
+
+	/// Packetize random frames with random settings and check the assumptions the client makes when depacketizing.
+	#[test]
+	fn random_frames_depacketize() {
+		let mut rng = Rng(0x5EED_1234_ABCD_0001);
+		for _ in 0..200 {
+			// Keep the number of shards low enough to fit in the FEC info, like the packet sizes Moonlight requests.
+			let packet_size = rng.range(256, 1500);
+			let minimum_fec_packets = rng.range(0, 3) as u32;
+			let fec_percentage = rng.range(0, 101) as u8;
+			let mut packetizer = Packetizer::new(packet_size, minimum_fec_packets, fec_percentage);
+
+			let frame: Vec<u8> = (0..rng.range(1, 20_000)).map(|_| rng.next() as u8).collect();
+			let shards = packetizer.packetize(&frame, false, 42, 1234, 0).unwrap();
+
+			let shard_size = HEADER_SIZE + packet_size - 16;
+			let mut data_shards = 0;
+			for (index, shard) in shards.iter().enumerate() {
+				assert_eq!(shard.len(), shard_size);
+
+				let parsed = parse(shard);
+				assert_eq!(parsed.sequence_number as usize, index);
+				assert_eq!(parsed.frame_index, 42);
+				if parsed.shard_index < parsed.data_shards {
+					assert_eq!(parsed.timestamp, 1234);
+					data_shards += 1;
+				}
+			}
+
+			let payload = depacketize(&shards);
+			assert_eq!(data_shards, (FRAME_HEADER_SIZE + frame.len()).div_ceil(packet_size - 16));
+			assert_eq!(payload.len(), data_shards * (packet_size - 16));
+			assert_eq!(&payload[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + frame.len()], frame.as_slice());
+			assert!(payload[FRAME_HEADER_SIZE + frame.len()..].iter().all(|&byte| byte == 0));
+		}
+	}
+}
@@ -0,0 +1,166 @@
+use std::{collections::VecDeque, sync::{Condvar, Mutex}, time::{Duration, Instant}};
+
+use ffmpeg::Frame;
+
+/// A captured frame, waiting to be encoded.
+struct CapturedFrame<F> {
+	frame: F,
+
+	/// Time at which the frame was captured, used to report how long the host took to process it.
+	captured_at: Instant,
+}
+
+/// Counters of the frames that passed through a [`FrameQueue`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameQueueStatistics {
+	/// Frames that the capture thread handed to the queue.
+	pub captured: u64,
+
+	/// Captured frames that were dropped because the encoder fell behind.
+	pub dropped: u64,
+}
+
+struct FrameQueueState<F> {
+	/// Captured frames in the order they were captured.
+	frames: VecDeque<CapturedFrame<F>>,
+
+	/// Buffers that the capture thread can capture the next frame in.
+	free: Vec<F>,
+
+	statistics: FrameQueueStatistics,
+}
+
+/// Hands captured frames from the capture thread to the encoder.
+///
+/// At most `depth` frames wait to be encoded. When the encoder falls behind, the oldest frame is dropped
+/// in favour of the newest one, so that the latency of the stream can't grow beyond the depth of the queue.
+/// Buffers are swapped in and out of the queue, so frames are never copied.
+pub struct FrameQueue<F = Frame> {
+	state: Mutex<FrameQueueState<F>>,
+	notifier: Condvar,
+	depth: usize,
+}
+
+impl<F> FrameQueue<F> {
+	/// Create a queue that holds at most one frame for every buffer in `buffers`, which must not be empty.
+	///
+	/// The capture thread and the encoder each own one more buffer, which they swap with the buffers of the queue.
+	pub fn new(buffers: Vec<F>) -> Self {
+		let depth = buffers.len();
+		Self {
+			state: Mutex::new(FrameQueueState {
+				frames: VecDeque::with_capacity(depth + 1),
+				free: buffers,
+				statistics: FrameQueueStatistics::default(),
+			}),
+			notifier: Condvar::new(),
+			depth,
+		}
+	}
+
+	/// Queue the frame in `frame`, replacing it with a buffer to capture the next frame in.
+	///
+	/// If the queue is full, the oldest frame is dropped.
+	pub fn push(&self, frame: &mut F, captured_at: Instant) -> Result<(), ()> {
+		let mut state = self.state.lock()
+			.map_err(|e| tracing::error!("Failed to lock frame queue: {e}"))?;
+
+		state.statistics.captured += 1;
+		if state.frames.len() >= self.depth {
+			if let Some(dropped) = state.frames.pop_front() {
+				state.free.push(dropped.frame);
+				state.statistics.dropped += 1;
+				tracing::trace!("Encoder fell behind, dropped the oldest captured frame.");
+			}
+		}
+
+		let Some(free) = state.free.pop() else {
+			tracing::error!("No free buffer to capture the next frame in.");
+			return Err(());
+		};
+		let frame = std::mem::replace(frame, free);
+		state.frames.push_back(CapturedFrame { frame, captured_at });
+		drop(state);
+
+		self.notifier.notify_one();
+		Ok(())
+	}
+
+	/// Take the oldest frame from the queue into `frame`, waiting at most `timeout` for one to be captured.
+	///
+	/// The previous contents of `frame` are reused to capture a next frame.
+	/// Returns the time at which the frame was captured, or `None` if no frame was captured in time.
+	pub fn pop(&self, frame: &mut F, timeout: Duration) -> Result<Option<Instant>, ()> {
+		let state = self.state.lock()
+			.map_err(|e| tracing::error!("Failed to lock frame queue: {e}"))?;
+		let (mut state, _) = self.notifier.wait_timeout_while(state, timeout, |state| state.frames.is_empty())
+			.map_err(|e| tracing::error!("Failed to wait for a captured frame: {e}"))?;
+
+		let Some(captured) = state.frames.pop_front() else {
+			return Ok(None);
+		};
+		let previous = std::mem::replace(frame, captured.frame);
+		state.free.push(previous);
+
+		Ok(Some(captured.captured_at))
+	}
+
+	pub fn statistics(&self) -> FrameQueueStatistics {
+		self.state.lock()
+			.map(|state| state.statistics)
+			.unwrap_or_default()
+	}
+}
+
+impl<F> Drop for FrameQueue<F> {
+	fn drop(&mut self) {
+		let statistics = self.statistics();
+		if statistics.dropped > 0 {
+			tracing::info!(
+				"Dropped {} of {} captured frames because encoding fell behind capturing.",
+				statistics.dropped, statistics.captured,
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn frames_are_encoded_in_order() {
+		let queue = FrameQueue::new(vec![0, 0]);
+		let start = Instant::now();
+		queue.push(&mut 1, start).unwrap();
+		queue.push(&mut 2, start + Duration::from_millis(1)).unwrap();
+
+		let mut encoder_buffer = 0;
+		assert_eq!(queue.pop(&mut encoder_buffer, Duration::ZERO).unwrap(), Some(start));
+		assert_eq!(encoder_buffer, 1);
+		assert_eq!(queue.pop(&mut encoder_buffer, Duration::ZERO).unwrap(), Some(start + Duration::from_millis(1)));
+		assert_eq!(encoder_buffer, 2);
+		assert_eq!(queue.pop(&mut encoder_buffer, Duration::ZERO).unwrap(), None);
+		assert_eq!(queue.statistics(), FrameQueueStatistics { captured: 2, dropped: 0 });
+	}
+
+	#[test]
+	fn latest_frame_wins_when_the_queue_is_full() {
+		let queue = FrameQueue::new(vec![0]);
+		for mut frame in 1..=3 {
+			queue.push(&mut frame, Instant::now()).unwrap();
+		}
+
+		let mut encoder_buffer = 0;
+		assert!(queue.pop(&mut encoder_buffer, Duration::ZERO).unwrap().is_some());
+		assert_eq!(encoder_buffer, 3);
+		assert_eq!(queue.statistics(), FrameQueueStatistics { captured: 3, dropped: 2 });
+
+		// Buffers of dropped frames are reused, so capturing never runs out of buffers.
+		for mut frame in 4..=10 {
+			queue.push(&mut frame, Instant::now()).unwrap();
+		}
+		assert!(queue.pop(&mut encoder_buffer, Duration::ZERO).unwrap().is_some());
+		assert_eq!(encoder_buffer, 10);
+	}
+}
@@ -0,0 +1,123 @@
+use std::sync::{atomic::{AtomicU32, Ordering}, Arc};
+
+/// Decides when the client needs an IDR frame to recover from lost frames, and keeps statistics about it.
+///
+/// Clients ask for an IDR frame when they can't decode a frame, but they also tell us about lost frames
+/// in their loss statistics and when invalidating reference frames. Loss that isn't covered yet by a key frame
+/// is recovered from immediately, instead of waiting for the client to ask for it, while loss that a key frame
+/// sent since then already covers doesn't cost another IDR frame.
+pub struct FrameRecovery {
+	/// Number of the last key frame that the encoder sent, or 0 if none was sent yet.
+	last_key_frame: Arc<AtomicU32>,
+
+	/// Value of `last_key_frame` when the last IDR frame was requested, which is pending until a new key frame is sent.
+	requested_after: Option<u32>,
+
+	/// Frames that the client reported as lost in its loss statistics.
+	lost_frames: u64,
+
+	/// IDR frames that the client asked for, explicitly or by invalidating reference frames.
+	requested_frames: u64,
+
+	/// IDR frames that were sent because the client reported loss, before it asked for them.
+	proactive_frames: u64,
+
+	/// Requests that were covered by a key frame that was already sent or requested.
+	covered_requests: u64,
+}
+
+impl FrameRecovery {
+	pub fn new() -> Self {
+		Self {
+			last_key_frame: Arc::new(AtomicU32::new(0)),
+			requested_after: None,
+			lost_frames: 0,
+			requested_frames: 0,
+			proactive_frames: 0,
+			covered_requests: 0,
+		}
+	}
+
+	/// Number of the last key frame, which the encoder updates whenever it sends one.
+	pub fn last_key_frame(&self) -> Arc<AtomicU32> {
+		self.last_key_frame.clone()
+	}
+
+	/// The client asked for an IDR frame, which is always sent.
+	pub fn on_idr_request(&mut self) {
+		self.requested_frames += 1;
+		self.requested_after = Some(self.last_key_frame.load(Ordering::Relaxed));
+	}
+
+	/// The client can't use frames up to and including `last_frame`, returns whether an IDR frame is needed.
+	pub fn on_invalidate_reference_frames(&mut self, last_frame: u64) -> bool {
+		if self.is_covered(last_frame) {
+			self.covered_requests += 1;
+			return false;
+		}
+
+		self.on_idr_request();
+		true
+	}
+
+	/// The client lost frames after `last_good_frame`, returns whether an IDR frame should be sent to recover.
+	pub fn on_loss_statistics(&mut self, lost_frames: u32, last_good_frame: u64) -> bool {
+		self.lost_frames += lost_frames as u64;
+		if lost_frames == 0 || self.is_covered(last_good_frame) {
+			return false;
+		}
+
+		self.requested_after = Some(self.last_key_frame.load(Ordering::Relaxed));
+		self.proactive_frames += 1;
+		true
+	}
+
+	/// Whether a key frame after `frame` was sent, or is about to be sent.
+	fn is_covered(&self, frame: u64) -> bool {
+		let last_key_frame = self.last_key_frame.load(Ordering::Relaxed);
+		self.requested_after == Some(last_key_frame) || last_key_frame as u64 > frame
+	}
+}
+
+impl Drop for FrameRecovery {
+	/// Log what happened to the frames of the stream, however the stream stopped.
+	fn drop(&mut self) {
+		if self.lost_frames == 0 && self.requested_frames == 0 && self.proactive_frames == 0 {
+			return;
+		}
+
+		tracing::info!(
+			"Client lost {} frame(s), sent {} requested and {} proactive IDR frame(s) to recover, {} request(s) were already covered.",
+			self.lost_frames, self.requested_frames, self.proactive_frames, self.covered_requests,
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn loss_is_recovered_once() {
+		let mut recovery = FrameRecovery::new();
+		recovery.last_key_frame().store(1, Ordering::Relaxed);
+		assert!(!recovery.on_loss_statistics(0, 10));
+		assert!(recovery.on_loss_statistics(2, 10));
+
+		// The IDR frame wasn't sent yet, so the same loss is still covered.
+		assert!(!recovery.on_loss_statistics(1, 10));
+		assert!(!recovery.on_invalidate_reference_frames(12));
+	}
+
+	#[test]
+	fn key_frame_after_the_loss_covers_it() {
+		let mut recovery = FrameRecovery::new();
+		recovery.last_key_frame().store(15, Ordering::Relaxed);
+		assert!(!recovery.on_invalidate_reference_frames(12));
+		assert!(recovery.on_invalidate_reference_frames(15));
+		assert!(!recovery.on_loss_statistics(1, 14));
+
+		recovery.last_key_frame().store(16, Ordering::Relaxed);
+		assert!(recovery.on_loss_statistics(1, 20));
+	}
+}
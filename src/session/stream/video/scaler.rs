@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+use cudarc::driver::{sys::CUdeviceptr, CudaDevice, CudaFunction, CudaSlice, DevicePtr, DeviceSlice, LaunchAsync, LaunchConfig};
+use ffmpeg::Frame;
+
+use crate::config::ScalingFilter;
+
+/// Name under which the kernels are loaded in the CUDA device.
+const MODULE_NAME: &str = "scaler";
+
+/// Size of a block of threads, each thread computes one pixel of the output.
+const BLOCK_SIZE: u32 = 16;
+
+/// Kernels that scale a packed 32 bit image, keeping the aspect ratio and filling the remaining area with black.
+const KERNELS: &str = r#"
+struct Fit {
+	float scale;
+	float offset_x;
+	float offset_y;
+	float width;
+	float height;
+};
+
+__device__ Fit fit(int source_width, int source_height, int width, int height) {
+	Fit result;
+	result.scale = fminf((float)width / source_width, (float)height / source_height);
+	result.width = source_width * result.scale;
+	result.height = source_height * result.scale;
+	result.offset_x = (width - result.width) / 2.0f;
+	result.offset_y = (height - result.height) / 2.0f;
+	return result;
+}
+
+__device__ uchar4 pixel(const unsigned char* source, int pitch, int x, int y) {
+	return *(const uchar4*)(source + y * pitch + x * 4);
+}
+
+__device__ bool position(
+	int source_width, int source_height,
+	int width, int height,
+	int x, int y,
+	float* source_x, float* source_y
+) {
+	Fit f = fit(source_width, source_height, width, height);
+	float px = x + 0.5f - f.offset_x;
+	float py = y + 0.5f - f.offset_y;
+	if (px < 0.0f || py < 0.0f || px >= f.width || py >= f.height) {
+		return false;
+	}
+
+	*source_x = px / f.scale - 0.5f;
+	*source_y = py / f.scale - 0.5f;
+	return true;
+}
+
+extern "C" __global__ void scale_nearest(
+	const unsigned char* source, int source_pitch, int source_width, int source_height,
+	unsigned char* destination, int destination_pitch, int width, int height
+) {
+	int x = blockIdx.x * blockDim.x + threadIdx.x;
+	int y = blockIdx.y * blockDim.y + threadIdx.y;
+	if (x >= width || y >= height) {
+		return;
+	}
+
+	uchar4* output = (uchar4*)(destination + y * destination_pitch + x * 4);
+	float source_x, source_y;
+	if (!position(source_width, source_height, width, height, x, y, &source_x, &source_y)) {
+		*output = make_uchar4(0, 0, 0, 0);
+		return;
+	}
+
+	int sx = min(max((int)roundf(source_x), 0), source_width - 1);
+	int sy = min(max((int)roundf(source_y), 0), source_height - 1);
+	*output = pixel(source, source_pitch, sx, sy);
+}
+
+extern "C" __global__ void scale_bilinear(
+	const unsigned char* source, int source_pitch, int source_width, int source_height,
+	unsigned char* destination, int destination_pitch, int width, int height
+) {
+	int x = blockIdx.x * blockDim.x + threadIdx.x;
+	int y = blockIdx.y * blockDim.y + threadIdx.y;
+	if (x >= width || y >= height) {
+		return;
+	}
+
+	uchar4* output = (uchar4*)(destination + y * destination_pitch + x * 4);
+	float source_x, source_y;
+	if (!position(source_width, source_height, width, height, x, y, &source_x, &source_y)) {
+		*output = make_uchar4(0, 0, 0, 0);
+		return;
+	}
+
+	source_x = fminf(fmaxf(source_x, 0.0f), source_width - 1);
+	source_y = fminf(fmaxf(source_y, 0.0f), source_height - 1);
+	int x0 = (int)source_x;
+	int y0 = (int)source_y;
+	int x1 = min(x0 + 1, source_width - 1);
+	int y1 = min(y0 + 1, source_height - 1);
+	float fx = source_x - x0;
+	float fy = source_y - y0;
+
+	uchar4 p00 = pixel(source, source_pitch, x0, y0);
+	uchar4 p10 = pixel(source, source_pitch, x1, y0);
+	uchar4 p01 = pixel(source, source_pitch, x0, y1);
+	uchar4 p11 = pixel(source, source_pitch, x1, y1);
+
+	#define LERP(c) (unsigned char)roundf( \
+		(p00.c * (1.0f - fx) + p10.c * fx) * (1.0f - fy) + \
+		(p01.c * (1.0f - fx) + p11.c * fx) * fy \
+	)
+	*output = make_uchar4(LERP(x), LERP(y), LERP(z), LERP(w));
+	#undef LERP
+}
+"#;
+
+/// Scales captured frames on the GPU to the resolution of the stream.
+///
+/// The aspect ratio of the captured frame is kept, remaining areas of the stream are black.
+pub struct Scaler {
+	cuda_device: Arc<CudaDevice>,
+	function: CudaFunction,
+
+	/// CUDA memory that images in host memory are uploaded to before they are scaled.
+	upload_buffer: Option<CudaSlice<u8>>,
+}
+
+impl Scaler {
+	pub fn new(cuda_device: Arc<CudaDevice>, filter: ScalingFilter) -> Result<Self, ()> {
+		let function_name = match filter {
+			ScalingFilter::Nearest => "scale_nearest",
+			ScalingFilter::Bilinear => "scale_bilinear",
+		};
+
+		if !cuda_device.has_func(MODULE_NAME, function_name) {
+			let ptx = cudarc::nvrtc::compile_ptx(KERNELS)
+				.map_err(|e| tracing::error!("Failed to compile scaling kernels: {e}"))?;
+			cuda_device.load_ptx(ptx, MODULE_NAME, &["scale_nearest", "scale_bilinear"])
+				.map_err(|e| tracing::error!("Failed to load scaling kernels: {e}"))?;
+		}
+
+		let function = cuda_device.get_func(MODULE_NAME, function_name)
+			.ok_or_else(|| tracing::error!("Failed to find scaling kernel '{function_name}'."))?;
+
+		Ok(Self { cuda_device, function, upload_buffer: None })
+	}
+
+	/// Scale a tightly packed image in CUDA memory to the size of a frame in CUDA memory.
+	pub fn scale(&self, source: CUdeviceptr, source_width: u32, source_height: u32, frame: &mut Frame) -> Result<(), ()> {
+		let (destination, destination_pitch, width, height) = unsafe {
+			let frame = &*frame.as_ptr();
+			(frame.data[0] as CUdeviceptr, frame.linesize[0], frame.width, frame.height)
+		};
+
+		let config = LaunchConfig {
+			grid_dim: ((width as u32).div_ceil(BLOCK_SIZE), (height as u32).div_ceil(BLOCK_SIZE), 1),
+			block_dim: (BLOCK_SIZE, BLOCK_SIZE, 1),
+			shared_mem_bytes: 0,
+		};
+
+		unsafe {
+			self.function.clone().launch(config, (
+				source,
+				source_width as i32 * 4,
+				source_width as i32,
+				source_height as i32,
+				destination,
+				destination_pitch,
+				width,
+				height,
+			))
+		}.map_err(|e| tracing::error!("Failed to scale frame: {e}"))?;
+
+		// The encoder doesn't use the stream of the kernel, so wait until the frame is complete.
+		self.cuda_device.synchronize()
+			.map_err(|e| tracing::error!("Failed to wait for scaled frame: {e}"))
+	}
+
+	/// Scale a tightly packed image in host memory to the size of a frame in CUDA memory.
+	pub fn scale_host(&mut self, source: &[u8], source_width: u32, source_height: u32, frame: &mut Frame) -> Result<(), ()> {
+		let upload_buffer = match self.upload_buffer.take() {
+			Some(upload_buffer) if upload_buffer.len() == source.len() => upload_buffer,
+			_ => self.cuda_device.alloc_zeros(source.len())
+				.map_err(|e| tracing::error!("Failed to allocate CUDA memory to upload frame: {e}"))?,
+		};
+		let upload_buffer = self.upload_buffer.insert(upload_buffer);
+		self.cuda_device.htod_sync_copy_into(source, upload_buffer)
+			.map_err(|e| tracing::error!("Failed to upload frame: {e}"))?;
+
+		let source = *upload_buffer.device_ptr();
+		self.scale(source, source_width, source_height, frame)
+	}
+}
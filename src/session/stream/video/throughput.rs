@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+/// Interval over which the throughput of the video stream is measured and logged.
+const MEASURE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Measures how much video data actually leaves the host, compared to the bitrate the client asked for.
+///
+/// Moonlight doesn't report what it received, so this only covers the host side of the connection.
+pub struct ThroughputMonitor {
+	/// Bitrate that the client asked for, in bits per second.
+	requested_bitrate: usize,
+
+	/// Bytes sent in the current interval.
+	bytes: usize,
+
+	/// Packets that failed to send in the current interval.
+	failed_packets: usize,
+
+	/// Bytes sent since the stream started.
+	total_bytes: usize,
+
+	interval_start: Instant,
+	started: Instant,
+}
+
+impl ThroughputMonitor {
+	pub fn new(requested_bitrate: usize) -> Self {
+		Self {
+			requested_bitrate,
+			bytes: 0,
+			failed_packets: 0,
+			total_bytes: 0,
+			interval_start: Instant::now(),
+			started: Instant::now(),
+		}
+	}
+
+	/// Register a packet that was sent to the client.
+	pub fn on_sent(&mut self, length: usize) {
+		self.bytes += length;
+		self.total_bytes += length;
+		self.check();
+	}
+
+	/// Register a packet that couldn't be sent to the client.
+	pub fn on_failed(&mut self) {
+		self.failed_packets += 1;
+		self.check();
+	}
+
+	fn check(&mut self) {
+		let elapsed = self.interval_start.elapsed();
+		if elapsed < MEASURE_INTERVAL {
+			return;
+		}
+
+		tracing::debug!(
+			"Sent video at {:.1} Mbps over the last {} s (client asked for {:.1} Mbps).",
+			megabits_per_second(self.bytes, elapsed),
+			elapsed.as_secs(),
+			self.requested_bitrate as f64 / 1_000_000.0,
+		);
+		if self.failed_packets > 0 {
+			tracing::warn!(
+				"Failed to send {} video packets over the last {} s, the network or the socket buffers can't keep up with the bitrate.",
+				self.failed_packets,
+				elapsed.as_secs(),
+			);
+		}
+
+		self.bytes = 0;
+		self.failed_packets = 0;
+		self.interval_start = Instant::now();
+	}
+}
+
+impl Drop for ThroughputMonitor {
+	fn drop(&mut self) {
+		if self.total_bytes > 0 {
+			tracing::debug!("Sent video at {:.1} Mbps on average.", megabits_per_second(self.total_bytes, self.started.elapsed()));
+		}
+	}
+}
+
+fn megabits_per_second(bytes: usize, duration: Duration) -> f64 {
+	bytes as f64 * 8.0 / duration.as_secs_f64().max(f64::EPSILON) / 1_000_000.0
+}
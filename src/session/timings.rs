@@ -0,0 +1,88 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use strum_macros::Display;
+
+/// Milestones of a session, in the order in which they are expected to happen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+pub enum Milestone {
+	/// The client asked to start streaming.
+	#[strum(serialize = "stream started")]
+	StreamStarted,
+
+	/// The client pinged the control stream for the first time.
+	#[strum(serialize = "first control ping")]
+	FirstControlPing,
+
+	/// The client pinged the video stream for the first time, letting us know where to send video.
+	#[strum(serialize = "first video ping")]
+	FirstVideoPing,
+
+	/// The first video frame was captured and encoded.
+	#[strum(serialize = "first frame encoded")]
+	FirstFrameEncoded,
+
+	/// The first video packet was sent to the client.
+	#[strum(serialize = "first frame sent")]
+	FirstFrameSent,
+}
+
+/// Keeps track of how long it takes from launching an application until the client receives video.
+///
+/// Only the first occurrence of each milestone is recorded, so a resumed stream doesn't change the timings.
+#[derive(Clone, Debug)]
+pub struct SessionTimings {
+	launched: Instant,
+	milestones: Arc<Mutex<Vec<(Milestone, Duration)>>>,
+}
+
+impl Default for SessionTimings {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl SessionTimings {
+	pub fn new() -> Self {
+		Self { launched: Instant::now(), milestones: Default::default() }
+	}
+
+	/// Record that a milestone was reached, logging the time since the application was launched.
+	pub fn record(&self, milestone: Milestone) {
+		let Ok(mut milestones) = self.milestones.lock() else {
+			return;
+		};
+
+		if milestones.iter().any(|(recorded, _)| *recorded == milestone) {
+			return;
+		}
+
+		let elapsed = self.launched.elapsed();
+		tracing::info!("Session reached milestone '{milestone}' {} ms after launch.", elapsed.as_millis());
+		milestones.push((milestone, elapsed));
+	}
+
+	/// Time since launch at which a milestone was reached, if it was reached.
+	pub fn get(&self, milestone: Milestone) -> Option<Duration> {
+		self.milestones.lock().ok()?
+			.iter()
+			.find(|(recorded, _)| *recorded == milestone)
+			.map(|(_, elapsed)| *elapsed)
+	}
+
+	/// Time since the application was launched.
+	pub fn elapsed(&self) -> Duration {
+		self.launched.elapsed()
+	}
+
+	/// Describe all recorded milestones, for logging.
+	pub fn summary(&self) -> String {
+		let Ok(milestones) = self.milestones.lock() else {
+			return String::new();
+		};
+
+		milestones.iter()
+			.map(|(milestone, elapsed)| format!("{milestone}: {} ms", elapsed.as_millis()))
+			.collect::<Vec<_>>()
+			.join(", ")
+	}
+}
@@ -0,0 +1,57 @@
+use super::host_display::run;
+
+/// Name of the sink that is created for a session.
+const SINK_NAME: &str = "moonshine";
+
+/// A null sink that is the default sink while streaming, so that audio only plays on the client.
+///
+/// The previous default sink is restored and the sink is removed when this is dropped.
+pub struct VirtualSink {
+	/// Index of the module that created the sink.
+	module: String,
+
+	/// Default sink before the virtual sink was created.
+	previous_default_sink: Option<String>,
+}
+
+impl VirtualSink {
+	pub fn new() -> Result<Self, ()> {
+		let previous_default_sink = default_sink()
+			.map_err(|()| tracing::warn!("Failed to get the default sink, it won't be restored after streaming."))
+			.ok();
+
+		let module = run("pactl", &[
+			"load-module",
+			"module-null-sink",
+			&format!("sink_name={SINK_NAME}"),
+			"sink_properties=device.description=Moonshine",
+		])?.trim().to_string();
+
+		let sink = Self { module, previous_default_sink };
+		run("pactl", &["set-default-sink", SINK_NAME])?;
+
+		tracing::info!("Created a virtual sink, audio of the host only plays on the client while streaming.");
+		Ok(sink)
+	}
+}
+
+impl Drop for VirtualSink {
+	fn drop(&mut self) {
+		if let Some(previous_default_sink) = &self.previous_default_sink {
+			let _ = run("pactl", &["set-default-sink", previous_default_sink]);
+		}
+		let _ = run("pactl", &["unload-module", &self.module]);
+
+		tracing::info!("Removed the virtual sink.");
+	}
+}
+
+/// Get the name of the default sink.
+fn default_sink() -> Result<String, ()> {
+	// `pactl get-default-sink` is only available in recent versions, `pactl info` works everywhere.
+	let info = run("pactl", &["info"])?;
+	info.lines()
+		.find_map(|line| line.strip_prefix("Default Sink:"))
+		.map(|sink| sink.trim().to_string())
+		.ok_or_else(|| tracing::warn!("Couldn't find the default sink in the output of 'pactl info'."))
+}
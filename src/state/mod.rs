@@ -0,0 +1,456 @@
+use serde::{Serialize, Deserialize};
+use tokio::sync::{mpsc, oneshot};
+
+use self::store::{FileStore, StateStore};
+
+mod store;
+
+/// Current version of the state format, increment when a change requires migrating stored state.
+const STATE_VERSION: u32 = 1;
+
+/// Maximum number of sessions to remember in the session history.
+const MAX_SESSION_HISTORY: usize = 100;
+
+enum StateCommand {
+	GetUuid(oneshot::Sender<String>),
+	Save(oneshot::Sender<Result<(), ()>>),
+	HasClient(String, oneshot::Sender<bool>),
+	AddClient(String),
+	HasClientCertificate(String, oneshot::Sender<bool>),
+	AddClientCertificate(String),
+	AddDevice(ClientDevice),
+	DeviceSeen(String, String, oneshot::Sender<Option<ClientDevice>>),
+	GetDevices(oneshot::Sender<Vec<ClientDevice>>),
+	SetLastStream(String, StreamSettings),
+	AddSession(SessionRecord),
+	GetActiveSession(oneshot::Sender<Option<ActiveSession>>),
+	SetActiveSession(Option<ActiveSession>),
+	// RemoveClient(String, oneshot::Sender<bool>),
+}
+
+#[derive(Clone)]
+pub struct State {
+	command_tx: mpsc::Sender<StateCommand>,
+}
+
+impl State {
+	pub async fn new() -> Result<Self, ()> {
+		let path = dirs::data_dir()
+			.ok_or_else(|| tracing::error!("Failed to get data directory."))?
+			.join("moonshine")
+			.join("state.toml");
+
+		Self::with_store(FileStore::new(path)).await
+	}
+
+	async fn with_store<S: StateStore>(store: S) -> Result<Self, ()> {
+		let data = match store.load()? {
+			Some(mut data) => {
+				if data.version < STATE_VERSION {
+					store.backup(data.version)?;
+					data.migrate();
+				}
+
+				tracing::trace!("State: {data:?}");
+				data
+			},
+			None => StateData::new(),
+		};
+
+		let (command_tx, command_rx) = mpsc::channel(10);
+		let inner = StateInner { data, store: Box::new(store) };
+		tokio::spawn(inner.run(command_rx));
+
+		let state = Self { command_tx };
+		state.save().await?;
+
+		Ok(state)
+	}
+
+	pub async fn get_uuid(&self) -> Result<String, ()> {
+		let (uuid_tx, uuid_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::GetUuid(uuid_tx)).await
+			.map_err(|e| tracing::error!("Failed to send GetUuid command: {e}"))?;
+		uuid_rx.await.map_err(|e| tracing::error!("Failed to receive GetUuid response: {e}"))
+	}
+
+	pub async fn save(&self) -> Result<(), ()> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::Save(result_tx)).await
+			.map_err(|e| tracing::error!("Failed to send Save command: {e}"))?;
+		result_rx.await.map_err(|e| tracing::error!("Failed to receive Save response: {e}"))?
+	}
+
+	pub async fn has_client(&self, client: String) -> Result<bool, ()> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::HasClient(client, result_tx)).await
+			.map_err(|e| tracing::error!("Failed to send HasClient command: {e}"))?;
+		let result = result_rx.await.map_err(|e| tracing::error!("Failed to receive HasClient response: {e}"))?;
+
+		self.save().await?;
+
+		Ok(result)
+	}
+
+	pub async fn add_client(&self, client: String) -> Result<(), ()> {
+		self.command_tx.send(StateCommand::AddClient(client)).await
+			.map_err(|e| tracing::error!("Failed to send AddClient command: {e}"))
+	}
+
+	pub async fn has_client_certificate(&self, certificate: String) -> Result<bool, ()> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::HasClientCertificate(certificate, result_tx)).await
+			.map_err(|e| tracing::error!("Failed to send HasClientCertificate command: {e}"))?;
+		result_rx.await.map_err(|e| tracing::error!("Failed to receive HasClientCertificate response: {e}"))
+	}
+
+	pub async fn add_client_certificate(&self, certificate: String) -> Result<(), ()> {
+		self.command_tx.send(StateCommand::AddClientCertificate(certificate)).await
+			.map_err(|e| tracing::error!("Failed to send AddClientCertificate command: {e}"))?;
+
+		self.save().await
+	}
+
+	/// Remember a paired device, replacing a device with the same certificate.
+	pub async fn add_device(&self, device: ClientDevice) -> Result<(), ()> {
+		self.command_tx.send(StateCommand::AddDevice(device)).await
+			.map_err(|e| tracing::error!("Failed to send AddDevice command: {e}"))?;
+
+		self.save().await
+	}
+
+	/// Record that the device with this certificate fingerprint connected from `address`, returning the device if it is known.
+	///
+	/// This is not saved right away, since clients connect every few seconds while they show the list of applications.
+	pub async fn device_seen(&self, fingerprint: String, address: String) -> Result<Option<ClientDevice>, ()> {
+		let (device_tx, device_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::DeviceSeen(fingerprint, address, device_tx)).await
+			.map_err(|e| tracing::error!("Failed to send DeviceSeen command: {e}"))?;
+		device_rx.await.map_err(|e| tracing::error!("Failed to receive DeviceSeen response: {e}"))
+	}
+
+	/// Get the devices that paired with this host.
+	pub async fn get_devices(&self) -> Result<Vec<ClientDevice>, ()> {
+		let (devices_tx, devices_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::GetDevices(devices_tx)).await
+			.map_err(|e| tracing::error!("Failed to send GetDevices command: {e}"))?;
+		devices_rx.await.map_err(|e| tracing::error!("Failed to receive GetDevices response: {e}"))
+	}
+
+	/// Remember the settings of the stream that the device with this certificate fingerprint negotiated.
+	pub async fn set_last_stream(&self, fingerprint: String, settings: StreamSettings) -> Result<(), ()> {
+		self.command_tx.send(StateCommand::SetLastStream(fingerprint, settings)).await
+			.map_err(|e| tracing::error!("Failed to send SetLastStream command: {e}"))?;
+
+		self.save().await
+	}
+
+	pub async fn add_session(&self, session: SessionRecord) -> Result<(), ()> {
+		self.command_tx.send(StateCommand::AddSession(session)).await
+			.map_err(|e| tracing::error!("Failed to send AddSession command: {e}"))?;
+
+		self.save().await
+	}
+
+	pub async fn get_active_session(&self) -> Result<Option<ActiveSession>, ()> {
+		let (session_tx, session_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::GetActiveSession(session_tx)).await
+			.map_err(|e| tracing::error!("Failed to send GetActiveSession command: {e}"))?;
+		session_rx.await.map_err(|e| tracing::error!("Failed to receive GetActiveSession response: {e}"))
+	}
+
+	/// Remember the session that is currently active, or that no session is active.
+	pub async fn set_active_session(&self, session: Option<ActiveSession>) -> Result<(), ()> {
+		self.command_tx.send(StateCommand::SetActiveSession(session)).await
+			.map_err(|e| tracing::error!("Failed to send SetActiveSession command: {e}"))?;
+
+		self.save().await
+	}
+
+	// pub async fn remove_client(&self, client: String) -> Result<bool, ()> {
+	// 	let (result_tx, result_rx) = oneshot::channel();
+	// 	self.command_tx.send(StateCommand::RemoveClient(client, result_tx)).await
+	// 		.map_err(|e| tracing::error!("Failed to send RemoveClient command: {e}"))?;
+	// 	let result = result_rx.await.map_err(|e| tracing::error!("Failed to receive RemoveClient response: {e}"))?;
+
+	// 	self.save().await?;
+
+	// 	Ok(result)
+	// }
+}
+
+/// A device that paired with this host.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientDevice {
+	/// SHA-256 fingerprint of the certificate of the device, which identifies the device.
+	pub fingerprint: String,
+
+	/// Name of the device, as reported by the client while pairing.
+	pub name: String,
+
+	/// Time at which the device paired, in seconds since the UNIX epoch.
+	pub paired_at: u64,
+
+	/// Time at which the device last connected, in seconds since the UNIX epoch.
+	#[serde(default)]
+	pub last_seen: Option<u64>,
+
+	/// Address from which the device last connected.
+	#[serde(default)]
+	pub last_address: Option<String>,
+
+	/// Settings of the last stream to the device.
+	#[serde(default)]
+	pub last_stream: Option<StreamSettings>,
+}
+
+/// Settings of a stream, as negotiated with the client after the limits of the device were applied.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StreamSettings {
+	pub width: u32,
+	pub height: u32,
+	pub fps: u32,
+
+	/// Bitrate of the video stream, in kbps.
+	pub bitrate: usize,
+
+	/// Codec of the video stream, for example "HEVC 4:4:4".
+	pub codec: String,
+
+	/// Number of audio channels.
+	pub audio_channels: u8,
+
+	/// Time at which the stream started, in seconds since the UNIX epoch.
+	pub started_at: u64,
+}
+
+/// A session that was started on this host.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionRecord {
+	/// Id of the application that was launched.
+	pub application_id: i32,
+
+	/// Title of the application that was launched.
+	pub application: String,
+
+	/// Time at which the session was started, in seconds since the UNIX epoch.
+	pub started_at: u64,
+}
+
+/// The session that was active when the state was last saved.
+///
+/// Used to restore the session after moonshine restarts, so that the client can resume it.
+/// The keys of the session are not stored, because the client sends new keys when it resumes the session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActiveSession {
+	/// Unique id of the client that launched the session.
+	pub client_id: String,
+
+	/// Name of the device that launched the session, if it is known.
+	#[serde(default)]
+	pub device_name: Option<String>,
+
+	/// SHA-256 fingerprint of the certificate of the device that launched the session, if it is known.
+	#[serde(default)]
+	pub device_fingerprint: Option<String>,
+
+	/// Id of the application that was launched.
+	pub application_id: i32,
+
+	/// Resolution of the video stream.
+	pub resolution: (u32, u32),
+
+	/// Refresh rate of the video stream.
+	pub refresh_rate: u32,
+
+	/// Whether the client asked to keep playing audio on the host.
+	#[serde(default)]
+	pub host_audio: bool,
+
+	/// Whether the client asked for an HDR stream.
+	#[serde(default)]
+	pub hdr: bool,
+}
+
+/// The state that is persisted by a `StateStore`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateData {
+	/// Version of the format of the state, states without version are from before versioning was added.
+	#[serde(default)]
+	version: u32,
+
+	unique_id: String,
+	clients: Vec<String>,
+
+	/// Certificates (in PEM format) of paired clients, used to authorize HTTPS connections.
+	#[serde(default)]
+	client_certificates: Vec<String>,
+
+	/// Devices that paired with this host.
+	#[serde(default)]
+	devices: Vec<ClientDevice>,
+
+	/// Most recent sessions, oldest first.
+	#[serde(default)]
+	sessions: Vec<SessionRecord>,
+
+	/// Session that was active when the state was saved, if any.
+	#[serde(default)]
+	active_session: Option<ActiveSession>,
+}
+
+impl StateData {
+	fn new() -> Self {
+		Self {
+			version: STATE_VERSION,
+			unique_id: uuid::Uuid::new_v4().to_string(),
+			clients: Default::default(),
+			client_certificates: Default::default(),
+			devices: Default::default(),
+			sessions: Default::default(),
+			active_session: None,
+		}
+	}
+
+	/// Migrate state from an older version to the current version.
+	fn migrate(&mut self) {
+		tracing::info!("Migrating state from version {} to version {STATE_VERSION}.", self.version);
+
+		// Version 0 is identical to version 1, except for the fields that are filled with defaults.
+		self.version = STATE_VERSION;
+	}
+}
+
+/// Current time in seconds since the UNIX epoch.
+pub fn unix_time() -> u64 {
+	std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.map(|duration| duration.as_secs())
+		.unwrap_or(0)
+}
+
+struct StateInner {
+	data: StateData,
+	store: Box<dyn StateStore>,
+}
+
+impl StateInner {
+
+	async fn run(mut self, mut command_rx: mpsc::Receiver<StateCommand>) {
+		while let Some(command) = command_rx.recv().await {
+			match command {
+				StateCommand::GetUuid(uuid_tx) => {
+					if uuid_tx.send(self.data.unique_id.clone()).is_err() {
+						tracing::error!("Failed to send GetUuid result.");
+					}
+				},
+
+				StateCommand::Save(result_tx) => {
+					let result = self.store.save(&self.data);
+					if result_tx.send(result).is_err() {
+						tracing::error!("Failed to send Save result.");
+					}
+				},
+
+				StateCommand::HasClient(client, result_tx) => {
+					if result_tx.send(self.has_client(&client)).is_err() {
+						tracing::error!("Failed to send HasClient result.");
+					}
+				},
+
+				StateCommand::AddClient(client) => {
+					// TODO: Return error to caller.
+					let _ = self.add_client(client);
+				},
+
+				StateCommand::HasClientCertificate(certificate, result_tx) => {
+					if result_tx.send(self.data.client_certificates.contains(&certificate)).is_err() {
+						tracing::error!("Failed to send HasClientCertificate result.");
+					}
+				},
+
+				StateCommand::AddClientCertificate(certificate) => {
+					if !self.data.client_certificates.contains(&certificate) {
+						self.data.client_certificates.push(certificate);
+					}
+				},
+
+				StateCommand::AddDevice(device) => {
+					self.data.devices.retain(|d| d.fingerprint != device.fingerprint);
+					self.data.devices.push(device);
+				},
+
+				StateCommand::DeviceSeen(fingerprint, address, device_tx) => {
+					let device = self.data.devices.iter_mut()
+						.find(|d| d.fingerprint == fingerprint)
+						.map(|device| {
+							device.last_seen = Some(unix_time());
+							device.last_address = Some(address);
+							device.clone()
+						});
+					if device_tx.send(device).is_err() {
+						tracing::error!("Failed to send DeviceSeen result.");
+					}
+				},
+
+				StateCommand::GetDevices(devices_tx) => {
+					if devices_tx.send(self.data.devices.clone()).is_err() {
+						tracing::error!("Failed to send GetDevices result.");
+					}
+				},
+
+				StateCommand::SetLastStream(fingerprint, settings) => {
+					if let Some(device) = self.data.devices.iter_mut().find(|d| d.fingerprint == fingerprint) {
+						device.last_stream = Some(settings);
+					}
+				},
+
+				StateCommand::AddSession(session) => {
+					self.data.sessions.push(session);
+					let excess = self.data.sessions.len().saturating_sub(MAX_SESSION_HISTORY);
+					self.data.sessions.drain(..excess);
+				},
+
+				StateCommand::GetActiveSession(session_tx) => {
+					if session_tx.send(self.data.active_session.clone()).is_err() {
+						tracing::error!("Failed to send GetActiveSession result.");
+					}
+				},
+
+				StateCommand::SetActiveSession(session) => {
+					self.data.active_session = session;
+				},
+
+				// StateCommand::RemoveClient(client, result_tx) => {
+				// 	if result_tx.send(self.remove_client(client)).is_err() {
+				// 		tracing::error!("Failed to send RemoveClient result.");
+				// 	}
+				// },
+			}
+		}
+	}
+
+	fn has_client(&self, key: &String) -> bool {
+		self.data.clients.contains(key)
+	}
+
+	fn add_client(&mut self, key: String) -> bool {
+		if self.data.clients.contains(&key) {
+			tracing::error!("Failed to add client ('{key}'), client already exists.");
+			false
+		} else {
+			self.data.clients.push(key);
+			true
+		}
+	}
+
+	// fn remove_client(&mut self, key: String) -> bool {
+	// 	if !self.data.clients.contains(&key) {
+	// 		tracing::error!("Failed to remove client ('{key}'), client doesn't exist.");
+	// 		false
+	// 	} else {
+	// 		self.data.clients.retain(|c| c != &key);
+	// 		true
+	// 	}
+	// }
+}
@@ -0,0 +1,101 @@
+use std::{fs::{File, OpenOptions, Permissions}, io::Write, os::unix::fs::{OpenOptionsExt, PermissionsExt}, path::{Path, PathBuf}};
+
+use super::StateData;
+
+/// Permissions of the state file and its backups, they describe the paired devices and are only readable by the owner.
+const STATE_FILE_MODE: u32 = 0o600;
+
+/// Storage backend for the persistent state.
+pub trait StateStore: Send + 'static {
+	/// Load the stored state, returns `None` if no state was stored yet.
+	fn load(&self) -> Result<Option<StateData>, ()>;
+
+	/// Store the state, replacing the previously stored state.
+	///
+	/// Implementations must make sure that a failure halfway (for example a power loss) leaves the previous state intact.
+	fn save(&self, state: &StateData) -> Result<(), ()>;
+
+	/// Keep a copy of the stored state before it is migrated to a newer version.
+	fn backup(&self, version: u32) -> Result<(), ()>;
+}
+
+/// Stores the state as a TOML file.
+///
+/// The file is replaced atomically by writing to a temporary file first and renaming it over the original.
+pub struct FileStore {
+	path: PathBuf,
+}
+
+impl FileStore {
+	pub fn new(path: PathBuf) -> Self {
+		Self { path }
+	}
+}
+
+impl StateStore for FileStore {
+	fn load(&self) -> Result<Option<StateData>, ()> {
+		if !self.path.exists() {
+			return Ok(None);
+		}
+
+		let serialized = std::fs::read_to_string(&self.path)
+			.map_err(|e| tracing::error!("Failed to read state file: {e}"))?;
+		let state = toml::from_str(&serialized)
+			.map_err(|e| tracing::error!("Failed to parse state file: {e}"))?;
+
+		tracing::debug!("Successfully loaded state from {:?}", self.path);
+		Ok(Some(state))
+	}
+
+	fn save(&self, state: &StateData) -> Result<(), ()> {
+		let parent_dir = self.path.parent()
+			.ok_or_else(|| tracing::error!("Failed to get state dir for file {:?}", self.path))?;
+		std::fs::create_dir_all(parent_dir)
+			.map_err(|e| tracing::error!("Failed to create state dir: {e}"))?;
+
+		let serialized = toml::to_string_pretty(state)
+			.map_err(|e| tracing::error!("Failed to serialize state: {e}"))?;
+
+		let temporary_path = self.path.with_extension("toml.tmp");
+		write_synced(&temporary_path, serialized.as_bytes())?;
+		std::fs::rename(&temporary_path, &self.path)
+			.map_err(|e| tracing::error!("Failed to move {:?} to {:?}: {e}", temporary_path, self.path))?;
+
+		// Make sure the rename itself is persisted.
+		File::open(parent_dir)
+			.and_then(|directory| directory.sync_all())
+			.map_err(|e| tracing::error!("Failed to sync state dir: {e}"))
+	}
+
+	fn backup(&self, version: u32) -> Result<(), ()> {
+		if !self.path.exists() {
+			return Ok(());
+		}
+
+		let backup_path = self.path.with_extension(format!("v{version}.toml"));
+		let contents = std::fs::read(&self.path)
+			.map_err(|e| tracing::error!("Failed to read state file: {e}"))?;
+		write_synced(&backup_path, &contents)?;
+
+		tracing::info!("Backed up state file to {:?}", backup_path);
+		Ok(())
+	}
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> Result<(), ()> {
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.mode(STATE_FILE_MODE)
+		.open(path)
+		.map_err(|e| tracing::error!("Failed to create {:?}: {e}", path))?;
+
+	// The mode only applies to new files, so files created by older versions are restricted here.
+	file.set_permissions(Permissions::from_mode(STATE_FILE_MODE))
+		.map_err(|e| tracing::error!("Failed to set permissions of {:?}: {e}", path))?;
+	file.write_all(contents)
+		.map_err(|e| tracing::error!("Failed to write {:?}: {e}", path))?;
+	file.sync_all()
+		.map_err(|e| tracing::error!("Failed to sync {:?}: {e}", path))
+}
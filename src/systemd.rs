@@ -0,0 +1,157 @@
+use std::{net::SocketAddr, os::{fd::{FromRawFd, OwnedFd, RawFd}, linux::net::SocketAddrExt, unix::net::{self, UnixDatagram}}, sync::Arc, time::Duration};
+
+/// First file descriptor that systemd passes for socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sends service state to systemd, for units with `Type=notify`.
+#[derive(Clone)]
+pub struct Notifier {
+	socket: Arc<UnixDatagram>,
+	address: net::SocketAddr,
+
+	/// Interval at which systemd expects a watchdog ping, if the watchdog is enabled.
+	watchdog_interval: Option<Duration>,
+}
+
+impl Notifier {
+	/// Connect to the socket that systemd passed in `NOTIFY_SOCKET`, if moonshine was started by systemd.
+	///
+	/// The variables are removed from the environment, so that applications started by moonshine don't pick them up.
+	/// Changing the environment is only sound while no other threads run, so this must be called before the runtime is started.
+	/// Logging isn't set up yet at that point, so problems are added to `warnings` to log them later.
+	pub fn from_env(warnings: &mut Vec<String>) -> Option<Self> {
+		let path = std::env::var("NOTIFY_SOCKET").ok()?;
+		let watchdog_interval = watchdog_interval();
+		std::env::remove_var("NOTIFY_SOCKET");
+		std::env::remove_var("WATCHDOG_USEC");
+		std::env::remove_var("WATCHDOG_PID");
+
+		let address = match path.strip_prefix('@') {
+			Some(name) => net::SocketAddr::from_abstract_name(name),
+			None => net::SocketAddr::from_pathname(&path),
+		};
+		let address = address
+			.map_err(|e| warnings.push(format!("Invalid systemd notification socket '{path}': {e}")))
+			.ok()?;
+		let socket = UnixDatagram::unbound()
+			.map_err(|e| warnings.push(format!("Failed to create socket for systemd notifications: {e}")))
+			.ok()?;
+
+		Some(Self { socket: Arc::new(socket), address, watchdog_interval })
+	}
+
+	/// Tell systemd that moonshine finished starting up and accepts connections.
+	pub fn ready(&self) {
+		self.notify("READY=1");
+	}
+
+	/// Tell systemd that moonshine is shutting down.
+	pub fn stopping(&self) {
+		self.notify("STOPPING=1");
+	}
+
+	/// Interval at which systemd expects `watchdog` to be called, if the watchdog is enabled.
+	pub fn watchdog_interval(&self) -> Option<Duration> {
+		self.watchdog_interval
+	}
+
+	/// Tell systemd that moonshine is still alive, so that it doesn't restart moonshine.
+	pub fn watchdog(&self) {
+		self.notify("WATCHDOG=1");
+	}
+
+	fn notify(&self, state: &str) {
+		if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+			tracing::warn!("Failed to send '{state}' to systemd: {e}");
+		}
+	}
+}
+
+/// Half of the watchdog timeout that systemd configured for this process, as recommended by `sd_watchdog_enabled`.
+fn watchdog_interval() -> Option<Duration> {
+	if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+		if pid.parse::<u32>().ok() != Some(std::process::id()) {
+			return None;
+		}
+	}
+
+	let timeout = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+	if timeout == 0 {
+		return None;
+	}
+
+	Some(Duration::from_micros(timeout / 2))
+}
+
+/// Listening sockets that systemd passed to moonshine through socket activation.
+#[derive(Default)]
+pub struct ActivatedSockets {
+	listeners: Vec<std::net::TcpListener>,
+}
+
+impl ActivatedSockets {
+	/// Take the sockets that systemd passed in `LISTEN_FDS`, if they are meant for this process.
+	///
+	/// The variables are removed from the environment, so that applications started by moonshine don't pick them up.
+	/// Changing the environment is only sound while no other threads run, so this must be called before the runtime is started.
+	/// Logging isn't set up yet at that point, so problems are added to `warnings` to log them later.
+	pub fn from_env(warnings: &mut Vec<String>) -> Self {
+		let pid = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+		let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok());
+		std::env::remove_var("LISTEN_PID");
+		std::env::remove_var("LISTEN_FDS");
+		std::env::remove_var("LISTEN_FDNAMES");
+
+		let (Some(pid), Some(count)) = (pid, count) else {
+			return Self::default();
+		};
+		if pid != std::process::id() {
+			return Self::default();
+		}
+
+		let mut listeners = Vec::new();
+		for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count) {
+			// Don't leak the sockets to the applications that we start.
+			if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+				warnings.push(format!("Ignoring invalid socket {fd} passed by systemd: {}", std::io::Error::last_os_error()));
+				continue;
+			}
+
+			// SAFETY: systemd passes these file descriptors to us, nothing else in the process owns them.
+			let listener = std::net::TcpListener::from(unsafe { OwnedFd::from_raw_fd(fd) });
+			match listener.local_addr() {
+				Ok(_) => listeners.push(listener),
+				Err(e) => warnings.push(format!("Ignoring socket {fd} passed by systemd, it is not a TCP socket: {e}")),
+			}
+		}
+
+		Self { listeners }
+	}
+
+	/// Listen on the socket that systemd passed for the port of `address`, or bind to `address` if there is none.
+	pub fn listen(&mut self, address: SocketAddr) -> Result<tokio::net::TcpListener, ()> {
+		let listener = match self.listeners.iter().position(|listener| listener.local_addr().is_ok_and(|local| local.port() == address.port())) {
+			Some(index) => {
+				let listener = self.listeners.swap_remove(index);
+				tracing::debug!("Using socket from systemd for {address}.");
+				listener
+			},
+			None => std::net::TcpListener::bind(address)
+				.map_err(|e| tracing::error!("Failed to bind to address {address}: {e}"))?,
+		};
+
+		listener.set_nonblocking(true)
+			.map_err(|e| tracing::error!("Failed to make socket for {address} non-blocking: {e}"))?;
+		tokio::net::TcpListener::from_std(listener)
+			.map_err(|e| tracing::error!("Failed to listen on {address}: {e}"))
+	}
+
+	/// Warn about sockets that systemd passed, but which don't match a port that moonshine listens on.
+	pub fn warn_unused(&self) {
+		for listener in &self.listeners {
+			if let Ok(address) = listener.local_addr() {
+				tracing::warn!("Socket for {address} was passed by systemd, but moonshine doesn't listen on that port.");
+			}
+		}
+	}
+}
@@ -0,0 +1,22 @@
+//! Helpers shared by the tests.
+
+/// Small deterministic random number generator (xorshift), so failures can be reproduced.
+pub struct Rng(pub u64);
+
+impl Rng {
+	pub fn next(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	pub fn range(&mut self, start: usize, end: usize) -> usize {
+		start + (self.next() % (end - start) as u64) as usize
+	}
+
+	/// Random bytes with a random length below `max_length`.
+	pub fn bytes(&mut self, max_length: usize) -> Vec<u8> {
+		(0..self.range(0, max_length)).map(|_| self.next() as u8).collect()
+	}
+}
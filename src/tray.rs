@@ -0,0 +1,160 @@
+use ksni::{menu::StandardItem, MenuItem, Status, ToolTip, TrayMethods};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+	events::{Event, Events},
+	session::{SessionManager, SessionShutdownReason},
+};
+
+/// Status notifier item that shows the state of the session in the tray of the desktop.
+struct StatusTray {
+	session_manager: SessionManager,
+
+	/// Title of the application of the active session, if there is one.
+	application: Option<String>,
+
+	/// Name of the device that started or is streaming the active session.
+	device_name: Option<String>,
+}
+
+impl StatusTray {
+	fn description(&self) -> String {
+		match (&self.application, &self.device_name) {
+			(Some(application), Some(device_name)) => format!("Streaming {application} to {device_name}"),
+			(Some(application), None) => format!("Streaming {application}"),
+			(None, _) => "No active session".to_string(),
+		}
+	}
+}
+
+impl ksni::Tray for StatusTray {
+	fn id(&self) -> String {
+		"moonshine".to_string()
+	}
+
+	fn title(&self) -> String {
+		"Moonshine".to_string()
+	}
+
+	fn status(&self) -> Status {
+		if self.application.is_some() {
+			Status::Active
+		} else {
+			Status::Passive
+		}
+	}
+
+	fn icon_name(&self) -> String {
+		if self.application.is_some() {
+			"media-record".to_string()
+		} else {
+			"network-server".to_string()
+		}
+	}
+
+	fn tool_tip(&self) -> ToolTip {
+		ToolTip {
+			title: "Moonshine".to_string(),
+			description: self.description(),
+			..Default::default()
+		}
+	}
+
+	fn menu(&self) -> Vec<MenuItem<Self>> {
+		vec![
+			StandardItem {
+				label: self.description(),
+				enabled: false,
+				..Default::default()
+			}.into(),
+			MenuItem::Separator,
+			StandardItem {
+				label: "Pause stream".to_string(),
+				icon_name: "media-playback-pause".to_string(),
+				enabled: self.application.is_some(),
+				activate: Box::new(|tray: &mut Self| {
+					let session_manager = tray.session_manager.clone();
+					tokio::spawn(async move {
+						let _ = session_manager.pause_session().await;
+					});
+				}),
+				..Default::default()
+			}.into(),
+			StandardItem {
+				label: "Resume stream".to_string(),
+				icon_name: "media-playback-start".to_string(),
+				enabled: self.application.is_some(),
+				activate: Box::new(|tray: &mut Self| {
+					let session_manager = tray.session_manager.clone();
+					tokio::spawn(async move {
+						let _ = session_manager.resume_session().await;
+					});
+				}),
+				..Default::default()
+			}.into(),
+			StandardItem {
+				label: "Stop session".to_string(),
+				icon_name: "media-playback-stop".to_string(),
+				enabled: self.application.is_some(),
+				activate: Box::new(|tray: &mut Self| {
+					let session_manager = tray.session_manager.clone();
+					tokio::spawn(async move {
+						let _ = session_manager.stop_session(SessionShutdownReason::HostStopped).await;
+					});
+				}),
+				..Default::default()
+			}.into(),
+		]
+	}
+}
+
+/// Show the state of the session in the tray of the desktop, for as long as moonshine runs.
+pub fn spawn(session_manager: SessionManager, events: &Events) {
+	let subscription = events.subscribe();
+	tokio::spawn(async move {
+		let _ = run(session_manager, subscription).await;
+	});
+}
+
+async fn run(session_manager: SessionManager, mut subscription: broadcast::Receiver<Event>) -> Result<(), ()> {
+	let context = session_manager.get_session_context().await
+		.map_err(|e| tracing::error!("Failed to get session context: {e}"))?;
+	let tray = StatusTray {
+		session_manager,
+		application: context.as_ref().map(|context| context.application.title.clone()),
+		device_name: context.and_then(|context| context.device_name),
+	};
+
+	// Desktops without a status notifier host (or without a session bus) can't show the tray, which is fine.
+	let handle = tray.spawn().await
+		.map_err(|e| tracing::warn!("Failed to show status in the tray: {e}"))?;
+
+	loop {
+		match subscription.recv().await {
+			Ok(Event::SessionStarted { device_name, application, .. }) => {
+				handle.update(|tray| {
+					tray.application = Some(application);
+					tray.device_name = device_name;
+				}).await;
+			},
+			Ok(Event::ClientConnected { device_name: Some(device_name), .. }) => {
+				handle.update(|tray| tray.device_name = Some(device_name)).await;
+			},
+			Ok(Event::SessionEnded { .. }) => {
+				handle.update(|tray| {
+					tray.application = None;
+					tray.device_name = None;
+				}).await;
+			},
+			Ok(_) => continue,
+			Err(RecvError::Lagged(skipped)) => {
+				tracing::warn!("Tray fell behind, skipped {skipped} event(s).");
+				continue;
+			},
+			Err(RecvError::Closed) => break,
+		}
+	}
+
+	handle.shutdown().await;
+	Ok(())
+}
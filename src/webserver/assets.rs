@@ -0,0 +1,138 @@
+use std::{collections::HashMap, convert::Infallible, path::Path, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}, time::SystemTime};
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use image::{imageops::FilterType, ImageFormat};
+
+/// Size that boxart is scaled down to when the client doesn't ask for a size, which is the size GameStream used.
+pub const BOXART_WIDTH: u32 = 628;
+pub const BOXART_HEIGHT: u32 = 888;
+
+/// Largest width or height that a client can ask for.
+pub const MAX_ASSET_SIZE: u32 = 2048;
+
+/// Number of scaled assets kept in memory, the cache is cleared when it grows beyond this.
+const MAX_CACHED_ASSETS: usize = 256;
+
+/// Size of the frames that an asset is sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Boxart that was scaled and encoded before, so that it doesn't have to be done again for every request.
+#[derive(Clone, Default)]
+pub struct AssetCache(Arc<Mutex<HashMap<AssetKey, CachedAsset>>>);
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct AssetKey {
+	application_id: i32,
+	width: u32,
+	height: u32,
+}
+
+#[derive(Clone)]
+pub struct CachedAsset {
+	/// Time at which the image on disk was modified when it was loaded.
+	modified: Option<SystemTime>,
+
+	/// Encoded PNG image.
+	pub data: Bytes,
+
+	/// Tag that changes when the image changes, so that clients can check whether their copy is still valid.
+	pub etag: String,
+}
+
+impl AssetCache {
+	/// Get the image at `path` as PNG, scaled down to fit in `width`x`height`.
+	///
+	/// The image is only loaded when it isn't cached yet, or when it changed on disk since it was cached.
+	pub fn get(&self, application_id: i32, path: &Path, width: u32, height: u32) -> Result<CachedAsset, String> {
+		let key = AssetKey { application_id, width, height };
+		let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+		if let Ok(cache) = self.0.lock() {
+			if let Some(asset) = cache.get(&key).filter(|asset| modified.is_some() && asset.modified == modified) {
+				return Ok(asset.clone());
+			}
+		}
+
+		let image = image::open(path)
+			.map_err(|e| format!("Failed to load boxart: {e}"))?;
+		let image = if image.width() > width || image.height() > height {
+			image.resize(width, height, FilterType::Lanczos3)
+		} else {
+			image
+		};
+
+		let mut buffer = std::io::Cursor::new(vec![]);
+		image.write_to(&mut buffer, ImageFormat::Png)
+			.map_err(|e| format!("Failed to encode boxart: {e}"))?;
+		let data = buffer.into_inner();
+		let etag = hex::encode(&openssl::sha::sha256(&data)[..16]);
+		let asset = CachedAsset { modified, data: Bytes::from(data), etag };
+
+		if let Ok(mut cache) = self.0.lock() {
+			if cache.len() >= MAX_CACHED_ASSETS {
+				cache.clear();
+			}
+			cache.insert(key, asset.clone());
+		}
+
+		Ok(asset)
+	}
+}
+
+/// Body that sends an asset in chunks, so that large images are streamed to the client as the connection allows.
+///
+/// The chunks share the memory of the cached asset, nothing is copied.
+pub struct ChunkedBody {
+	data: Bytes,
+}
+
+impl ChunkedBody {
+	pub fn new(data: Bytes) -> Self {
+		Self { data }
+	}
+}
+
+impl Body for ChunkedBody {
+	type Data = Bytes;
+	type Error = Infallible;
+
+	fn poll_frame(mut self: Pin<&mut Self>, _context: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+		if self.data.is_empty() {
+			return Poll::Ready(None);
+		}
+
+		let length = self.data.len().min(CHUNK_SIZE);
+		Poll::Ready(Some(Ok(Frame::data(self.data.split_to(length)))))
+	}
+
+	fn is_end_stream(&self) -> bool {
+		self.data.is_empty()
+	}
+
+	fn size_hint(&self) -> SizeHint {
+		SizeHint::with_exact(self.data.len() as u64)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn boxart_is_scaled_and_cached() {
+		let path = std::env::temp_dir().join(format!("moonshine-boxart-{}.png", std::process::id()));
+		image::RgbImage::new(1000, 2000).save(&path).unwrap();
+
+		let cache = AssetCache::default();
+		let asset = cache.get(1, &path, BOXART_WIDTH, BOXART_HEIGHT).unwrap();
+		let image = image::load_from_memory(&asset.data).unwrap();
+		assert_eq!((image.width(), image.height()), (444, BOXART_HEIGHT));
+		assert_eq!(cache.get(1, &path, BOXART_WIDTH, BOXART_HEIGHT).unwrap().etag, asset.etag);
+
+		// Small images aren't scaled up.
+		let asset = cache.get(1, &path, MAX_ASSET_SIZE, MAX_ASSET_SIZE).unwrap();
+		let image = image::load_from_memory(&asset.data).unwrap();
+		assert_eq!((image.width(), image.height()), (1000, 2000));
+
+		std::fs::remove_file(path).unwrap();
+	}
+}
@@ -0,0 +1,67 @@
+use std::{convert::Infallible, pin::Pin, task::{Context, Poll}, time::Duration};
+
+use http_body_util::BodyExt;
+use hyper::{body::{Body, Bytes, Frame}, header::{self, HeaderValue}, Response};
+use tokio::sync::{broadcast::{self, error::RecvError}, mpsc};
+
+use crate::events::Event;
+
+use super::ResponseBody;
+
+/// Interval at which a comment is sent when there are no events, so that proxies don't close the connection
+/// and the stream notices when the subscriber went away.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Stream `subscription` to the client as server-sent events, each event is a JSON object.
+pub fn event_stream(mut subscription: broadcast::Receiver<Event>) -> Response<ResponseBody> {
+	let (message_tx, message_rx) = mpsc::channel(16);
+
+	tokio::spawn(async move {
+		let mut keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+		loop {
+			let message = tokio::select! {
+				_ = message_tx.closed() => break,
+				_ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+				event = subscription.recv() => match event {
+					Ok(event) => match serde_json::to_string(&event) {
+						Ok(event) => format!("data: {event}\n\n"),
+						Err(e) => {
+							tracing::warn!("Failed to serialize event: {e}");
+							continue;
+						},
+					},
+					Err(RecvError::Lagged(skipped)) => {
+						tracing::warn!("Subscriber fell behind, skipped {skipped} event(s).");
+						continue;
+					},
+					Err(RecvError::Closed) => break,
+				},
+			};
+
+			if message_tx.send(Bytes::from(message)).await.is_err() {
+				break;
+			}
+		}
+
+		tracing::debug!("Stopped streaming events.");
+	});
+
+	let mut response = Response::new(EventBody { message_rx }.boxed());
+	response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+	response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+	response
+}
+
+/// Body that sends messages as they arrive, until the sender stops.
+struct EventBody {
+	message_rx: mpsc::Receiver<Bytes>,
+}
+
+impl Body for EventBody {
+	type Data = Bytes;
+	type Error = Infallible;
+
+	fn poll_frame(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+		self.message_rx.poll_recv(context).map(|message| message.map(|message| Ok(Frame::data(message))))
+	}
+}
@@ -1,18 +1,19 @@
-use std::{collections::HashMap, convert::Infallible, net::{IpAddr, SocketAddr, ToSocketAddrs}, path::PathBuf, str::FromStr};
+use std::{collections::HashMap, convert::Infallible, net::{IpAddr, SocketAddr, ToSocketAddrs}, path::PathBuf, str::FromStr, time::Duration};
 
 use async_shutdown::ShutdownManager;
-use http_body_util::Full;
-use hyper::{body::Bytes, header::{self, HeaderValue}, service::service_fn, Method, Request, Response, StatusCode};
-use hyper_util::rt::tokio::TokioIo;
-use image::ImageFormat;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{body::Bytes, header::{self, HeaderMap, HeaderValue}, service::service_fn, Method, Request, Response, StatusCode};
+use hyper_util::{rt::{TokioExecutor, TokioIo, TokioTimer}, server::conn::auto};
 use network_interface::NetworkInterfaceConfig;
-use openssl::x509::X509;
-use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::Instrument;
 
-use crate::{config::Config, clients::ClientManager, webserver::tls::TlsAcceptor, session::{manager::SessionManager, SessionContext, SessionKeys}};
+use crate::{certificate::ServerIdentity, error::MoonshineError, events::Events, metrics, systemd::ActivatedSockets, config::{ApplicationConfig, Config}, clients::{ClientManager, ConnectedDevice, PAIRING_TOKEN_LIFETIME}, webserver::tls::TlsAcceptor, session::{current_display_mode, manager::SessionManager, parse_launch_arguments, SessionShutdownReason, wait_until_ready, stream::{find_gpu, gpu_name, probe_input, EncoderCapabilities}, SessionContext, SessionKeys, SessionTimings}};
 
-use self::pairing::handle_pair_request;
+use self::{assets::{AssetCache, ChunkedBody, BOXART_HEIGHT, BOXART_WIDTH, MAX_ASSET_SIZE}, pairing::{handle_pair_request, pairing_url, PairingNotification}};
 
+mod assets;
+mod events;
 mod pairing;
 mod tls;
 
@@ -20,23 +21,51 @@ mod tls;
 const SERVERINFO_APP_VERSION: &str = "7.1.431.-1";
 const SERVERINFO_GFE_VERSION: &str = "3.23.0.74";
 
+/// Time a client gets to send the headers of a request, before the connection is closed.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval at which idle HTTP/2 connections are pinged, and how long to wait for the ping to be answered.
+const HTTP2_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
+const HTTP2_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Number of requests a client can make at the same time over a single HTTP/2 connection.
+const HTTP2_MAX_CONCURRENT_STREAMS: u32 = 32;
+
+/// Body of a response, which is either sent at once or streamed.
+type ResponseBody = BoxBody<Bytes, Infallible>;
+
 #[derive(Clone)]
 pub struct Webserver {
 	config: Config,
 	unique_id: String,
 	client_manager: ClientManager,
 	session_manager: SessionManager,
-	server_certs: X509,
+	identity: watch::Receiver<ServerIdentity>,
+
+	/// Capabilities of the encoders, probed on startup.
+	encoder_capabilities: EncoderCapabilities,
+
+	/// Name of the GPU used for streaming, if it can be found.
+	gpu_name: Option<String>,
+
+	/// Boxart that was scaled before.
+	assets: AssetCache,
+
+	/// Events that are pushed to subscribers of `/api/v1/events`.
+	events: Events,
 }
 
 impl Webserver {
-	#[allow(clippy::result_unit_err)]
+	#[allow(clippy::result_unit_err, clippy::too_many_arguments)]
 	pub fn new(
 		config: Config,
 		unique_id: String,
-		server_certs: X509,
+		identity: watch::Receiver<ServerIdentity>,
+		encoder_capabilities: EncoderCapabilities,
 		client_manager: ClientManager,
 		session_manager: SessionManager,
+		events: Events,
+		sockets: &mut ActivatedSockets,
 		shutdown: ShutdownManager<i32>,
 	) -> Result<Self, ()> {
 		let server = Self {
@@ -44,7 +73,11 @@ impl Webserver {
 			unique_id,
 			client_manager,
 			session_manager,
-			server_certs,
+			identity,
+			encoder_capabilities,
+			gpu_name: gpu_name(config.stream.video.gpu.as_deref()),
+			assets: AssetCache::default(),
+			events,
 		};
 
 		// Run HTTP webserver.
@@ -52,6 +85,7 @@ impl Webserver {
 			.map_err(|e| tracing::error!("Failed to resolve address '{}:{}': {e}", config.address, config.webserver.port))?
 			.next()
 			.ok_or_else(|| tracing::error!("Failed to resolve address '{}:{}'", config.address, config.webserver.port))?;
+		let listener = sockets.listen(http_address)?;
 
 		tokio::spawn({
 			let server = server.clone();
@@ -60,14 +94,11 @@ impl Webserver {
 			async move {
 				let server = server.clone();
 				let _ = shutdown.wrap_cancel(shutdown.wrap_trigger_shutdown(1, async move {
-					let listener = TcpListener::bind(http_address).await
-						.map_err(|e| tracing::error!("Failed to bind to address {http_address}: {e}"))?;
-
 					tracing::info!("HTTP server listening for connections on {http_address}");
 					loop {
-						let (connection, address) = listener.accept().await
+						let (connection, peer_address) = listener.accept().await
 							.map_err(|e| tracing::error!("Failed to accept connection: {e}"))?;
-						tracing::trace!("Accepted connection from {address}.");
+						tracing::trace!("Accepted connection from {peer_address}.");
 
 						let address = connection.local_addr().ok();
 						let mac_address = if let Some(address) = address {
@@ -81,11 +112,15 @@ impl Webserver {
 						tokio::spawn({
 							let server = server.clone();
 							async move {
-								let _ = hyper::server::conn::http1::Builder::new()
-									.serve_connection(io, service_fn(|request| {
-										server.serve(request, address, mac_address.clone(), false)
-									})).await;
-							}
+								let service = service_fn(move |request| {
+									let server = server.clone();
+									let mac_address = mac_address.clone();
+									async move { server.serve(request, peer_address, address, mac_address, false, false, None).await }
+								});
+								if let Err(e) = connection_builder().serve_connection(io, service).await {
+									tracing::debug!("Connection closed with error: {e}");
+								}
+							}.instrument(tracing::info_span!("connection", peer = %peer_address))
 						});
 					}
 
@@ -103,20 +138,19 @@ impl Webserver {
 			.map_err(|e| tracing::error!("Failed to resolve address '{}:{}': {e}", config.address, config.webserver.port_https))?
 			.next()
 			.ok_or_else(|| tracing::error!("Failed to resolve address '{}:{}'", config.address, config.webserver.port_https))?;
+		let listener = sockets.listen(https_address)?;
 
 		tokio::spawn({
 			let server = server.clone();
 			async move {
 				let _ = shutdown.wrap_cancel(shutdown.wrap_trigger_shutdown(2, async move {
-					let listener = TcpListener::bind(https_address).await
-						.map_err(|e| tracing::error!("Failed to bind to address '{:?}': {e}", https_address))?;
-					let acceptor = TlsAcceptor::from_config(config.webserver.certificate, config.webserver.private_key)?;
+					let mut acceptor = TlsAcceptor::new(server.identity.clone())?;
 
 					tracing::info!("HTTPS server listening for connections on {https_address}");
 					loop {
-						let (connection, address) = listener.accept().await
+						let (connection, peer_address) = listener.accept().await
 							.map_err(|e| tracing::error!("Failed to accept connection: {e}"))?;
-						tracing::trace!("Accepted TLS connection from {address}.");
+						tracing::trace!("Accepted TLS connection from {peer_address}.");
 
 						let address = connection.local_addr().ok();
 						let mac_address = if let Some(address) = address {
@@ -130,16 +164,41 @@ impl Webserver {
 							Err(()) => continue,
 						};
 
+						// Only clients that present the certificate they paired with are allowed to use the HTTPS server.
+						let (paired, device) = match connection.ssl().peer_certificate() {
+							Some(certificate) => {
+								let paired = server.client_manager.is_certificate_paired(certificate.clone()).await.unwrap_or(false);
+								let device = if paired {
+									server.client_manager.device_seen(certificate, peer_address).await.ok()
+								} else {
+									None
+								};
+								(paired, device)
+							},
+							None => (false, None),
+						};
+
 						let io = TokioIo::new(connection);
 
 						tokio::spawn({
+							let span = tracing::info_span!(
+								"connection",
+								peer = %peer_address,
+								https = true,
+								device = device.as_ref().and_then(|device| device.name.as_deref()).unwrap_or("unknown"),
+							);
 							let server = server.clone();
 							async move {
-								let _ = hyper::server::conn::http1::Builder::new()
-									.serve_connection(io, service_fn(|request| {
-										server.serve(request, address, mac_address.clone(), true)
-									})).await;
-							}
+								let service = service_fn(move |request| {
+									let server = server.clone();
+									let mac_address = mac_address.clone();
+									let device = device.clone();
+									async move { server.serve(request, peer_address, address, mac_address, true, paired, device).await }
+								});
+								if let Err(e) = connection_builder().serve_connection(io, service).await {
+									tracing::debug!("Connection closed with error: {e}");
+								}
+							}.instrument(span)
 						});
 					}
 
@@ -155,13 +214,17 @@ impl Webserver {
 		Ok(server)
 	}
 
+	#[allow(clippy::too_many_arguments)]
 	async fn serve(
 		&self,
 		request: Request<hyper::body::Incoming>,
+		peer_address: SocketAddr,
 		local_address: Option<SocketAddr>,
 		mac_address: Option<String>,
 		https: bool,
-	) -> Result<Response<Full<Bytes>>, Infallible> {
+		paired: bool,
+		device: Option<ConnectedDevice>,
+	) -> Result<Response<ResponseBody>, Infallible> {
 		let params = request.uri()
 			.query()
 			.map(|v| {
@@ -171,19 +234,43 @@ impl Webserver {
 			})
 			.unwrap_or_default();
 
+		// Correlate everything that happens while handling this request with the client that made it.
+		let span = tracing::info_span!(
+			"request",
+			client_id = params.get("uniqueid").map(String::as_str).unwrap_or("unknown"),
+		);
+
+		Ok(self.route(request, params, peer_address, local_address, mac_address, https, paired, device).instrument(span).await)
+	}
+
+	#[allow(clippy::too_many_arguments)]
+	async fn route(
+		&self,
+		request: Request<hyper::body::Incoming>,
+		params: HashMap<String, String>,
+		peer_address: SocketAddr,
+		local_address: Option<SocketAddr>,
+		mac_address: Option<String>,
+		https: bool,
+		paired: bool,
+		device: Option<ConnectedDevice>,
+	) -> Response<ResponseBody> {
 		tracing::info!("Received {} request for {}.", request.method(), request.uri().path());
 
-		let response = if https {
+		let response = if https && !paired {
+			tracing::warn!("Rejecting {} request for {} from a client that is not paired.", request.method(), request.uri().path());
+			unauthorized()
+		} else if https {
 			match (request.method(), request.uri().path()) {
 				(&Method::GET, "/serverinfo") => self.server_info(params, mac_address, https).await,
-				(&Method::GET, "/applist") => self.app_list(),
-				(&Method::GET, "/appasset") => self.app_asset(params),
+				(&Method::GET, "/applist") => self.app_list(device.as_ref()),
+				(&Method::GET, "/appasset") => return self.app_asset(params, request.headers()),
 				(&Method::GET, "/pair") => {
-					handle_pair_request(request, params, local_address, &self.server_certs, &self.client_manager).await
+					handle_pair_request(request, params, peer_address, local_address, &self.server_certificate(), &self.client_manager, self.pairing_notification().await, &self.events).await
 				}
 				// (&Method::GET, "/unpair") => self.unpair(params).await,
-				(&Method::GET, "/launch") => self.launch(params).await,
-				(&Method::GET, "/resume") => self.resume(params).await,
+				(&Method::GET, "/launch") => self.launch(params, local_address, device).await,
+				(&Method::GET, "/resume") => self.resume(params, local_address, device).await,
 				(&Method::GET, "/cancel") => self.cancel().await,
 				(method, uri) => {
 					tracing::warn!("Unhandled {method} request with URI '{uri}'");
@@ -194,10 +281,17 @@ impl Webserver {
 			match (request.method(), request.uri().path()) {
 				(&Method::GET, "/serverinfo") => self.server_info(params, mac_address, https).await,
 				(&Method::GET, "/pair") => {
-					handle_pair_request(request, params, local_address, &self.server_certs, &self.client_manager).await
+					handle_pair_request(request, params, peer_address, local_address, &self.server_certificate(), &self.client_manager, self.pairing_notification().await, &self.events).await
 				}
 				(&Method::GET, "/pin") => self.pin().await,
-				(&Method::GET, "/submit-pin") => self.submit_pin(params).await,
+				(&Method::POST, "/pin") => self.pairing_qr_code(params, request.headers(), local_address).await,
+				(&Method::POST, "/submit-pin") => self.submit_pin(params, request.headers(), local_address).await,
+				(&Method::POST, "/stop-session") => self.stop_session(request.headers(), local_address).await,
+				(&Method::GET, "/api/v1/events") => return self.event_stream(request.headers(), local_address),
+				(&Method::POST, "/api/v1/session/pause") => self.set_paused(true, request.headers(), local_address).await,
+				(&Method::POST, "/api/v1/session/resume") => self.set_paused(false, request.headers(), local_address).await,
+				(&Method::GET, "/api/v1/metrics") => self.metrics(request.headers(), local_address),
+				(&Method::GET, "/api/v1/clients") => self.clients(request.headers(), local_address).await,
 				(method, uri) => {
 					tracing::warn!("Unhandled {method} request with URI '{uri}'");
 					not_found()
@@ -205,18 +299,55 @@ impl Webserver {
 			}
 		};
 
-		Ok(response)
+		response.map(BodyExt::boxed)
+	}
+
+	/// Names of the encoders that are used, depending on the codec the client picks.
+	fn video_encoders(&self) -> String {
+		let mut encoders = vec![self.config.stream.video.codec_h264.as_str()];
+		if self.encoder_capabilities.hevc_supported() {
+			encoders.push(self.config.stream.video.codec_hevc.as_str());
+		}
+
+		encoders.join(",")
+	}
+
+	/// How to tell the user about a pairing request, as an overlay when it arrives during a session and that is enabled.
+	async fn pairing_notification(&self) -> PairingNotification {
+		if self.config.headless.enabled {
+			return PairingNotification::None;
+		}
+
+		let session_active = matches!(self.session_manager.get_session_context().await, Ok(Some(_)));
+		if self.config.host_display.pairing_overlay && session_active {
+			PairingNotification::Overlay
+		} else {
+			PairingNotification::Desktop
+		}
+	}
+
+	fn server_certificate(&self) -> openssl::x509::X509 {
+		self.identity.borrow().certificate.clone()
 	}
 
-	fn app_list(&self) -> Response<Full<Bytes>> {
+	/// List the applications that the device is allowed to see.
+	fn app_list(&self, device: Option<&ConnectedDevice>) -> Response<Full<Bytes>> {
 		let mut response = "<root status_code=\"200\">".to_string();
-		for application in self.config.applications.iter() {
+		let applications = self.config.applications.iter()
+			.filter(|application| !application.hidden && is_visible(&self.config, application, device));
+		for application in applications {
 			response += "<App>";
 
-			// TODO: Fix HDR support.
-			response += "<IsHdrSupported>0</IsHdrSupported>";
+			let hdr_supported = application.hdr.unwrap_or(true) && self.encoder_capabilities.hdr_supported();
+			response += &format!("<IsHdrSupported>{}</IsHdrSupported>", hdr_supported as u8);
 			response += format!("<AppTitle>{}</AppTitle>", escape_xml(&application.title)).as_ref();
 			response += format!("<ID>{}</ID>", application.id()).as_ref();
+			if let Some(category) = application.category.as_ref().or(application.group.as_ref()) {
+				response += &format!("<AppCategory>{}</AppCategory>", escape_xml(category));
+			}
+			if let Some(install_path) = &application.install_path {
+				response += &format!("<AppInstallPath>{}</AppInstallPath>", escape_xml(&install_path.to_string_lossy()));
+			}
 
 			response += "</App>";
 		}
@@ -228,13 +359,13 @@ impl Webserver {
 		response
 	}
 
-	fn app_asset(&self, mut params: HashMap<String, String>) -> Response<Full<Bytes>> {
+	fn app_asset(&self, mut params: HashMap<String, String>, headers: &header::HeaderMap) -> Response<ResponseBody> {
 		let application_id = match params.remove("appid") {
 			Some(application_id) => application_id,
 			None => {
 				let message = format!("Expected 'appasset' in launch request, got {:?}.", params.keys());
 				tracing::warn!("{message}");
-				return bad_request(message);
+				return bad_request(message).map(BodyExt::boxed);
 			}
 		};
 		let application_id: i32 = match application_id.parse() {
@@ -242,7 +373,7 @@ impl Webserver {
 			Err(e) => {
 				let message = format!("Failed to parse application ID: {e}");
 				tracing::warn!("{message}");
-				return bad_request(message);
+				return bad_request(message).map(BodyExt::boxed);
 			}
 		};
 
@@ -251,7 +382,7 @@ impl Webserver {
 			None => {
 				let message = format!("Couldn't find application with ID {}.", application_id - 1);
 				tracing::warn!("{message}");
-				return bad_request(message);
+				return bad_request(message).map(BodyExt::boxed);
 			}
 		};
 
@@ -260,7 +391,7 @@ impl Webserver {
 			None => {
 				let message = format!("No boxart defined for app '{}'.", application.title);
 				tracing::warn!("{message}");
-				return bad_request(message);
+				return bad_request(message).map(BodyExt::boxed);
 			}
 		};
 		let boxart_path = boxart_path.to_string_lossy();
@@ -269,7 +400,7 @@ impl Webserver {
 			Err(e) => {
 				let message = format!("Failed to expand boxart path: {e}");
 				tracing::warn!("{message}");
-				return bad_request(message);
+				return bad_request(message).map(BodyExt::boxed);
 			},
 		};
 		let boxart_path = match PathBuf::from_str(&boxart_path) {
@@ -277,28 +408,41 @@ impl Webserver {
 			Err(e) => {
 				let message = format!("Failed to create boxart path: {e}");
 				tracing::warn!("{message}");
-				return bad_request(message);
+				return bad_request(message).map(BodyExt::boxed);
 			},
 		};
 
-		let asset = match image::open(boxart_path) {
+		// Moonlight doesn't ask for a size, other clients can ask for a size that suits them.
+		let size = |name: &str, default: u32| {
+			params.get(name)
+				.and_then(|size| size.parse::<u32>().ok())
+				.map_or(default, |size| size.clamp(1, MAX_ASSET_SIZE))
+		};
+		let (width, height) = (size("width", BOXART_WIDTH), size("height", BOXART_HEIGHT));
+
+		let asset = match self.assets.get(application_id, &boxart_path, width, height) {
 			Ok(asset) => asset,
-			Err(e) => {
-				let message = format!("Failed to load boxart: {e}");
+			Err(message) => {
 				tracing::warn!("{message}");
-				return bad_request(message);
+				return bad_request(message).map(BodyExt::boxed);
 			}
 		};
 
-		let mut buffer = std::io::Cursor::new(vec![]);
-		if let Err(e) = asset.write_to(&mut buffer, ImageFormat::Png) {
-			let message = format!("Failed to encode boxart: {e}");
-			tracing::warn!("{message}");
-			return bad_request(message);
-		}
+		let etag = format!("\"{}\"", asset.etag);
+		let unchanged = headers.get(header::IF_NONE_MATCH)
+			.and_then(|value| value.to_str().ok())
+			.is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+		let body = if unchanged { Bytes::new() } else { asset.data };
 
-		let mut response = Response::new(Full::new(Bytes::from(buffer.into_inner())));
+		let mut response = Response::new(ChunkedBody::new(body).boxed());
+		if unchanged {
+			*response.status_mut() = StatusCode::NOT_MODIFIED;
+		}
 		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+		response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("max-age=3600"));
+		if let Ok(etag) = HeaderValue::from_str(&etag) {
+			response.headers_mut().insert(header::ETAG, etag);
+		}
 		response
 	}
 
@@ -319,10 +463,9 @@ impl Webserver {
 
 		let session_context = match self.session_manager.get_session_context().await {
 			Ok(session_context) => session_context,
-			Err(()) => {
-				let message = "Failed to get session context".to_string();
-				tracing::warn!("{message}");
-				return bad_request(message);
+			Err(e) => {
+				tracing::warn!("Failed to get session context: {e}.");
+				return error_response(&e);
 			},
 		};
 
@@ -344,10 +487,20 @@ impl Webserver {
 		response += &format!("<HttpsPort>{}</HttpsPort>", self.config.webserver.port_https);
 		response += "<ExternalPort></ExternalPort>";
 		response += &format!("<mac>{}</mac>", mac_address.unwrap_or("".to_string()));
-		response += "<MaxLumaPixelsHEVC>1869449984</MaxLumaPixelsHEVC>"; // TODO: Check if HEVC is supported, set this to 0 if it is not.
+		response += &format!("<MaxLumaPixelsHEVC>{}</MaxLumaPixelsHEVC>", self.encoder_capabilities.max_luma_pixels_hevc());
 		response += "<LocalIP></LocalIP>";
-		response += "<ServerCodecModeSupport>259</ServerCodecModeSupport>";
-		response += "<SupportedDisplayMode></SupportedDisplayMode>";
+		response += &format!("<ServerCodecModeSupport>{}</ServerCodecModeSupport>", self.encoder_capabilities.codec_mode_support());
+		match current_display_mode() {
+			Ok(mode) => {
+				response += "<SupportedDisplayMode><DisplayMode>";
+				response += &format!("<Width>{}</Width><Height>{}</Height><RefreshRate>{}</RefreshRate>", mode.width, mode.height, mode.refresh_rate);
+				response += "</DisplayMode></SupportedDisplayMode>";
+			},
+			Err(()) => response += "<SupportedDisplayMode></SupportedDisplayMode>",
+		}
+		response += &format!("<gputype>{}</gputype>", escape_xml(self.gpu_name.as_deref().unwrap_or("")));
+		response += &format!("<VideoEncoder>{}</VideoEncoder>", escape_xml(self.video_encoders()));
+		response += &format!("<IsHdrSupported>{}</IsHdrSupported>", self.encoder_capabilities.hdr_supported() as u8);
 		response += &format!("<PairStatus>{paired}</PairStatus>");
 		response += &format!("<currentgame>{}</currentgame>", session_context.clone().map(|s| s.application_id).unwrap_or(0));
 		response += &format!("<state>{}</state>", session_context.map(|_| "MOONSHINE_SERVER_BUSY").unwrap_or("MOONSHINE_SERVER_FREE"));
@@ -368,10 +521,91 @@ impl Webserver {
 		response
 	}
 
+	/// QR code with the address of this host, a one-time PIN and the token to pair with it, so that a client can pair by scanning it.
+	///
+	/// The code is an SVG image, or text to print in a terminal with `format=text`.
+	/// Only the host can get a QR code, since anyone that sees the code can pair with the host.
+	async fn pairing_qr_code(&self, params: HashMap<String, String>, headers: &HeaderMap, local_address: Option<SocketAddr>) -> Response<Full<Bytes>> {
+		if let Err(response) = only_from_host(headers, local_address, "get a pairing QR code") {
+			return response;
+		}
+
+		// Clients can't reach the host on a loopback address, so the QR code points them to the address of the default route.
+		// Without a default route, the first address on the network is the best guess.
+		let host = crate::publisher::default_route_address()
+			.map(IpAddr::V4)
+			.or_else(|| crate::publisher::network_addresses()
+				.into_iter()
+				.find(|address| match address {
+					IpAddr::V4(address) => !address.is_loopback() && !address.is_link_local(),
+					IpAddr::V6(_) => false,
+				})
+			);
+		let Some(host) = host else {
+			return Response::builder()
+				.status(StatusCode::INTERNAL_SERVER_ERROR)
+				.body(Full::new(Bytes::from("No network address to pair with.")))
+				.unwrap();
+		};
+
+		let Ok(pairing_token) = self.client_manager.create_pairing_token().await else {
+			return Response::builder()
+				.status(StatusCode::INTERNAL_SERVER_ERROR)
+				.body(Full::new(Bytes::from("Failed to create a PIN for pairing.")))
+				.unwrap();
+		};
+
+		let url = pairing_url(host, self.config.webserver.port, &pairing_token);
+		let code = match qrcode::QrCode::new(&url) {
+			Ok(code) => code,
+			Err(e) => {
+				tracing::error!("Failed to create pairing QR code: {e}");
+				return Response::builder()
+					.status(StatusCode::INTERNAL_SERVER_ERROR)
+					.body(Full::new(Bytes::from("Failed to create the pairing QR code.")))
+					.unwrap();
+			},
+		};
+
+		tracing::info!(
+			"Created pairing QR code that points clients to {host}:{}, they can start pairing with it for {} seconds.",
+			self.config.webserver.port,
+			PAIRING_TOKEN_LIFETIME.as_secs(),
+		);
+
+		let (body, content_type) = if params.get("format").is_some_and(|format| format == "text") {
+			// Terminals usually draw light text on a dark background, so the colors are inverted to print dark modules.
+			let text = code.render::<qrcode::render::unicode::Dense1x2>()
+				.dark_color(qrcode::render::unicode::Dense1x2::Light)
+				.light_color(qrcode::render::unicode::Dense1x2::Dark)
+				.build();
+			(format!("{text}\nPIN: {}\n", pairing_token.pin), "text/plain; charset=UTF-8")
+		} else {
+			let svg = code.render::<qrcode::render::svg::Color>()
+				.min_dimensions(256, 256)
+				.build();
+			(svg, "image/svg+xml")
+		};
+
+		let mut response = Response::new(Full::new(Bytes::from(body)));
+		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+		response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+		response
+	}
+
+	/// Submit the PIN that a client shows while pairing.
+	///
+	/// Only the host can submit a PIN, otherwise anyone on the network could pair a client of their own.
 	async fn submit_pin(
 		&self,
 		params: HashMap<String, String>,
+		headers: &HeaderMap,
+		local_address: Option<SocketAddr>,
 	) -> Response<Full<Bytes>> {
+		if let Err(response) = only_from_host(headers, local_address, "submit a PIN") {
+			return response;
+		}
+
 		let unique_id = match params.get("uniqueid") {
 			Some(unique_id) => unique_id,
 			None => {
@@ -437,6 +671,8 @@ impl Webserver {
 	async fn launch(
 		&self,
 		mut params: HashMap<String, String>,
+		local_address: Option<SocketAddr>,
+		device: Option<ConnectedDevice>,
 	) -> Response<Full<Bytes>> {
 		let unique_id = match params.remove("uniqueid") {
 			Some(unique_id) => unique_id,
@@ -447,7 +683,7 @@ impl Webserver {
 			}
 		};
 
-		match self.client_manager.is_paired(unique_id).await {
+		match self.client_manager.is_paired(unique_id.clone()).await {
 			Ok(paired) => paired,
 			Err(()) => return bad_request("Failed to check client paired status".to_string()),
 		};
@@ -542,6 +778,12 @@ impl Webserver {
 			}
 		};
 
+		// Moonlight asks to keep playing audio on the host with `localAudioPlayMode=1`.
+		let host_audio = params.remove("localAudioPlayMode").is_some_and(|mode| mode == "1");
+
+		// Moonlight tells whether it wants to stream in HDR with `hdrMode=1`, which commands can use to enable HDR on the host.
+		let hdr = params.remove("hdrMode").is_some_and(|mode| mode == "1");
+
 		let application = match self.config.applications.iter().find(|&a| a.id() == application_id) {
 			Some(application) => application,
 			None => {
@@ -551,27 +793,64 @@ impl Webserver {
 			}
 		};
 
+		if !is_allowed(&self.config, application, device.as_ref()) {
+			return forbidden(&format!("This device is not allowed to launch '{}'.", application.title));
+		}
+
+		let launch_arguments = params.remove("args").unwrap_or_default();
+		let launch_arguments = match parse_launch_arguments(application.launch_arguments.as_ref(), &launch_arguments) {
+			Ok(launch_arguments) => launch_arguments,
+			Err(message) => {
+				tracing::warn!("Refusing to launch '{}': {message}", application.title);
+				return bad_request(message);
+			}
+		};
+
+		// Fail early with an explanation, instead of starting a stream in which the client can't control anything.
+		if let Err(e) = probe_input() {
+			tracing::error!("Can't launch application, input from the client can't be handled. {e}");
+			return service_unavailable(&e.to_string());
+		}
+
+		// Likewise, a misconfigured GPU would only fail once the stream starts.
+		if find_gpu(self.config.stream.video.gpu.as_deref()).is_err() {
+			tracing::error!("Can't launch application, the configured GPU is not available.");
+			return service_unavailable("The configured GPU is not available.");
+		}
+
 		let initialize_result = self.session_manager.initialize_session(SessionContext {
+			client_id: unique_id,
+			device_name: device.as_ref().and_then(|device| device.name.clone()),
+			device_fingerprint: device.map(|device| device.fingerprint),
 			application: application.clone(),
 			application_id,
 			resolution: (width, height),
 			refresh_rate,
+			host_audio,
+			hdr,
+			launch_arguments,
 			keys: SessionKeys {
 				remote_input_key,
 				remote_input_key_id,
-			}
+			},
+			timings: SessionTimings::new(),
 		}).await;
 
-		if initialize_result.is_err() {
-			return bad_request("Failed to start session".to_string());
+		if let Err(e) = initialize_result {
+			tracing::warn!("Failed to start session: {e}.");
+			return error_response(&e);
+		}
+
+		// The client would only see a black screen if it connects before the application is ready.
+		if let Some(ready_check) = &application.ready_check {
+			wait_until_ready(ready_check.clone()).await;
 		}
 
 		let mut response = "<root status_code=\"200\">".to_string();
 		response += "<gamesession>1</gamesession>";
+		response += &self.session_url(local_address);
 		response += "</root>";
 
-		// TODO: Return sessionUrl0.
-
 		let mut response = Response::new(Full::new(Bytes::from(response)));
 		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
 
@@ -581,6 +860,8 @@ impl Webserver {
 	async fn resume(
 		&self,
 		mut params: HashMap<String, String>,
+		local_address: Option<SocketAddr>,
+		device: Option<ConnectedDevice>,
 	) -> Response<Full<Bytes>> {
 		let unique_id = match params.remove("uniqueid") {
 			Some(unique_id) => unique_id,
@@ -630,18 +911,30 @@ impl Webserver {
 			}
 		};
 
+		match self.session_manager.get_session_context().await {
+			Ok(Some(session_context)) => {
+				if !is_allowed(&self.config, &session_context.application, device.as_ref()) {
+					return forbidden(&format!("This device is not allowed to resume '{}'.", session_context.application.title));
+				}
+			},
+			Ok(None) => {},
+			Err(e) => {
+				tracing::warn!("Failed to get session context: {e}.");
+				return error_response(&e);
+			},
+		}
+
 		let update_result = self.session_manager.update_keys(SessionKeys {
 			remote_input_key,
 			remote_input_key_id,
 		}).await;
-		if update_result.is_err() {
-			return bad_request("Failed to update session keys".to_string());
+		if let Err(e) = update_result {
+			tracing::warn!("Failed to update session keys: {e}.");
+			return error_response(&e);
 		}
 
 		let mut response = "<root status_code=\"200\">".to_string();
-
-		// TODO: Return sessionUrl0.
-
+		response += &self.session_url(local_address);
 		response += "<resume>1</resume>";
 		response += "</root>";
 
@@ -651,11 +944,114 @@ impl Webserver {
 		response
 	}
 
+	/// URL of the RTSP server, so that the client doesn't assume the default port.
+	fn session_url(&self, local_address: Option<SocketAddr>) -> String {
+		match local_address {
+			Some(local_address) => {
+				let rtsp_address = SocketAddr::new(local_address.ip(), self.config.stream.port);
+				format!("<sessionUrl0>rtsp://{rtsp_address}</sessionUrl0>")
+			},
+			None => String::new(),
+		}
+	}
+
+	/// Stop the active session from the host, for example when a client disappeared without quitting.
+	///
+	/// Only requests to a loopback address are accepted, which can only come from the host itself.
+	async fn stop_session(&self, headers: &HeaderMap, local_address: Option<SocketAddr>) -> Response<Full<Bytes>> {
+		if let Err(response) = only_from_host(headers, local_address, "stop the session") {
+			return response;
+		}
+
+		if let Err(e) = self.session_manager.stop_session(SessionShutdownReason::HostStopped).await {
+			tracing::warn!("Failed to stop session: {e}.");
+			return error_response(&e);
+		}
+
+		tracing::info!("Stopped session on request of the host.");
+		Response::new(Full::new(Bytes::from("Stopped session.")))
+	}
+
+	/// Pause or resume the stream of the active session, without stopping the session.
+	async fn set_paused(&self, paused: bool, headers: &HeaderMap, local_address: Option<SocketAddr>) -> Response<Full<Bytes>> {
+		if let Err(response) = only_from_host(headers, local_address, "pause or resume the stream") {
+			return response;
+		}
+
+		let result = if paused {
+			self.session_manager.pause_session().await
+		} else {
+			self.session_manager.resume_session().await
+		};
+		if let Err(e) = result {
+			tracing::warn!("Failed to pause or resume the stream: {e}.");
+			return error_response(&e);
+		}
+
+		if paused {
+			Response::new(Full::new(Bytes::from("Paused stream.")))
+		} else {
+			Response::new(Full::new(Bytes::from("Resumed stream.")))
+		}
+	}
+
+	/// Latency of the stages of the video stream, in the Prometheus text format.
+	fn metrics(&self, headers: &HeaderMap, local_address: Option<SocketAddr>) -> Response<Full<Bytes>> {
+		if let Err(response) = only_from_host(headers, local_address, "get the metrics") {
+			return response;
+		}
+
+		let mut response = Response::new(Full::new(Bytes::from(metrics::render())));
+		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+		response
+	}
+
+	/// List the paired devices, with when they were last seen and the settings of their last stream.
+	async fn clients(&self, headers: &HeaderMap, local_address: Option<SocketAddr>) -> Response<Full<Bytes>> {
+		if let Err(response) = only_from_host(headers, local_address, "get the list of clients") {
+			return response;
+		}
+
+		let Ok(devices) = self.client_manager.devices().await else {
+			return Response::builder()
+				.status(StatusCode::INTERNAL_SERVER_ERROR)
+				.body(Full::new(Bytes::from("Failed to get the paired devices.")))
+				.unwrap();
+		};
+		let body = match serde_json::to_string(&devices) {
+			Ok(body) => body,
+			Err(e) => {
+				tracing::error!("Failed to serialize paired devices: {e}");
+				return Response::builder()
+					.status(StatusCode::INTERNAL_SERVER_ERROR)
+					.body(Full::new(Bytes::from("Failed to serialize the paired devices.")))
+					.unwrap();
+			},
+		};
+
+		let mut response = Response::new(Full::new(Bytes::from(body)));
+		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+		response
+	}
+
+	/// Push events to the host as they happen, for example to update a dashboard without polling.
+	fn event_stream(&self, headers: &HeaderMap, local_address: Option<SocketAddr>) -> Response<ResponseBody> {
+		if let Err(response) = only_from_host(headers, local_address, "receive events") {
+			return response.map(BodyExt::boxed);
+		}
+
+		tracing::debug!("Streaming events to the host.");
+		events::event_stream(self.events.subscribe())
+	}
+
 	async fn cancel(&self) -> Response<Full<Bytes>> {
-		if self.session_manager.stop_session().await.is_err() {
-			let message = "Failed to stop session".to_string();
-			tracing::warn!("{message}");
-			return bad_request(message);
+		match self.session_manager.stop_session(SessionShutdownReason::ClientStopped).await {
+			// Nothing to cancel is fine, the client wanted no session to be running.
+			Ok(()) | Err(MoonshineError::NoSession) => {},
+			Err(e) => {
+				tracing::warn!("Failed to stop session: {e}.");
+				return error_response(&e);
+			},
 		}
 
 		let mut response = "<root status_code=\"200\">".to_string();
@@ -675,6 +1071,118 @@ fn bad_request(message: String) -> Response<Full<Bytes>> {
 		.unwrap()
 }
 
+fn unauthorized() -> Response<Full<Bytes>> {
+	let response = "<root status_code=\"401\" status_message=\"The client is not authorized. Certificate verification failed.\"/>";
+
+	Response::builder()
+		.status(StatusCode::UNAUTHORIZED)
+		.header(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"))
+		.body(Full::new(Bytes::from(response)))
+		.unwrap()
+}
+
+fn service_unavailable(message: &str) -> Response<Full<Bytes>> {
+	let response = format!("<root status_code=\"503\" status_message=\"{}\"/>", escape_xml(message));
+
+	Response::builder()
+		.status(StatusCode::SERVICE_UNAVAILABLE)
+		.header(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"))
+		.body(Full::new(Bytes::from(response)))
+		.unwrap()
+}
+
+/// Respond to a request that failed because of `error`, telling the client why.
+fn error_response(error: &MoonshineError) -> Response<Full<Bytes>> {
+	let status = match error {
+		MoonshineError::NoSession => StatusCode::NOT_FOUND,
+		MoonshineError::SessionActive | MoonshineError::NotStreaming => StatusCode::CONFLICT,
+		MoonshineError::StreamNotSetUp => StatusCode::BAD_REQUEST,
+		MoonshineError::SessionManagerStopped => StatusCode::SERVICE_UNAVAILABLE,
+		MoonshineError::SessionStopped(_) | MoonshineError::Network(_) | MoonshineError::Logged => StatusCode::INTERNAL_SERVER_ERROR,
+	};
+	let response = format!("<root status_code=\"{}\" status_message=\"{}\"/>", status.as_u16(), escape_xml(error.to_string()));
+
+	Response::builder()
+		.status(status)
+		.header(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"))
+		.body(Full::new(Bytes::from(response)))
+		.unwrap()
+}
+
+fn forbidden(message: &str) -> Response<Full<Bytes>> {
+	let response = format!("<root status_code=\"403\" status_message=\"{}\"/>", escape_xml(message));
+
+	Response::builder()
+		.status(StatusCode::FORBIDDEN)
+		.header(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"))
+		.body(Full::new(Bytes::from(response)))
+		.unwrap()
+}
+
+/// Reject a request that didn't come from the host itself, which can only have been sent to a loopback address.
+///
+/// Browsers on the host can also send requests to a loopback address on behalf of any website,
+/// so requests that a browser sent from a page that isn't served by the host are rejected too.
+/// `action` describes what the request wanted to do, for the log and the response.
+fn only_from_host(headers: &HeaderMap, local_address: Option<SocketAddr>, action: &str) -> Result<(), Response<Full<Bytes>>> {
+	// Browsers send the origin of the page with every request that changes something, tools like curl don't send it at all.
+	let local_origin = headers.get(header::ORIGIN)
+		.map(|origin| origin.to_str().is_ok_and(is_local_url))
+		.unwrap_or(true);
+
+	// A website can point its own domain to a loopback address (DNS rebinding), in which case the browser sends that domain as host.
+	let local_host = headers.get(header::HOST)
+		.map(|host| host.to_str().is_ok_and(|host| is_local_url(&format!("http://{host}"))))
+		.unwrap_or(true);
+
+	if local_origin && local_host && local_address.is_some_and(|address| address.ip().to_canonical().is_loopback()) {
+		return Ok(());
+	}
+
+	tracing::warn!("Rejecting request to {action} that didn't come from the host.");
+	Err(Response::builder()
+		.status(StatusCode::FORBIDDEN)
+		.body(Full::new(Bytes::from(format!("Only the host can {action}."))))
+		.unwrap())
+}
+
+/// Check if `url` points to the host itself, by name or by a loopback address.
+fn is_local_url(url: &str) -> bool {
+	match url::Url::parse(url).as_ref().map(url::Url::host) {
+		Ok(Some(url::Host::Domain(domain))) => domain.eq_ignore_ascii_case("localhost"),
+		Ok(Some(url::Host::Ipv4(address))) => address.is_loopback(),
+		Ok(Some(url::Host::Ipv6(address))) => address.is_loopback(),
+		_ => false,
+	}
+}
+
+/// Check if a device is allowed to use an application, logging a warning if it isn't.
+fn is_allowed(config: &Config, application: &ApplicationConfig, device: Option<&ConnectedDevice>) -> bool {
+	if is_visible(config, application, device) {
+		return true;
+	}
+
+	match device {
+		Some(device) => {
+			tracing::warn!(
+				"Device '{}' with fingerprint {} is not allowed to use '{}'.",
+				device.name.as_deref().unwrap_or("unknown"),
+				device.fingerprint,
+				application.title,
+			);
+		},
+		None => tracing::warn!("Unknown device is not allowed to use '{}'.", application.title),
+	}
+
+	false
+}
+
+/// Check if a device is allowed to see and use an application, through the application itself and through its group.
+fn is_visible(config: &Config, application: &ApplicationConfig, device: Option<&ConnectedDevice>) -> bool {
+	config.allowed_clients(application).into_iter()
+		.all(|allowed_clients| device.is_some_and(|device| device.is_listed(allowed_clients)))
+}
+
 fn not_found() -> Response<Full<Bytes>> {
 	Response::builder()
 		.status(StatusCode::NOT_FOUND)
@@ -682,6 +1190,21 @@ fn not_found() -> Response<Full<Bytes>> {
 		.unwrap()
 }
 
+/// Serves connections over HTTP/1.1, or HTTP/2 for clients that negotiate it.
+fn connection_builder() -> auto::Builder<TokioExecutor> {
+	let mut builder = auto::Builder::new(TokioExecutor::new());
+	builder.http1()
+		.timer(TokioTimer::new())
+		.keep_alive(true)
+		.header_read_timeout(HEADER_READ_TIMEOUT);
+	builder.http2()
+		.timer(TokioTimer::new())
+		.keep_alive_interval(HTTP2_KEEP_ALIVE_INTERVAL)
+		.keep_alive_timeout(HTTP2_KEEP_ALIVE_TIMEOUT)
+		.max_concurrent_streams(HTTP2_MAX_CONCURRENT_STREAMS);
+	builder
+}
+
 fn get_mac_address(address: IpAddr) -> Result<Option<String>, ()> {
 	let interfaces = network_interface::NetworkInterface::show()
 		.map_err(|e| tracing::error!("Failed to retrieve network interfaces: {e}"))?;
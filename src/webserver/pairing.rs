@@ -1,11 +1,33 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::Arc, time::Instant};
 
 use http_body_util::Full;
 use hyper::{body::Bytes, header::{self, HeaderValue}, Request, Response};
-use notify_rust::Notification;
+use notify_rust::{Notification, Timeout, Urgency};
 use tokio::sync::Notify;
 
-use crate::{clients::PendingClient, webserver::bad_request, clients::ClientManager};
+use crate::{clients::{PairingToken, PendingClient}, events::{Event, Events}, webserver::bad_request, clients::ClientManager};
+
+/// How the user is told about a pairing request on the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PairingNotification {
+	/// Don't show anything, for example because there is no desktop to show it on.
+	None,
+
+	/// Show a desktop notification with a button that opens the PIN page.
+	Desktop,
+
+	/// Show a notification that stays on the host display until pairing finishes,
+	/// so that it is visible in the stream of the session that is active.
+	Overlay,
+}
+
+/// Parameter with which clients present the token of a pairing QR code when they start pairing.
+const PAIRING_TOKEN_PARAMETER: &str = "pairingtoken";
+
+/// URL in a pairing QR code, with the address of the host, the PIN to pair with and the token that gives access to that PIN.
+pub fn pairing_url(host: IpAddr, port: u16, pairing_token: &PairingToken) -> String {
+	format!("moonlight://pair?host={host}&port={port}&pin={}&{PAIRING_TOKEN_PARAMETER}={}", pairing_token.pin, pairing_token.token)
+}
 
 /// Handle a pairing request from a client.
 ///
@@ -21,16 +43,20 @@ use crate::{clients::PendingClient, webserver::bad_request, clients::ClientManag
 ///   5. /pair?clientpairingsecret=...
 ///
 /// After completing these steps, we have paired with the client.
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_pair_request(
 	request: Request<hyper::body::Incoming>,
 	mut params: HashMap<String, String>,
+	peer_address: SocketAddr,
 	local_address: Option<SocketAddr>,
 	server_certs: &openssl::x509::X509,
 	client_manager: &ClientManager,
+	notification: PairingNotification,
+	events: &Events,
 ) -> Response<Full<Bytes>> {
 	if params.contains_key("phrase") {
 		match params.remove("phrase").unwrap().as_str() {
-			"getservercert" => get_server_cert(request, params, local_address, server_certs, client_manager).await,
+			"getservercert" => get_server_cert(request, params, peer_address, local_address, server_certs, client_manager, notification, events).await,
 			"pairchallenge" => pair_challenge(params, client_manager).await,
 			unknown => {
 				let message = format!("Unknown pair phrase received: {}", unknown);
@@ -51,12 +77,16 @@ pub async fn handle_pair_request(
 	}
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn get_server_cert(
 	request: Request<hyper::body::Incoming>,
 	mut params: HashMap<String, String>,
+	peer_address: SocketAddr,
 	local_address: Option<SocketAddr>,
 	server_pem: &openssl::x509::X509,
 	client_manager: &ClientManager,
+	notification: PairingNotification,
+	events: &Events,
 ) -> Response<Full<Bytes>> {
 	let client_cert = match params.remove("clientcert") {
 		Some(client_cert) => client_cert,
@@ -84,6 +114,12 @@ async fn get_server_cert(
 		}
 	};
 
+	// Only used to show which device paired, so it is not required.
+	let device_name = params.remove("devicename").unwrap_or_else(|| "Unknown device".to_string());
+
+	// Clients that scanned a pairing QR code present its token, to pair with its PIN instead of waiting for it to be entered.
+	let pairing_token = params.remove(PAIRING_TOKEN_PARAMETER);
+
 	let salt = match params.remove("salt") {
 		Some(salt) => salt,
 		None => {
@@ -121,7 +157,11 @@ async fn get_server_cert(
 	let pin_notifier = {
 		let pending_client = PendingClient {
 			id: unique_id.clone(),
+			name: device_name.clone(),
+			address: peer_address.ip(),
+			started: Instant::now(),
 			pem,
+			pairing_token,
 			salt,
 			pin_notify: Arc::new(Notify::new()),
 			key: None,
@@ -143,28 +183,39 @@ async fn get_server_cert(
 		notify
 	};
 
+	events.send(Event::PairingRequested { client_id: unique_id.clone(), device_name: device_name.clone() });
+
 	// Emit a notification, allowing the user to automatically open the PIN page.
+	// The overlay is closed when it is dropped, which is when pairing finished or the client gave up.
+	let mut _overlay = None;
 	if let Some(local_address) = local_address {
 		let scheme = request.uri().scheme().map(|s| s.to_string()).unwrap_or("http".to_string());
-		let pin_url = format!("{}://{}:{}/pin", scheme, local_address.ip(), local_address.port());
+		// Only the host can submit the PIN or get a pairing QR code, which it does through a loopback address.
+		let pin_url = format!("{}://localhost:{}/pin", scheme, local_address.port());
 		tracing::info!("Waiting for pin to be sent at {pin_url}");
 
-		let _ = std::thread::Builder::new().name("pin-notification".to_string()).spawn(move || {
-			Notification::new()
-				.appname("Moonshine")
-				.summary("Received pairing request.")
-				.action("default", "default")
-				.action("open", "Enter PIN")
-				.show()
-				.map_err(|e| tracing::warn!("Failed to show PIN notification: {e}"))?
-				.wait_for_action(|action| {
-					if action != "__closed" {
-						let _ = open::that(pin_url);
-					}
-				});
+		if notification == PairingNotification::None {
+			tracing::debug!("Not showing a notification for the pairing request.");
+		} else if notification == PairingNotification::Overlay {
+			_overlay = Some(show_pairing_overlay(device_name, pin_url));
+		} else {
+			let _ = std::thread::Builder::new().name("pin-notification".to_string()).spawn(move || {
+				Notification::new()
+					.appname("Moonshine")
+					.summary(&format!("Received pairing request from '{device_name}'."))
+					.action("default", "default")
+					.action("open", "Enter PIN")
+					.show()
+					.map_err(|e| tracing::warn!("Failed to show PIN notification: {e}"))?
+					.wait_for_action(|action| {
+						if action != "__closed" {
+							let _ = open::that(pin_url);
+						}
+					});
 
 				Ok::<(), ()>(())
-		});
+			});
+		}
 	}
 
 	pin_notifier.notified().await;
@@ -285,6 +336,29 @@ async fn server_challenge_response(
 	response
 }
 
+/// Show a pairing request on the host display until the returned sender is dropped.
+fn show_pairing_overlay(device_name: String, pin_url: String) -> std::sync::mpsc::Sender<()> {
+	let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+	let _ = std::thread::Builder::new().name("pin-overlay".to_string()).spawn(move || {
+		let handle = Notification::new()
+			.appname("Moonshine")
+			.summary(&format!("'{device_name}' wants to pair."))
+			.body(&format!("Enter the PIN that '{device_name}' shows at {pin_url}"))
+			.urgency(Urgency::Critical)
+			.timeout(Timeout::Never)
+			.show()
+			.map_err(|e| tracing::warn!("Failed to show pairing overlay: {e}"))?;
+
+		// Nothing is ever sent, this returns once the sender is dropped.
+		let _ = done_rx.recv();
+		handle.close();
+
+		Ok::<(), ()>(())
+	});
+
+	done_tx
+}
+
 async fn pair_challenge(
 	mut params: HashMap<String, String>,
 	client_manager: &ClientManager,
@@ -357,3 +431,20 @@ async fn client_pairing_secret(
 
 	response
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pairing_url_gives_token_to_pairing() {
+		let pairing_token = PairingToken { token: "0123456789abcdef0123456789abcdef".to_string(), pin: "1234".to_string() };
+		let url = url::Url::parse(&pairing_url(IpAddr::from([192, 168, 0, 2]), 47989, &pairing_token)).unwrap();
+
+		// A client passes the parameters of the URL on when it starts pairing, where the token is taken from them.
+		let mut params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+		assert_eq!(params.get("host").map(String::as_str), Some("192.168.0.2"));
+		assert_eq!(params.get("pin").map(String::as_str), Some("1234"));
+		assert_eq!(params.remove(PAIRING_TOKEN_PARAMETER), Some(pairing_token.token));
+	}
+}
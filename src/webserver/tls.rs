@@ -1,20 +1,32 @@
-use std::{path::Path, pin::Pin};
+use std::pin::Pin;
 
-use openssl::ssl::{SslMethod, SslFiletype, SslAcceptor, Ssl};
-use tokio::net::TcpStream;
+use openssl::ssl::{select_next_proto, AlpnError, SslMethod, SslAcceptor, Ssl, SslVerifyMode};
+use tokio::{net::TcpStream, sync::watch};
 use tokio_openssl::SslStream;
 
+use crate::certificate::ServerIdentity;
+
+/// Protocols that are offered to clients during the handshake, in order of preference.
+const ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
 pub struct TlsAcceptor {
 	acceptor: SslAcceptor,
+	identity: watch::Receiver<ServerIdentity>,
 }
 
 impl TlsAcceptor {
-	pub fn from_config<P: AsRef<Path>>(certificate: P, private_key: P) -> Result<Self, ()> {
-		let acceptor = load_tls_files(certificate, private_key)?;
-		Ok(Self { acceptor })
+	pub fn new(mut identity: watch::Receiver<ServerIdentity>) -> Result<Self, ()> {
+		let acceptor = create_acceptor(&identity.borrow_and_update())?;
+		Ok(Self { acceptor, identity })
 	}
 
-	pub async fn accept(&self, connection: TcpStream) -> Result<SslStream<TcpStream>, ()> {
+	pub async fn accept(&mut self, connection: TcpStream) -> Result<SslStream<TcpStream>, ()> {
+		// Use the new certificate for new connections if it changed, existing connections are unaffected.
+		if self.identity.has_changed().unwrap_or(false) {
+			tracing::info!("Server certificate changed, using the new certificate for new connections.");
+			self.acceptor = create_acceptor(&self.identity.borrow_and_update())?;
+		}
+
 		let ssl = Ssl::new(self.acceptor.context())
 			.map_err(|e| tracing::error!("Failed to initialize TLS session: {}", e))?;
 
@@ -27,15 +39,27 @@ impl TlsAcceptor {
 	}
 }
 
-fn load_tls_files<P: AsRef<Path>>(certificate: P, private_key: P) -> Result<SslAcceptor, ()> {
+fn create_acceptor(identity: &ServerIdentity) -> Result<SslAcceptor, ()> {
 	let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls_server())
 		.map_err(|e| tracing::error!("Failed to initialize SSL acceptor: {}", e))?;
 	builder
-		.set_private_key_file(&private_key, SslFiletype::PEM)
-		.map_err(|e| tracing::error!("Failed to set private key file '{:?}': {}", private_key.as_ref(), e))?;
+		.set_private_key(&identity.private_key)
+		.map_err(|e| tracing::error!("Failed to set private key: {}", e))?;
 	builder
-		.set_certificate_chain_file(&certificate)
-		.map_err(|e| tracing::error!("Failed to set certificate file '{:?}': {}", certificate.as_ref(), e))?;
+		.set_certificate(&identity.certificate)
+		.map_err(|e| tracing::error!("Failed to set certificate: {}", e))?;
+
+	// Request a certificate from clients, so that it can be matched against the certificates of paired clients.
+	// Client certificates are self-signed, so they can't be verified against a CA. Whether a certificate is
+	// trusted is decided after the handshake, based on the certificates of paired clients.
+	builder.set_verify_callback(SslVerifyMode::PEER, |_preverified, _context| true);
+	builder.set_session_id_context(b"moonshine")
+		.map_err(|e| tracing::error!("Failed to set TLS session id context: {}", e))?;
+
+	// Clients that don't negotiate a protocol, like Moonlight, continue to use HTTP/1.1.
+	builder.set_alpn_select_callback(|_ssl, client_protocols| {
+		select_next_proto(ALPN_PROTOCOLS, client_protocols).ok_or(AlpnError::NOACK)
+	});
 
 	Ok(builder.build())
 }
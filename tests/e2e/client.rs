@@ -0,0 +1,247 @@
+use std::{
+	io::{Read, Write},
+	net::{SocketAddr, TcpStream},
+	time::{Duration, Instant},
+};
+
+use openssl::{
+	asn1::Asn1Time,
+	hash::MessageDigest,
+	pkey::{PKey, Private},
+	rsa::Rsa,
+	sign::{Signer, Verifier},
+	ssl::{SslConnector, SslMethod, SslVerifyMode},
+	symm::{Cipher, Crypter, Mode},
+	x509::{X509NameBuilder, X509},
+};
+
+use crate::server::TestServer;
+
+/// How long to keep trying to submit the PIN, the server only accepts it once the client started pairing.
+const PIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Response to an HTTP request.
+pub struct HttpResponse {
+	pub status: u16,
+	pub body: String,
+}
+
+impl HttpResponse {
+	/// Content of the first `<name>` element in the body.
+	pub fn tag(&self, name: &str) -> Option<&str> {
+		let start = self.body.find(&format!("<{name}>"))? + name.len() + 2;
+		let end = start + self.body[start..].find(&format!("</{name}>"))?;
+		Some(&self.body[start..end])
+	}
+}
+
+/// A client that talks to moonshine like Moonlight does, with its own identity.
+pub struct Client {
+	pub unique_id: String,
+	key: PKey<Private>,
+	certificate: X509,
+}
+
+impl Client {
+	pub fn new() -> Self {
+		let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+		let mut name = X509NameBuilder::new().unwrap();
+		name.append_entry_by_text("CN", "NVIDIA GameStream Client").unwrap();
+		let name = name.build();
+
+		let mut certificate = X509::builder().unwrap();
+		certificate.set_version(2).unwrap();
+		certificate.set_subject_name(&name).unwrap();
+		certificate.set_issuer_name(&name).unwrap();
+		certificate.set_pubkey(&key).unwrap();
+		certificate.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+		certificate.set_not_after(&Asn1Time::days_from_now(365).unwrap()).unwrap();
+		certificate.sign(&key, MessageDigest::sha256()).unwrap();
+
+		Self {
+			unique_id: hex::encode(random::<8>()),
+			key,
+			certificate: certificate.build(),
+		}
+	}
+
+	/// Send a request to the HTTP server.
+	pub fn get(&self, server: &TestServer, path: &str, params: &[(&str, &str)]) -> HttpResponse {
+		let connection = TcpStream::connect(server.http_address()).expect("failed to connect to HTTP server");
+		self.request(connection, "GET", server.http_address(), path, params)
+	}
+
+	/// Send a POST request to the HTTP server, for requests that only the host may make.
+	pub fn post(&self, server: &TestServer, path: &str, params: &[(&str, &str)]) -> HttpResponse {
+		let connection = TcpStream::connect(server.http_address()).expect("failed to connect to HTTP server");
+		self.request(connection, "POST", server.http_address(), path, params)
+	}
+
+	/// Send a request to the HTTPS server, authenticated with the certificate of the client.
+	pub fn get_https(&self, server: &TestServer, path: &str, params: &[(&str, &str)]) -> Result<HttpResponse, String> {
+		let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+		connector.set_certificate(&self.certificate).unwrap();
+		connector.set_private_key(&self.key).unwrap();
+
+		// The server has a self-signed certificate, which is checked during pairing instead.
+		connector.set_verify(SslVerifyMode::NONE);
+
+		let connection = TcpStream::connect(server.https_address()).expect("failed to connect to HTTPS server");
+		let connection = connector.build()
+			.configure()
+			.unwrap()
+			.verify_hostname(false)
+			.connect("localhost", connection)
+			.map_err(|e| format!("TLS handshake failed: {e}"))?;
+		Ok(self.request(connection, "GET", server.https_address(), path, params))
+	}
+
+	fn request(&self, mut connection: impl Read + Write, method: &str, address: SocketAddr, path: &str, params: &[(&str, &str)]) -> HttpResponse {
+		let mut query = format!("uniqueid={}", self.unique_id);
+		for (key, value) in params {
+			query += &format!("&{key}={value}");
+		}
+
+		let request = format!("{method} {path}?{query} HTTP/1.1\r\nHost: {address}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+		connection.write_all(request.as_bytes()).expect("failed to send HTTP request");
+
+		let mut response = Vec::new();
+		let _ = connection.read_to_end(&mut response);
+		let response = String::from_utf8_lossy(&response);
+
+		let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+		let status = head.split(' ').nth(1)
+			.and_then(|status| status.parse().ok())
+			.unwrap_or_else(|| panic!("invalid HTTP response: {response}"));
+
+		HttpResponse { status, body: body.to_string() }
+	}
+
+	/// Pair with the server, with the user entering `pin` on the host.
+	pub fn pair(&self, server: &TestServer, pin: &str) -> Result<(), String> {
+		self.pair_with_pins(server, pin, pin)
+	}
+
+	/// Pair with the server while the client shows `client_pin` and the user enters `host_pin` on the host.
+	///
+	/// Every step is verified the way Moonlight verifies it, so a server that deviates from the protocol fails here.
+	pub fn pair_with_pins(&self, server: &TestServer, client_pin: &str, host_pin: &str) -> Result<(), String> {
+		let salt = random::<16>();
+		let key = pairing_key(&salt, client_pin);
+
+		// Step 1: exchange certificates, which waits until the user entered the PIN.
+		let server_certificate = std::thread::scope(|scope| {
+			let pin_thread = scope.spawn(|| self.submit_pin(server, host_pin));
+
+			let response = self.get(server, "/pair", &[
+				("phrase", "getservercert"),
+				("devicename", "e2e"),
+				("salt", &hex::encode(salt)),
+				("clientcert", &hex::encode(self.certificate.to_pem().unwrap())),
+			]);
+			pin_thread.join().unwrap()?;
+			let certificate = expect_paired(&response, "getservercert")?.tag("plaincert")
+				.ok_or("no server certificate in response")?;
+			X509::from_pem(&hex::decode(certificate).map_err(|e| e.to_string())?).map_err(|e| e.to_string())
+		})?;
+
+		// Step 2: challenge the server, which answers with a hash that proves it knows the PIN and its own challenge.
+		let client_challenge = random::<16>();
+		let response = self.get(server, "/pair", &[("clientchallenge", &hex::encode(aes_ecb(Mode::Encrypt, &key, &client_challenge)))]);
+		let challenge_response = expect_paired(&response, "clientchallenge")?.tag("challengeresponse")
+			.ok_or("no challenge response in response")?;
+		let challenge_response = aes_ecb(Mode::Decrypt, &key, &hex::decode(challenge_response).map_err(|e| e.to_string())?);
+		if challenge_response.len() != 48 {
+			return Err(format!("expected challenge response of 48 bytes, got {}", challenge_response.len()));
+		}
+		let (server_hash, server_challenge) = challenge_response.split_at(32);
+
+		// Step 3: answer the challenge of the server, the server reveals its secret in return.
+		let client_secret = random::<16>();
+		let client_hash = sha256(&[server_challenge, self.certificate.signature().as_slice(), client_secret.as_slice()].concat());
+		let response = self.get(server, "/pair", &[("serverchallengeresp", &hex::encode(aes_ecb(Mode::Encrypt, &key, &client_hash)))]);
+		let pairing_secret = expect_paired(&response, "serverchallengeresp")?.tag("pairingsecret")
+			.ok_or("no pairing secret in response")?;
+		let pairing_secret = hex::decode(pairing_secret).map_err(|e| e.to_string())?;
+		if pairing_secret.len() < 16 {
+			return Err(format!("expected pairing secret of more than 16 bytes, got {}", pairing_secret.len()));
+		}
+		let (server_secret, server_signature) = pairing_secret.split_at(16);
+
+		// The server must own its certificate, and must have known the PIN to compute its hash.
+		let server_key = server_certificate.public_key().map_err(|e| e.to_string())?;
+		let mut verifier = Verifier::new(MessageDigest::sha256(), &server_key).map_err(|e| e.to_string())?;
+		if !verifier.verify_oneshot(server_signature, server_secret).map_err(|e| e.to_string())? {
+			return Err("server secret is not signed by the server certificate".to_string());
+		}
+		let expected_hash = sha256(&[client_challenge.as_slice(), server_certificate.signature().as_slice(), server_secret].concat());
+		if server_hash != expected_hash {
+			return Err("server hash doesn't match, the PIN is probably incorrect".to_string());
+		}
+
+		// Step 4: prove that the client owns its certificate.
+		let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+		let signature = signer.sign_oneshot_to_vec(&client_secret).unwrap();
+		let response = self.get(server, "/pair", &[("clientpairingsecret", &hex::encode([client_secret.as_slice(), signature.as_slice()].concat()))]);
+		expect_paired(&response, "clientpairingsecret")?;
+
+		// Step 5: the client is paired now, which is confirmed over HTTPS.
+		let response = self.get_https(server, "/pair", &[("phrase", "pairchallenge")])?;
+		expect_paired(&response, "pairchallenge")?;
+
+		Ok(())
+	}
+
+	/// Enter the PIN on the host, retrying until the server waits for it.
+	fn submit_pin(&self, server: &TestServer, pin: &str) -> Result<(), String> {
+		let start = Instant::now();
+		loop {
+			let response = self.post(server, "/submit-pin", &[("pin", pin)]);
+			if response.status == 200 {
+				return Ok(());
+			}
+
+			if start.elapsed() > PIN_TIMEOUT {
+				return Err(format!("failed to submit PIN: {}", response.body));
+			}
+
+			std::thread::sleep(Duration::from_millis(50));
+		}
+	}
+}
+
+fn expect_paired<'a>(response: &'a HttpResponse, step: &str) -> Result<&'a HttpResponse, String> {
+	if response.status != 200 || response.tag("paired") != Some("1") {
+		return Err(format!("pairing step '{step}' failed with status {}: {}", response.status, response.body));
+	}
+
+	Ok(response)
+}
+
+/// Key that the client and the server derive from the salt and the PIN.
+fn pairing_key(salt: &[u8], pin: &str) -> Vec<u8> {
+	sha256(&[salt, pin.as_bytes()].concat())[..16].to_vec()
+}
+
+fn aes_ecb(mode: Mode, key: &[u8], data: &[u8]) -> Vec<u8> {
+	let cipher = Cipher::aes_128_ecb();
+	let mut crypter = Crypter::new(cipher, mode, key, None).unwrap();
+	crypter.pad(false);
+
+	let mut output = vec![0u8; data.len() + cipher.block_size()];
+	let length = crypter.update(data, &mut output).unwrap();
+	let length = length + crypter.finalize(&mut output[length..]).unwrap();
+	output.truncate(length);
+	output
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+	openssl::sha::sha256(data).to_vec()
+}
+
+pub fn random<const N: usize>() -> [u8; N] {
+	let mut buffer = [0u8; N];
+	openssl::rand::rand_bytes(&mut buffer).unwrap();
+	buffer
+}
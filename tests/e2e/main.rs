@@ -0,0 +1,167 @@
+//! End-to-end tests, which start moonshine and talk to it the way Moonlight does.
+//!
+//! The server streams a test pattern, so no X server is needed. Streaming still needs a GPU to encode
+//! and permission to create input devices, so those tests are ignored by default.
+//! Run them on a machine that can stream with `cargo test --test e2e -- --ignored`.
+
+use std::{net::{Ipv4Addr, UdpSocket}, time::{Duration, Instant}};
+
+use client::{random, Client};
+use rtsp::{announce_description, RtspClient};
+use server::TestServer;
+use stream::{ControlClient, Depacketizer};
+
+mod client;
+mod rtsp;
+mod server;
+mod stream;
+
+/// Control messages that the client sends to start the streams and keep the session alive.
+const START_A: u16 = 0x0305;
+const START_B: u16 = 0x0307;
+const PING: u16 = 0x0200;
+
+/// Control message that tells the client the session stopped.
+const TERMINATION: u16 = 0x0109;
+
+/// Number of frames that have to be received before the stream is considered working.
+const EXPECTED_FRAMES: usize = 30;
+
+#[test]
+fn unpaired_client_is_rejected() {
+	let server = TestServer::start();
+	let client = Client::new();
+
+	let response = client.get(&server, "/serverinfo", &[]);
+	assert_eq!(response.status, 200);
+	assert_eq!(response.tag("PairStatus"), Some("0"));
+
+	// The HTTPS server only serves paired clients.
+	if let Ok(response) = client.get_https(&server, "/applist", &[]) {
+		assert_eq!(response.status, 401);
+	}
+}
+
+#[test]
+fn pairing_gives_access_to_applications() {
+	let server = TestServer::start();
+	let client = Client::new();
+
+	client.pair(&server, "1234").unwrap_or_else(|e| panic!("failed to pair: {e}\n{}", server.log()));
+
+	let response = client.get_https(&server, "/serverinfo", &[]).unwrap();
+	assert_eq!(response.tag("PairStatus"), Some("1"));
+	assert_eq!(response.tag("state"), Some("MOONSHINE_SERVER_FREE"));
+
+	let response = client.get_https(&server, "/applist", &[]).unwrap();
+	assert_eq!(response.status, 200);
+	assert_eq!(response.tag("AppTitle"), Some("Desktop"));
+}
+
+#[test]
+fn pairing_with_wrong_pin_fails() {
+	let server = TestServer::start();
+	let client = Client::new();
+
+	// The server and the client derive different keys, which both sides notice.
+	let result = client.pair_with_pins(&server, "1234", "4321");
+	assert!(result.is_err(), "pairing with a wrong PIN succeeded");
+
+	let response = client.get(&server, "/serverinfo", &[]);
+	assert_eq!(response.tag("PairStatus"), Some("0"));
+}
+
+#[test]
+#[ignore = "needs a GPU and permission to create input devices"]
+fn stream_is_received() {
+	let server = TestServer::start();
+	let client = Client::new();
+	client.pair(&server, "1234").unwrap_or_else(|e| panic!("failed to pair: {e}\n{}", server.log()));
+
+	let applications = client.get_https(&server, "/applist", &[]).unwrap();
+	let application_id = applications.tag("ID").expect("no application to launch").to_string();
+
+	let remote_input_key = random::<16>();
+	let response = client.get_https(&server, "/launch", &[
+		("appid", &application_id),
+		("mode", "1280x720x60"),
+		("rikey", &hex::encode(remote_input_key)),
+		("rikeyid", "0"),
+		("localAudioPlayMode", "0"),
+	]).unwrap();
+	assert_eq!(response.status, 200, "failed to launch: {}\n{}", response.body, server.log());
+	assert_eq!(response.tag("sessionUrl0"), Some(format!("rtsp://127.0.0.1:{}", server.rtsp_port).as_str()));
+
+	// Set up the streams like Moonlight does.
+	let mut rtsp = RtspClient::new(server.rtsp_port);
+	assert_eq!(rtsp.request("OPTIONS", &format!("rtsp://127.0.0.1:{}", server.rtsp_port), &[], "").status, 200);
+	let description = rtsp.request("DESCRIBE", &format!("rtsp://127.0.0.1:{}", server.rtsp_port), &[], "");
+	assert!(description.body.contains("sprop-parameter-sets"), "unexpected description: {}", description.body);
+	let audio = rtsp.setup("audio");
+	let video = rtsp.setup("video");
+	let control = rtsp.setup("control");
+	let announce = rtsp.request("ANNOUNCE", "streamid=control/13/0", &[], &announce_description(1280, 720, 60, 1024, 10_000));
+	assert_eq!(announce.status, 200, "ANNOUNCE failed:\n{}", server.log());
+	assert_eq!(rtsp.request("PLAY", "/", &[], "").status, 200, "PLAY failed:\n{}", server.log());
+
+	// Tell the host where to send the streams.
+	let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+	socket.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+	let ping = |port: u16, payload: &Option<String>, sequence_number: u32| {
+		let payload = payload.as_ref().expect("no ping payload in SETUP response");
+		let message = [payload.as_bytes(), &sequence_number.to_be_bytes()].concat();
+		socket.send_to(&message, (Ipv4Addr::LOCALHOST, port)).unwrap();
+	};
+
+	let enet = enet::Enet::new().expect("failed to initialize enet");
+	let mut control = ControlClient::connect(&enet, control.port, &remote_input_key);
+	control.send(START_A, &[0, 0]);
+	control.send(START_B, &[0, 0, 0, 0]);
+
+	let mut depacketizer = Depacketizer::default();
+	let mut frames = Vec::new();
+	let mut buffer = vec![0u8; 2048];
+	let start = Instant::now();
+	let mut next_ping = start;
+	while frames.len() < EXPECTED_FRAMES {
+		assert!(start.elapsed() < Duration::from_secs(15), "received {} frames:\n{}", frames.len(), server.log());
+
+		if Instant::now() >= next_ping {
+			let sequence_number = start.elapsed().as_millis() as u32;
+			ping(video.port, &video.ping_payload, sequence_number);
+			ping(audio.port, &audio.ping_payload, sequence_number);
+			control.send(PING, &[0, 0, 0, 0]);
+			next_ping += Duration::from_millis(500);
+		}
+
+		// Audio packets arrive on another port, only video is received on this socket.
+		if let Ok((length, address)) = socket.recv_from(&mut buffer) {
+			if address.port() == video.port {
+				if let Some(frame) = depacketizer.push(&buffer[..length]).unwrap() {
+					frames.push(frame);
+				}
+			}
+		}
+		control.receive(Duration::ZERO);
+	}
+
+	// The stream starts with a key frame in Annex B format, followed by consecutive frames.
+	assert!(frames[0].key_frame, "first frame is not a key frame");
+	for frame in &frames {
+		assert!(frame.data.starts_with(&[0, 0, 0, 1]) || frame.data.starts_with(&[0, 0, 1]), "frame {} isn't in Annex B format", frame.index);
+	}
+	for pair in frames.windows(2) {
+		assert_eq!(pair[1].index, pair[0].index + 1, "frames are not consecutive");
+	}
+
+	// Stopping the session tells the client over the encrypted control stream.
+	let response = client.get_https(&server, "/cancel", &[]).unwrap();
+	assert_eq!(response.status, 200);
+	let start = Instant::now();
+	loop {
+		assert!(start.elapsed() < Duration::from_secs(5), "no termination message received:\n{}", server.log());
+		if let Some((TERMINATION, _)) = control.receive(Duration::from_millis(100)) {
+			break;
+		}
+	}
+}
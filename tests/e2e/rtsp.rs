@@ -0,0 +1,114 @@
+use std::{
+	io::{Read, Write},
+	net::{Ipv4Addr, TcpStream},
+};
+
+/// Response to an RTSP request.
+pub struct RtspResponse {
+	pub status: u16,
+	headers: Vec<(String, String)>,
+	pub body: String,
+}
+
+impl RtspResponse {
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.iter()
+			.find(|(header, _)| header.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_str())
+	}
+}
+
+/// Ports and ping payload that the server gave for a stream in its SETUP response.
+pub struct StreamSetup {
+	pub port: u16,
+	pub ping_payload: Option<String>,
+}
+
+/// Sets up the streams of a session over RTSP, sending one request per connection like Moonlight does.
+pub struct RtspClient {
+	port: u16,
+	sequence_number: u32,
+}
+
+impl RtspClient {
+	pub fn new(port: u16) -> Self {
+		Self { port, sequence_number: 0 }
+	}
+
+	pub fn request(&mut self, method: &str, target: &str, headers: &[(&str, &str)], body: &str) -> RtspResponse {
+		self.sequence_number += 1;
+
+		let mut request = format!("{method} {target} RTSP/1.0\r\nCSeq: {}\r\nX-GS-ClientVersion: 14\r\n", self.sequence_number);
+		for (name, value) in headers {
+			request += &format!("{name}: {value}\r\n");
+		}
+		if !body.is_empty() {
+			request += &format!("Content-Length: {}\r\n", body.len());
+		}
+		request += "\r\n";
+		request += body;
+
+		let mut connection = TcpStream::connect((Ipv4Addr::LOCALHOST, self.port)).expect("failed to connect to RTSP server");
+		connection.write_all(request.as_bytes()).expect("failed to send RTSP request");
+
+		let mut response = Vec::new();
+		let _ = connection.read_to_end(&mut response);
+		let response = String::from_utf8_lossy(&response);
+
+		let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+		let mut lines = head.lines();
+		let status = lines.next()
+			.and_then(|line| line.split(' ').nth(1))
+			.and_then(|status| status.parse().ok())
+			.unwrap_or_else(|| panic!("invalid RTSP response to {method}: {response}"));
+		let headers = lines
+			.filter_map(|line| line.split_once(':'))
+			.map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+			.collect();
+
+		let response = RtspResponse { status, headers, body: body.to_string() };
+		assert_eq!(response.header("CSeq"), Some(self.sequence_number.to_string().as_str()), "CSeq of {method} response doesn't match");
+		response
+	}
+
+	/// Set up a stream (`video`, `audio` or `control`).
+	pub fn setup(&mut self, stream: &str) -> StreamSetup {
+		let response = self.request(
+			"SETUP",
+			&format!("streamid={stream}/0/0"),
+			&[("Transport", "unicast;X-GS-ClientPort=50000-50001")],
+			"",
+		);
+		assert_eq!(response.status, 200, "SETUP of {stream} failed");
+
+		let port = response.header("Transport")
+			.and_then(|transport| transport.strip_prefix("server_port="))
+			.and_then(|port| port.parse().ok())
+			.unwrap_or_else(|| panic!("no server port in SETUP response for {stream}"));
+
+		StreamSetup { port, ping_payload: response.header("X-SS-Ping-Payload").map(str::to_string) }
+	}
+}
+
+/// Description of the streams that the client wants, as Moonlight sends it in its ANNOUNCE request.
+pub fn announce_description(width: u32, height: u32, fps: u32, packet_size: usize, bitrate_kbps: u32) -> String {
+	let attributes = [
+		("x-nv-video[0].clientViewportWd", width.to_string()),
+		("x-nv-video[0].clientViewportHt", height.to_string()),
+		("x-nv-video[0].maxFPS", fps.to_string()),
+		("x-nv-video[0].packetSize", packet_size.to_string()),
+		("x-ml-video.configuredBitrateKbps", bitrate_kbps.to_string()),
+		("x-nv-vqos[0].fec.minRequiredFecPackets", "2".to_string()),
+		("x-nv-vqos[0].qosTrafficType", "0".to_string()),
+		("x-nv-vqos[0].bitStreamFormat", "0".to_string()),
+		("x-nv-aqos.packetDuration", "5".to_string()),
+		("x-nv-aqos.qosTrafficType", "0".to_string()),
+	];
+
+	let mut description = "v=0\r\no=android 0 14 IN IPv4 127.0.0.1\r\ns=NVIDIA Streaming Client\r\nt=0 0\r\n".to_string();
+	for (name, value) in attributes {
+		description += &format!("a={name}:{value} \r\n");
+	}
+	description += "m=video 47998 RTP/AVP 96\r\n";
+	description
+}
@@ -0,0 +1,144 @@
+use std::{
+	fs::File,
+	net::{Ipv4Addr, SocketAddr, TcpStream},
+	path::PathBuf,
+	process::{Child, Command, Stdio},
+	sync::atomic::{AtomicU16, Ordering},
+	time::{Duration, Instant},
+};
+
+/// Number of ports that every server uses: HTTP, HTTPS and the four ports of the streams.
+const PORTS_PER_SERVER: u16 = 6;
+
+/// How long to wait for moonshine to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Servers started by this process, used to give every server its own ports and directory.
+static SERVER_COUNT: AtomicU16 = AtomicU16::new(0);
+
+/// A moonshine process with its own configuration, state and certificate, which is stopped when dropped.
+pub struct TestServer {
+	process: Child,
+	directory: PathBuf,
+	pub http_port: u16,
+	pub https_port: u16,
+	pub rtsp_port: u16,
+}
+
+impl TestServer {
+	pub fn start() -> Self {
+		let index = SERVER_COUNT.fetch_add(1, Ordering::Relaxed);
+
+		// Spread the ports of concurrently running test processes, so that they don't collide.
+		let http_port = 30000 + (std::process::id() % 500) as u16 * 40 + index * PORTS_PER_SERVER;
+		let https_port = http_port + 1;
+		let rtsp_port = http_port + 2;
+
+		let directory = std::env::temp_dir().join(format!("moonshine-e2e-{}-{index}", std::process::id()));
+		std::fs::create_dir_all(&directory).expect("failed to create directory for test server");
+
+		let config = format!(
+			r#"
+name = "Moonshine E2E"
+address = "127.0.0.1"
+stream_timeout = 10
+
+[webserver]
+port = {http_port}
+port_https = {https_port}
+certificate = "{directory}/cert.pem"
+private_key = "{directory}/key.pem"
+
+[mdns]
+backend = "builtin"
+
+[stream]
+port = {rtsp_port}
+port_base = {rtsp_port}
+
+[stream.video]
+port = {video_port}
+capture = "test_pattern"
+codec_h264 = "h264_nvenc"
+codec_hevc = "hevc_nvenc"
+fec_percentage = 20
+
+[stream.audio]
+port = {audio_port}
+
+[stream.control]
+port = {control_port}
+
+[[application]]
+title = "Desktop"
+"#,
+			directory = directory.display(),
+			video_port = rtsp_port + 1,
+			control_port = rtsp_port + 2,
+			audio_port = rtsp_port + 3,
+		);
+		let config_path = directory.join("config.toml");
+		std::fs::write(&config_path, config).expect("failed to write config of test server");
+
+		let log = File::create(directory.join("moonshine.log")).expect("failed to create log of test server");
+		let process = Command::new(env!("CARGO_BIN_EXE_moonshine"))
+			.arg(&config_path)
+			.env("XDG_DATA_HOME", directory.join("data"))
+			.env("RUST_LOG", "moonshine=debug")
+			.stdin(Stdio::null())
+			.stdout(log.try_clone().expect("failed to share log of test server"))
+			.stderr(log)
+			.spawn()
+			.expect("failed to start moonshine");
+
+		let mut server = Self { process, directory, http_port, https_port, rtsp_port };
+		server.wait_until_listening();
+		server
+	}
+
+	pub fn http_address(&self) -> SocketAddr {
+		(Ipv4Addr::LOCALHOST, self.http_port).into()
+	}
+
+	pub fn https_address(&self) -> SocketAddr {
+		(Ipv4Addr::LOCALHOST, self.https_port).into()
+	}
+
+	/// Output of moonshine so far, to explain why a test failed.
+	pub fn log(&self) -> String {
+		std::fs::read_to_string(self.directory.join("moonshine.log")).unwrap_or_default()
+	}
+
+	fn wait_until_listening(&mut self) {
+		let start = Instant::now();
+		loop {
+			if let Ok(Some(status)) = self.process.try_wait() {
+				panic!("moonshine exited with {status} before it was listening:\n{}", self.log());
+			}
+
+			let listening = [self.http_address(), self.https_address()].iter()
+				.all(|address| TcpStream::connect_timeout(address, Duration::from_millis(100)).is_ok());
+			if listening {
+				return;
+			}
+
+			if start.elapsed() > STARTUP_TIMEOUT {
+				panic!("moonshine wasn't listening after {STARTUP_TIMEOUT:?}:\n{}", self.log());
+			}
+
+			std::thread::sleep(Duration::from_millis(100));
+		}
+	}
+}
+
+impl Drop for TestServer {
+	fn drop(&mut self) {
+		let _ = self.process.kill();
+		let _ = self.process.wait();
+
+		// Keep the log around when the test failed.
+		if !std::thread::panicking() {
+			let _ = std::fs::remove_dir_all(&self.directory);
+		}
+	}
+}
@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, net::Ipv4Addr, time::{Duration, Instant}};
+
+use enet::{Address, BandwidthLimit, ChannelLimit, Enet, Event, Host, Packet, PacketMode, PeerState};
+use openssl::symm::Cipher;
+
+/// Size of the RTP header, the padding and the NVIDIA video header in front of every video shard.
+const VIDEO_HEADER_SIZE: usize = 12 + 4 + 16;
+
+/// Size of the header in front of the data of a frame, which tells whether it is a key frame.
+const FRAME_HEADER_SIZE: usize = 8;
+
+/// Type of a control message that wraps an encrypted message.
+const ENCRYPTED: u16 = 0x0001;
+
+/// Length of the tag of an encrypted control message.
+const TAG_LENGTH: usize = 16;
+
+/// An encoded frame that was put back together from its shards.
+pub struct Frame {
+	pub index: u32,
+	pub key_frame: bool,
+
+	/// Encoded data, followed by the padding of the last shard.
+	pub data: Vec<u8>,
+}
+
+/// Shards of a frame that was not completely received yet.
+#[derive(Default)]
+struct PartialFrame {
+	/// Index of the last FEC block of the frame.
+	last_block: u8,
+
+	/// Number of data shards in each block that was seen so far.
+	data_shards: BTreeMap<u8, usize>,
+
+	/// Payload of the data shards by block and index in the block.
+	shards: BTreeMap<(u8, usize), Vec<u8>>,
+}
+
+impl PartialFrame {
+	fn is_complete(&self) -> bool {
+		(0..=self.last_block).all(|block| {
+			self.data_shards.get(&block)
+				.is_some_and(|&data_shards| (0..data_shards).all(|index| self.shards.contains_key(&(block, index))))
+		})
+	}
+}
+
+/// Puts frames back together from the shards of the video stream, like Moonlight does.
+///
+/// Parity shards are skipped, a loopback client receives every data shard.
+#[derive(Default)]
+pub struct Depacketizer {
+	frames: BTreeMap<u32, PartialFrame>,
+}
+
+impl Depacketizer {
+	/// Add a shard, returning the frame it belongs to once all of its data shards arrived.
+	pub fn push(&mut self, packet: &[u8]) -> Result<Option<Frame>, String> {
+		if packet.len() < VIDEO_HEADER_SIZE {
+			return Err(format!("video packet of {} bytes is too small", packet.len()));
+		}
+		if packet[0] != 0x90 {
+			return Err(format!("unexpected RTP header {:#04x}", packet[0]));
+		}
+
+		let video_header = &packet[16..VIDEO_HEADER_SIZE];
+		let frame_index = u32::from_le_bytes(video_header[4..8].try_into().unwrap());
+		let multi_fec_blocks = video_header[11];
+		let fec_info = u32::from_le_bytes(video_header[12..16].try_into().unwrap());
+
+		let block = (multi_fec_blocks >> 4) & 0x3;
+		let shard_index = ((fec_info >> 12) & 0x3FF) as usize;
+		let data_shards = ((fec_info >> 22) & 0x3FF) as usize;
+		if shard_index >= data_shards {
+			return Ok(None);
+		}
+
+		let frame = self.frames.entry(frame_index).or_default();
+		frame.last_block = multi_fec_blocks >> 6;
+		frame.data_shards.insert(block, data_shards);
+		frame.shards.insert((block, shard_index), packet[VIDEO_HEADER_SIZE..].to_vec());
+		if !frame.is_complete() {
+			return Ok(None);
+		}
+
+		let frame = self.frames.remove(&frame_index).unwrap();
+		let data: Vec<u8> = frame.shards.into_values().flatten().collect();
+		if data.len() < FRAME_HEADER_SIZE || data[0] != 0x01 {
+			return Err(format!("frame {frame_index} doesn't start with a frame header"));
+		}
+
+		Ok(Some(Frame {
+			index: frame_index,
+			key_frame: data[3] == 2,
+			data: data[FRAME_HEADER_SIZE..].to_vec(),
+		}))
+	}
+}
+
+/// Client side of the encrypted control stream.
+pub struct ControlClient {
+	host: Host<()>,
+	key: Vec<u8>,
+	sequence_number: u32,
+}
+
+impl ControlClient {
+	/// Connect to the control stream of the session that was launched with `key`.
+	pub fn connect(enet: &Enet, port: u16, key: &[u8]) -> Self {
+		let mut host = enet.create_host::<()>(None, 1, ChannelLimit::Maximum, BandwidthLimit::Unlimited, BandwidthLimit::Unlimited)
+			.expect("failed to create enet host");
+		host.connect(&Address::new(Ipv4Addr::LOCALHOST, port), 1, 0)
+			.expect("failed to connect to control stream");
+
+		let start = Instant::now();
+		loop {
+			if let Ok(Some(Event::Connect(_))) = host.service(100) {
+				break;
+			}
+			assert!(start.elapsed() < Duration::from_secs(5), "failed to connect to control stream");
+		}
+
+		Self { host, key: key.to_vec(), sequence_number: 0 }
+	}
+
+	/// Encrypt a message and send it to the host.
+	pub fn send(&mut self, message_type: u16, payload: &[u8]) {
+		let mut message = Vec::with_capacity(4 + payload.len());
+		message.extend(message_type.to_le_bytes());
+		message.extend((payload.len() as u16).to_le_bytes());
+		message.extend(payload);
+
+		let mut tag = [0u8; TAG_LENGTH];
+		let encrypted = openssl::symm::encrypt_aead(
+			Cipher::aes_128_gcm(),
+			&self.key,
+			Some(&control_iv(self.sequence_number)),
+			&[],
+			&message,
+			&mut tag,
+		).expect("failed to encrypt control message");
+
+		let mut buffer = Vec::with_capacity(8 + TAG_LENGTH + encrypted.len());
+		buffer.extend(ENCRYPTED.to_le_bytes());
+		buffer.extend(((4 + TAG_LENGTH + encrypted.len()) as u16).to_le_bytes());
+		buffer.extend(self.sequence_number.to_le_bytes());
+		buffer.extend(tag);
+		buffer.extend(encrypted);
+		self.sequence_number += 1;
+
+		let packet = Packet::new(&buffer, PacketMode::ReliableSequenced).expect("failed to create control packet");
+		self.host.peers()
+			.find(|peer| matches!(peer.state(), PeerState::Connected))
+			.expect("not connected to the control stream")
+			.send_packet(packet, 0)
+			.expect("failed to send control message");
+		self.host.flush();
+	}
+
+	/// Service the connection for at most `timeout`, returning the type and payload of a message from the host.
+	pub fn receive(&mut self, timeout: Duration) -> Option<(u16, Vec<u8>)> {
+		let buffer = match self.host.service(timeout.as_millis() as u32) {
+			Ok(Some(Event::Receive { ref packet, .. })) => packet.data().to_vec(),
+			_ => return None,
+		};
+
+		assert!(buffer.len() >= 8 + TAG_LENGTH, "control message of {} bytes is too small", buffer.len());
+		assert_eq!(u16::from_le_bytes([buffer[0], buffer[1]]), ENCRYPTED, "host sent an unencrypted control message");
+		let sequence_number = u32::from_le_bytes(buffer[4..8].try_into().unwrap());
+		let decrypted = openssl::symm::decrypt_aead(
+			Cipher::aes_128_gcm(),
+			&self.key,
+			Some(&control_iv(sequence_number)),
+			&[],
+			&buffer[8 + TAG_LENGTH..],
+			&buffer[8..8 + TAG_LENGTH],
+		).expect("failed to decrypt control message from the host");
+
+		assert!(decrypted.len() >= 4, "decrypted control message is too small");
+		Some((u16::from_le_bytes([decrypted[0], decrypted[1]]), decrypted[4..].to_vec()))
+	}
+}
+
+/// Initialization vector of a control message, only the lowest byte of the sequence number is used.
+fn control_iv(sequence_number: u32) -> [u8; 16] {
+	let mut iv = [0u8; 16];
+	iv[0] = sequence_number as u8;
+	iv
+}